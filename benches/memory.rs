@@ -0,0 +1,57 @@
+//! Reports how many bytes a parsed exposition keeps alive, with and without label interning.
+//! Run with `cargo bench --bench memory`
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicIsize, Ordering},
+};
+
+use openmetrics_parser::{prometheus::parse_prometheus_with_options, ParseOptions};
+
+struct CountingAllocator;
+
+static LIVE_BYTES: AtomicIsize = AtomicIsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        LIVE_BYTES.fetch_add(layout.size() as isize, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        LIVE_BYTES.fetch_sub(layout.size() as isize, Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn retained_bytes(input: &str, options: &ParseOptions) -> isize {
+    let before = LIVE_BYTES.load(Ordering::Relaxed);
+    let exposition = parse_prometheus_with_options(input, options).unwrap();
+    let retained = LIVE_BYTES.load(Ordering::Relaxed) - before;
+    drop(exposition);
+
+    retained
+}
+
+fn main() {
+    let input = include_str!("../src/prometheus/testdata/node_exporter.txt");
+
+    let plain = retained_bytes(input, &ParseOptions::default());
+    let interned = retained_bytes(
+        input,
+        &ParseOptions {
+            intern: true,
+            ..ParseOptions::default()
+        },
+    );
+
+    println!("node_exporter exposition retains {} bytes", plain);
+    println!(
+        "node_exporter exposition retains {} bytes with interning ({:.1}% less)",
+        interned,
+        (plain - interned) as f64 / plain as f64 * 100.
+    );
+}
@@ -0,0 +1,65 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use openmetrics_parser::{openmetrics::parse_openmetrics, prometheus::parse_prometheus};
+
+/// Builds a scrape with a few high cardinality families, each sample having a realistic number of labels
+fn large_exposition(num_series: usize) -> String {
+    let mut out = String::new();
+
+    out.push_str(
+        "# HELP http_requests_in_flight The number of HTTP requests currently being served.\n",
+    );
+    out.push_str("# TYPE http_requests_in_flight gauge\n");
+    for i in 0..num_series {
+        out.push_str(&format!(
+            "http_requests_in_flight{{method=\"post\",code=\"{}\",handler=\"/api/v{}\",instance=\"host-{}:9090\",job=\"api\"}} {}\n",
+            200 + i % 5,
+            i % 7,
+            i,
+            i * 3
+        ));
+    }
+
+    out.push_str("# HELP http_request_duration_seconds A histogram of the request duration.\n");
+    out.push_str("# TYPE http_request_duration_seconds histogram\n");
+    for i in 0..num_series / 10 {
+        let labels = format!(
+            "handler=\"/api/v{}\",instance=\"host-{}:9090\",job=\"api\"",
+            i % 7,
+            i
+        );
+        for (j, le) in ["0.05", "0.1", "0.5", "1", "+Inf"].iter().enumerate() {
+            out.push_str(&format!(
+                "http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}\n",
+                labels,
+                le,
+                j * 10
+            ));
+        }
+        out.push_str(&format!(
+            "http_request_duration_seconds_sum{{{}}} 53423\n",
+            labels
+        ));
+        out.push_str(&format!(
+            "http_request_duration_seconds_count{{{}}} 40\n",
+            labels
+        ));
+    }
+
+    out
+}
+
+fn bench_parsers(c: &mut Criterion) {
+    let prometheus = large_exposition(2_000);
+    let openmetrics = format!("{}# EOF\n", prometheus);
+
+    c.bench_function("parse_prometheus", |b| {
+        b.iter(|| parse_prometheus(black_box(&prometheus)).unwrap())
+    });
+
+    c.bench_function("parse_openmetrics", |b| {
+        b.iter(|| parse_openmetrics(black_box(&openmetrics)).unwrap())
+    });
+}
+
+criterion_group!(benches, bench_parsers);
+criterion_main!(benches);
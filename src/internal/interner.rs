@@ -0,0 +1,36 @@
+use std::{collections::HashSet, sync::Arc};
+
+use crate::SharedString;
+
+/// Deduplicates the label names and values of the families in an exposition. Each distinct string
+/// (e.g. a `device` name, or a `cpu="0"` value repeated across dozens of node_exporter families) is kept once,
+/// and families with the same label names (e.g. all the `{device}` families) share one list of them
+#[derive(Debug, Default)]
+pub struct LabelInterner {
+    strings: HashSet<SharedString>,
+    label_names: HashSet<Arc<Vec<SharedString>>>,
+}
+
+impl LabelInterner {
+    pub fn intern(&mut self, s: &SharedString) -> SharedString {
+        if let Some(existing) = self.strings.get(s) {
+            return existing.clone();
+        }
+
+        self.strings.insert(s.clone());
+        s.clone()
+    }
+
+    pub fn intern_label_names(
+        &mut self,
+        label_names: &Arc<Vec<SharedString>>,
+    ) -> Arc<Vec<SharedString>> {
+        if let Some(existing) = self.label_names.get(label_names) {
+            return existing.clone();
+        }
+
+        let interned = Arc::new(label_names.iter().map(|name| self.intern(name)).collect());
+        self.label_names.insert(Arc::clone(&interned));
+        interned
+    }
+}
@@ -1,11 +1,21 @@
-use std::fmt;
+use std::{borrow::Cow, collections::HashSet, fmt};
+
+use smallvec::SmallVec;
 
 use crate::{
-    CounterValue, Exemplar, HistogramValue, MetricNumber, ParseError, PrometheusCounterValue,
-    SummaryValue, Timestamp,
+    format_float, CounterValue, Exemplar, HistogramValue, MetricNumber, ParseError, ParseOptions,
+    PrometheusCounterValue, RawTimestamp, SharedString, SummaryValue, Timestamp,
 };
 
-use super::MetricsType;
+use super::{parse_bucket_bound, unescape_help, units_not_allowed, MetricsType};
+
+/// Label names and values are stored inline for the common case of a handful of labels,
+/// to avoid a heap allocation per sample
+pub type LabelValues = SmallVec<[SharedString; 8]>;
+
+/// The labels of a sample line as they're parsed, sorted by name. Names and values borrow from the input unless
+/// they had escapes in them
+pub type ParsedLabels<'a> = SmallVec<[(Cow<'a, str>, Cow<'a, str>); 8]>;
 
 #[derive(Debug)]
 pub enum MetricValueMarshal {
@@ -56,8 +66,60 @@ where
     pub help: Option<String>,
     pub unit: Option<String>,
     pub metrics: Vec<MetricMarshal>,
-    pub seen_label_sets: Vec<Vec<String>>,
-    pub current_label_set: Option<Vec<String>>,
+    pub seen_label_sets: Vec<LabelValues>,
+    pub current_label_set: Option<LabelValues>,
+    pub seen_lines: HashSet<String>,
+    pub line_positions: Vec<LinePosition>,
+    pub options: ParseOptions,
+}
+
+/// Checks that a histogram's bucket bounds are unique, which also means there can only be one +Inf bucket
+pub fn check_bucket_bounds(histogram: &HistogramValue) -> Result<(), ParseError> {
+    let buckets = &histogram.buckets;
+    for (i, bucket) in buckets.iter().enumerate() {
+        if let Some(previous) = buckets[..i]
+            .iter()
+            .find(|b| b.upper_bound == bucket.upper_bound)
+        {
+            // Both lines having exemplars is the more specific problem, so is reported over the duplicate bound
+            if previous.exemplar.is_some() && bucket.exemplar.is_some() {
+                return Err(ParseError::InvalidMetric(format!(
+                    "Histogram buckets can't have more than one exemplar (got two on the {} bucket)",
+                    format_float(bucket.upper_bound)
+                )));
+            }
+
+            return Err(ParseError::InvalidMetric(format!(
+                "Histograms can't have duplicate bucket bounds (got {} twice)",
+                format_float(bucket.upper_bound)
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that a histogram or summary has both a sum and a count, or neither
+pub fn check_sum_and_count(has_sum: bool, has_count: bool) -> Result<(), ParseError> {
+    match (has_sum, has_count) {
+        (true, false) => Err(ParseError::InvalidMetric(
+            "Count must be present if sum is present".to_owned(),
+        )),
+        (false, true) => Err(ParseError::InvalidMetric(
+            "Sum must be present if count is present".to_owned(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Where a sample line ended up in its family, recorded as it's parsed when `preserve_line_order` is set: the
+/// labels of the sample it was added to, the suffix of its name (e.g. `_bucket`), and its `le` or `quantile`
+/// bound, if it has one
+#[derive(Debug, Clone)]
+pub struct LinePosition {
+    pub label_values: LabelValues,
+    pub suffix: &'static str,
+    pub bound: Option<f64>,
 }
 
 impl<T> MetricFamilyMarshal<T>
@@ -65,6 +127,10 @@ where
     T: MetricsType + Clone + Default + fmt::Debug,
 {
     pub fn empty() -> MetricFamilyMarshal<T> {
+        MetricFamilyMarshal::with_options(ParseOptions::default())
+    }
+
+    pub fn with_options(options: ParseOptions) -> MetricFamilyMarshal<T> {
         MetricFamilyMarshal {
             name: None,
             label_names: None,
@@ -74,17 +140,208 @@ where
             metrics: Vec::new(),
             seen_label_sets: Vec::new(),
             current_label_set: None,
+            seen_lines: HashSet::new(),
+            line_positions: Vec::new(),
+            options,
+        }
+    }
+
+    /// Returns whether the given sample line should be skipped, because it's identical to an earlier one and the
+    /// `dedupe_identical` option is set
+    pub fn is_repeated_line(&mut self, line: &str) -> bool {
+        if !self.options.dedupe_identical {
+            return false;
+        }
+
+        let line = line.trim_end();
+        if self.seen_lines.contains(line) {
+            return true;
+        }
+
+        self.seen_lines.insert(line.to_owned());
+        false
+    }
+
+    /// Applies the `max_label_value_len` option to a sample's label values, truncating the values that are too
+    /// long if `truncate_label_values` is set, and returning an error for them otherwise. The type's reserved labels
+    /// (e.g. `le`) are left alone, as cutting them down would change the bound they hold
+    pub fn limit_label_values(
+        &self,
+        metric_type: &T,
+        label_names: &[SharedString],
+        mut label_values: LabelValues,
+    ) -> Result<LabelValues, ParseError> {
+        let max_len = match self.options.max_label_value_len {
+            Some(max_len) => max_len,
+            None => return Ok(label_values),
+        };
+
+        let reserved_labels = metric_type.get_reserved_labels();
+        for (name, value) in label_names.iter().zip(label_values.iter_mut()) {
+            if value.len() <= max_len || reserved_labels.contains(&name.as_str()) {
+                continue;
+            }
+
+            if !self.options.truncate_label_values {
+                return Err(ParseError::InvalidMetric(format!(
+                    "Label value for {} is {} bytes long, which is over the maximum of {}",
+                    name,
+                    value.len(),
+                    max_len
+                )));
+            }
+
+            let mut end = max_len;
+            while !value.is_char_boundary(end) {
+                end -= 1;
+            }
+            *value = SharedString::from(&value[..end]);
+        }
+
+        Ok(label_values)
+    }
+
+    /// Records where a sample line ended up, if the `preserve_line_order` option is set. `label_values` are those of
+    /// the sample it was added to, and `bound_label` names the label holding its `le` or `quantile` bound, if any
+    pub fn record_line_position(
+        &mut self,
+        label_values: LabelValues,
+        suffix: &'static str,
+        bound_label: Option<&str>,
+        line_label_names: &[SharedString],
+        line_label_values: &[SharedString],
+    ) {
+        if !self.options.preserve_line_order {
+            return;
+        }
+
+        let bound = bound_label.and_then(|label| {
+            let index = line_label_names.iter().position(|s| s == label)?;
+            parse_bucket_bound(&line_label_values[index])
+        });
+
+        self.line_positions.push(LinePosition {
+            label_values,
+            suffix,
+            bound,
+        });
+    }
+
+    /// Checks that a sample is allowed an exemplar, if it has one. Which samples can have them depends on the
+    /// family's type and the sample's suffix, e.g. only the `_bucket` samples of a histogram
+    pub fn check_exemplar_allowed(
+        &self,
+        metric_type: &T,
+        metric_name: &str,
+        exemplar: Option<&Exemplar>,
+    ) -> Result<(), ParseError> {
+        if exemplar.is_some() && !metric_type.can_have_exemplar(metric_name) {
+            return Err(ParseError::InvalidMetric(format!(
+                "Metric Type {:?} is not allowed exemplars",
+                metric_type
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// In strict mode, checks that a histogram's +Inf bucket matches its `_count`, as they both count every
+    /// observation
+    pub fn check_inf_bucket_count(&self, histogram: &HistogramValue) -> Result<(), ParseError> {
+        if !self.options.strict {
+            return Ok(());
+        }
+
+        if let Some(count) = histogram.count {
+            let inf_bucket = match histogram
+                .buckets
+                .iter()
+                .find(|b| b.upper_bound == f64::INFINITY)
+            {
+                Some(bucket) => bucket,
+                None => {
+                    return Err(ParseError::InvalidMetric(format!(
+                        "Histograms must have a +INF bucket: {:?}",
+                        histogram.buckets
+                    )))
+                }
+            };
+
+            if inf_bucket.count.as_u64() != Some(count) {
+                return Err(ParseError::InvalidMetric(format!(
+                    "Histogram +Inf bucket count ({}) doesn't match its count ({})",
+                    inf_bucket.count, count
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that a summary doesn't repeat a quantile and, in strict mode, that its quantiles are sorted and
+    /// their values don't decrease
+    pub fn check_quantiles(&self, summary: &SummaryValue) -> Result<(), ParseError> {
+        let quantiles = &summary.quantiles;
+        for (i, quantile) in quantiles.iter().enumerate() {
+            if quantiles[..i]
+                .iter()
+                .any(|q| q.quantile == quantile.quantile)
+            {
+                return Err(ParseError::InvalidMetric(format!(
+                    "Summaries can't have duplicate quantiles (got {} twice)",
+                    quantile.quantile
+                )));
+            }
+        }
+
+        if !self.options.strict {
+            return Ok(());
         }
+
+        let mut last_quantile = f64::NEG_INFINITY;
+        let mut last_value = f64::NEG_INFINITY;
+        for quantile in quantiles {
+            if quantile.quantile < last_quantile {
+                return Err(ParseError::InvalidMetric(format!(
+                    "Summary quantiles must be sorted (got {} after {})",
+                    quantile.quantile, last_quantile
+                )));
+            }
+
+            // NaN values indicate that there haven't been any observations, so don't take part in the ordering
+            let value = quantile.value.as_f64();
+            if value < last_value {
+                return Err(ParseError::InvalidMetric(format!(
+                    "Summary quantile values must not decrease (got {} for quantile {} after {})",
+                    value, quantile.quantile, last_value
+                )));
+            }
+
+            last_quantile = quantile.quantile;
+            if !value.is_nan() {
+                last_value = value;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Summaries don't have to have a sum or count, but strict consumers want both if they have either
+    pub fn check_summary_sum_and_count(&self, summary: &SummaryValue) -> Result<(), ParseError> {
+        if self.options.strict {
+            check_sum_and_count(summary.sum.is_some(), summary.count.is_some())?;
+        }
+
+        Ok(())
     }
 
     pub fn get_metric_by_labelset_mut(
         &mut self,
-        label_values: &[String],
+        label_values: &[SharedString],
     ) -> Option<&mut MetricMarshal> {
-        return self
-            .metrics
+        self.metrics
             .iter_mut()
-            .find(|m| m.label_values == label_values);
+            .find(|m| m.label_values.as_slice() == label_values)
     }
 
     pub fn add_metric(&mut self, metric: MetricMarshal) {
@@ -102,26 +359,51 @@ where
         }
 
         let old_names = self.label_names.as_ref().unwrap();
-        if !old_names.matches(sample_name, &names) {
-            return Err(ParseError::InvalidMetric(
-                "Labels in metrics have different label sets".to_owned(),
-            ));
+        let (missing, extra) = old_names.differences(sample_name, &names);
+        if !missing.is_empty() || !extra.is_empty() {
+            let quote = |names: Vec<&str>| {
+                names
+                    .iter()
+                    .map(|n| format!("'{}'", n))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+
+            let mut problems = Vec::new();
+            match extra.len() {
+                0 => {}
+                1 => problems.push(format!("has extra label {}", quote(extra))),
+                _ => problems.push(format!("has extra labels {}", quote(extra))),
+            }
+
+            match missing.len() {
+                0 => {}
+                1 => problems.push(format!("is missing label {}", quote(missing))),
+                _ => problems.push(format!("is missing labels {}", quote(missing))),
+            }
+
+            return Err(ParseError::InvalidMetric(format!(
+                "Metrics in family have different label sets: {} {}; family expects [{}]",
+                sample_name,
+                problems.join(" and "),
+                old_names.names.join(", ")
+            )));
         }
 
         Ok(())
     }
 
     pub fn set_or_test_name(&mut self, name: String) -> Result<(), ParseError> {
-        let name = Some(name);
-        if self.name.is_some() && self.name != name {
-            return Err(ParseError::InvalidMetric(format!(
-                "Invalid metric name in family. Family name is {}, but got a metric called {}",
-                self.name.as_ref().unwrap(),
-                name.as_ref().unwrap()
-            )));
+        if let Some(family_name) = &self.name {
+            if family_name != &name {
+                return Err(ParseError::InvalidMetric(format!(
+                    "Invalid metric name in family. Family name is {}, but got a metric called {}",
+                    family_name, name
+                )));
+            }
         }
 
-        self.name = name;
+        self.name = Some(name);
         Ok(())
     }
 
@@ -132,7 +414,7 @@ where
             ));
         }
 
-        self.help = Some(help);
+        self.help = Some(unescape_help(&help));
 
         Ok(())
     }
@@ -148,17 +430,9 @@ where
             ));
         }
 
-        if !self
-            .family_type
-            .as_ref()
-            .cloned()
-            .unwrap_or_default()
-            .can_have_units()
-        {
-            return Err(ParseError::InvalidMetric(format!(
-                "{:?} metrics can't have units",
-                self.family_type
-            )));
+        let family_type = self.family_type.as_ref().cloned().unwrap_or_default();
+        if !family_type.can_have_units() {
+            return Err(units_not_allowed(&family_type));
         }
 
         self.unit = Some(unit);
@@ -173,6 +447,11 @@ where
             ));
         }
 
+        // The UNIT line can come before the TYPE line, in which case we couldn't check it when it was added
+        if self.unit.is_some() && !family_type.can_have_units() {
+            return Err(units_not_allowed(&family_type));
+        }
+
         self.family_type = Some(family_type);
 
         Ok(())
@@ -184,7 +463,7 @@ pub struct LabelNames<T>
 where
     T: MetricsType,
 {
-    pub names: Vec<String>,
+    pub names: LabelValues,
     pub metric_type: T,
 }
 
@@ -192,7 +471,7 @@ impl<T> LabelNames<T>
 where
     T: MetricsType,
 {
-    pub fn new(sample_name: &str, metric_type: T, labels: Vec<String>) -> LabelNames<T> {
+    pub fn new(sample_name: &str, metric_type: T, labels: LabelValues) -> LabelNames<T> {
         let ignored_labels = <T>::get_ignored_labels(&metric_type, sample_name);
         let names = labels
             .into_iter()
@@ -202,35 +481,56 @@ where
         LabelNames { names, metric_type }
     }
 
-    pub fn matches(&self, sample_name: &str, other_labels: &LabelNames<T>) -> bool {
+    /// Compares these label names with those of another sample in the family, returning the names that the
+    /// sample is missing, and the names that the sample has that these don't
+    pub fn differences<'a>(
+        &'a self,
+        sample_name: &str,
+        other_labels: &'a LabelNames<T>,
+    ) -> (Vec<&'a str>, Vec<&'a str>) {
         let ignored_labels = <T>::get_ignored_labels(&self.metric_type, sample_name);
-        for name in self.names.iter() {
-            if !ignored_labels.contains(&name.as_str()) && !other_labels.names.contains(name) {
-                return false;
-            }
-        }
+        let missing = self
+            .names
+            .iter()
+            .map(SharedString::as_str)
+            .filter(|name| !ignored_labels.contains(name))
+            .filter(|name| !other_labels.names.iter().any(|n| n == name))
+            .collect();
+        let extra = other_labels
+            .names
+            .iter()
+            .map(SharedString::as_str)
+            .filter(|name| !ignored_labels.contains(name))
+            .filter(|name| !self.names.iter().any(|n| n == name))
+            .collect();
 
-        true
+        (missing, extra)
     }
 }
 
 #[derive(Debug)]
 pub struct MetricMarshal {
-    pub label_values: Vec<String>,
-    pub timestamp: Option<Timestamp>,
+    pub label_values: LabelValues,
+    pub timestamp: Option<RawTimestamp>,
     pub value: MetricValueMarshal,
+
+    /// Whether `timestamp` came from one of the metric's lines. `_created` lines don't count, as their
+    /// timestamp isn't the sample's, so a metric that has only seen a `_created` line takes the timestamp of
+    /// the next line instead of checking against it
+    pub timestamp_seen: bool,
 }
 
 impl MetricMarshal {
     pub fn new(
-        label_values: Vec<String>,
-        timestamp: Option<Timestamp>,
+        label_values: LabelValues,
+        timestamp: Option<RawTimestamp>,
         value: MetricValueMarshal,
     ) -> MetricMarshal {
         MetricMarshal {
             label_values,
             timestamp,
             value,
+            timestamp_seen: true,
         }
     }
 }
@@ -240,8 +540,8 @@ pub struct MetricProcesser(pub Box<MetricProccessFunc>);
 type MetricProccessFunc = dyn Fn(
     &mut MetricMarshal,
     MetricNumber,
-    Vec<String>,
-    Vec<String>,
+    &[SharedString],
+    &[SharedString],
     Option<Exemplar>,
     bool,
 ) -> Result<(), ParseError>;
@@ -252,8 +552,8 @@ impl MetricProcesser {
         F: Fn(
                 &mut MetricMarshal,
                 MetricNumber,
-                Vec<String>,
-                Vec<String>,
+                &[SharedString],
+                &[SharedString],
                 Option<Exemplar>,
                 bool,
             ) -> Result<(), ParseError>
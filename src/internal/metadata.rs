@@ -0,0 +1,94 @@
+use std::convert::TryFrom;
+
+use crate::{FamilyMetadata, ParseError};
+
+use super::unescape_help;
+
+/// Splits a descriptor's metric name off the front of the given string, returning the name and the remainder
+/// after the following space. Quoted names (e.g. `"my.metric"`) are returned without their quotes
+fn split_descriptor_name(descriptor: &str) -> Option<(&str, &str)> {
+    if let Some(quoted) = descriptor.strip_prefix('"') {
+        let mut escaped = false;
+        for (i, c) in quoted.char_indices() {
+            match c {
+                '\\' if !escaped => escaped = true,
+                '"' if !escaped => {
+                    let rest = &quoted[i + 1..];
+                    return Some((&quoted[..i], rest.strip_prefix(' ').unwrap_or(rest)));
+                }
+                _ => escaped = false,
+            }
+        }
+
+        return None;
+    }
+
+    match descriptor.find(' ') {
+        Some(i) => Some((&descriptor[..i], &descriptor[i + 1..])),
+        None => Some((descriptor, "")),
+    }
+}
+
+/// Scans the HELP, TYPE, and (if `allow_units` is set) UNIT lines of an exposition, without parsing any of its samples.
+/// Families are returned in the order they were first described in. A family described more than once has its
+/// metadata filled in from each of its descriptors
+pub fn parse_metadata<T>(
+    exposition_bytes: &str,
+    allow_units: bool,
+) -> Result<Vec<FamilyMetadata<T>>, ParseError>
+where
+    T: for<'a> TryFrom<&'a str, Error = ParseError> + Default,
+{
+    let mut families: Vec<FamilyMetadata<T>> = Vec::new();
+
+    for line in exposition_bytes.lines() {
+        let line = line.trim_end_matches('\r');
+        let (keyword, descriptor) = match line.strip_prefix("# ") {
+            Some(descriptor) if descriptor.len() > 5 => descriptor.split_at(5),
+            _ => continue,
+        };
+
+        let is_descriptor = match keyword {
+            "HELP " | "TYPE " => true,
+            "UNIT " => allow_units,
+            _ => false,
+        };
+
+        if !is_descriptor {
+            continue;
+        }
+
+        let (name, value) = match split_descriptor_name(descriptor) {
+            Some(split) => split,
+            None => {
+                return Err(ParseError::InvalidMetric(format!(
+                    "Unterminated metric name in descriptor: {}",
+                    line
+                )))
+            }
+        };
+
+        let index = match families.iter().position(|family| family.name == name) {
+            Some(index) => index,
+            None => {
+                families.push(FamilyMetadata {
+                    name: name.to_owned(),
+                    family_type: T::default(),
+                    help: String::new(),
+                    unit: String::new(),
+                });
+
+                families.len() - 1
+            }
+        };
+
+        let family = &mut families[index];
+        match keyword {
+            "HELP " => family.help = unescape_help(value),
+            "TYPE " => family.family_type = T::try_from(value.trim_end())?,
+            _ => family.unit = value.to_owned(),
+        }
+    }
+
+    Ok(families)
+}
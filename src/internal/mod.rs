@@ -1,7 +1,11 @@
+mod interner;
 mod marshals;
+mod metadata;
 mod traits;
 mod utils;
 
+pub use interner::*;
 pub use marshals::*;
+pub use metadata::*;
 pub use traits::*;
 pub use utils::*;
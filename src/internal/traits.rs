@@ -1,14 +1,18 @@
 use std::fmt;
 
-use crate::{Exemplar, MetricNumber, ParseError, Timestamp};
+use crate::{Exemplar, MetricNumber, ParseError, RawTimestamp, Timestamp};
 
-use super::{MetricFamilyMarshal, MetricValueMarshal};
+use super::{LabelValues, MetricFamilyMarshal, MetricValueMarshal};
 
 pub trait MetricsType {
     fn can_have_exemplar(&self, metric_name: &str) -> bool;
     fn can_have_units(&self) -> bool;
     fn can_have_multiple_lines(&self) -> bool;
     fn get_ignored_labels(&self, metric_name: &str) -> &[&str];
+
+    /// Label names that have a special meaning for this type (e.g. `le` for histograms), and so can't be used as
+    /// label names of the family itself
+    fn get_reserved_labels(&self) -> &[&str];
     fn get_type_value(&self) -> MetricValueMarshal;
 }
 
@@ -18,9 +22,9 @@ pub trait MarshalledMetricFamily {
         &mut self,
         metric_name: &str,
         value: MetricNumber,
-        label_names: Vec<String>,
-        label_values: Vec<String>,
-        timestamp: Option<Timestamp>,
+        label_names: LabelValues,
+        label_values: LabelValues,
+        timestamp: Option<RawTimestamp>,
         exemplar: Option<Exemplar>,
     ) -> Result<(), Self::Error>;
 
@@ -43,4 +47,53 @@ pub trait RenderableMetricValue {
         label_names: &[&str],
         label_values: &[&str],
     ) -> fmt::Result;
+
+    /// The number of series this value expands to when it's rendered, e.g. a histogram has a series for
+    /// each of its buckets, plus its `_sum`, `_count`, and `_created` series (if it has them)
+    fn num_series(&self) -> usize {
+        1
+    }
+
+    /// Where the line with the given suffix (e.g. `_bucket`) and `le` or `quantile` bound is among the lines that
+    /// this value renders, or None if it doesn't render one
+    fn line_index(&self, _suffix: &str, _bound: Option<f64>) -> Option<usize> {
+        Some(0)
+    }
+}
+
+pub trait TransformableMetricValue {
+    /// Calls `f` with every timestamp held inside the value (e.g. `created` timestamps, and the timestamps of any exemplars)
+    fn visit_timestamps_mut(&mut self, f: &mut dyn FnMut(&mut Option<Timestamp>));
+
+    /// Calls `f` with every numeric measurement held inside the value. See `MetricFamily::map_values` for which components are visited
+    fn visit_numbers_mut(&mut self, f: &mut dyn FnMut(&mut MetricNumber));
+
+    /// Returns the value of a Counter, or None if this isn't a Counter
+    fn counter_value_mut(&mut self) -> Option<&mut MetricNumber> {
+        None
+    }
+
+    /// Returns the value of a Gauge, Counter, or Unknown, or None for types that don't have a single value
+    fn scalar_value(&self) -> Option<MetricNumber> {
+        None
+    }
+
+    /// A mutable version of `scalar_value`
+    fn scalar_value_mut(&mut self) -> Option<&mut MetricNumber> {
+        None
+    }
+
+    /// Attaches an exemplar to the value, or removes all its exemplars if `exemplar` is None. Errors for values
+    /// that can't have exemplars
+    fn set_exemplar(&mut self, _exemplar: Option<Exemplar>) -> Result<(), ParseError> {
+        Err(exemplars_not_allowed())
+    }
+}
+
+pub fn exemplars_not_allowed() -> ParseError {
+    ParseError::InvalidMetric("Only Counters and Histograms can have exemplars".to_string())
+}
+
+pub fn units_not_allowed(family_type: &dyn fmt::Debug) -> ParseError {
+    ParseError::InvalidMetric(format!("{:?} metrics can't have units", family_type))
 }
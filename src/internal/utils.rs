@@ -1,17 +1,345 @@
+use std::{borrow::Cow, collections::HashMap, io::Read};
+
+use pest::{error::LineColLocation, RuleType};
+
+use crate::{Exemplar, MetricNumber, ParseError, ParseOptions, SharedString};
+
 pub fn render_label_values(label_names: &[&str], label_values: &[&str]) -> String {
     if label_names.is_empty() {
         return String::new();
     }
 
-    let mut build = String::new();
+    format!(
+        "{{{}}}",
+        render_label_pairs(label_names, label_values).join(",")
+    )
+}
+
+/// Renders a series, i.e. a metric name and its labels. Names that aren't valid legacy
+/// metric names are quoted and moved inside the braces, e.g. `{"my.metric",label="value"}`
+pub fn render_series(metric_name: &str, label_names: &[&str], label_values: &[&str]) -> String {
+    if is_legacy_metric_name(metric_name) {
+        return format!(
+            "{}{}",
+            metric_name,
+            render_label_values(label_names, label_values)
+        );
+    }
+
+    let mut labels = vec![format!("\"{}\"", escape_string(metric_name))];
+    labels.extend(render_label_pairs(label_names, label_values));
+
+    format!("{{{}}}", labels.join(","))
+}
+
+/// Renders a metric name as it appears in a HELP/TYPE/UNIT line, quoting it if it isn't a valid legacy metric name
+pub fn render_metric_name(metric_name: &str) -> String {
+    if is_legacy_metric_name(metric_name) {
+        metric_name.to_owned()
+    } else {
+        format!("\"{}\"", escape_string(metric_name))
+    }
+}
+
+fn render_label_pairs(label_names: &[&str], label_values: &[&str]) -> Vec<String> {
+    label_names
+        .iter()
+        .zip(label_values.iter())
+        .map(|(name, value)| {
+            if is_legacy_label_name(name) {
+                format!("{}=\"{}\"", name, escape_string(value))
+            } else {
+                format!("\"{}\"=\"{}\"", escape_string(name), escape_string(value))
+            }
+        })
+        .collect()
+}
+
+/// Returns whether the given name matches `[a-zA-Z_:][a-zA-Z0-9_:]*`, and so can be rendered without quotes
+pub fn is_legacy_metric_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' || c == ':' => {}
+        _ => return false,
+    }
+
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+/// Returns whether the given name matches `[a-zA-Z_][a-zA-Z0-9_]*`, and so can be rendered without quotes
+pub fn is_legacy_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Unescapes a HELP string, turning `\\` and `\n` back into a backslash and newline.
+/// Any other escape sequence is left as is
+pub fn unescape_help(help: &str) -> String {
+    let mut unescaped = String::with_capacity(help.len());
+    let mut chars = help.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('\\') => unescaped.push('\\'),
+            Some(other) => {
+                unescaped.push('\\');
+                unescaped.push(other);
+            }
+            None => unescaped.push('\\'),
+        }
+    }
+
+    unescaped
+}
+
+/// Escapes a HELP string for rendering. HELP lines escape backslashes and newlines, but not quotes
+pub fn escape_help(help: &str) -> String {
+    help.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+/// Unescapes a quoted string, i.e. a label value or quoted name, turning `\\`, `\"` and `\n` back into a
+/// backslash, quote and newline. Any other escape sequence is left as is. Strings without escapes are borrowed
+pub fn unescape_string(value: &str) -> Cow<'_, str> {
+    if !value.contains('\\') {
+        return Cow::Borrowed(value);
+    }
+
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some(c) if c == '\\' || c == '"' => unescaped.push(c),
+            Some(other) => {
+                unescaped.push('\\');
+                unescaped.push(other);
+            }
+            None => unescaped.push('\\'),
+        }
+    }
+
+    Cow::Owned(unescaped)
+}
+
+/// Escapes a string for rendering between quotes, i.e. a label value or quoted name
+pub fn escape_string(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Replaces a `DuplicateMetric` error raised while processing a sample with one that names the series that was
+/// duplicated, so it can be found in a large exposition. Other errors are returned as is
+pub fn describe_duplicate(
+    err: ParseError,
+    metric_name: &str,
+    label_names: &[SharedString],
+    label_values: &[SharedString],
+) -> ParseError {
+    match err {
+        ParseError::DuplicateMetric => {
+            let label_names: Vec<&str> = label_names.iter().map(SharedString::as_str).collect();
+            let label_values: Vec<&str> = label_values.iter().map(SharedString::as_str).collect();
+            ParseError::InvalidMetric(format!(
+                "Found two metrics with the same labelset: {}",
+                render_series(metric_name, &label_names, &label_values)
+            ))
+        }
+        err => err,
+    }
+}
+
+/// Parses the bound of a histogram bucket, i.e. the value of its `le` label. Exporters write infinite bounds
+/// in a few different ways (`+Inf`, `Inf`, `inf`, `+Infinity`...), so they're all normalised to infinity.
+/// Large but finite bounds (e.g. `1e308`) are left as they are. Some generators pad bounds with whitespace
+/// (e.g. `le=" 0.5 "`), so that's ignored
+pub fn parse_bucket_bound(bound: &str) -> Option<f64> {
+    let bound = bound.trim();
+    let (negative, magnitude) = match bound.as_bytes().first() {
+        Some(b'-') => (true, &bound[1..]),
+        Some(b'+') => (false, &bound[1..]),
+        _ => (false, bound),
+    };
+
+    if magnitude.eq_ignore_ascii_case("inf") || magnitude.eq_ignore_ascii_case("infinity") {
+        return Some(if negative {
+            f64::NEG_INFINITY
+        } else {
+            f64::INFINITY
+        });
+    }
+
+    bound.parse().ok()
+}
+
+/// Builds an exemplar from its labels and the text of its value and timestamp. Both have to be numbers, and the
+/// timestamp has to be finite, as it couldn't be rendered in a way that parses again otherwise
+pub fn parse_exemplar_parts(
+    labels: HashMap<String, String>,
+    value: &str,
+    timestamp: Option<&str>,
+) -> Result<Exemplar, ParseError> {
+    let id = value.parse().map_err(|_| {
+        ParseError::InvalidMetric(format!("Exemplar value must be a number (got: {})", value))
+    })?;
+
+    let timestamp = match timestamp {
+        Some(timestamp) => match timestamp.parse::<f64>() {
+            Ok(f) if f.is_finite() => Some(f),
+            Ok(_) => {
+                return Err(ParseError::InvalidMetric(format!(
+                    "Exemplar timestamp must be finite (got: {})",
+                    timestamp
+                )))
+            }
+            Err(_) => {
+                return Err(ParseError::InvalidMetric(format!(
+                    "Exemplar timestamp must be a number (got: {})",
+                    timestamp
+                )))
+            }
+        },
+        None => None,
+    };
+
+    Ok(Exemplar::new(labels, id, timestamp))
+}
+
+/// Parses the value of a histogram bucket's `le` label like `parse_bucket_bound`, with an error naming the bound if
+/// it isn't a number
+pub fn parse_le(bound: &str) -> Result<f64, ParseError> {
+    parse_bucket_bound(bound)
+        .ok_or_else(|| ParseError::InvalidMetric(format!("Invalid histogram bound: {}", bound)))
+}
+
+/// Parses the value of a summary's `quantile` label, which has to be a number between 0 and 1. As with histogram
+/// bounds, whitespace around it is ignored
+pub fn parse_quantile(quantile: &str) -> Result<f64, ParseError> {
+    let parsed: f64 = quantile.trim().parse().map_err(|_| {
+        ParseError::InvalidMetric(format!(
+            "Summary bounds must be numbers (got: {})",
+            quantile
+        ))
+    })?;
+
+    if !(0. ..=1.).contains(&parsed) || parsed.is_nan() {
+        return Err(ParseError::InvalidMetric(format!(
+            "Summary bounds must be between 0 and 1 (got: {})",
+            parsed
+        )));
+    }
+
+    Ok(parsed)
+}
+
+/// Converts the value of a count sample (e.g. `foo_count`) into a u64, with an error naming the type of the
+/// metric if it isn't a non-negative integer
+pub fn parse_count(value: MetricNumber, metric_type: &str) -> Result<u64, ParseError> {
+    value.as_u64().ok_or_else(|| {
+        let requirement = if value.as_f64() < 0. {
+            "positive"
+        } else if value.as_f64().fract() != 0. || value.as_f64().is_nan() {
+            "integers"
+        } else {
+            "less than 2^64"
+        };
+
+        ParseError::InvalidMetric(format!(
+            "{} counts must be {} (got: {})",
+            metric_type, requirement, value
+        ))
+    })
+}
+
+/// Checks the size of an exposition against the `max_size` option
+pub fn check_size(size: usize, options: &ParseOptions) -> Result<(), ParseError> {
+    match options.max_size {
+        Some(max_size) if size > max_size => Err(ParseError::ParseError(format!(
+            "Exposition is larger than the maximum size of {} bytes",
+            max_size
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Converts a pest error into a `ParseError::Syntax`, keeping the position where it went wrong. For errors
+/// that cover a span, that's the start of the span
+pub fn syntax_error<R: RuleType>(err: pest::error::Error<R>) -> ParseError {
+    let (line, col) = match err.line_col {
+        LineColLocation::Pos(pos) | LineColLocation::Span(pos, _) => pos,
+    };
+
+    ParseError::Syntax {
+        message: err.to_string(),
+        line,
+        col,
+    }
+}
+
+/// Reads an exposition to the end of `reader`, stopping early if it's larger than the `max_size` option
+pub fn read_exposition<R: Read>(
+    mut reader: R,
+    options: &ParseOptions,
+) -> Result<Vec<u8>, ParseError> {
+    let mut exposition_bytes = Vec::new();
+    match options.max_size {
+        // Read one byte past the limit, so that we can tell whether there was more to read
+        Some(max_size) => reader
+            .take(max_size as u64 + 1)
+            .read_to_end(&mut exposition_bytes)?,
+        None => reader.read_to_end(&mut exposition_bytes)?,
+    };
+
+    check_size(exposition_bytes.len(), options)?;
+    Ok(exposition_bytes)
+}
+
+/// Returns whether `name` matches the glob `pattern`, where `*` matches any run of characters (including none)
+/// and `?` matches exactly one character. Everything else matches itself
+pub fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
 
-    build.push('{');
-    let mut labels = Vec::new();
-    for (name, value) in label_names.iter().zip(label_values.iter()) {
-        labels.push(format!("{}=\"{}\"", name, value));
+    let (mut p, mut n) = (0, 0);
+    // Where the last `*` was in the pattern, and where in the name we started matching it
+    let mut last_star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                last_star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            // On a mismatch, let the last `*` swallow one more character and try again from there
+            _ => match last_star {
+                Some((star, matched)) => {
+                    last_star = Some((star, matched + 1));
+                    p = star + 1;
+                    n = matched + 1;
+                }
+                None => return false,
+            },
+        }
     }
-    build.push_str(&labels.join(","));
-    build.push('}');
 
-    build
+    pattern[p..].iter().all(|&c| c == '*')
 }
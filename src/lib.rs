@@ -8,6 +8,8 @@ extern crate serde;
 mod internal;
 pub mod openmetrics;
 pub mod prometheus;
+#[cfg(feature = "protobuf")]
+pub mod protobuf;
 mod public;
 pub use public::*;
-pub use internal::RenderableMetricValue;
+pub use internal::{RenderableMetricValue, TransformableMetricValue};
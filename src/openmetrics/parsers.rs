@@ -1,13 +1,17 @@
 use crate::{
     internal::{
-        CounterValueMarshal, LabelNames, MarshalledMetric, MarshalledMetricFamily,
-        MetricFamilyMarshal, MetricMarshal, MetricProcesser, MetricValueMarshal, MetricsType,
+        check_bucket_bounds, check_size, check_sum_and_count, describe_duplicate, parse_count,
+        parse_exemplar_parts, parse_le, parse_metadata, parse_quantile, read_exposition,
+        syntax_error, unescape_string, CounterValueMarshal, LabelInterner, LabelNames, LabelValues,
+        MarshalledMetric, MarshalledMetricFamily, MetricFamilyMarshal, MetricMarshal,
+        MetricProcesser, MetricValueMarshal, MetricsType, ParsedLabels,
     },
     public::*,
 };
-use std::convert::TryFrom;
+use std::{borrow::Cow, convert::TryFrom, io::Read, str::FromStr};
 
-use pest::Parser;
+use pest::{iterators::Pairs, Parser};
+use smallvec::SmallVec;
 
 #[derive(Parser)]
 #[grammar = "openmetrics/openmetrics.pest"]
@@ -15,7 +19,7 @@ struct OpenMetricsParser;
 
 impl From<pest::error::Error<Rule>> for ParseError {
     fn from(err: pest::error::Error<Rule>) -> Self {
-        ParseError::ParseError(err.to_string())
+        syntax_error(err)
     }
 }
 
@@ -56,6 +60,14 @@ impl MetricsType for OpenMetricsType {
         }
     }
 
+    fn get_reserved_labels(&self) -> &[&str] {
+        match self {
+            OpenMetricsType::Histogram | OpenMetricsType::GaugeHistogram => &["le"],
+            OpenMetricsType::Summary => &["quantile"],
+            _ => &[],
+        }
+    }
+
     fn get_type_value(&self) -> MetricValueMarshal {
         match self {
             OpenMetricsType::Histogram => MetricValueMarshal::Histogram(HistogramValue::default()),
@@ -93,7 +105,7 @@ impl TryFrom<&str> for OpenMetricsType {
     type Error = ParseError;
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        match value {
+        match value.to_ascii_lowercase().as_str() {
             "counter" => Ok(OpenMetricsType::Counter),
             "gauge" => Ok(OpenMetricsType::Gauge),
             "histogram" => Ok(OpenMetricsType::Histogram),
@@ -110,15 +122,9 @@ impl TryFrom<&str> for OpenMetricsType {
     }
 }
 
-impl Default for OpenMetricsType {
-    fn default() -> Self {
-        OpenMetricsType::Unknown
-    }
-}
-
 impl From<MetricMarshal> for Sample<OpenMetricsValue> {
     fn from(s: MetricMarshal) -> Sample<OpenMetricsValue> {
-        Sample::new(s.label_values, s.timestamp, s.value.into())
+        Sample::with_raw_timestamp(s.label_values, s.timestamp, s.value.into())
     }
 }
 
@@ -129,8 +135,13 @@ impl MarshalledMetric<OpenMetricsType> for MetricMarshal {
             || (family.label_names.as_ref().unwrap().names.len() != self.label_values.len())
         {
             return Err(ParseError::InvalidMetric(format!(
-                "Metrics in family have different label sets: {:?} {:?}",
-                &family.label_names, self.label_values
+                "Metrics in family have different label sets: family expects [{}], but a sample has values [{}]",
+                family
+                    .label_names
+                    .as_ref()
+                    .map(|l| l.names.join(", "))
+                    .unwrap_or_default(),
+                self.label_values.join(", ")
             )));
         }
 
@@ -163,6 +174,7 @@ impl MarshalledMetric<OpenMetricsType> for MetricMarshal {
                 }
 
                 let buckets = &histogram_value.buckets;
+                check_bucket_bounds(histogram_value)?;
 
                 let has_negative_bucket = buckets.iter().any(|f| f.upper_bound < 0.);
 
@@ -181,17 +193,12 @@ impl MarshalledMetric<OpenMetricsType> for MetricMarshal {
                     ));
                 }
 
-                if histogram_value.sum.is_some() && histogram_value.count.is_none() {
-                    return Err(ParseError::InvalidMetric(
-                        "Count must be present if sum is present".to_owned(),
-                    ));
-                }
+                check_sum_and_count(
+                    histogram_value.sum.is_some(),
+                    histogram_value.count.is_some(),
+                )?;
 
-                if histogram_value.sum.is_none() && histogram_value.count.is_some() {
-                    return Err(ParseError::InvalidMetric(
-                        "Sum must be present if count is present".to_owned(),
-                    ));
-                }
+                family.check_inf_bucket_count(histogram_value)?;
 
                 let mut last = f64::NEG_INFINITY;
                 for bucket in buckets {
@@ -204,12 +211,15 @@ impl MarshalledMetric<OpenMetricsType> for MetricMarshal {
                     last = bucket.count.as_f64();
                 }
             }
-            MetricValueMarshal::Counter(counter_value) => {
-                if counter_value.value.is_none() {
-                    return Err(ParseError::InvalidMetric(
-                        "Counter is missing a _total".to_string(),
-                    ));
-                }
+            MetricValueMarshal::Summary(summary_value) => {
+                family.check_quantiles(summary_value)?;
+
+                family.check_summary_sum_and_count(summary_value)?;
+            }
+            MetricValueMarshal::Counter(counter_value) if counter_value.value.is_none() => {
+                return Err(ParseError::InvalidMetric(
+                    "Counter is missing a _total".to_string(),
+                ));
             }
             _ => {}
         }
@@ -235,7 +245,8 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<OpenMetricsType> {
                 .as_ref()
                 .unwrap()
                 .names
-                .contains(self.name.as_ref().unwrap())
+                .iter()
+                .any(|name| Some(name.as_str()) == self.name.as_deref())
         {
             return Err(ParseError::InvalidMetric(
                 "Stateset must not have a label with the same name as its MetricFamily".to_string(),
@@ -253,11 +264,12 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<OpenMetricsType> {
         &mut self,
         metric_name: &str,
         metric_value: MetricNumber,
-        label_names: Vec<String>,
-        label_values: Vec<String>,
-        timestamp: Option<Timestamp>,
+        label_names: LabelValues,
+        label_values: LabelValues,
+        timestamp: Option<RawTimestamp>,
         exemplar: Option<Exemplar>,
     ) -> Result<(), Self::Error> {
+        let allow_negative_counters = self.options.allow_negative_counters;
         let handlers = vec![
             (
                 vec![OpenMetricsType::Histogram],
@@ -268,24 +280,20 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<OpenMetricsType> {
                         MetricProcesser::new(
                             |existing_metric: &mut MetricMarshal,
                              metric_value: MetricNumber,
-                             label_names: Vec<String>,
-                             label_values: Vec<String>,
+                             label_names: &[SharedString],
+                             label_values: &[SharedString],
                              exemplar: Option<Exemplar>,
                              _: bool| {
-                                let bucket_bound: f64 = {
+                                if metric_value.as_f64().is_nan() {
+                                    return Err(ParseError::InvalidMetric(
+                                        "Histogram bucket counts can't be NaN".to_owned(),
+                                    ));
+                                }
+
+                                let bucket_bound = {
                                     let bound_index =
                                         label_names.iter().position(|s| s == "le").unwrap();
-
-                                    let bound = &label_values[bound_index];
-                                    match bound.parse() {
-                                        Ok(f) => f,
-                                        Err(_) => {
-                                            return Err(ParseError::InvalidMetric(format!(
-                                                "Invalid histogram bound: {}",
-                                                bound
-                                            )));
-                                        }
-                                    }
+                                    parse_le(&label_values[bound_index])?
                                 };
 
                                 let bucket = HistogramBucket {
@@ -312,28 +320,14 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<OpenMetricsType> {
                         MetricProcesser::new(
                             |existing_metric: &mut MetricMarshal,
                              metric_value: MetricNumber,
-                             _: Vec<String>,
-                             _: Vec<String>,
+                             _: &[SharedString],
+                             _: &[SharedString],
                              _: Option<Exemplar>,
                              _: bool| {
                                 if let MetricValueMarshal::Histogram(histogram_value) =
                                     &mut existing_metric.value
                                 {
-                                    let metric_value = if let Some(value) = metric_value.as_i64() {
-                                        if value < 0 {
-                                            return Err(ParseError::InvalidMetric(format!(
-                                                "Histogram counts must be positive (got: {})",
-                                                value
-                                            )));
-                                        }
-
-                                        value as u64
-                                    } else {
-                                        return Err(ParseError::InvalidMetric(format!(
-                                            "Histogram counts must be integers (got: {})",
-                                            metric_value.as_f64()
-                                        )));
-                                    };
+                                    let metric_value = parse_count(metric_value, "Histogram")?;
 
                                     match histogram_value.count {
                                         Some(_) => {
@@ -357,8 +351,8 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<OpenMetricsType> {
                         MetricProcesser::new(
                             |existing_metric: &mut MetricMarshal,
                              metric_value: MetricNumber,
-                             _: Vec<String>,
-                             _: Vec<String>,
+                             _: &[SharedString],
+                             _: &[SharedString],
                              _: Option<Exemplar>,
                              _: bool| {
                                 if let MetricValueMarshal::Histogram(histogram_value) =
@@ -386,8 +380,8 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<OpenMetricsType> {
                         MetricProcesser::new(
                             |existing_metric: &mut MetricMarshal,
                              metric_value: MetricNumber,
-                             _: Vec<String>,
-                             _: Vec<String>,
+                             _: &[SharedString],
+                             _: &[SharedString],
                              _: Option<Exemplar>,
                              _: bool| {
                                 if let MetricValueMarshal::Histogram(histogram_value) =
@@ -417,21 +411,20 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<OpenMetricsType> {
                         MetricProcesser::new(
                             |existing_metric: &mut MetricMarshal,
                              metric_value: MetricNumber,
-                             label_names: Vec<String>,
-                             label_values: Vec<String>,
+                             label_names: &[SharedString],
+                             label_values: &[SharedString],
                              exemplar: Option<Exemplar>,
                              _: bool| {
-                                let bucket_bound: f64 = {
+                                if metric_value.as_f64().is_nan() {
+                                    return Err(ParseError::InvalidMetric(
+                                        "Histogram bucket counts can't be NaN".to_owned(),
+                                    ));
+                                }
+
+                                let bucket_bound = {
                                     let bound_index =
                                         label_names.iter().position(|s| s == "le").unwrap();
-
-                                    let bound = &label_values[bound_index];
-                                    match bound.parse() {
-                                        Ok(f) => f,
-                                        Err(_) => {
-                                            return Err(ParseError::InvalidMetric(format!("Expected histogram bucket bound to be an f64 (got: {})", bound)));
-                                        }
-                                    }
+                                    parse_le(&label_values[bound_index])?
                                 };
 
                                 let bucket = HistogramBucket {
@@ -458,28 +451,14 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<OpenMetricsType> {
                         MetricProcesser::new(
                             |existing_metric: &mut MetricMarshal,
                              metric_value: MetricNumber,
-                             _: Vec<String>,
-                             _: Vec<String>,
+                             _: &[SharedString],
+                             _: &[SharedString],
                              _: Option<Exemplar>,
                              _: bool| {
                                 if let MetricValueMarshal::GaugeHistogram(histogram_value) =
                                     &mut existing_metric.value
                                 {
-                                    let metric_value = if let Some(value) = metric_value.as_i64() {
-                                        if value < 0 {
-                                            return Err(ParseError::InvalidMetric(format!(
-                                                "Histogram counts must be positive (got: {})",
-                                                value
-                                            )));
-                                        }
-
-                                        value as u64
-                                    } else {
-                                        return Err(ParseError::InvalidMetric(format!(
-                                            "Histogram counts must be integers (got: {})",
-                                            metric_value.as_f64()
-                                        )));
-                                    };
+                                    let metric_value = parse_count(metric_value, "Histogram")?;
 
                                     match histogram_value.count {
                                         Some(_) => {
@@ -503,8 +482,8 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<OpenMetricsType> {
                         MetricProcesser::new(
                             |existing_metric: &mut MetricMarshal,
                              metric_value: MetricNumber,
-                             _: Vec<String>,
-                             _: Vec<String>,
+                             _: &[SharedString],
+                             _: &[SharedString],
                              _: Option<Exemplar>,
                              _: bool| {
                                 if let MetricValueMarshal::GaugeHistogram(histogram_value) =
@@ -532,12 +511,12 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<OpenMetricsType> {
                         "_total",
                         vec![],
                         MetricProcesser::new(
-                            |existing_metric: &mut MetricMarshal,
-                             metric_value: MetricNumber,
-                             _: Vec<String>,
-                             _: Vec<String>,
-                             _: Option<Exemplar>,
-                             _: bool| {
+                            move |existing_metric: &mut MetricMarshal,
+                                  metric_value: MetricNumber,
+                                  _: &[SharedString],
+                                  _: &[SharedString],
+                                  _: Option<Exemplar>,
+                                  _: bool| {
                                 if let MetricValueMarshal::Counter(counter_value) =
                                     &mut existing_metric.value
                                 {
@@ -546,7 +525,7 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<OpenMetricsType> {
                                     }
 
                                     let value = metric_value.as_f64();
-                                    if value < 0. || value.is_nan() {
+                                    if !allow_negative_counters && (value < 0. || value.is_nan()) {
                                         return Err(ParseError::InvalidMetric(format!(
                                             "Counter totals must be non negative (got: {})",
                                             metric_value.as_f64()
@@ -568,8 +547,8 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<OpenMetricsType> {
                         MetricProcesser::new(
                             |existing_metric: &mut MetricMarshal,
                              metric_value: MetricNumber,
-                             _: Vec<String>,
-                             _: Vec<String>,
+                             _: &[SharedString],
+                             _: &[SharedString],
                              _: Option<Exemplar>,
                              _: bool| {
                                 if let MetricValueMarshal::Counter(counter_value) =
@@ -597,8 +576,8 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<OpenMetricsType> {
                     MetricProcesser::new(
                         |existing_metric: &mut MetricMarshal,
                          metric_value: MetricNumber,
-                         _: Vec<String>,
-                         _: Vec<String>,
+                         _: &[SharedString],
+                         _: &[SharedString],
                          _: Option<Exemplar>,
                          _: bool| {
                             if let MetricValueMarshal::Gauge(gauge_value) =
@@ -627,8 +606,8 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<OpenMetricsType> {
                     MetricProcesser::new(
                         |existing_metric: &mut MetricMarshal,
                          metric_value: MetricNumber,
-                         _: Vec<String>,
-                         _: Vec<String>,
+                         _: &[SharedString],
+                         _: &[SharedString],
                          _: Option<Exemplar>,
                          _: bool| {
                             if let MetricValueMarshal::StateSet(stateset_value) =
@@ -672,8 +651,8 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<OpenMetricsType> {
                     MetricProcesser::new(
                         |existing_metric: &mut MetricMarshal,
                          metric_value: MetricNumber,
-                         _: Vec<String>,
-                         _: Vec<String>,
+                         _: &[SharedString],
+                         _: &[SharedString],
                          _: Option<Exemplar>,
                          _: bool| {
                             if let MetricValueMarshal::Unknown(unknown_value) =
@@ -702,12 +681,12 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<OpenMetricsType> {
                     MetricProcesser::new(
                         |_: &mut MetricMarshal,
                          metric_value: MetricNumber,
-                         _: Vec<String>,
-                         _: Vec<String>,
+                         _: &[SharedString],
+                         _: &[SharedString],
                          _: Option<Exemplar>,
                          created: bool| {
-                            let metric_value = if let Some(value) = metric_value.as_i64() {
-                                value as u64
+                            let metric_value = if let Some(value) = metric_value.as_u64() {
+                                value
                             } else {
                                 return Err(ParseError::InvalidMetric(format!(
                                     "Info values must be integers (got: {})",
@@ -734,33 +713,46 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<OpenMetricsType> {
             (
                 vec![OpenMetricsType::Summary],
                 vec![
+                    (
+                        "_created",
+                        vec![],
+                        MetricProcesser::new(
+                            |existing_metric: &mut MetricMarshal,
+                             metric_value: MetricNumber,
+                             _: &[SharedString],
+                             _: &[SharedString],
+                             _: Option<Exemplar>,
+                             _: bool| {
+                                if let MetricValueMarshal::Summary(summary_value) =
+                                    &mut existing_metric.value
+                                {
+                                    if summary_value.created.is_some() {
+                                        return Err(ParseError::DuplicateMetric);
+                                    }
+
+                                    summary_value.created = Some(metric_value.as_f64());
+                                } else {
+                                    unreachable!();
+                                }
+
+                                Ok(())
+                            },
+                        ),
+                    ),
                     (
                         "_count",
                         vec![],
                         MetricProcesser::new(
                             |existing_metric: &mut MetricMarshal,
                              metric_value: MetricNumber,
-                             _: Vec<String>,
-                             _: Vec<String>,
+                             _: &[SharedString],
+                             _: &[SharedString],
                              _: Option<Exemplar>,
                              _: bool| {
                                 if let MetricValueMarshal::Summary(summary_value) =
                                     &mut existing_metric.value
                                 {
-                                    let metric_value = if let Some(value) = metric_value.as_i64() {
-                                        if value < 0 {
-                                            return Err(ParseError::InvalidMetric(format!(
-                                                "Summary counts must be positive (got: {})",
-                                                value
-                                            )));
-                                        }
-                                        value as u64
-                                    } else {
-                                        return Err(ParseError::InvalidMetric(format!(
-                                            "Summary counts must be integers (got: {})",
-                                            metric_value.as_f64()
-                                        )));
-                                    };
+                                    let metric_value = parse_count(metric_value, "Summary")?;
 
                                     if summary_value.count.is_none() {
                                         summary_value.count = Some(metric_value);
@@ -781,12 +773,12 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<OpenMetricsType> {
                         MetricProcesser::new(
                             |existing_metric: &mut MetricMarshal,
                              metric_value: MetricNumber,
-                             _: Vec<String>,
-                             _: Vec<String>,
+                             _: &[SharedString],
+                             _: &[SharedString],
                              _: Option<Exemplar>,
                              _: bool| {
                                 let value = metric_value.as_f64();
-                                if value < 0. || value.is_nan() {
+                                if !value.is_nan() && value < 0. {
                                     return Err(ParseError::InvalidMetric(format!(
                                         "Summary sums must be non negative (got: {})",
                                         metric_value.as_f64()
@@ -814,8 +806,8 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<OpenMetricsType> {
                         MetricProcesser::new(
                             |existing_metric: &mut MetricMarshal,
                              metric_value: MetricNumber,
-                             label_names: Vec<String>,
-                             label_values: Vec<String>,
+                             label_names: &[SharedString],
+                             label_values: &[SharedString],
                              _: Option<Exemplar>,
                              _: bool| {
                                 let value = metric_value.as_f64();
@@ -825,29 +817,12 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<OpenMetricsType> {
                                     ));
                                 }
 
-                                let bucket_bound: f64 = {
+                                let bucket_bound = {
                                     let bound_index =
                                         label_names.iter().position(|s| s == "quantile").unwrap();
-                                    let bound = &label_values[bound_index];
-
-                                    match bound.parse() {
-                                        Ok(f) => f,
-                                        Err(_) => {
-                                            return Err(ParseError::InvalidMetric(format!(
-                                                "Summary bounds must be numbers (got: {})",
-                                                bound
-                                            )));
-                                        }
-                                    }
+                                    parse_quantile(&label_values[bound_index])?
                                 };
 
-                                if !(0. ..=1.).contains(&bucket_bound) || bucket_bound.is_nan() {
-                                    return Err(ParseError::InvalidMetric(format!(
-                                        "Summary bounds must be between 0 and 1 (got: {})",
-                                        bucket_bound
-                                    )));
-                                }
-
                                 let quantile = Quantile {
                                     quantile: bucket_bound,
                                     value: metric_value,
@@ -870,14 +845,17 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<OpenMetricsType> {
         ];
 
         let metric_type = self.family_type.as_ref().cloned().unwrap_or_default();
+        let label_values = self.limit_label_values(&metric_type, &label_names, label_values)?;
 
-        if !metric_type.can_have_exemplar(metric_name) && exemplar.is_some() {
+        if self.options.reject_nan && metric_value.as_f64().is_nan() {
             return Err(ParseError::InvalidMetric(format!(
-                "Metric Type {:?} is not allowed exemplars",
-                metric_type
+                "NaN values aren't allowed (got one on {})",
+                metric_name
             )));
         }
 
+        self.check_exemplar_allowed(&metric_type, metric_name, exemplar.as_ref())?;
+
         for (test_type, actions) in handlers {
             if test_type.contains(&metric_type) {
                 for (suffix, mandatory_labels, action) in actions {
@@ -887,20 +865,33 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<OpenMetricsType> {
 
                     let mut actual_label_names = label_names.clone();
                     let mut actual_label_values = label_values.clone();
-                    for label in mandatory_labels {
-                        if !label_names.contains(&label.to_owned()) {
-                            return Err(ParseError::InvalidMetric(format!(
-                                "Missing mandatory label for metric: {}",
-                                label
-                            )));
-                        }
-
-                        let index = actual_label_names.iter().position(|s| s == label).unwrap();
+                    for label in mandatory_labels.iter() {
+                        // Search the labels that are left, rather than all of them, so a label can't be taken twice
+                        let index = match actual_label_names.iter().position(|s| s == label) {
+                            Some(index) => index,
+                            None => {
+                                return Err(ParseError::InvalidMetric(format!(
+                                    "Missing mandatory label for metric: {}",
+                                    label
+                                )))
+                            }
+                        };
 
                         actual_label_names.remove(index);
                         actual_label_values.remove(index);
                     }
 
+                    let reserved_labels = metric_type.get_reserved_labels();
+                    if let Some(label) = actual_label_names
+                        .iter()
+                        .find(|name| reserved_labels.contains(&name.as_str()))
+                    {
+                        return Err(ParseError::InvalidMetric(format!(
+                            "{:?} metrics can't have a `{}` label (got it on {})",
+                            metric_type, label, metric_name
+                        )));
+                    }
+
                     match &self.current_label_set {
                         None => self.current_label_set = Some(actual_label_values.clone()),
                         Some(s) => {
@@ -926,21 +917,30 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<OpenMetricsType> {
                     )?;
 
                     let metric_name = metric_name.trim_end_matches(suffix);
-                    if self.name.is_some() && self.name.as_ref().unwrap() != metric_name {
-                        return Err(ParseError::InvalidMetric(format!(
-                            "Invalid Name in metric family: {} != {}",
-                            metric_name,
-                            self.name.as_ref().unwrap()
-                        )));
-                    } else if self.name.is_none() {
-                        self.name = Some(metric_name.to_owned());
+                    match &self.name {
+                        Some(family_name) if family_name != metric_name => {
+                            return Err(ParseError::InvalidMetric(format!(
+                                "Invalid Name in metric family: {} != {}",
+                                metric_name, family_name
+                            )));
+                        }
+                        Some(_) => {}
+                        None => self.name = Some(metric_name.to_owned()),
                     }
 
+                    // `_created` lines can have their own timestamps, so aren't held to the sample's one
+                    let is_created_line = suffix == "_created";
                     let (existing_metric, created) = match self
                         .get_metric_by_labelset_mut(&actual_label_values)
                     {
+                        Some(metric) if is_created_line => (metric, false),
+                        Some(metric) if !metric.timestamp_seen => {
+                            metric.timestamp = timestamp;
+                            metric.timestamp_seen = true;
+                            (metric, false)
+                        }
                         Some(metric) => {
-                            match (metric.timestamp.as_ref(), timestamp.as_ref()) {
+                            match (metric.timestamp.map(|t| t.as_timestamp()), timestamp.map(|t| t.as_timestamp())) {
                                 (Some(metric_timestamp), Some(timestamp)) if timestamp < metric_timestamp => return Err(ParseError::InvalidMetric(format!("Timestamps went backwarts in family - saw {} and then saw{}", metric_timestamp, timestamp))),
                                 (Some(_), None) | (None, Some(_)) => return Err(ParseError::InvalidMetric("Missing timestamp in family (one metric had a timestamp, another didn't)".to_string())),
                                 (Some(metric_timestamp), Some(timestamp)) if timestamp >= metric_timestamp && !metric_type.can_have_multiple_lines() => return Ok(()),
@@ -953,11 +953,13 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<OpenMetricsType> {
                                 .as_ref()
                                 .unwrap_or(&OpenMetricsType::Unknown)
                                 .get_type_value();
-                            self.add_metric(MetricMarshal::new(
+                            let mut metric = MetricMarshal::new(
                                 actual_label_values.clone(),
-                                timestamp,
+                                if is_created_line { None } else { timestamp },
                                 new_metric,
-                            ));
+                            );
+                            metric.timestamp_seen = !is_created_line;
+                            self.add_metric(metric);
                             (
                                 self.get_metric_by_labelset_mut(&actual_label_values)
                                     .unwrap(),
@@ -966,22 +968,33 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<OpenMetricsType> {
                         }
                     };
 
-                    return action.0(
+                    action.0(
                         existing_metric,
                         metric_value,
-                        label_names,
-                        label_values,
+                        &label_names,
+                        &label_values,
                         exemplar,
                         created,
+                    )
+                    .map_err(|e| describe_duplicate(e, metric_name, &label_names, &label_values))?;
+
+                    self.record_line_position(
+                        actual_label_values,
+                        suffix,
+                        mandatory_labels.first().copied(),
+                        &label_names,
+                        &label_values,
                     );
+
+                    return Ok(());
                 }
             }
         }
 
-        return Err(ParseError::InvalidMetric(format!(
+        Err(ParseError::InvalidMetric(format!(
             "Found weird metric name for type ({:?}): {}",
             metric_type, metric_name
-        )));
+        )))
     }
 }
 
@@ -991,11 +1004,11 @@ impl From<MetricFamilyMarshal<OpenMetricsType>>
     fn from(marshal: MetricFamilyMarshal<OpenMetricsType>) -> Self {
         assert!(marshal.name.is_some());
 
-        MetricFamily::new(
+        MetricFamily::with_shared_label_names(
             marshal.name.unwrap(),
             marshal
                 .label_names
-                .map(|names| names.names)
+                .map(|names| names.names.into_vec())
                 .unwrap_or_default(),
             marshal.family_type.unwrap_or_default(),
             marshal.help.unwrap_or_default(),
@@ -1008,6 +1021,53 @@ impl From<MetricFamilyMarshal<OpenMetricsType>>
 
 pub fn parse_openmetrics(
     exposition_bytes: &str,
+) -> Result<MetricsExposition<OpenMetricsType, OpenMetricsValue>, ParseError> {
+    parse_openmetrics_with_options(exposition_bytes, &ParseOptions::default())
+}
+
+/// Parses an exposition from raw bytes, e.g. the body of an HTTP response, returning a `ParseError::Utf8`
+/// if they aren't valid UTF-8
+pub fn parse_openmetrics_bytes(
+    exposition_bytes: &[u8],
+) -> Result<MetricsExposition<OpenMetricsType, OpenMetricsValue>, ParseError> {
+    parse_openmetrics(std::str::from_utf8(exposition_bytes)?)
+}
+
+impl FromStr for MetricsExposition<OpenMetricsType, OpenMetricsValue> {
+    type Err = ParseError;
+
+    /// Parses an exposition with the default options, so that `input.parse()` works like `parse_openmetrics(input)`
+    fn from_str(exposition_bytes: &str) -> Result<Self, Self::Err> {
+        parse_openmetrics(exposition_bytes)
+    }
+}
+
+impl TryFrom<&str> for MetricsExposition<OpenMetricsType, OpenMetricsValue> {
+    type Error = ParseError;
+
+    fn try_from(exposition_bytes: &str) -> Result<Self, Self::Error> {
+        exposition_bytes.parse()
+    }
+}
+
+/// Reads an exposition to the end of `reader`, and parses it. Read failures are returned as a `ParseError::Io`
+pub fn parse_openmetrics_reader<R: Read>(
+    reader: R,
+) -> Result<MetricsExposition<OpenMetricsType, OpenMetricsValue>, ParseError> {
+    parse_openmetrics_reader_with_options(reader, &ParseOptions::default())
+}
+
+pub fn parse_openmetrics_reader_with_options<R: Read>(
+    reader: R,
+    options: &ParseOptions,
+) -> Result<MetricsExposition<OpenMetricsType, OpenMetricsValue>, ParseError> {
+    let exposition_bytes = read_exposition(reader, options)?;
+    parse_openmetrics_with_options(std::str::from_utf8(&exposition_bytes)?, options)
+}
+
+pub fn parse_openmetrics_with_options(
+    exposition_bytes: &str,
+    options: &ParseOptions,
 ) -> Result<MetricsExposition<OpenMetricsType, OpenMetricsValue>, ParseError> {
     use pest::iterators::Pair;
 
@@ -1019,7 +1079,7 @@ pub fn parse_openmetrics(
 
         let mut descriptor = pair.into_inner();
         let descriptor_type = descriptor.next().unwrap();
-        let metric_name = descriptor.next().unwrap().as_str().to_string();
+        let metric_name = parse_name(descriptor.next().unwrap()).into_owned();
 
         match descriptor_type.as_rule() {
             Rule::kw_help => {
@@ -1055,46 +1115,31 @@ pub fn parse_openmetrics(
 
         let labels = parse_labels(labels)?
             .into_iter()
-            .map(|(a, b)| (a.to_owned(), b.to_owned()))
+            .map(|(a, b)| (a.into_owned(), b.into_owned()))
             .collect();
 
-        let id = inner.next().unwrap().as_str();
-        let id = match id.parse() {
-            Ok(i) => i,
-            Err(_) => {
-                return Err(ParseError::InvalidMetric(format!(
-                    "Exemplar value must be a number (got: {})",
-                    id
-                )))
-            }
-        };
-
-        let timestamp = match inner.next() {
-            Some(timestamp) => match timestamp.as_str().parse() {
-                Ok(f) => Some(f),
-                Err(_) => {
-                    return Err(ParseError::InvalidMetric(format!(
-                        "Exemplar timestamp must be a number (got: {})",
-                        timestamp.as_str()
-                    )))
-                }
-            },
-            None => None,
-        };
+        let value = inner.next().unwrap().as_str();
+        let timestamp = inner.next().map(|timestamp| timestamp.as_str());
+        parse_exemplar_parts(labels, value, timestamp)
+    }
 
-        Ok(Exemplar::new(labels, id, timestamp))
+    fn parse_name(pair: Pair<'_, Rule>) -> Cow<'_, str> {
+        match pair.as_rule() {
+            Rule::quotedname => unescape_string(pair.into_inner().next().unwrap().as_str()),
+            _ => Cow::Borrowed(pair.as_str()),
+        }
     }
 
-    fn parse_labels(pair: Pair<Rule>) -> Result<Vec<(&str, &str)>, ParseError> {
-        assert_eq!(pair.as_rule(), Rule::labels);
+    fn parse_labels(pair: Pair<'_, Rule>) -> Result<ParsedLabels<'_>, ParseError> {
+        assert!(matches!(pair.as_rule(), Rule::labels | Rule::quotedlabels));
 
-        let mut label_pairs = pair.into_inner();
-        let mut labels: Vec<(&str, &str)> = Vec::new();
+        let label_pairs = pair.into_inner().filter(|p| p.as_rule() == Rule::label);
+        let mut labels = ParsedLabels::new();
 
-        while label_pairs.peek().is_some() && label_pairs.peek().unwrap().as_rule() == Rule::label {
-            let mut label = label_pairs.next().unwrap().into_inner();
-            let name = label.next().unwrap().as_str();
-            let value = label.next().unwrap().as_str();
+        for label in label_pairs {
+            let mut label = label.into_inner();
+            let name = parse_name(label.next().unwrap());
+            let value = unescape_string(label.next().unwrap().as_str());
 
             if labels.iter().any(|(n, _)| n == &name) {
                 return Err(ParseError::InvalidMetric(format!(
@@ -1106,7 +1151,7 @@ pub fn parse_openmetrics(
             labels.push((name, value));
         }
 
-        labels.sort_by_key(|l| l.0);
+        labels.sort_by(|a, b| a.0.cmp(&b.0));
 
         Ok(labels)
     }
@@ -1116,40 +1161,47 @@ pub fn parse_openmetrics(
         family: &mut MetricFamilyMarshal<OpenMetricsType>,
     ) -> Result<(), ParseError> {
         assert_eq!(pair.as_rule(), Rule::sample);
+        if family.is_repeated_line(pair.as_str()) {
+            return Ok(());
+        }
 
         let mut descriptor = pair.into_inner();
-        let metric_name = descriptor.next().unwrap().as_str();
+        let name_pair = descriptor.next().unwrap();
 
-        let labels = if descriptor.peek().unwrap().as_rule() == Rule::labels {
-            parse_labels(descriptor.next().unwrap())?
-        } else {
-            Vec::new()
+        let (metric_name, labels) = match name_pair.as_rule() {
+            // Quoted metric names live inside the labels, e.g. `{"my.metric",label="value"}`
+            Rule::quotedlabels => (
+                parse_name(name_pair.clone().into_inner().next().unwrap()),
+                parse_labels(name_pair)?,
+            ),
+            _ => {
+                let labels = if descriptor.peek().map(|p| p.as_rule()) == Some(Rule::labels) {
+                    parse_labels(descriptor.next().unwrap())?
+                } else {
+                    SmallVec::new()
+                };
+
+                (Cow::Borrowed(name_pair.as_str()), labels)
+            }
         };
 
         let (label_names, label_values) = {
-            let mut names = Vec::new();
-            let mut values = Vec::new();
+            let mut names = LabelValues::new();
+            let mut values = LabelValues::new();
             for (name, value) in labels.into_iter() {
-                names.push(name.to_owned());
-                values.push(value.to_owned());
+                names.push(SharedString::from(name.as_ref()));
+                values.push(SharedString::from(value.as_ref()));
             }
 
             (names, values)
         };
 
-        let value = descriptor.next().unwrap().as_str();
-        let value = match value.parse() {
-            Ok(f) => MetricNumber::Int(f),
-            Err(_) => match value.parse() {
-                Ok(f) => MetricNumber::Float(f),
-                Err(_) => {
-                    return Err(ParseError::InvalidMetric(format!(
-                        "Metric Value must be a number (got: {})",
-                        value
-                    )));
-                }
-            },
+        // The grammar always includes a value, but error rather than panicking if that ever changes
+        let value = match descriptor.next() {
+            Some(value) if value.as_rule() == Rule::number => value.as_str(),
+            _ => return Err(ParseError::InvalidMetric("missing metric value".to_owned())),
         };
+        let value: MetricNumber = value.parse()?;
 
         let mut timestamp = None;
         let mut exemplar = None;
@@ -1157,7 +1209,8 @@ pub fn parse_openmetrics(
         if descriptor.peek().is_some()
             && descriptor.peek().as_ref().unwrap().as_rule() == Rule::timestamp
         {
-            timestamp = Some(descriptor.next().unwrap().as_str().parse().unwrap());
+            let seconds = descriptor.next().unwrap().as_str().parse().unwrap();
+            timestamp = Some(RawTimestamp::Seconds(seconds));
         }
 
         if descriptor.peek().is_some()
@@ -1167,7 +1220,7 @@ pub fn parse_openmetrics(
         }
 
         family.process_new_metric(
-            metric_name,
+            &metric_name,
             value,
             label_names,
             label_values,
@@ -1180,10 +1233,11 @@ pub fn parse_openmetrics(
 
     fn parse_metric_family(
         pair: Pair<Rule>,
+        options: &ParseOptions,
     ) -> Result<MetricFamily<OpenMetricsType, OpenMetricsValue>, ParseError> {
         assert_eq!(pair.as_rule(), Rule::metricfamily);
 
-        let mut metric_family = MetricFamilyMarshal::empty();
+        let mut metric_family = MetricFamilyMarshal::with_options(options.clone());
 
         for child in pair.into_inner() {
             match child.as_rule() {
@@ -1199,13 +1253,42 @@ pub fn parse_openmetrics(
                 Rule::sample => {
                     parse_sample(child, &mut metric_family)?;
                 }
+                Rule::comment => check_comment(child, options)?,
                 _ => unreachable!(),
             }
         }
 
         metric_family.validate()?;
 
-        Ok(metric_family.into())
+        let line_positions = std::mem::take(&mut metric_family.line_positions);
+        let mut family: MetricFamily<OpenMetricsType, OpenMetricsValue> = metric_family.into();
+        if options.preserve_line_order {
+            family.set_line_order(&line_positions);
+        }
+
+        Ok(family)
+    }
+
+    fn check_comment(pair: Pair<Rule>, options: &ParseOptions) -> Result<(), ParseError> {
+        assert_eq!(pair.as_rule(), Rule::comment);
+
+        if options.strict {
+            return Err(ParseError::InvalidMetric(format!(
+                "OpenMetrics doesn't allow comments (got `{}`)",
+                pair.as_str().trim_end()
+            )));
+        }
+
+        Ok(())
+    }
+
+    check_size(exposition_bytes.len(), options)?;
+
+    // OpenMetrics expositions must always be terminated by an EOF, even if they're empty
+    if exposition_bytes.trim().is_empty() {
+        return Err(ParseError::InvalidMetric(
+            "Didn't find an EOF token".to_string(),
+        ));
     }
 
     let exposition_marshal = OpenMetricsParser::parse(Rule::exposition, exposition_bytes)?
@@ -1216,32 +1299,48 @@ pub fn parse_openmetrics(
     assert_eq!(exposition_marshal.as_rule(), Rule::exposition);
 
     let mut found_eof = false;
+    let mut interner = LabelInterner::default();
     for span in exposition_marshal.into_inner() {
         match span.as_rule() {
             Rule::metricfamily => {
-                let family = parse_metric_family(span)?;
-
-                if exposition.families.contains_key(&family.family_name) {
-                    return Err(ParseError::InvalidMetric(format!(
-                        "Found a metric family called {}, after that family was finalised",
-                        family.family_name
-                    )));
+                let mut family = parse_metric_family(span, options)?;
+                if options.intern {
+                    family.intern_labels(&mut interner);
                 }
 
-                exposition
-                    .families
-                    .insert(family.family_name.clone(), family);
+                if let Some(existing) = exposition.families.get_mut(&family.family_name) {
+                    if !options.merge_families {
+                        return Err(ParseError::InvalidMetric(format!(
+                            "Found a metric family called {}, after that family was finalised",
+                            family.family_name
+                        )));
+                    }
+
+                    existing.merge(family)?;
+                } else {
+                    exposition
+                        .families
+                        .insert(family.family_name.clone(), family);
+                }
             }
+            Rule::comment => check_comment(span, options)?,
             Rule::kw_eof => {
                 found_eof = true;
 
-                if span.as_span().end() != exposition_bytes.len()
-                    && !(span.as_span().end() == exposition_bytes.len() - 1
-                        && exposition_bytes.ends_with('\n'))
-                {
-                    return Err(ParseError::InvalidMetric(
-                        "Found text after the EOF token".to_string(),
-                    ));
+                let end = span.as_span().end();
+                let trailing = &exposition_bytes[end..];
+                if !matches!(trailing, "" | "\n" | "\r\n") {
+                    let newline_len = if trailing.starts_with("\r\n") {
+                        2
+                    } else if trailing.starts_with('\n') {
+                        1
+                    } else {
+                        0
+                    };
+
+                    return Err(ParseError::TextAfterEOF {
+                        offset: end + newline_len,
+                    });
                 }
             }
             _ => unreachable!(),
@@ -1256,3 +1355,22 @@ pub fn parse_openmetrics(
 
     Ok(exposition)
 }
+
+/// Parses an exposition into the raw pest parse tree, without building a `MetricsExposition`. This is an escape
+/// hatch for tooling that needs the position of every token, e.g. for editor diagnostics. The shape of the tree
+/// (and so `Rule`) follows the grammar, which isn't part of the stable API and can change in any release
+pub fn parse_openmetrics_pairs(exposition_bytes: &str) -> Result<Pairs<'_, Rule>, ParseError> {
+    Ok(OpenMetricsParser::parse(
+        Rule::exposition,
+        exposition_bytes,
+    )?)
+}
+
+/// Reads only the metadata (HELP, TYPE, and UNIT lines) of an exposition, skipping over its samples entirely.
+/// This is much faster than a full parse, but doesn't validate the samples, and families that don't have
+/// any descriptor lines aren't returned
+pub fn parse_openmetrics_metadata(
+    exposition_bytes: &str,
+) -> Result<Vec<OpenMetricsFamilyMetadata>, ParseError> {
+    parse_metadata(exposition_bytes, true)
+}
@@ -1,5 +1,12 @@
 use serde::Deserialize;
+
+use crate::openmetrics::{
+    parse_openmetrics, parse_openmetrics_bytes, parse_openmetrics_metadata,
+    parse_openmetrics_pairs, parse_openmetrics_reader, parse_openmetrics_with_options, Rule,
+};
+use crate::{MetricsExposition, OpenMetricsType, OpenMetricsValue, ParseOptions};
 use std::{
+    convert::TryFrom,
     fs,
     path::{Path, PathBuf},
 };
@@ -65,3 +72,843 @@ fn run_openmetrics_validation() {
         }
     }
 }
+
+#[test]
+fn test_empty_input() {
+    for input in ["", "\n\n", "   ", "# Just a comment\n"] {
+        assert!(
+            crate::openmetrics::parse_openmetrics(input).is_err(),
+            "{:?} shouldn't parse",
+            input
+        );
+    }
+
+    let exposition = crate::openmetrics::parse_openmetrics("# EOF\n").unwrap();
+    assert!(exposition.families.is_empty());
+}
+
+#[test]
+fn test_quoted_names() {
+    let test_str = r#"# TYPE "my.metric" gauge
+# HELP "my.metric" A metric with a dot in it
+{"my.metric",label="x"} 1
+{"my.metric","label with space"="y",label="z"} 2
+# TYPE plain_metric gauge
+plain_metric{"label with space"="y"} 3
+# EOF
+"#;
+    let exposition = crate::openmetrics::parse_openmetrics(test_str);
+    assert!(exposition.is_err(), "labelsets must match within a family");
+
+    let test_str = r#"# TYPE "my.metric" gauge
+# HELP "my.metric" A metric with a dot in it
+{"my.metric","label with space"="x"} 1
+{"my.metric","label with space"="y"} 2
+# TYPE plain_metric gauge
+plain_metric{"label with space"="y"} 3
+# EOF
+"#;
+    let exposition = crate::openmetrics::parse_openmetrics(test_str).unwrap();
+    assert_eq!(
+        exposition.families["my.metric"].help,
+        "A metric with a dot in it"
+    );
+    assert_eq!(
+        exposition.families["plain_metric"].get_label_names(),
+        &["label with space"]
+    );
+}
+
+#[test]
+fn test_created() {
+    let test_str = r#"# TYPE foo histogram
+foo_bucket{le="1"} 1
+foo_bucket{le="+Inf"} 2
+foo_count 2
+foo_sum 3
+foo_created 1520430000.123
+# TYPE bar summary
+bar_count 2
+bar_sum 3
+bar_created 1520430001
+# EOF
+"#;
+    let exposition = crate::openmetrics::parse_openmetrics(test_str).unwrap();
+
+    match exposition.families["foo"]
+        .iter_samples()
+        .next()
+        .unwrap()
+        .value
+    {
+        crate::OpenMetricsValue::Histogram(ref histogram) => {
+            assert_eq!(histogram.created(), Some(1520430000.123))
+        }
+        ref other => panic!("Expected a histogram, got {:?}", other),
+    }
+
+    match exposition.families["bar"]
+        .iter_samples()
+        .next()
+        .unwrap()
+        .value
+    {
+        crate::OpenMetricsValue::Summary(ref summary) => {
+            assert_eq!(summary.created(), Some(1520430001.))
+        }
+        ref other => panic!("Expected a summary, got {:?}", other),
+    };
+}
+
+#[test]
+fn test_render_round_trip() {
+    for file in fs::read_dir("./src/openmetrics/testdata").unwrap() {
+        let path = file.unwrap().path();
+        let exposition = parse_openmetrics(&fs::read_to_string(&path).unwrap()).unwrap();
+
+        let rendered = exposition.to_openmetrics_text();
+        assert!(rendered.ends_with("# EOF\n"));
+
+        let reparsed = parse_openmetrics(&rendered).unwrap_or_else(|e| {
+            panic!("failed to reparse {}: {}\n{}", path.display(), e, rendered)
+        });
+        assert_eq!(reparsed.families.len(), exposition.families.len());
+        assert_eq!(reparsed.to_openmetrics_text(), rendered);
+    }
+}
+
+#[test]
+fn test_mixed_case_types() {
+    for keyword in ["GAUGE", "Gauge", "gauge"] {
+        let test_str = format!("# TYPE foo {}\nfoo 1\n# EOF\n", keyword);
+        let exposition = parse_openmetrics(&test_str).unwrap();
+        assert_eq!(
+            exposition.families["foo"].family_type,
+            crate::OpenMetricsType::Gauge
+        );
+        assert!(exposition
+            .to_openmetrics_text()
+            .contains("# TYPE foo gauge\n"));
+    }
+
+    let exposition =
+        parse_openmetrics("# TYPE foo GaugeHistogram\nfoo_bucket{le=\"+Inf\"} 1\n# EOF\n").unwrap();
+    assert_eq!(
+        exposition.families["foo"].family_type,
+        crate::OpenMetricsType::GaugeHistogram
+    );
+}
+
+#[test]
+fn test_explicitly_positive_values() {
+    for (value, expected) in [("+5", 5.), ("+5.0", 5.), ("+1.5e2", 150.)] {
+        let test_str = format!("# TYPE foo gauge\nfoo {}\n# EOF\n", value);
+        let exposition = parse_openmetrics(&test_str).unwrap();
+        let sample = exposition.families["foo"].iter_samples().next().unwrap();
+        assert_eq!(
+            sample.value.as_f64(),
+            Some(expected),
+            "failed to parse {}",
+            value
+        );
+    }
+}
+
+#[test]
+fn test_parse_metadata() {
+    let test_str = fs::read_to_string("./src/openmetrics/testdata/upstream_example.txt").unwrap();
+    let exposition = parse_openmetrics(&test_str).unwrap();
+    let metadata = parse_openmetrics_metadata(&test_str).unwrap();
+
+    assert_eq!(metadata.len(), exposition.families.len());
+    for family_metadata in metadata.iter() {
+        let family = &exposition.families[&family_metadata.name];
+        assert_eq!(family_metadata.family_type, family.family_type);
+        assert_eq!(family_metadata.help, family.help);
+        assert_eq!(family_metadata.unit, family.unit);
+    }
+
+    assert_eq!(metadata[2].name, "process_cpu_seconds");
+    assert_eq!(metadata[2].unit, "seconds");
+}
+
+#[test]
+fn test_reserved_labels() {
+    for (family_type, sample) in [
+        ("histogram", "foo_sum{le=\"1\"} 3"),
+        ("histogram", "foo_count{le=\"1\"} 2"),
+        ("gaugehistogram", "foo_gsum{le=\"1\"} 3"),
+        ("summary", "foo_sum{quantile=\"0.5\"} 3"),
+        ("summary", "foo_count{quantile=\"0.5\"} 2"),
+    ] {
+        let test_str = format!("# TYPE foo {}\n{}\n# EOF\n", family_type, sample);
+        assert!(
+            parse_openmetrics(&test_str).is_err(),
+            "parsed {} with a reserved label",
+            sample
+        );
+    }
+}
+
+#[test]
+fn test_crlf_line_endings() {
+    let test_str = include_str!("testdata/upstream_example.txt");
+    let crlf_str = test_str.replace('\n', "\r\n");
+
+    let exposition = parse_openmetrics(test_str).unwrap();
+    let crlf_exposition = parse_openmetrics(&crlf_str).unwrap();
+    assert_eq!(
+        crlf_exposition.to_openmetrics_text(),
+        exposition.to_openmetrics_text()
+    );
+}
+
+#[test]
+fn test_text_after_eof() {
+    let test_str = "# TYPE foo gauge\nfoo 1\n# EOF\nfoo 2\n";
+    let offset = match parse_openmetrics(test_str) {
+        Err(crate::ParseError::TextAfterEOF { offset }) => offset,
+        other => panic!("Expected a TextAfterEOF error, got {:?}", other),
+    };
+
+    assert_eq!(&test_str[offset..], "foo 2\n");
+    assert!(parse_openmetrics(&test_str[..offset]).is_ok());
+
+    let test_str = "# TYPE foo gauge\r\nfoo 1\r\n# EOF\r\n\r\n";
+    assert!(matches!(
+        parse_openmetrics(test_str),
+        Err(crate::ParseError::TextAfterEOF { offset: 32 })
+    ));
+}
+
+#[test]
+fn test_stray_comments() {
+    let test_str = "# this is a comment\n# TYPE foo gauge\nfoo{a=\"1\"} 1\n# this is a comment\nfoo{a=\"2\"} 2\n#\n# EOF\n";
+    let exposition = parse_openmetrics(test_str).unwrap();
+    assert_eq!(exposition.families["foo"].num_samples(), 2);
+
+    let strict = ParseOptions {
+        strict: true,
+        ..ParseOptions::default()
+    };
+    assert!(parse_openmetrics_with_options(test_str, &strict).is_err());
+
+    // Descriptors with a missing space still aren't comments
+    assert!(parse_openmetrics("# TYPE foo\nfoo 1\n# EOF\n").is_err());
+}
+
+#[test]
+fn test_allow_negative_counters() {
+    use crate::{MetricNumber, OpenMetricsValue};
+
+    let test_str = "# TYPE foo counter\nfoo_total -3\nfoo_created 10\n# EOF\n";
+    assert!(parse_openmetrics(test_str).is_err());
+
+    let options = ParseOptions {
+        allow_negative_counters: true,
+        ..ParseOptions::default()
+    };
+    let exposition = parse_openmetrics_with_options(test_str, &options).unwrap();
+    let sample = exposition.get_sample("foo", &[]).unwrap();
+    match &sample.value {
+        OpenMetricsValue::Counter(c) => assert_eq!(c.value, MetricNumber::Int(-3)),
+        other => panic!("Expected a counter, got {:?}", other),
+    }
+
+    let test_str = "# TYPE foo counter\nfoo_total NaN\n# EOF\n";
+    assert!(parse_openmetrics(test_str).is_err());
+    assert!(parse_openmetrics_with_options(test_str, &options).is_ok());
+}
+
+#[test]
+fn test_nan_policy() {
+    let reject_nan = ParseOptions {
+        reject_nan: true,
+        ..ParseOptions::default()
+    };
+
+    let gauge = "# TYPE foo gauge\nfoo NaN\n# EOF\n";
+    assert!(parse_openmetrics(gauge).is_ok());
+    assert!(parse_openmetrics_with_options(gauge, &reject_nan).is_err());
+
+    let quantile = "# TYPE foo summary\nfoo{quantile=\"0.5\"} NaN\nfoo_sum 0\nfoo_count 0\n# EOF\n";
+    assert!(parse_openmetrics(quantile).is_ok());
+    assert!(parse_openmetrics_with_options(quantile, &reject_nan).is_err());
+
+    let histogram_sum =
+        "# TYPE foo histogram\nfoo_bucket{le=\"+Inf\"} 1\nfoo_sum NaN\nfoo_count 1\n# EOF\n";
+    assert!(parse_openmetrics(histogram_sum).is_ok());
+    assert!(parse_openmetrics_with_options(histogram_sum, &reject_nan).is_err());
+
+    let gauge_histogram_sum =
+        "# TYPE foo gaugehistogram\nfoo_bucket{le=\"+Inf\"} 1\nfoo_gsum NaN\nfoo_gcount 1\n# EOF\n";
+    assert!(parse_openmetrics(gauge_histogram_sum).is_ok());
+    assert!(parse_openmetrics_with_options(gauge_histogram_sum, &reject_nan).is_err());
+
+    let bucket = "# TYPE foo histogram\nfoo_bucket{le=\"+Inf\"} NaN\nfoo_count 1\n# EOF\n";
+    assert!(parse_openmetrics(bucket).is_err());
+}
+
+#[test]
+fn test_parse_pairs() {
+    let test_str = include_str!("testdata/upstream_example.txt");
+    let pairs = parse_openmetrics_pairs(test_str).unwrap();
+
+    let exposition = pairs.peek().unwrap();
+    assert_eq!(exposition.as_rule(), Rule::exposition);
+    assert_eq!(exposition.as_span().start(), 0);
+    assert_eq!(exposition.as_span().end(), test_str.len());
+    assert!(pairs.flatten().any(|p| p.as_rule() == Rule::kw_eof));
+
+    assert!(parse_openmetrics_pairs("foo 1\n").is_err());
+}
+
+#[test]
+fn test_different_label_sets() {
+    let extra = "# TYPE node_power_supply_info gauge
+node_power_supply_info{power_supply=\"BAT0\",type=\"Battery\"} 1
+node_power_supply_info{power_supply=\"ucsi\",type=\"USB\",usb_type=\"C\"} 1
+# EOF
+";
+    let err = parse_openmetrics(extra).unwrap_err().to_string();
+    assert!(err.contains("has extra label 'usb_type'"), "{}", err);
+    assert!(
+        err.contains("family expects [power_supply, type]"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn test_duplicate_metric() {
+    let duplicate = "# TYPE temperature_celsius gauge
+temperature_celsius{room=\"bedroom\"} 18
+temperature_celsius{room=\"kitchen\"} 21
+temperature_celsius{room=\"kitchen\"} 22
+# EOF
+";
+    let err = parse_openmetrics(duplicate).unwrap_err().to_string();
+    assert_eq!(
+        err,
+        "Found two metrics with the same labelset: temperature_celsius{room=\"kitchen\"}"
+    );
+}
+
+#[test]
+fn test_strict_summary_sum_count() {
+    let strict = ParseOptions {
+        strict: true,
+        ..ParseOptions::default()
+    };
+
+    let count_only = r#"# TYPE rpc_duration_seconds summary
+rpc_duration_seconds{quantile="0.5"} 4773
+rpc_duration_seconds_count 2693
+# EOF
+"#;
+    assert!(parse_openmetrics(count_only).is_ok());
+    let err = parse_openmetrics_with_options(count_only, &strict).unwrap_err();
+    assert_eq!(err.to_string(), "Sum must be present if count is present");
+}
+
+#[test]
+fn test_large_integers() {
+    use crate::{MetricNumber, OpenMetricsValue};
+
+    let test_str = "# TYPE big gauge
+big{size=\"u64\"} 18446744073709551615
+big{size=\"huge\"} 99999999999999999999999
+# EOF
+";
+
+    let exposition = parse_openmetrics(test_str).unwrap();
+    let family = &exposition.families["big"];
+    let value = |size: &str| match &family
+        .get_sample_by_label_values(&[size.to_owned()])
+        .unwrap()
+        .value
+    {
+        OpenMetricsValue::Gauge(n) => *n,
+        other => panic!("Expected a gauge, got {:?}", other),
+    };
+
+    assert_eq!(value("u64"), MetricNumber::Float(18446744073709551615.));
+    assert_eq!(value("huge"), MetricNumber::Float(99999999999999999999999.));
+}
+
+#[test]
+fn test_summary_created() {
+    let test_str = r#"# TYPE rpc_duration_seconds summary
+rpc_duration_seconds{quantile="0.5"} 0.25
+rpc_duration_seconds{quantile="0.99"} 1.5
+rpc_duration_seconds_sum 42.5
+rpc_duration_seconds_count 100
+rpc_duration_seconds_created 1520430001.5
+# EOF
+"#;
+
+    let exposition = parse_openmetrics(test_str).unwrap();
+    let family = &exposition.families["rpc_duration_seconds"];
+    match &family.iter_samples().next().unwrap().value {
+        crate::OpenMetricsValue::Summary(summary) => {
+            assert_eq!(summary.created(), Some(1520430001.5));
+            assert_eq!(summary.quantiles.len(), 2);
+        }
+        other => panic!("Expected a summary, got {:?}", other),
+    }
+
+    let rendered = exposition.to_openmetrics_text();
+    assert!(rendered.contains("rpc_duration_seconds_created 1520430001.5\n"));
+    assert!(family.assert_roundtrips().is_ok());
+
+    // A summary can only be created once
+    let duplicate = test_str.replace("# EOF", "rpc_duration_seconds_created 1520430002\n# EOF");
+    assert!(parse_openmetrics(&duplicate).is_err());
+}
+
+#[test]
+fn test_infinite_bucket_spellings() {
+    for inf in ["+Inf", "Inf"].iter() {
+        let test_str = format!(
+            "# TYPE foo histogram
+foo_bucket{{le=\"1\"}} 1
+foo_bucket{{le=\"{}\"}} 2
+foo_sum 3
+foo_count 2
+# EOF
+",
+            inf
+        );
+
+        let exposition = parse_openmetrics(&test_str).unwrap();
+        match &exposition.families["foo"]
+            .iter_samples()
+            .next()
+            .unwrap()
+            .value
+        {
+            crate::OpenMetricsValue::Histogram(h) => {
+                assert_eq!(h.buckets[1].upper_bound, f64::INFINITY, "{}", inf)
+            }
+            other => panic!("Expected a histogram, got {:?}", other),
+        };
+    }
+
+    let two_infs = "# TYPE foo histogram
+foo_bucket{le=\"+Inf\"} 2
+foo_bucket{le=\"Inf\"} 2
+# EOF
+";
+    assert!(parse_openmetrics(two_infs).is_err());
+}
+
+#[test]
+fn test_duplicate_bucket_bounds() {
+    let test_str = "# TYPE foo histogram
+foo_bucket{le=\"0.5\"} 1
+foo_bucket{le=\"0.5\"} 1
+foo_bucket{le=\"+Inf\"} 2
+foo_sum 3
+foo_count 2
+# EOF
+";
+    let err = parse_openmetrics(test_str).unwrap_err().to_string();
+    assert_eq!(
+        err,
+        "Histograms can't have duplicate bucket bounds (got 0.5 twice)"
+    );
+
+    let gauge_histogram = "# TYPE foo gaugehistogram
+foo_bucket{le=\"+Inf\"} 2
+foo_bucket{le=\"Inf\"} 2
+foo_gsum 3
+foo_gcount 2
+# EOF
+";
+    let err = parse_openmetrics(gauge_histogram).unwrap_err().to_string();
+    assert!(err.contains("+Inf"), "{}", err);
+}
+
+#[test]
+fn test_exemplar_timestamps() {
+    use crate::OpenMetricsValue;
+
+    let exposition = |exemplar: &str| {
+        format!(
+            "# TYPE foo histogram
+foo_bucket{{le=\"+Inf\"}} 1 # {{trace_id=\"abc\"}} 0.5{}
+foo_sum 0.5
+foo_count 1
+# EOF
+",
+            exemplar
+        )
+    };
+
+    let exemplar = |exemplar: &str| {
+        let exposition = parse_openmetrics(&exposition(exemplar)).unwrap();
+        let sample = exposition.families["foo"].iter_samples().next().unwrap();
+        match &sample.value {
+            OpenMetricsValue::Histogram(h) => h.buckets[0].exemplar.clone().unwrap(),
+            other => panic!("Expected a histogram, got {:?}", other),
+        }
+    };
+
+    let timestamped = exemplar(" 1520879607.789");
+    assert!(timestamped.has_timestamp());
+    assert_eq!(timestamped.timestamp(), Some(1520879607.789));
+
+    let untimestamped = exemplar("");
+    assert!(!untimestamped.has_timestamp());
+    assert_eq!(untimestamped.timestamp(), None);
+
+    assert!(parse_openmetrics(&exposition(" NaN")).is_err());
+    assert_eq!(
+        parse_openmetrics(&exposition(" 1e999"))
+            .unwrap_err()
+            .to_string(),
+        "Exemplar timestamp must be finite (got: 1e999)"
+    );
+}
+
+#[test]
+fn test_parse_bytes_and_readers() {
+    use crate::ParseError;
+
+    let test_str = "# TYPE up gauge\nup 1\n# EOF\n";
+    let expected = parse_openmetrics(test_str).unwrap();
+    assert_eq!(
+        parse_openmetrics_bytes(test_str.as_bytes()).unwrap(),
+        expected
+    );
+    assert_eq!(
+        parse_openmetrics_reader(test_str.as_bytes()).unwrap(),
+        expected
+    );
+
+    let invalid = b"# TYPE up gauge\nup{instance=\"\xff\"} 1\n# EOF\n";
+    assert!(matches!(
+        parse_openmetrics_bytes(invalid),
+        Err(ParseError::Utf8(_))
+    ));
+}
+
+#[test]
+fn test_negative_and_fractional_timestamps() {
+    let test_str = "# TYPE mymetric gauge
+mymetric{a=\"1\"} 5 -100.5
+mymetric{a=\"2\"} 5 1520879607.789123
+# EOF
+";
+    let exposition = parse_openmetrics(test_str).unwrap();
+    let family = &exposition.families["mymetric"];
+    let timestamps: Vec<_> = family.iter_samples().map(|s| s.timestamp).collect();
+    assert_eq!(timestamps, vec![Some(-100.5), Some(1520879607.789123)]);
+
+    let rendered = format!("{}# EOF\n", exposition);
+    assert_eq!(rendered, test_str);
+    assert_eq!(parse_openmetrics(&rendered).unwrap(), exposition);
+}
+
+#[test]
+fn test_missing_value() {
+    for test_str in [
+        "foo\n# EOF\n",
+        "foo{a=\"b\"}\n# EOF\n",
+        "foo{a=\"b\"} \n# EOF\n",
+        "{\"foo\"}\n# EOF\n",
+    ]
+    .iter()
+    {
+        assert!(parse_openmetrics(test_str).is_err(), "{}", test_str);
+        assert!(parse_openmetrics_pairs(test_str).is_err(), "{}", test_str);
+    }
+}
+
+#[test]
+fn test_info_units() {
+    use crate::{MetricFamily, OpenMetricsType, OpenMetricsValue};
+
+    // Info families can't have a unit, whichever order the TYPE and UNIT lines come in
+    for test_str in [
+        "# TYPE build_seconds info\n# UNIT build_seconds seconds\nbuild_seconds_info{version=\"1\"} 1\n# EOF\n",
+        "# HELP build_seconds Build info\n# UNIT build_seconds seconds\n# TYPE build_seconds info\nbuild_seconds_info{version=\"1\"} 1\n# EOF\n",
+    ]
+    .iter()
+    {
+        assert_eq!(
+            parse_openmetrics(test_str).unwrap_err().to_string(),
+            "Info metrics can't have units"
+        );
+    }
+
+    let family = |family_type: OpenMetricsType| {
+        MetricFamily::<OpenMetricsType, OpenMetricsValue>::new(
+            String::from("build_seconds"),
+            vec![],
+            family_type,
+            String::new(),
+            String::from("seconds"),
+        )
+    };
+
+    assert_eq!(
+        family(OpenMetricsType::Info)
+            .validate()
+            .unwrap_err()
+            .to_string(),
+        "Info metrics can't have units"
+    );
+    assert!(family(OpenMetricsType::Gauge).validate().is_ok());
+}
+
+#[test]
+fn test_timestamp_units() {
+    // OpenMetrics timestamps are seconds since the epoch
+    let exposition = parse_openmetrics("# TYPE foo gauge\nfoo 5 1395066363.123\n# EOF\n").unwrap();
+    let sample = exposition.families["foo"].iter_samples().next().unwrap();
+    assert_eq!(sample.timestamp, Some(1395066363.123));
+    assert_eq!(sample.timestamp_as_millis_i64(), Some(1395066363123));
+    assert_eq!(sample.timestamp_as_seconds_f64(), Some(1395066363.123));
+    assert_eq!(
+        sample.raw_timestamp(),
+        Some(crate::RawTimestamp::Seconds(1395066363.123))
+    );
+}
+
+#[test]
+fn test_padded_bounds() {
+    use crate::OpenMetricsValue;
+
+    let test_str = "# TYPE foo histogram
+foo_bucket{le=\" 0.5\"} 1
+foo_bucket{le=\" +Inf \"} 2
+foo_sum 1.5
+foo_count 2
+# TYPE bar summary
+bar{quantile=\"0.9 \"} 1
+# EOF
+";
+    let exposition = parse_openmetrics(test_str).unwrap();
+
+    let histogram = exposition.families["foo"].iter_samples().next().unwrap();
+    match &histogram.value {
+        OpenMetricsValue::Histogram(h) => {
+            let bounds: Vec<_> = h.buckets.iter().map(|b| b.upper_bound).collect();
+            assert_eq!(bounds, vec![0.5, f64::INFINITY]);
+        }
+        other => panic!("Expected a histogram, got {:?}", other),
+    }
+
+    let summary = exposition.families["bar"].iter_samples().next().unwrap();
+    match &summary.value {
+        OpenMetricsValue::Summary(s) => assert_eq!(s.quantiles[0].quantile, 0.9),
+        other => panic!("Expected a summary, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_stateset_roundtrip() {
+    // Each state is its own sample, labelled with the state, so it renders as one line per state
+    let test_str = "# TYPE feature stateset
+feature{feature=\"a\"} 1
+feature{feature=\"b\"} 0
+feature{feature=\"c\"} 0
+# EOF
+";
+    let exposition = parse_openmetrics(test_str).unwrap();
+    assert_eq!(exposition.families["feature"].iter_samples().count(), 3);
+
+    let rendered = format!("{}# EOF\n", exposition);
+    assert_eq!(rendered, test_str);
+    assert_eq!(parse_openmetrics(&rendered).unwrap(), exposition);
+}
+
+#[test]
+fn test_preserve_line_order() {
+    let test_str = "# TYPE foo histogram
+foo_count{a=\"1\"} 2
+foo_sum{a=\"1\"} 3
+foo_bucket{a=\"1\",le=\"1\"} 1
+foo_bucket{a=\"1\",le=\"+Inf\"} 2
+# TYPE bar counter
+bar_created 1520430000
+bar_total 5
+# TYPE baz gaugehistogram
+baz_gcount 2
+baz_bucket{le=\"+Inf\"} 2
+baz_gsum 3
+# EOF
+";
+
+    let options = ParseOptions {
+        preserve_line_order: true,
+        ..Default::default()
+    };
+    let exposition = parse_openmetrics_with_options(test_str, &options).unwrap();
+    for (name, expected) in [
+        ("foo", "# TYPE foo histogram\nfoo_count{a=\"1\"} 2\nfoo_sum{a=\"1\"} 3\nfoo_bucket{a=\"1\",le=\"1\"} 1\nfoo_bucket{a=\"1\",le=\"+Inf\"} 2\n"),
+        ("bar", "# TYPE bar counter\nbar_created 1520430000\nbar_total 5\n"),
+        // Gauge histograms' `_gsum` and `_gcount` lines keep their place too
+        ("baz", "# TYPE baz gaugehistogram\nbaz_count 2\nbaz_bucket{le=\"+Inf\"} 2\nbaz_sum 3\n"),
+    ]
+    .iter()
+    {
+        assert_eq!(exposition.families[*name].to_string(), *expected);
+    }
+
+    // Without the option, lines are grouped by series, and the order doesn't affect equality
+    let grouped = parse_openmetrics(test_str).unwrap();
+    assert_eq!(grouped, exposition);
+    assert_ne!(
+        grouped.families["foo"].to_string(),
+        exposition.families["foo"].to_string()
+    );
+}
+
+#[test]
+fn test_dedupe_identical() {
+    let options = ParseOptions {
+        dedupe_identical: true,
+        ..Default::default()
+    };
+
+    // An exact duplicate is ignored with the option, but is still an error without it
+    let duplicated = "# TYPE foo gauge\nfoo{a=\"1\"} 5\nfoo{a=\"2\"} 6\nfoo{a=\"1\"} 5\n# EOF\n";
+    let exposition = parse_openmetrics_with_options(duplicated, &options).unwrap();
+    assert_eq!(exposition.families["foo"].iter_samples().count(), 2);
+    assert_eq!(
+        exposition,
+        parse_openmetrics("# TYPE foo gauge\nfoo{a=\"1\"} 5\nfoo{a=\"2\"} 6\n# EOF\n").unwrap()
+    );
+    assert!(parse_openmetrics(duplicated).is_err());
+
+    // Duplicates with a different value or timestamp conflict, so are still errors
+    for conflicting in [
+        "# TYPE foo gauge\nfoo{a=\"1\"} 5\nfoo{a=\"1\"} 6\n# EOF\n",
+        "# TYPE foo gauge\nfoo{a=\"1\"} 5\nfoo{a=\"1\"} 5 200\n# EOF\n",
+    ]
+    .iter()
+    {
+        assert!(parse_openmetrics_with_options(conflicting, &options).is_err());
+    }
+}
+
+#[test]
+fn test_from_str() {
+    let input = "# TYPE foo gauge\nfoo{a=\"1\"} 5\n# EOF\n";
+    let exposition: MetricsExposition<OpenMetricsType, OpenMetricsValue> = input.parse().unwrap();
+    assert_eq!(exposition, parse_openmetrics(input).unwrap());
+    assert_eq!(MetricsExposition::try_from(input).unwrap(), exposition);
+
+    assert!("foo{a=\"1\"}\n"
+        .parse::<MetricsExposition<OpenMetricsType, OpenMetricsValue>>()
+        .is_err());
+}
+
+#[test]
+fn test_missing_mandatory_label() {
+    // Buckets without an `le`, and quantiles without a `quantile`, are errors rather than panics
+    for (input, label) in [
+        (
+            "# TYPE foo histogram\nfoo_bucket{a=\"1\"} 1\nfoo_sum 1\nfoo_count 1\n# EOF\n",
+            "le",
+        ),
+        (
+            "# TYPE foo gaugehistogram\nfoo_bucket{a=\"1\"} 1\n# EOF\n",
+            "le",
+        ),
+        (
+            "# TYPE foo summary\nfoo{a=\"1\"} 1\nfoo_sum 1\nfoo_count 1\n# EOF\n",
+            "quantile",
+        ),
+    ]
+    .iter()
+    {
+        let err = parse_openmetrics(input).unwrap_err().to_string();
+        assert!(
+            err.contains(&format!("Missing mandatory label for metric: {}", label)),
+            "{}",
+            err
+        );
+    }
+}
+
+#[test]
+fn test_multiple_bucket_exemplars() {
+    let test_str = "# TYPE foo histogram
+foo_bucket{le=\"0.5\"} 1 # {trace_id=\"abc\"} 0.25
+foo_bucket{le=\"0.5\"} 1 # {trace_id=\"def\"} 0.3
+foo_bucket{le=\"+Inf\"} 2
+foo_sum 3
+foo_count 2
+# EOF
+";
+    let err = parse_openmetrics(test_str).unwrap_err().to_string();
+    assert_eq!(
+        err,
+        "Histogram buckets can't have more than one exemplar (got two on the 0.5 bucket)"
+    );
+}
+
+#[test]
+fn test_syntax_error_position() {
+    let err = parse_openmetrics("# TYPE foo gauge\nfoo{a=\"1\" 2\n# EOF\n").unwrap_err();
+    assert!(matches!(err, crate::ParseError::Syntax { .. }), "{:?}", err);
+    assert_eq!(err.line_col(), Some((2, 4)));
+}
+
+#[test]
+fn test_created_with_sample_timestamps() {
+    // `_created` lines aren't held to the sample's timestamp, whichever order they come in
+    for test_str in [
+        "# TYPE foo histogram\nfoo_bucket{le=\"+Inf\"} 2\nfoo_count 2\nfoo_sum 3\nfoo_created 1520430000.123\n# EOF\n",
+        "# TYPE foo histogram\nfoo_bucket{le=\"+Inf\"} 2 100\nfoo_count 2 100\nfoo_sum 3 100\nfoo_created 1520430000.123\n# EOF\n",
+        "# TYPE foo histogram\nfoo_created 1520430000.123\nfoo_bucket{le=\"+Inf\"} 2 100\nfoo_count 2 100\nfoo_sum 3 100\n# EOF\n",
+        "# TYPE foo histogram\nfoo_bucket{le=\"+Inf\"} 2\nfoo_count 2\nfoo_sum 3\nfoo_created 1520430000.123 100\n# EOF\n",
+    ]
+    .iter()
+    {
+        let exposition = parse_openmetrics(test_str).unwrap();
+        let sample = exposition.families["foo"].iter_samples().next().unwrap();
+        match &sample.value {
+            crate::OpenMetricsValue::Histogram(histogram) => {
+                assert_eq!(histogram.created(), Some(1520430000.123))
+            }
+            other => panic!("Expected a histogram, got {:?}", other),
+        }
+
+        let expected = if test_str.contains("2 100") {
+            Some(100.)
+        } else {
+            None
+        };
+        assert_eq!(sample.timestamp, expected);
+    }
+
+    let counter = "# TYPE foo counter\nfoo_total 3 100\nfoo_created 1520430000.123\n# EOF\n";
+    assert!(parse_openmetrics(counter).is_ok());
+
+    // The sample's other lines still have to agree with each other
+    let mismatched = "# TYPE foo histogram\nfoo_created 1520430000.123\nfoo_bucket{le=\"+Inf\"} 2 100\nfoo_count 2\nfoo_sum 3\n# EOF\n";
+    assert!(parse_openmetrics(mismatched).is_err());
+}
+
+#[test]
+fn test_max_label_value_len() {
+    let test_str = "# TYPE foo gauge\nfoo{a=\"abcdefgh\"} 1\n# EOF\n";
+
+    let options = ParseOptions {
+        max_label_value_len: Some(4),
+        ..Default::default()
+    };
+    assert!(parse_openmetrics_with_options(test_str, &options).is_err());
+
+    let options = ParseOptions {
+        max_label_value_len: Some(4),
+        truncate_label_values: true,
+        ..Default::default()
+    };
+    let exposition = parse_openmetrics_with_options(test_str, &options).unwrap();
+    assert!(exposition.families["foo"].contains_labelset(&[("a", "abcd")]));
+}
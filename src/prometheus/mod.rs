@@ -3,4 +3,9 @@ mod tests;
 
 mod parsers;
 
-pub use parsers::parse_prometheus;
+pub use parsers::{
+    parse_prometheus, parse_prometheus_bytes, parse_prometheus_metadata, parse_prometheus_multi,
+    parse_prometheus_multi_with_options, parse_prometheus_multi_with_separator,
+    parse_prometheus_pairs, parse_prometheus_reader, parse_prometheus_reader_with_options,
+    parse_prometheus_visit, parse_prometheus_with_options, Rule,
+};
@@ -1,11 +1,18 @@
-use std::convert::TryFrom;
+use std::{borrow::Cow, convert::TryFrom, io::Read, str::FromStr};
 
-use pest::Parser;
+use pest::{
+    iterators::{Pair, Pairs},
+    Parser,
+};
+use smallvec::SmallVec;
 
 use crate::{
     internal::{
-        CounterValueMarshal, LabelNames, MarshalledMetric, MarshalledMetricFamily,
-        MetricFamilyMarshal, MetricMarshal, MetricProcesser, MetricValueMarshal, MetricsType,
+        check_bucket_bounds, check_size, check_sum_and_count, describe_duplicate, parse_count,
+        parse_exemplar_parts, parse_le, parse_metadata, parse_quantile, read_exposition,
+        syntax_error, unescape_help, unescape_string, CounterValueMarshal, LabelInterner,
+        LabelNames, LabelValues, MarshalledMetric, MarshalledMetricFamily, MetricFamilyMarshal,
+        MetricMarshal, MetricProcesser, MetricValueMarshal, MetricsType, ParsedLabels,
     },
     public::*,
 };
@@ -16,7 +23,7 @@ struct PrometheusParser;
 
 impl From<pest::error::Error<Rule>> for ParseError {
     fn from(err: pest::error::Error<Rule>) -> Self {
-        ParseError::ParseError(err.to_string())
+        syntax_error(err)
     }
 }
 
@@ -27,7 +34,10 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<PrometheusType> {
         if let Some(name) = &self.name {
             // Counters have to end with _total
             if self.family_type == Some(PrometheusType::Counter) && !name.ends_with("_total") {
-                return Err(ParseError::InvalidMetric(format!("Counters should have a _total suffix. Got {}", name)));
+                return Err(ParseError::InvalidMetric(format!(
+                    "Counters should have a _total suffix. Got {}",
+                    name
+                )));
             }
         }
 
@@ -42,11 +52,12 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<PrometheusType> {
         &mut self,
         metric_name: &str,
         metric_value: MetricNumber,
-        label_names: Vec<String>,
-        label_values: Vec<String>,
-        timestamp: Option<Timestamp>,
+        label_names: LabelValues,
+        label_values: LabelValues,
+        timestamp: Option<RawTimestamp>,
         exemplar: Option<Exemplar>,
     ) -> Result<(), Self::Error> {
+        let allow_negative_counters = self.options.allow_negative_counters;
         let handlers = vec![
             (
                 vec![PrometheusType::Histogram],
@@ -57,24 +68,20 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<PrometheusType> {
                         MetricProcesser::new(
                             |existing_metric: &mut MetricMarshal,
                              metric_value: MetricNumber,
-                             label_names: Vec<String>,
-                             label_values: Vec<String>,
+                             label_names: &[SharedString],
+                             label_values: &[SharedString],
                              exemplar: Option<Exemplar>,
                              _: bool| {
-                                let bucket_bound: f64 = {
+                                if metric_value.as_f64().is_nan() {
+                                    return Err(ParseError::InvalidMetric(
+                                        "Histogram bucket counts can't be NaN".to_owned(),
+                                    ));
+                                }
+
+                                let bucket_bound = {
                                     let bound_index =
                                         label_names.iter().position(|s| s == "le").unwrap();
-
-                                    let bound = &label_values[bound_index];
-                                    match bound.parse() {
-                                        Ok(f) => f,
-                                        Err(_) => {
-                                            return Err(ParseError::InvalidMetric(format!(
-                                                "Invalid histogram bound: {}",
-                                                bound
-                                            )));
-                                        }
-                                    }
+                                    parse_le(&label_values[bound_index])?
                                 };
 
                                 let bucket = HistogramBucket {
@@ -101,28 +108,14 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<PrometheusType> {
                         MetricProcesser::new(
                             |existing_metric: &mut MetricMarshal,
                              metric_value: MetricNumber,
-                             _: Vec<String>,
-                             _: Vec<String>,
+                             _: &[SharedString],
+                             _: &[SharedString],
                              _: Option<Exemplar>,
                              _: bool| {
                                 if let MetricValueMarshal::Histogram(histogram_value) =
                                     &mut existing_metric.value
                                 {
-                                    let metric_value = if let Some(value) = metric_value.as_i64() {
-                                        if value < 0 {
-                                            return Err(ParseError::InvalidMetric(format!(
-                                                "Histogram counts must be positive (got: {})",
-                                                value
-                                            )));
-                                        }
-
-                                        value as u64
-                                    } else {
-                                        return Err(ParseError::InvalidMetric(format!(
-                                            "Histogram counts must be integers (got: {})",
-                                            metric_value.as_f64()
-                                        )));
-                                    };
+                                    let metric_value = parse_count(metric_value, "Histogram")?;
 
                                     match histogram_value.count {
                                         Some(_) => {
@@ -146,8 +139,8 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<PrometheusType> {
                         MetricProcesser::new(
                             |existing_metric: &mut MetricMarshal,
                              metric_value: MetricNumber,
-                             _: Vec<String>,
-                             _: Vec<String>,
+                             _: &[SharedString],
+                             _: &[SharedString],
                              _: Option<Exemplar>,
                              _: bool| {
                                 if let MetricValueMarshal::Histogram(histogram_value) =
@@ -172,28 +165,14 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<PrometheusType> {
                         MetricProcesser::new(
                             |existing_metric: &mut MetricMarshal,
                              metric_value: MetricNumber,
-                             _: Vec<String>,
-                             _: Vec<String>,
+                             _: &[SharedString],
+                             _: &[SharedString],
                              _: Option<Exemplar>,
                              _: bool| {
                                 if let MetricValueMarshal::Histogram(histogram_value) =
                                     &mut existing_metric.value
                                 {
-                                    let metric_value = if let Some(value) = metric_value.as_i64() {
-                                        if value < 0 {
-                                            return Err(ParseError::InvalidMetric(format!(
-                                                "Histogram counts must be positive (got: {})",
-                                                value
-                                            )));
-                                        }
-
-                                        value as u64
-                                    } else {
-                                        return Err(ParseError::InvalidMetric(format!(
-                                            "Histogram counts must be integers (got: {})",
-                                            metric_value.as_f64()
-                                        )));
-                                    };
+                                    let metric_value = parse_count(metric_value, "Histogram")?;
 
                                     match histogram_value.count {
                                         Some(_) => {
@@ -219,12 +198,12 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<PrometheusType> {
                     "",
                     vec![],
                     MetricProcesser::new(
-                        |existing_metric: &mut MetricMarshal,
-                         metric_value: MetricNumber,
-                         _: Vec<String>,
-                         _: Vec<String>,
-                         _: Option<Exemplar>,
-                         _: bool| {
+                        move |existing_metric: &mut MetricMarshal,
+                              metric_value: MetricNumber,
+                              _: &[SharedString],
+                              _: &[SharedString],
+                              _: Option<Exemplar>,
+                              _: bool| {
                             if let MetricValueMarshal::Counter(counter_value) =
                                 &mut existing_metric.value
                             {
@@ -233,7 +212,7 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<PrometheusType> {
                                 }
 
                                 let value = metric_value.as_f64();
-                                if value < 0. || value.is_nan() {
+                                if !allow_negative_counters && (value < 0. || value.is_nan()) {
                                     return Err(ParseError::InvalidMetric(format!(
                                         "Counter totals must be non negative (got: {})",
                                         metric_value.as_f64()
@@ -258,8 +237,8 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<PrometheusType> {
                     MetricProcesser::new(
                         |existing_metric: &mut MetricMarshal,
                          metric_value: MetricNumber,
-                         _: Vec<String>,
-                         _: Vec<String>,
+                         _: &[SharedString],
+                         _: &[SharedString],
                          _: Option<Exemplar>,
                          _: bool| {
                             if let MetricValueMarshal::Gauge(gauge_value) =
@@ -288,8 +267,8 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<PrometheusType> {
                     MetricProcesser::new(
                         |existing_metric: &mut MetricMarshal,
                          metric_value: MetricNumber,
-                         _: Vec<String>,
-                         _: Vec<String>,
+                         _: &[SharedString],
+                         _: &[SharedString],
                          _: Option<Exemplar>,
                          _: bool| {
                             if let MetricValueMarshal::Unknown(unknown_value) =
@@ -319,27 +298,14 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<PrometheusType> {
                         MetricProcesser::new(
                             |existing_metric: &mut MetricMarshal,
                              metric_value: MetricNumber,
-                             _: Vec<String>,
-                             _: Vec<String>,
+                             _: &[SharedString],
+                             _: &[SharedString],
                              _: Option<Exemplar>,
                              _: bool| {
                                 if let MetricValueMarshal::Summary(summary_value) =
                                     &mut existing_metric.value
                                 {
-                                    let metric_value = if let Some(value) = metric_value.as_i64() {
-                                        if value < 0 {
-                                            return Err(ParseError::InvalidMetric(format!(
-                                                "Summary counts must be positive (got: {})",
-                                                value
-                                            )));
-                                        }
-                                        value as u64
-                                    } else {
-                                        return Err(ParseError::InvalidMetric(format!(
-                                            "Summary counts must be integers (got: {})",
-                                            metric_value.as_f64()
-                                        )));
-                                    };
+                                    let metric_value = parse_count(metric_value, "Summary")?;
 
                                     if summary_value.count.is_none() {
                                         summary_value.count = Some(metric_value);
@@ -360,14 +326,14 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<PrometheusType> {
                         MetricProcesser::new(
                             |existing_metric: &mut MetricMarshal,
                              metric_value: MetricNumber,
-                             _: Vec<String>,
-                             _: Vec<String>,
+                             _: &[SharedString],
+                             _: &[SharedString],
                              _: Option<Exemplar>,
                              _: bool| {
                                 let value = metric_value.as_f64();
-                                if value < 0. || value.is_nan() {
+                                if !value.is_nan() && value < 0. {
                                     return Err(ParseError::InvalidMetric(format!(
-                                        "Counter totals must be non negative (got: {})",
+                                        "Summary sums must be non negative (got: {})",
                                         metric_value.as_f64()
                                     )));
                                 }
@@ -393,8 +359,8 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<PrometheusType> {
                         MetricProcesser::new(
                             |existing_metric: &mut MetricMarshal,
                              metric_value: MetricNumber,
-                             label_names: Vec<String>,
-                             label_values: Vec<String>,
+                             label_names: &[SharedString],
+                             label_values: &[SharedString],
                              _: Option<Exemplar>,
                              _: bool| {
                                 let value = metric_value.as_f64();
@@ -404,29 +370,12 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<PrometheusType> {
                                     ));
                                 }
 
-                                let bucket_bound: f64 = {
+                                let bucket_bound = {
                                     let bound_index =
                                         label_names.iter().position(|s| s == "quantile").unwrap();
-                                    let bound = &label_values[bound_index];
-
-                                    match bound.parse() {
-                                        Ok(f) => f,
-                                        Err(_) => {
-                                            return Err(ParseError::InvalidMetric(format!(
-                                                "Summary bounds must be numbers (got: {})",
-                                                bound
-                                            )));
-                                        }
-                                    }
+                                    parse_quantile(&label_values[bound_index])?
                                 };
 
-                                if !(0. ..=1.).contains(&bucket_bound) || bucket_bound.is_nan() {
-                                    return Err(ParseError::InvalidMetric(format!(
-                                        "Summary bounds must be between 0 and 1 (got: {})",
-                                        bucket_bound
-                                    )));
-                                }
-
                                 let quantile = Quantile {
                                     quantile: bucket_bound,
                                     value: metric_value,
@@ -449,14 +398,17 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<PrometheusType> {
         ];
 
         let metric_type = self.family_type.as_ref().cloned().unwrap_or_default();
+        let label_values = self.limit_label_values(&metric_type, &label_names, label_values)?;
 
-        if !metric_type.can_have_exemplar(metric_name) && exemplar.is_some() {
+        if self.options.reject_nan && metric_value.as_f64().is_nan() {
             return Err(ParseError::InvalidMetric(format!(
-                "Metric Type {:?} is not allowed exemplars",
-                metric_type
+                "NaN values aren't allowed (got one on {})",
+                metric_name
             )));
         }
 
+        self.check_exemplar_allowed(&metric_type, metric_name, exemplar.as_ref())?;
+
         for (test_type, actions) in handlers {
             if test_type.contains(&metric_type) {
                 for (suffix, mandatory_labels, action) in actions {
@@ -466,20 +418,33 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<PrometheusType> {
 
                     let mut actual_label_names = label_names.clone();
                     let mut actual_label_values = label_values.clone();
-                    for label in mandatory_labels {
-                        if !label_names.contains(&label.to_owned()) {
-                            return Err(ParseError::InvalidMetric(format!(
-                                "Missing mandatory label for metric: {}",
-                                label
-                            )));
-                        }
-
-                        let index = actual_label_names.iter().position(|s| s == label).unwrap();
+                    for label in mandatory_labels.iter() {
+                        // Search the labels that are left, rather than all of them, so a label can't be taken twice
+                        let index = match actual_label_names.iter().position(|s| s == label) {
+                            Some(index) => index,
+                            None => {
+                                return Err(ParseError::InvalidMetric(format!(
+                                    "Missing mandatory label for metric: {}",
+                                    label
+                                )))
+                            }
+                        };
 
                         actual_label_names.remove(index);
                         actual_label_values.remove(index);
                     }
 
+                    let reserved_labels = metric_type.get_reserved_labels();
+                    if let Some(label) = actual_label_names
+                        .iter()
+                        .find(|name| reserved_labels.contains(&name.as_str()))
+                    {
+                        return Err(ParseError::InvalidMetric(format!(
+                            "{:?} metrics can't have a `{}` label (got it on {})",
+                            metric_type, label, metric_name
+                        )));
+                    }
+
                     let name = &metric_name.to_owned();
                     self.try_set_label_names(
                         name,
@@ -487,21 +452,22 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<PrometheusType> {
                     )?;
 
                     let metric_name = metric_name.trim_end_matches(suffix);
-                    if self.name.is_some() && self.name.as_ref().unwrap() != metric_name {
-                        return Err(ParseError::InvalidMetric(format!(
-                            "Invalid Name in metric family: {} != {}",
-                            metric_name,
-                            self.name.as_ref().unwrap()
-                        )));
-                    } else if self.name.is_none() {
-                        self.name = Some(metric_name.to_owned());
+                    match &self.name {
+                        Some(family_name) if family_name != metric_name => {
+                            return Err(ParseError::InvalidMetric(format!(
+                                "Invalid Name in metric family: {} != {}",
+                                metric_name, family_name
+                            )));
+                        }
+                        Some(_) => {}
+                        None => self.name = Some(metric_name.to_owned()),
                     }
 
                     let (existing_metric, created) = match self
                         .get_metric_by_labelset_mut(&actual_label_values)
                     {
                         Some(metric) => {
-                            match (metric.timestamp.as_ref(), timestamp.as_ref()) {
+                            match (metric.timestamp.map(|t| t.as_timestamp()), timestamp.map(|t| t.as_timestamp())) {
                                 (Some(metric_timestamp), Some(timestamp)) if timestamp < metric_timestamp => return Err(ParseError::InvalidMetric(format!("Timestamps went backwarts in family - saw {} and then saw{}", metric_timestamp, timestamp))),
                                 (Some(_), None) | (None, Some(_)) => return Err(ParseError::InvalidMetric("Missing timestamp in family (one metric had a timestamp, another didn't)".to_string())),
                                 (Some(metric_timestamp), Some(timestamp)) if timestamp >= metric_timestamp && !metric_type.can_have_multiple_lines() => return Ok(()),
@@ -527,34 +493,39 @@ impl MarshalledMetricFamily for MetricFamilyMarshal<PrometheusType> {
                         }
                     };
 
-                    return action.0(
+                    action.0(
                         existing_metric,
                         metric_value,
-                        label_names,
-                        label_values,
+                        &label_names,
+                        &label_values,
                         exemplar,
                         created,
+                    )
+                    .map_err(|e| describe_duplicate(e, metric_name, &label_names, &label_values))?;
+
+                    self.record_line_position(
+                        actual_label_values,
+                        suffix,
+                        mandatory_labels.first().copied(),
+                        &label_names,
+                        &label_values,
                     );
+
+                    return Ok(());
                 }
             }
         }
 
-        return Err(ParseError::InvalidMetric(format!(
+        Err(ParseError::InvalidMetric(format!(
             "Found weird metric name for type ({:?}): {}",
             metric_type, metric_name
-        )));
-    }
-}
-
-impl Default for PrometheusType {
-    fn default() -> Self {
-        PrometheusType::Unknown
+        )))
     }
 }
 
 impl From<MetricMarshal> for Sample<PrometheusValue> {
     fn from(s: MetricMarshal) -> Sample<PrometheusValue> {
-        Sample::new(s.label_values, s.timestamp, s.value.into())
+        Sample::with_raw_timestamp(s.label_values, s.timestamp, s.value.into())
     }
 }
 
@@ -565,8 +536,13 @@ impl MarshalledMetric<PrometheusType> for MetricMarshal {
             || (family.label_names.as_ref().unwrap().names.len() != self.label_values.len())
         {
             return Err(ParseError::InvalidMetric(format!(
-                "Metrics in family have different label sets: {:?} {:?}",
-                &family.label_names, self.label_values
+                "Metrics in family have different label sets: family expects [{}], but a sample has values [{}]",
+                family
+                    .label_names
+                    .as_ref()
+                    .map(|l| l.names.join(", "))
+                    .unwrap_or_default(),
+                self.label_values.join(", ")
             )));
         }
 
@@ -595,6 +571,7 @@ impl MarshalledMetric<PrometheusType> for MetricMarshal {
             }
 
             let buckets = &histogram_value.buckets;
+            check_bucket_bounds(histogram_value)?;
 
             let has_negative_bucket = buckets.iter().any(|f| f.upper_bound < 0.);
 
@@ -612,17 +589,12 @@ impl MarshalledMetric<PrometheusType> for MetricMarshal {
                 ));
             }
 
-            if histogram_value.sum.is_some() && histogram_value.count.is_none() {
-                return Err(ParseError::InvalidMetric(
-                    "Count must be present if sum is present".to_owned(),
-                ));
-            }
+            check_sum_and_count(
+                histogram_value.sum.is_some(),
+                histogram_value.count.is_some(),
+            )?;
 
-            if histogram_value.sum.is_none() && histogram_value.count.is_some() {
-                return Err(ParseError::InvalidMetric(
-                    "Sum must be present if count is present".to_owned(),
-                ));
-            }
+            family.check_inf_bucket_count(histogram_value)?;
 
             let mut last = f64::NEG_INFINITY;
             for bucket in buckets {
@@ -636,6 +608,12 @@ impl MarshalledMetric<PrometheusType> for MetricMarshal {
             }
         }
 
+        if let MetricValueMarshal::Summary(summary_value) = &self.value {
+            family.check_quantiles(summary_value)?;
+
+            family.check_summary_sum_and_count(summary_value)?;
+        }
+
         Ok(())
     }
 }
@@ -661,6 +639,14 @@ impl MetricsType for PrometheusType {
         }
     }
 
+    fn get_reserved_labels(&self) -> &[&str] {
+        match self {
+            PrometheusType::Histogram => &["le"],
+            PrometheusType::Summary => &["quantile"],
+            _ => &[],
+        }
+    }
+
     fn get_type_value(&self) -> MetricValueMarshal {
         match self {
             PrometheusType::Histogram => MetricValueMarshal::Histogram(HistogramValue::default()),
@@ -695,11 +681,11 @@ impl From<MetricFamilyMarshal<PrometheusType>> for MetricFamily<PrometheusType,
     fn from(marshal: MetricFamilyMarshal<PrometheusType>) -> Self {
         assert!(marshal.name.is_some());
 
-        MetricFamily::new(
+        MetricFamily::with_shared_label_names(
             marshal.name.unwrap(),
             marshal
                 .label_names
-                .map(|names| names.names)
+                .map(|names| names.names.into_vec())
                 .unwrap_or_default(),
             marshal.family_type.unwrap_or_default(),
             marshal.help.unwrap_or_default(),
@@ -714,7 +700,7 @@ impl TryFrom<&str> for PrometheusType {
     type Error = ParseError;
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        match value {
+        match value.to_ascii_lowercase().as_str() {
             "counter" => Ok(PrometheusType::Counter),
             "gauge" => Ok(PrometheusType::Gauge),
             "histogram" => Ok(PrometheusType::Histogram),
@@ -728,11 +714,207 @@ impl TryFrom<&str> for PrometheusType {
     }
 }
 
+fn parse_exemplar(pair: Pair<Rule>) -> Result<Exemplar, ParseError> {
+    let mut inner = pair.into_inner();
+
+    let labels = inner.next().unwrap();
+    assert_eq!(labels.as_rule(), Rule::labels);
+
+    let labels = parse_labels(labels)?
+        .into_iter()
+        .map(|(a, b)| (a.into_owned(), b.into_owned()))
+        .collect();
+
+    let value = inner.next().unwrap().as_str();
+    let timestamp = inner.next().map(|timestamp| timestamp.as_str());
+    parse_exemplar_parts(labels, value, timestamp)
+}
+
+fn parse_name(pair: Pair<'_, Rule>) -> Cow<'_, str> {
+    match pair.as_rule() {
+        Rule::quotedname => unescape_string(pair.into_inner().next().unwrap().as_str()),
+        _ => Cow::Borrowed(pair.as_str()),
+    }
+}
+
+fn parse_labels(pair: Pair<'_, Rule>) -> Result<ParsedLabels<'_>, ParseError> {
+    assert!(matches!(pair.as_rule(), Rule::labels | Rule::quotedlabels));
+
+    let label_pairs = pair.into_inner().filter(|p| p.as_rule() == Rule::label);
+    let mut labels = ParsedLabels::new();
+
+    for label in label_pairs {
+        let mut label = label.into_inner();
+        let name = parse_name(label.next().unwrap());
+        let value = unescape_string(label.next().unwrap().as_str());
+
+        if labels.iter().any(|(n, _)| n == &name) {
+            return Err(ParseError::InvalidMetric(format!(
+                "Found label `{}` twice in the same labelset",
+                name
+            )));
+        }
+
+        labels.push((name, value));
+    }
+
+    labels.sort_by(|a, b| a.0.cmp(&b.0));
+
+    Ok(labels)
+}
+
+/// A single sample line, before it has been attached to a family
+struct SampleLine<'a> {
+    name: Cow<'a, str>,
+    labels: ParsedLabels<'a>,
+    value: MetricNumber,
+    timestamp: Option<RawTimestamp>,
+    exemplar: Option<Exemplar>,
+}
+
+/// Prometheus timestamps are integer milliseconds, so they're parsed as integers, keeping them exact. Other numbers
+/// (e.g. with a fractional part, which some exporters write) are only accepted if `fractional_timestamps` is set, in
+/// which case they're parsed as floats, and read as milliseconds too
+fn parse_timestamp(timestamp: &str, options: &ParseOptions) -> Result<RawTimestamp, ParseError> {
+    if let Ok(millis) = timestamp.parse::<i64>() {
+        return Ok(RawTimestamp::Millis(millis));
+    }
+
+    if !options.fractional_timestamps {
+        return Err(ParseError::InvalidMetric(format!(
+            "Timestamps must be a whole number of milliseconds that fits in an i64 (got: {})",
+            timestamp
+        )));
+    }
+
+    match timestamp.parse::<f64>() {
+        Ok(millis) if millis.is_finite() => Ok(RawTimestamp::from_millis(millis)),
+        _ => Err(ParseError::InvalidMetric(format!(
+            "Timestamps must be finite (got: {})",
+            timestamp
+        ))),
+    }
+}
+
+/// Returns the metric name of a sample line, which for quoted names is the first item inside its braces
+fn parse_sample_name<'i>(pair: &Pair<'i, Rule>) -> Cow<'i, str> {
+    let name_pair = pair.clone().into_inner().next().unwrap();
+    match name_pair.as_rule() {
+        Rule::quotedlabels => parse_name(name_pair.into_inner().next().unwrap()),
+        _ => Cow::Borrowed(name_pair.as_str()),
+    }
+}
+
+fn parse_sample_line<'i>(
+    pair: Pair<'i, Rule>,
+    options: &ParseOptions,
+) -> Result<SampleLine<'i>, ParseError> {
+    assert_eq!(pair.as_rule(), Rule::metric);
+
+    let mut descriptor = pair.into_inner();
+    let name_pair = descriptor.next().unwrap();
+
+    let (name, labels) = match name_pair.as_rule() {
+        // Quoted metric names live inside the labels, e.g. `{"my.metric",label="value"}`
+        Rule::quotedlabels => (
+            parse_name(name_pair.clone().into_inner().next().unwrap()),
+            parse_labels(name_pair)?,
+        ),
+        _ => {
+            let labels = if descriptor.peek().map(|p| p.as_rule()) == Some(Rule::labels) {
+                parse_labels(descriptor.next().unwrap())?
+            } else {
+                SmallVec::new()
+            };
+
+            (Cow::Borrowed(name_pair.as_str()), labels)
+        }
+    };
+
+    // The grammar always includes a value, but error rather than panicking if that ever changes
+    let value = match descriptor.next() {
+        Some(value) if value.as_rule() == Rule::number => value.as_str(),
+        _ => return Err(ParseError::InvalidMetric("missing metric value".to_owned())),
+    };
+    let value: MetricNumber = value.parse()?;
+
+    let mut timestamp = None;
+    let mut exemplar = None;
+
+    if descriptor.peek().is_some()
+        && descriptor.peek().as_ref().unwrap().as_rule() == Rule::timestamp
+    {
+        timestamp = Some(parse_timestamp(
+            descriptor.next().unwrap().as_str(),
+            options,
+        )?);
+    }
+
+    if descriptor.peek().is_some()
+        && descriptor.peek().as_ref().unwrap().as_rule() == Rule::exemplar
+    {
+        exemplar = Some(parse_exemplar(descriptor.next().unwrap())?);
+    }
+
+    Ok(SampleLine {
+        name,
+        labels,
+        value,
+        timestamp,
+        exemplar,
+    })
+}
+
 pub fn parse_prometheus(
     exposition_bytes: &str,
 ) -> Result<MetricsExposition<PrometheusType, PrometheusValue>, ParseError> {
-    use pest::iterators::Pair;
+    parse_prometheus_with_options(exposition_bytes, &ParseOptions::default())
+}
+
+/// Parses an exposition from raw bytes, e.g. the body of an HTTP response, returning a `ParseError::Utf8`
+/// if they aren't valid UTF-8
+pub fn parse_prometheus_bytes(
+    exposition_bytes: &[u8],
+) -> Result<MetricsExposition<PrometheusType, PrometheusValue>, ParseError> {
+    parse_prometheus(std::str::from_utf8(exposition_bytes)?)
+}
+
+impl FromStr for MetricsExposition<PrometheusType, PrometheusValue> {
+    type Err = ParseError;
+
+    /// Parses an exposition with the default options, so that `input.parse()` works like `parse_prometheus(input)`
+    fn from_str(exposition_bytes: &str) -> Result<Self, Self::Err> {
+        parse_prometheus(exposition_bytes)
+    }
+}
+
+impl TryFrom<&str> for MetricsExposition<PrometheusType, PrometheusValue> {
+    type Error = ParseError;
+
+    fn try_from(exposition_bytes: &str) -> Result<Self, Self::Error> {
+        exposition_bytes.parse()
+    }
+}
+
+/// Reads an exposition to the end of `reader`, and parses it. Read failures are returned as a `ParseError::Io`
+pub fn parse_prometheus_reader<R: Read>(
+    reader: R,
+) -> Result<MetricsExposition<PrometheusType, PrometheusValue>, ParseError> {
+    parse_prometheus_reader_with_options(reader, &ParseOptions::default())
+}
+
+pub fn parse_prometheus_reader_with_options<R: Read>(
+    reader: R,
+    options: &ParseOptions,
+) -> Result<MetricsExposition<PrometheusType, PrometheusValue>, ParseError> {
+    let exposition_bytes = read_exposition(reader, options)?;
+    parse_prometheus_with_options(std::str::from_utf8(&exposition_bytes)?, options)
+}
 
+pub fn parse_prometheus_with_options(
+    exposition_bytes: &str,
+    options: &ParseOptions,
+) -> Result<MetricsExposition<PrometheusType, PrometheusValue>, ParseError> {
     fn parse_metric_descriptor(
         pair: Pair<Rule>,
         family: &mut MetricFamilyMarshal<PrometheusType>,
@@ -741,11 +923,11 @@ pub fn parse_prometheus(
 
         let mut descriptor = pair.into_inner();
         let descriptor_type = descriptor.next().unwrap();
-        let metric_name = descriptor.next().unwrap().as_str().to_string();
+        let metric_name = parse_name(descriptor.next().unwrap()).into_owned();
 
         match descriptor_type.as_rule() {
             Rule::kw_help => {
-                let help_text = descriptor.next().unwrap().as_str();
+                let help_text = descriptor.next().map(|s| s.as_str()).unwrap_or_default();
                 family.set_or_test_name(metric_name)?;
                 family.try_add_help(help_text.to_string())?;
             }
@@ -760,145 +942,52 @@ pub fn parse_prometheus(
         Ok(())
     }
 
-    fn parse_exemplar(pair: Pair<Rule>) -> Result<Exemplar, ParseError> {
-        let mut inner = pair.into_inner();
-
-        let labels = inner.next().unwrap();
-        assert_eq!(labels.as_rule(), Rule::labels);
-
-        let labels = parse_labels(labels)?
-            .into_iter()
-            .map(|(a, b)| (a.to_owned(), b.to_owned()))
-            .collect();
-
-        let id = inner.next().unwrap().as_str();
-        let id = match id.parse() {
-            Ok(i) => i,
-            Err(_) => {
-                return Err(ParseError::InvalidMetric(format!(
-                    "Exemplar value must be a number (got: {})",
-                    id
-                )))
-            }
-        };
-
-        let timestamp = match inner.next() {
-            Some(timestamp) => match timestamp.as_str().parse() {
-                Ok(f) => Some(f),
-                Err(_) => {
-                    return Err(ParseError::InvalidMetric(format!(
-                        "Exemplar timestamp must be a number (got: {})",
-                        timestamp.as_str()
-                    )))
-                }
-            },
-            None => None,
-        };
-
-        Ok(Exemplar::new(labels, id, timestamp))
-    }
-
-    fn parse_labels(pair: Pair<Rule>) -> Result<Vec<(&str, &str)>, ParseError> {
-        assert_eq!(pair.as_rule(), Rule::labels);
-
-        let mut label_pairs = pair.into_inner();
-        let mut labels: Vec<(&str, &str)> = Vec::new();
-
-        while label_pairs.peek().is_some() && label_pairs.peek().unwrap().as_rule() == Rule::label {
-            let mut label = label_pairs.next().unwrap().into_inner();
-            let name = label.next().unwrap().as_str();
-            let value = label.next().unwrap().as_str();
-
-            if labels.iter().any(|(n, _)| n == &name) {
-                return Err(ParseError::InvalidMetric(format!(
-                    "Found label `{}` twice in the same labelset",
-                    name
-                )));
-            }
-
-            labels.push((name, value));
-        }
-
-        labels.sort_by_key(|l| l.0);
-
-        Ok(labels)
-    }
-
     fn parse_sample(
         pair: Pair<Rule>,
         family: &mut MetricFamilyMarshal<PrometheusType>,
     ) -> Result<(), ParseError> {
-        assert_eq!(pair.as_rule(), Rule::metric);
-
-        let mut descriptor = pair.into_inner();
-        let metric_name = descriptor.next().unwrap().as_str();
+        if family.is_repeated_line(pair.as_str()) {
+            return Ok(());
+        }
 
-        let labels = if descriptor.peek().unwrap().as_rule() == Rule::labels {
-            parse_labels(descriptor.next().unwrap())?
-        } else {
-            Vec::new()
-        };
+        let sample = parse_sample_line(pair, &family.options)?;
 
         let (label_names, label_values) = {
-            let mut names = Vec::new();
-            let mut values = Vec::new();
-            for (name, value) in labels.into_iter() {
-                names.push(name.to_owned());
-                values.push(value.to_owned());
+            let mut names = LabelValues::new();
+            let mut values = LabelValues::new();
+            for (name, value) in sample.labels.into_iter() {
+                names.push(SharedString::from(name.as_ref()));
+                values.push(SharedString::from(value.as_ref()));
             }
 
             (names, values)
         };
 
-        let value = descriptor.next().unwrap().as_str();
-        let value = match value.parse() {
-            Ok(f) => MetricNumber::Int(f),
-            Err(_) => match value.parse() {
-                Ok(f) => MetricNumber::Float(f),
-                Err(_) => {
-                    return Err(ParseError::InvalidMetric(format!(
-                        "Metric Value must be a number (got: {})",
-                        value
-                    )));
-                }
-            },
-        };
-
-        let mut timestamp = None;
-        let mut exemplar = None;
-
-        if descriptor.peek().is_some()
-            && descriptor.peek().as_ref().unwrap().as_rule() == Rule::timestamp
-        {
-            timestamp = Some(descriptor.next().unwrap().as_str().parse().unwrap());
-        }
-
-        if descriptor.peek().is_some()
-            && descriptor.peek().as_ref().unwrap().as_rule() == Rule::exemplar
-        {
-            exemplar = Some(parse_exemplar(descriptor.next().unwrap())?);
-        }
-
         family.process_new_metric(
-            metric_name,
-            value,
+            &sample.name,
+            sample.value,
             label_names,
             label_values,
-            timestamp,
-            exemplar,
+            sample.timestamp,
+            sample.exemplar,
         )?;
 
         Ok(())
     }
 
     fn parse_metric_family(
-        pair: Pair<Rule>,
+        mut children: Vec<Pair<Rule>>,
+        options: &ParseOptions,
     ) -> Result<MetricFamily<PrometheusType, PrometheusValue>, ParseError> {
-        assert_eq!(pair.as_rule(), Rule::metricfamily);
+        let mut metric_family = MetricFamilyMarshal::with_options(options.clone());
 
-        let mut metric_family = MetricFamilyMarshal::empty();
+        // Late descriptors are applied before any of the samples, so that the samples are read with the family's type
+        let late_descriptors = options.late_descriptors && !options.strict;
+        if late_descriptors {
+            children.sort_by_key(|child| child.as_rule() != Rule::metricdescriptor);
+        }
 
-        for child in pair.into_inner() {
+        for child in children {
             match child.as_rule() {
                 Rule::metricdescriptor => {
                     if metric_family.metrics.is_empty() {
@@ -909,16 +998,27 @@ pub fn parse_prometheus(
                         ));
                     }
                 }
-                Rule::metric => {
-                    parse_sample(child, &mut metric_family)?;
-                }
+                Rule::metric => parse_sample(child, &mut metric_family)?,
                 _ => unreachable!(),
             }
         }
 
         metric_family.validate()?;
 
-        Ok(metric_family.into())
+        let line_positions = std::mem::take(&mut metric_family.line_positions);
+        let mut family: MetricFamily<PrometheusType, PrometheusValue> = metric_family.into();
+        if options.preserve_line_order {
+            family.set_line_order(&line_positions);
+        }
+
+        Ok(family)
+    }
+
+    check_size(exposition_bytes.len(), options)?;
+
+    // An empty (or whitespace only) exposition is valid, it just doesn't have any metrics in it
+    if exposition_bytes.trim().is_empty() {
+        return Ok(MetricsExposition::new());
     }
 
     let exposition_marshal = PrometheusParser::parse(Rule::exposition, exposition_bytes)?
@@ -928,26 +1028,245 @@ pub fn parse_prometheus(
 
     assert_eq!(exposition_marshal.as_rule(), Rule::exposition);
 
+    // The grammar starts a new metricfamily at every descriptor, so a HELP or TYPE line after a family's samples
+    // splits it in two. With late_descriptors, the second half is joined back onto the first
+    let late_descriptors = options.late_descriptors && !options.strict;
+    let mut families: Vec<Vec<Pair<Rule>>> = Vec::new();
     for span in exposition_marshal.into_inner() {
         match span.as_rule() {
             Rule::metricfamily => {
-                let family = parse_metric_family(span)?;
+                let children: Vec<Pair<Rule>> = span.into_inner().collect();
+                match families.last_mut() {
+                    Some(previous) if late_descriptors && is_same_family(previous, &children) => {
+                        previous.extend(children)
+                    }
+                    _ => families.push(children),
+                }
+            }
+            Rule::EOI => {}
+            _ => unreachable!(),
+        }
+    }
+
+    let mut interner = LabelInterner::default();
+    for children in families {
+        let mut family = parse_metric_family(children, options)?;
+        if options.intern {
+            family.intern_labels(&mut interner);
+        }
+
+        if let Some(existing) = exposition.families.get_mut(&family.family_name) {
+            if !options.merge_families {
+                return Err(ParseError::InvalidMetric(format!(
+                    "Found a metric family called {}, after that family was finalised",
+                    family.family_name
+                )));
+            }
+
+            existing.merge(family)?;
+        } else {
+            exposition
+                .families
+                .insert(family.family_name.clone(), family);
+        }
+    }
+
+    Ok(exposition)
+}
+
+/// Checks whether the metricfamily `next` is a continuation of the one before it, `previous`, i.e. `next` starts
+/// with a descriptor for the same family. If `previous` starts with a descriptor, the names have to match exactly.
+/// A family that starts with samples is identified by the name of its first sample, which for histograms and
+/// summaries has a suffix, so the suffixes are only allowed if `next` says that the family is one of those
+fn is_same_family(previous: &[Pair<'_, Rule>], next: &[Pair<'_, Rule>]) -> bool {
+    fn descriptor_name<'i>(pair: &Pair<'i, Rule>) -> Cow<'i, str> {
+        parse_name(pair.clone().into_inner().nth(1).unwrap())
+    }
+
+    let next_name = match next.first() {
+        Some(pair) if pair.as_rule() == Rule::metricdescriptor => descriptor_name(pair),
+        _ => return false,
+    };
+
+    let sample_name = match previous.first() {
+        Some(pair) if pair.as_rule() == Rule::metricdescriptor => {
+            return descriptor_name(pair) == next_name
+        }
+        Some(pair) => parse_sample_name(pair),
+        None => return false,
+    };
+
+    let next_type = next
+        .iter()
+        .take_while(|pair| pair.as_rule() == Rule::metricdescriptor)
+        .find_map(|pair| {
+            let mut descriptor = pair.clone().into_inner();
+            match descriptor.next().unwrap().as_rule() {
+                Rule::kw_type => PrometheusType::try_from(descriptor.nth(1).unwrap().as_str()).ok(),
+                _ => None,
+            }
+        });
+
+    let suffixes: &[&str] = match next_type {
+        Some(PrometheusType::Histogram) => &["", "_bucket", "_sum", "_count", "_created"],
+        Some(PrometheusType::Summary) => &["", "_sum", "_count", "_created"],
+        _ => &[""],
+    };
+
+    match sample_name.strip_prefix(next_name.as_ref()) {
+        Some(suffix) => suffixes.contains(&suffix),
+        None => false,
+    }
+}
+
+/// Parses several expositions that have been concatenated together, e.g. the bodies of a batch of scrapes, where
+/// each exposition ends with a `# EOF` line. Each exposition is parsed independently, so families can repeat across
+/// them. Expositions that are empty once split (e.g. after a trailing `# EOF`) are skipped
+pub fn parse_prometheus_multi(
+    exposition_bytes: &str,
+) -> Result<Vec<MetricsExposition<PrometheusType, PrometheusValue>>, ParseError> {
+    parse_prometheus_multi_with_options(exposition_bytes, &ParseOptions::default())
+}
+
+pub fn parse_prometheus_multi_with_options(
+    exposition_bytes: &str,
+    options: &ParseOptions,
+) -> Result<Vec<MetricsExposition<PrometheusType, PrometheusValue>>, ParseError> {
+    parse_prometheus_multi_with_separator(exposition_bytes, "# EOF", options)
+}
+
+/// Parses concatenated expositions like `parse_prometheus_multi`, where each exposition ends with a line consisting of
+/// just `separator` rather than `# EOF`. Blank lines are allowed inside an exposition, so the separator can't be empty
+pub fn parse_prometheus_multi_with_separator(
+    exposition_bytes: &str,
+    separator: &str,
+    options: &ParseOptions,
+) -> Result<Vec<MetricsExposition<PrometheusType, PrometheusValue>>, ParseError> {
+    let separator = separator.trim_end();
+    if separator.is_empty() {
+        return Err(ParseError::ParseError(
+            "The separator between expositions can't be empty".to_string(),
+        ));
+    }
+
+    let mut expositions = Vec::new();
+    let mut start = 0;
+    let mut offset = 0;
+    for line in exposition_bytes.split_inclusive('\n') {
+        if line.trim_end() == separator {
+            let chunk = &exposition_bytes[start..offset];
+            if !chunk.trim().is_empty() {
+                expositions.push(parse_prometheus_with_options(chunk, options)?);
+            }
+
+            start = offset + line.len();
+        }
+
+        offset += line.len();
+    }
+
+    let chunk = &exposition_bytes[start..];
+    if !chunk.trim().is_empty() {
+        expositions.push(parse_prometheus_with_options(chunk, options)?);
+    }
+
+    Ok(expositions)
+}
+
+/// Parses an exposition, passing each family and sample to the visitor as it's read rather than building
+/// a `MetricsExposition`. Only the syntax of the exposition is checked - the samples aren't validated
+/// against their families, so e.g. a histogram without a +Inf bucket is passed through as-is
+pub fn parse_prometheus_visit(
+    exposition_bytes: &str,
+    visitor: &mut dyn MetricVisitor,
+) -> Result<(), ParseError> {
+    if exposition_bytes.trim().is_empty() {
+        return Ok(());
+    }
+
+    // Only the syntax is checked, so any timestamp that's a number is passed through
+    let options = ParseOptions {
+        fractional_timestamps: true,
+        ..ParseOptions::default()
+    };
+
+    let exposition = PrometheusParser::parse(Rule::exposition, exposition_bytes)?
+        .next()
+        .unwrap();
+
+    for span in exposition.into_inner() {
+        match span.as_rule() {
+            Rule::metricfamily => {
+                let mut name = None;
+                let mut family_type = PrometheusType::default();
+                let mut help = String::new();
+                let mut started = false;
+
+                for child in span.into_inner() {
+                    match child.as_rule() {
+                        Rule::metricdescriptor => {
+                            let mut descriptor = child.into_inner();
+                            let descriptor_type = descriptor.next().unwrap();
+                            name = Some(parse_name(descriptor.next().unwrap()));
+                            let value = descriptor.next().map(|s| s.as_str()).unwrap_or_default();
+                            match descriptor_type.as_rule() {
+                                Rule::kw_help => help = unescape_help(value),
+                                Rule::kw_type => family_type = PrometheusType::try_from(value)?,
+                                _ => unreachable!(),
+                            }
+                        }
+                        Rule::metric => {
+                            let sample = parse_sample_line(child, &options)?;
+                            if !started {
+                                let name = name.as_deref().unwrap_or(&sample.name);
+                                visitor.on_family_start(name, &family_type, &help, "");
+                                started = true;
+                            }
 
-                if exposition.families.contains_key(&family.family_name) {
-                    return Err(ParseError::InvalidMetric(format!(
-                        "Found a metric family called {}, after that family was finalised",
-                        family.family_name
-                    )));
+                            let labels: SmallVec<[(&str, &str); 8]> = sample
+                                .labels
+                                .iter()
+                                .map(|(name, value)| (name.as_ref(), value.as_ref()))
+                                .collect();
+                            visitor.on_sample(
+                                &sample.name,
+                                &labels,
+                                sample.value,
+                                sample.timestamp.map(|t| t.as_timestamp()),
+                                sample.exemplar.as_ref(),
+                            );
+                        }
+                        _ => unreachable!(),
+                    }
                 }
 
-                exposition
-                    .families
-                    .insert(family.family_name.clone(), family);
+                // Families with descriptors, but no samples
+                if !started {
+                    visitor.on_family_start(&name.unwrap_or_default(), &family_type, &help, "");
+                }
+
+                visitor.on_family_end();
             }
             Rule::EOI => {}
             _ => unreachable!(),
         }
     }
 
-    Ok(exposition)
+    Ok(())
+}
+
+/// Parses an exposition into the raw pest parse tree, without building a `MetricsExposition`. This is an escape
+/// hatch for tooling that needs the position of every token, e.g. for editor diagnostics. The shape of the tree
+/// (and so `Rule`) follows the grammar, which isn't part of the stable API and can change in any release
+pub fn parse_prometheus_pairs(exposition_bytes: &str) -> Result<Pairs<'_, Rule>, ParseError> {
+    Ok(PrometheusParser::parse(Rule::exposition, exposition_bytes)?)
+}
+
+/// Reads only the metadata (HELP and TYPE lines) of an exposition, skipping over its samples entirely.
+/// This is much faster than a full parse, but doesn't validate the samples, and families that don't have
+/// any descriptor lines aren't returned
+pub fn parse_prometheus_metadata(
+    exposition_bytes: &str,
+) -> Result<Vec<PrometheusFamilyMetadata>, ParseError> {
+    parse_metadata(exposition_bytes, false)
 }
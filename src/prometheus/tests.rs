@@ -1,6 +1,15 @@
-use std::fs;
+use std::{convert::TryFrom, fs};
 
-use super::parsers::parse_prometheus;
+use super::parsers::{
+    parse_prometheus, parse_prometheus_bytes, parse_prometheus_metadata, parse_prometheus_multi,
+    parse_prometheus_multi_with_options, parse_prometheus_multi_with_separator,
+    parse_prometheus_pairs, parse_prometheus_reader, parse_prometheus_reader_with_options,
+    parse_prometheus_visit, parse_prometheus_with_options, Rule,
+};
+use crate::{
+    MetricsExposition, ParseError, ParseOptions, PrometheusType, PrometheusValue, RawTimestamp,
+    Sample,
+};
 
 #[test]
 fn test_prometheus_parser() {
@@ -14,3 +23,1224 @@ fn test_prometheus_parser() {
         }
     }
 }
+
+#[test]
+fn test_empty_input() {
+    for input in [
+        "",
+        "\n\n",
+        "   ",
+        "# EOF\n",
+        "# Just a comment\n\n# And another\n",
+    ] {
+        let result = parse_prometheus(input);
+        assert!(
+            result.is_ok(),
+            "failed to parse {:?}: {}",
+            input,
+            result.err().unwrap()
+        );
+        assert!(result.unwrap().families.is_empty());
+    }
+}
+
+#[test]
+fn test_no_trailing_newline() {
+    let exposition = parse_prometheus("mymetric 5").unwrap();
+    assert_eq!(exposition.families["mymetric"].iter_samples().count(), 1);
+
+    let exposition = parse_prometheus("# TYPE mymetric gauge\nmymetric{a=\"b\"} 5 1000").unwrap();
+    let sample = exposition.families["mymetric"]
+        .iter_samples()
+        .next()
+        .unwrap();
+    assert_eq!(sample.timestamp, Some(1000.));
+}
+
+#[test]
+fn test_merge_families() {
+    let test_str = r#"# TYPE foo gauge
+foo{a="1"} 1
+# TYPE bar gauge
+bar 2
+# TYPE foo gauge
+foo{a="2"} 3
+"#;
+
+    assert!(parse_prometheus(test_str).is_err());
+
+    let options = ParseOptions {
+        merge_families: true,
+        ..ParseOptions::default()
+    };
+    let exposition = parse_prometheus_with_options(test_str, &options).unwrap();
+    assert_eq!(exposition.families.len(), 2);
+    assert_eq!(exposition.families["foo"].iter_samples().count(), 2);
+
+    let test_str = r#"# TYPE foo gauge
+foo{a="1"} 1
+# TYPE bar gauge
+bar 2
+# TYPE foo gauge
+foo{a="1"} 3
+"#;
+    assert!(parse_prometheus_with_options(test_str, &options).is_err());
+}
+
+#[test]
+fn test_quoted_names() {
+    let test_str = r#"# TYPE "my.metric" gauge
+{"my.metric",label="x","label with space"="w"} 1
+{"my.metric",label="y","label with space"="z"} 2
+"#;
+    let exposition = parse_prometheus(test_str).unwrap();
+    let family = &exposition.families["my.metric"];
+    assert_eq!(family.get_label_names(), &["label", "label with space"]);
+
+    let sample = family.iter_samples().last().unwrap();
+    assert_eq!(
+        sample
+            .get_labelset()
+            .unwrap()
+            .get_label_value("label with space"),
+        Some("z")
+    );
+
+    let exposition_str = exposition.to_string();
+    assert!(exposition_str.contains(r#"# TYPE "my.metric" gauge"#));
+    assert!(exposition_str.contains(r#"{"my.metric",label="y","label with space"="z"} 2"#));
+
+    let reparsed = parse_prometheus(&exposition_str).unwrap();
+    assert_eq!(reparsed.families["my.metric"].iter_samples().count(), 2);
+
+    let test_str = r#"# TYPE "my \"quoted\"\nmetric" gauge
+{"my \"quoted\"\nmetric","a \\ label"="x"} 1
+"#;
+    let exposition = parse_prometheus(test_str).unwrap();
+    let family = &exposition.families["my \"quoted\"\nmetric"];
+    assert_eq!(family.get_label_names(), &["a \\ label"]);
+
+    let exposition_str = exposition.to_string();
+    assert_eq!(exposition_str, test_str);
+    assert_eq!(
+        parse_prometheus(&exposition_str).unwrap().to_string(),
+        exposition_str
+    );
+}
+
+#[test]
+fn test_strict_histogram_count() {
+    let strict = ParseOptions {
+        strict: true,
+        ..ParseOptions::default()
+    };
+
+    let test_str = r#"# TYPE latency histogram
+latency_bucket{le="1"} 1
+latency_bucket{le="+Inf"} 3
+latency_sum 4.5
+latency_count 3
+"#;
+    assert!(parse_prometheus_with_options(test_str, &strict).is_ok());
+
+    let test_str = r#"# TYPE latency histogram
+latency_bucket{le="1"} 1
+latency_bucket{le="+Inf"} 3
+latency_sum 4.5
+latency_count 4
+"#;
+    assert!(parse_prometheus(test_str).is_ok());
+    let err = parse_prometheus_with_options(test_str, &strict).unwrap_err();
+    assert!(err.to_string().contains("(3)"));
+    assert!(err.to_string().contains("(4)"));
+
+    // Counts past i64::MAX are compared as they are, rather than wrapping around
+    let test_str = r#"# TYPE latency histogram
+latency_bucket{le="+Inf"} 9223372036854775808
+latency_sum 4.5
+latency_count 9223372036854775808
+"#;
+    assert!(parse_prometheus_with_options(test_str, &strict).is_ok());
+}
+
+#[test]
+fn test_strict_summary_quantiles() {
+    let strict = ParseOptions {
+        strict: true,
+        ..ParseOptions::default()
+    };
+
+    let test_str = r#"# TYPE rpc_duration_seconds summary
+rpc_duration_seconds{quantile="0.5"} 4773
+rpc_duration_seconds{quantile="0.9"} NaN
+rpc_duration_seconds{quantile="0.99"} 76656
+rpc_duration_seconds_sum 1.7560473e+07
+rpc_duration_seconds_count 2693
+"#;
+    assert!(parse_prometheus_with_options(test_str, &strict).is_ok());
+
+    let test_str = r#"# TYPE rpc_duration_seconds summary
+rpc_duration_seconds{quantile="0.5"} 4773
+rpc_duration_seconds{quantile="0.9"} 3102
+rpc_duration_seconds_sum 1.7560473e+07
+rpc_duration_seconds_count 2693
+"#;
+    assert!(parse_prometheus(test_str).is_ok());
+    assert!(parse_prometheus_with_options(test_str, &strict).is_err());
+
+    let test_str = r#"# TYPE rpc_duration_seconds summary
+rpc_duration_seconds{quantile="0.9"} 3102
+rpc_duration_seconds{quantile="0.5"} 4773
+"#;
+    assert!(parse_prometheus(test_str).is_ok());
+    assert!(parse_prometheus_with_options(test_str, &strict).is_err());
+
+    let test_str = r#"# TYPE rpc_duration_seconds summary
+rpc_duration_seconds{quantile="0.5"} 3102
+rpc_duration_seconds{quantile="0.5"} 4773
+"#;
+    assert!(parse_prometheus(test_str).is_err());
+}
+
+#[test]
+fn test_render_round_trip() {
+    for file in fs::read_dir("./src/prometheus/testdata").unwrap() {
+        let path = file.unwrap().path();
+        let exposition = parse_prometheus(&fs::read_to_string(&path).unwrap()).unwrap();
+
+        let rendered = exposition.to_prometheus_text();
+        let reparsed = parse_prometheus(&rendered).unwrap_or_else(|e| {
+            panic!("failed to reparse {}: {}\n{}", path.display(), e, rendered)
+        });
+        assert_eq!(reparsed.families.len(), exposition.families.len());
+        assert_eq!(reparsed.to_prometheus_text(), rendered);
+    }
+}
+
+#[test]
+fn test_mixed_case_types() {
+    for keyword in ["GAUGE", "Gauge", "gauge"] {
+        let test_str = format!("# TYPE foo {}\nfoo 1\n", keyword);
+        let exposition = parse_prometheus(&test_str).unwrap();
+        assert_eq!(
+            exposition.families["foo"].family_type,
+            crate::PrometheusType::Gauge
+        );
+        assert!(exposition
+            .to_prometheus_text()
+            .contains("# TYPE foo gauge\n"));
+    }
+
+    let exposition = parse_prometheus("# TYPE foo_total Counter\nfoo_total 1\n").unwrap();
+    assert_eq!(
+        exposition.families["foo_total"].family_type,
+        crate::PrometheusType::Counter
+    );
+}
+
+#[test]
+fn test_explicitly_positive_values() {
+    for (value, expected) in [("+5", 5.), ("+5.0", 5.), ("+1.5e2", 150.)] {
+        let test_str = format!("# TYPE foo gauge\nfoo {}\n", value);
+        let exposition = parse_prometheus(&test_str).unwrap();
+        let sample = exposition.families["foo"].iter_samples().next().unwrap();
+        assert_eq!(
+            sample.value.as_f64(),
+            Some(expected),
+            "failed to parse {}",
+            value
+        );
+    }
+}
+
+#[test]
+fn test_intern_label_names() {
+    let test_str = fs::read_to_string("./src/prometheus/testdata/node_exporter.txt").unwrap();
+    let options = ParseOptions {
+        intern: true,
+        ..ParseOptions::default()
+    };
+
+    let interned = parse_prometheus_with_options(&test_str, &options).unwrap();
+    let exposition = parse_prometheus(&test_str).unwrap();
+    assert_eq!(
+        interned.to_prometheus_text(),
+        exposition.to_prometheus_text()
+    );
+
+    let label_names = |exposition: &crate::MetricsExposition<_, _>, name: &str| {
+        let family: &crate::PrometheusMetricFamily = &exposition.families[name];
+        family.get_label_names().as_ptr()
+    };
+
+    assert_eq!(
+        label_names(&interned, "node_disk_reads_completed_total"),
+        label_names(&interned, "node_network_receive_bytes_total")
+    );
+    assert_ne!(
+        label_names(&exposition, "node_disk_reads_completed_total"),
+        label_names(&exposition, "node_network_receive_bytes_total")
+    );
+
+    let label_value = |exposition: &crate::MetricsExposition<_, _>, name: &str| {
+        let family: &crate::PrometheusMetricFamily = &exposition.families[name];
+        family.iter_samples().next().unwrap().label_values()[0].clone()
+    };
+
+    let reads = label_value(&interned, "node_disk_reads_completed_total");
+    let writes = label_value(&interned, "node_disk_writes_completed_total");
+    assert_eq!(reads, "nvme0n1");
+    assert!(reads.ptr_eq(&writes));
+    let reads = label_value(&exposition, "node_disk_reads_completed_total");
+    let writes = label_value(&exposition, "node_disk_writes_completed_total");
+    assert!(!reads.ptr_eq(&writes));
+
+    // Label names are shared between families with different label lists, too
+    let name = |exposition: &crate::MetricsExposition<_, _>, family: &str| {
+        let family: &crate::PrometheusMetricFamily = &exposition.families[family];
+        family.get_label_names()[0].clone()
+    };
+    let disk = name(&interned, "node_disk_reads_completed_total");
+    let filesystem = name(&interned, "node_filesystem_avail_bytes");
+    assert_eq!(filesystem, "device");
+    assert!(disk.ptr_eq(&filesystem));
+}
+
+#[test]
+fn test_parse_metadata() {
+    let test_str = fs::read_to_string("./src/prometheus/testdata/node_exporter.txt").unwrap();
+    let exposition = parse_prometheus(&test_str).unwrap();
+    let metadata = parse_prometheus_metadata(&test_str).unwrap();
+
+    assert_eq!(metadata.len(), exposition.families.len());
+    for family_metadata in metadata.iter() {
+        let family = &exposition.families[&family_metadata.name];
+        assert_eq!(family_metadata.family_type, family.family_type);
+        assert_eq!(family_metadata.help, family.help);
+        assert_eq!(family_metadata.unit, family.unit);
+    }
+
+    // Samples aren't parsed at all, so a broken one doesn't stop us reading the metadata
+    let test_str = format!("{}node_load1 not_a_number\n", test_str);
+    assert!(parse_prometheus(&test_str).is_err());
+    assert_eq!(parse_prometheus_metadata(&test_str).unwrap(), metadata);
+}
+
+#[test]
+fn test_reserved_labels() {
+    let test_str = r#"# TYPE foo histogram
+foo_bucket{le="1"} 1
+foo_bucket{le="+Inf"} 2
+foo_sum{le="1"} 3
+foo_count 2
+"#;
+    assert!(parse_prometheus(test_str).is_err());
+
+    let test_str = r#"# TYPE foo histogram
+foo_count{le="1"} 2
+foo_bucket{le="+Inf"} 2
+"#;
+    assert!(parse_prometheus(test_str).is_err());
+
+    let test_str = r#"# TYPE foo summary
+foo{quantile="0.5"} 1
+foo_sum{quantile="0.5"} 3
+foo_count 2
+"#;
+    assert!(parse_prometheus(test_str).is_err());
+
+    // `le` and `quantile` are only reserved for the types that use them
+    let test_str = r#"# TYPE foo gauge
+foo{le="1",quantile="0.5"} 1
+"#;
+    assert!(parse_prometheus(test_str).is_ok());
+}
+
+#[test]
+fn test_crlf_line_endings() {
+    let test_str = include_str!("testdata/upstream_example.txt");
+    let crlf_str = test_str.replace('\n', "\r\n");
+
+    let exposition = parse_prometheus(test_str).unwrap();
+    let crlf_exposition = parse_prometheus(&crlf_str).unwrap();
+    assert_eq!(
+        crlf_exposition.to_prometheus_text(),
+        exposition.to_prometheus_text()
+    );
+    assert_eq!(
+        parse_prometheus_metadata(&crlf_str).unwrap(),
+        parse_prometheus_metadata(test_str).unwrap()
+    );
+}
+
+#[test]
+fn test_trailing_eof() {
+    let test_str = include_str!("testdata/upstream_example.txt");
+    let exposition = parse_prometheus(test_str).unwrap();
+
+    for eof in ["# EOF", "# EOF\n", "# EOF\r\n", "\n# EOF\n"] {
+        let with_eof = parse_prometheus(&format!("{}{}", test_str, eof)).unwrap();
+        assert_eq!(
+            with_eof.to_prometheus_text(),
+            exposition.to_prometheus_text()
+        );
+    }
+}
+
+#[test]
+fn test_stray_comments() {
+    let test_str = "# TYPE foo gauge\nfoo{a=\"1\"} 1\n# this is a comment\nfoo{a=\"2\"} 2\n";
+    let exposition = parse_prometheus(test_str).unwrap();
+    assert_eq!(exposition.families["foo"].num_samples(), 2);
+}
+
+#[test]
+fn test_visitor() {
+    use crate::{Exemplar, MetricNumber, MetricVisitor, PrometheusType, Timestamp};
+
+    #[derive(Default)]
+    struct SumVisitor {
+        families: usize,
+        samples: usize,
+        scalar_sum: f64,
+        in_scalar_family: bool,
+    }
+
+    impl MetricVisitor for SumVisitor {
+        fn on_family_start(&mut self, _: &str, family_type: &PrometheusType, _: &str, _: &str) {
+            self.families += 1;
+            self.in_scalar_family = matches!(
+                family_type,
+                PrometheusType::Counter | PrometheusType::Gauge | PrometheusType::Unknown
+            );
+        }
+
+        fn on_sample(
+            &mut self,
+            _: &str,
+            _: &[(&str, &str)],
+            value: MetricNumber,
+            _: Option<Timestamp>,
+            _: Option<&Exemplar>,
+        ) {
+            self.samples += 1;
+            if self.in_scalar_family {
+                self.scalar_sum += value.as_f64();
+            }
+        }
+    }
+
+    let test_str = include_str!("testdata/node_exporter.txt");
+    let mut visitor = SumVisitor::default();
+    parse_prometheus_visit(test_str, &mut visitor).unwrap();
+
+    let exposition = parse_prometheus(test_str).unwrap();
+    let model_sum: f64 = exposition
+        .iter_all_samples()
+        .filter_map(|(_, sample)| sample.value.as_f64())
+        .sum();
+
+    assert_eq!(visitor.families, exposition.families.len());
+    assert_eq!(visitor.samples, exposition.cardinality());
+    // The model is summed in (random) family order, so allow for rounding differences
+    assert!((visitor.scalar_sum - model_sum).abs() <= model_sum.abs() * 1e-12);
+
+    assert!(parse_prometheus_visit("foo{a=\"b\" 1\n", &mut visitor).is_err());
+}
+
+#[test]
+fn test_allow_negative_counters() {
+    use crate::{MetricNumber, PrometheusValue};
+
+    let test_str = "# TYPE foo_total counter\nfoo_total -3\n";
+    assert!(parse_prometheus(test_str).is_err());
+
+    let options = ParseOptions {
+        allow_negative_counters: true,
+        ..ParseOptions::default()
+    };
+    let exposition = parse_prometheus_with_options(test_str, &options).unwrap();
+    let sample = exposition.get_sample("foo_total", &[]).unwrap();
+    match &sample.value {
+        PrometheusValue::Counter(c) => assert_eq!(c.value, MetricNumber::Int(-3)),
+        other => panic!("Expected a counter, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_nan_policy() {
+    let reject_nan = ParseOptions {
+        reject_nan: true,
+        ..ParseOptions::default()
+    };
+
+    let gauge = "# TYPE foo gauge\nfoo NaN\n";
+    assert!(parse_prometheus(gauge).is_ok());
+    assert!(parse_prometheus_with_options(gauge, &reject_nan).is_err());
+
+    let quantile = "# TYPE foo summary\nfoo{quantile=\"0.5\"} NaN\nfoo_sum 0\nfoo_count 0\n";
+    assert!(parse_prometheus(quantile).is_ok());
+    assert!(parse_prometheus_with_options(quantile, &reject_nan).is_err());
+
+    let histogram_sum =
+        "# TYPE foo histogram\nfoo_bucket{le=\"+Inf\"} 1\nfoo_sum NaN\nfoo_count 1\n";
+    assert!(parse_prometheus(histogram_sum).is_ok());
+    assert!(parse_prometheus_with_options(histogram_sum, &reject_nan).is_err());
+
+    let summary_sum = "# TYPE foo summary\nfoo_sum NaN\nfoo_count 1\n";
+    assert!(parse_prometheus(summary_sum).is_ok());
+    assert!(parse_prometheus_with_options(summary_sum, &reject_nan).is_err());
+
+    let bucket = "# TYPE foo histogram\nfoo_bucket{le=\"+Inf\"} NaN\n";
+    assert!(parse_prometheus(bucket).is_err());
+}
+
+#[test]
+fn test_parse_pairs() {
+    let test_str = include_str!("testdata/upstream_example.txt");
+    let pairs = parse_prometheus_pairs(test_str).unwrap();
+
+    let exposition = pairs.peek().unwrap();
+    assert_eq!(exposition.as_rule(), Rule::exposition);
+    assert_eq!(exposition.as_span().start(), 0);
+    assert_eq!(exposition.as_span().end(), test_str.len());
+
+    let samples = pairs.flatten().filter(|p| p.as_rule() == Rule::metric);
+    assert_eq!(samples.count(), 20);
+
+    assert!(parse_prometheus_pairs("foo{").is_err());
+}
+
+#[test]
+fn test_different_label_sets() {
+    let extra = "# TYPE node_power_supply_info gauge
+node_power_supply_info{power_supply=\"BAT0\",type=\"Battery\"} 1
+node_power_supply_info{power_supply=\"ucsi\",type=\"USB\",usb_type=\"C\"} 1
+";
+    let err = parse_prometheus(extra).unwrap_err().to_string();
+    assert!(err.contains("has extra label 'usb_type'"), "{}", err);
+    assert!(
+        err.contains("family expects [power_supply, type]"),
+        "{}",
+        err
+    );
+
+    let missing = "# TYPE node_power_supply_info gauge
+node_power_supply_info{power_supply=\"BAT0\",type=\"Battery\"} 1
+node_power_supply_info{power_supply=\"AC\"} 1
+";
+    let err = parse_prometheus(missing).unwrap_err().to_string();
+    assert!(err.contains("is missing label 'type'"), "{}", err);
+}
+
+#[test]
+fn test_duplicate_metric() {
+    let duplicate = "# TYPE temperature_celsius gauge
+temperature_celsius{room=\"kitchen\"} 21
+temperature_celsius{room=\"bedroom\"} 18
+temperature_celsius{room=\"kitchen\"} 22
+";
+    let err = parse_prometheus(duplicate).unwrap_err().to_string();
+    assert_eq!(
+        err,
+        "Found two metrics with the same labelset: temperature_celsius{room=\"kitchen\"}"
+    );
+}
+
+#[test]
+fn test_strict_summary_sum_count() {
+    let strict = ParseOptions {
+        strict: true,
+        ..ParseOptions::default()
+    };
+
+    let count_only = r#"# TYPE rpc_duration_seconds summary
+rpc_duration_seconds{quantile="0.5"} 4773
+rpc_duration_seconds_count 2693
+"#;
+    assert!(parse_prometheus(count_only).is_ok());
+    let err = parse_prometheus_with_options(count_only, &strict).unwrap_err();
+    assert_eq!(err.to_string(), "Sum must be present if count is present");
+
+    let sum_only = r#"# TYPE rpc_duration_seconds summary
+rpc_duration_seconds_sum 1.7560473e+07
+"#;
+    assert!(parse_prometheus(sum_only).is_ok());
+    assert!(parse_prometheus_with_options(sum_only, &strict).is_err());
+
+    let neither = r#"# TYPE rpc_duration_seconds summary
+rpc_duration_seconds{quantile="0.5"} 4773
+"#;
+    assert!(parse_prometheus_with_options(neither, &strict).is_ok());
+}
+
+#[test]
+fn test_histogram_bucket_exemplars() {
+    let test_str = r#"# TYPE request_duration_seconds histogram
+request_duration_seconds_bucket{le="0.5"} 3 # {trace_id="abc"} 0.25 1520879607.789
+request_duration_seconds_bucket{le="1"} 5 # {trace_id="def"} 0.75
+request_duration_seconds_bucket{le="+Inf"} 6
+request_duration_seconds_sum 3.5
+request_duration_seconds_count 6
+"#;
+
+    let exposition = parse_prometheus(test_str).unwrap();
+    let family = &exposition.families["request_duration_seconds"];
+    let histogram = match &family.iter_samples().next().unwrap().value {
+        PrometheusValue::Histogram(h) => h,
+        other => panic!("Expected a histogram, got {:?}", other),
+    };
+
+    let exemplar = histogram.buckets[0].exemplar.as_ref().unwrap();
+    assert_eq!(exemplar.labels["trace_id"], "abc");
+    assert_eq!(exemplar.id, 0.25);
+    assert_eq!(exemplar.timestamp, Some(1520879607.789));
+
+    let exemplar = histogram.buckets[1].exemplar.as_ref().unwrap();
+    assert_eq!(exemplar.labels["trace_id"], "def");
+    assert_eq!(exemplar.timestamp, None);
+
+    assert!(histogram.buckets[2].exemplar.is_none());
+
+    // Exemplars survive a round trip through rendering
+    let rendered = exposition.to_string();
+    assert!(rendered.contains(r#"{le="0.5"} 3 # {trace_id="abc"} 0.25 1520879607.789"#));
+}
+
+#[test]
+fn test_large_integers() {
+    use crate::MetricNumber;
+
+    let test_str = "# TYPE big gauge
+big{size=\"i64\"} 9223372036854775807
+big{size=\"u64\"} 18446744073709551615
+big{size=\"huge\"} 99999999999999999999999
+big{size=\"negative\"} -99999999999999999999999
+";
+
+    let exposition = parse_prometheus(test_str).unwrap();
+    let family = &exposition.families["big"];
+    let value = |size: &str| match &family
+        .get_sample_by_label_values(&[size.to_owned()])
+        .unwrap()
+        .value
+    {
+        PrometheusValue::Gauge(n) => *n,
+        other => panic!("Expected a gauge, got {:?}", other),
+    };
+
+    assert_eq!(value("i64"), MetricNumber::Int(i64::MAX));
+    assert_eq!(value("u64"), MetricNumber::Float(18446744073709551615.));
+    assert_eq!(value("huge"), MetricNumber::Float(99999999999999999999999.));
+    assert_eq!(
+        value("negative"),
+        MetricNumber::Float(-99999999999999999999999.)
+    );
+}
+
+#[test]
+fn test_infinite_bucket_spellings() {
+    for inf in ["+Inf", "Inf", "inf", "+Infinity"].iter() {
+        let test_str = format!(
+            "# TYPE foo histogram
+foo_bucket{{le=\"1\"}} 1
+foo_bucket{{le=\"{}\"}} 2
+foo_sum 3
+foo_count 2
+",
+            inf
+        );
+
+        let exposition = parse_prometheus(&test_str).unwrap();
+        match &exposition.families["foo"]
+            .iter_samples()
+            .next()
+            .unwrap()
+            .value
+        {
+            PrometheusValue::Histogram(h) => {
+                assert_eq!(h.buckets[1].upper_bound, f64::INFINITY, "{}", inf)
+            }
+            other => panic!("Expected a histogram, got {:?}", other),
+        };
+    }
+
+    let two_infs = "# TYPE foo histogram
+foo_bucket{le=\"+Inf\"} 2
+foo_bucket{le=\"Inf\"} 2
+";
+    assert!(parse_prometheus(two_infs).is_err());
+}
+
+#[test]
+fn test_duplicate_bucket_bounds() {
+    let test_str = "# TYPE foo histogram
+foo_bucket{le=\"0.5\"} 1
+foo_bucket{le=\"0.5\"} 1
+foo_bucket{le=\"+Inf\"} 2
+foo_sum 3
+foo_count 2
+";
+    let err = parse_prometheus(test_str).unwrap_err().to_string();
+    assert_eq!(
+        err,
+        "Histograms can't have duplicate bucket bounds (got 0.5 twice)"
+    );
+}
+
+#[test]
+fn test_parse_multi() {
+    let first = "# TYPE up gauge
+up{instance=\"a\"} 1
+# TYPE requests_total counter
+requests_total 10
+";
+    let second = "# TYPE up gauge
+up{instance=\"b\"} 0
+";
+
+    // The same family appears in both, which would be an error in a single exposition
+    assert!(parse_prometheus(&format!("{}{}", first, second)).is_err());
+
+    let expositions =
+        parse_prometheus_multi(&format!("{}# EOF\n{}# EOF\n", first, second)).unwrap();
+    assert_eq!(expositions.len(), 2);
+    assert_eq!(expositions[0], parse_prometheus(first).unwrap());
+    assert_eq!(expositions[1], parse_prometheus(second).unwrap());
+
+    // Blank lines are part of an exposition, rather than separating them
+    let spaced = first.replace("# TYPE requests_total", "\n# TYPE requests_total");
+    let expositions = parse_prometheus_multi(&spaced).unwrap();
+    assert_eq!(expositions.len(), 1);
+    assert_eq!(expositions[0], parse_prometheus(first).unwrap());
+
+    let input = format!("{}---\n{}", first, second);
+    let expositions =
+        parse_prometheus_multi_with_separator(&input, "---", &ParseOptions::default()).unwrap();
+    assert_eq!(expositions.len(), 2);
+    assert_eq!(expositions[1], parse_prometheus(second).unwrap());
+    assert!(parse_prometheus_multi_with_separator(&input, "", &ParseOptions::default()).is_err());
+
+    // Each exposition is parsed with the given options
+    let nan = "# TYPE up gauge\nup{instance=\"c\"} NaN\n";
+    let input = format!("{}# EOF\n{}# EOF\n", first, nan);
+    assert!(parse_prometheus_multi(&input).is_ok());
+    let options = ParseOptions {
+        reject_nan: true,
+        ..ParseOptions::default()
+    };
+    assert!(parse_prometheus_multi_with_options(&input, &options).is_err());
+
+    // Errors in any of the expositions are returned
+    assert!(parse_prometheus_multi(&format!("{}# EOF\nup{{\n", first)).is_err());
+}
+
+#[test]
+fn test_late_descriptors() {
+    let lenient = ParseOptions {
+        late_descriptors: true,
+        ..ParseOptions::default()
+    };
+
+    let late_help = r#"# TYPE requests_total counter
+requests_total{path="/"} 10
+# HELP requests_total The number of requests
+requests_total{path="/login"} 2
+"#;
+    assert!(parse_prometheus(late_help).is_err());
+    let exposition = parse_prometheus_with_options(late_help, &lenient).unwrap();
+    assert_eq!(exposition.families.len(), 1);
+    let family = &exposition.families["requests_total"];
+    assert_eq!(family.help, "The number of requests");
+    assert_eq!(family.iter_samples().count(), 2);
+
+    // A late TYPE applies to the samples before it
+    let late_type = r#"request_duration_seconds_bucket{le="+Inf"} 3
+request_duration_seconds_sum 1.5
+request_duration_seconds_count 3
+# TYPE request_duration_seconds histogram
+"#;
+    let exposition = parse_prometheus_with_options(late_type, &lenient).unwrap();
+    let family = &exposition.families["request_duration_seconds"];
+    assert_eq!(family.family_type, crate::PrometheusType::Histogram);
+    assert!(matches!(
+        family.iter_samples().next().unwrap().value,
+        PrometheusValue::Histogram(_)
+    ));
+
+    // Families whose names only differ by a suffix aren't joined, unless the suffix is one of a histogram's
+    let suffixed = "# TYPE foo_sum gauge\nfoo_sum 1\n# TYPE foo gauge\nfoo 2\n";
+    for options in [ParseOptions::default(), lenient.clone()].iter() {
+        let exposition = parse_prometheus_with_options(suffixed, options).unwrap();
+        assert_eq!(exposition.families.len(), 2);
+    }
+
+    let untyped_sum = "foo_sum 1\n# TYPE foo gauge\nfoo 2\n";
+    let exposition = parse_prometheus_with_options(untyped_sum, &lenient).unwrap();
+    assert_eq!(exposition.families.len(), 2);
+
+    // Late descriptors still can't contradict earlier ones, and strict mode rejects them
+    let two_types = r#"# TYPE foo gauge
+foo 1
+# TYPE foo counter
+"#;
+    assert!(parse_prometheus_with_options(two_types, &lenient).is_err());
+
+    let strict = ParseOptions {
+        strict: true,
+        ..lenient
+    };
+    assert!(parse_prometheus_with_options(late_help, &strict).is_err());
+}
+
+#[test]
+fn test_exemplar_timestamps() {
+    let test_str = "# TYPE foo histogram
+foo_bucket{le=\"+Inf\"} 1 # {trace_id=\"abc\"} 0.5 1e999
+foo_sum 0.5
+foo_count 1
+";
+    assert_eq!(
+        parse_prometheus(test_str).unwrap_err().to_string(),
+        "Exemplar timestamp must be finite (got: 1e999)"
+    );
+    assert!(parse_prometheus(&test_str.replace("1e999", "NaN")).is_err());
+    assert!(parse_prometheus(&test_str.replace("1e999", "1520879607.789")).is_ok());
+}
+
+#[test]
+fn test_parse_bytes_and_readers() {
+    let test_str = "# TYPE up gauge\nup 1\n";
+    let expected = parse_prometheus(test_str).unwrap();
+    assert_eq!(
+        parse_prometheus_bytes(test_str.as_bytes()).unwrap(),
+        expected
+    );
+    assert_eq!(
+        parse_prometheus_reader(test_str.as_bytes()).unwrap(),
+        expected
+    );
+
+    let invalid = b"# TYPE up gauge\nup{instance=\"\xff\"} 1\n";
+    assert!(matches!(
+        parse_prometheus_bytes(invalid),
+        Err(ParseError::Utf8(_))
+    ));
+    assert!(matches!(
+        parse_prometheus_reader(&invalid[..]),
+        Err(ParseError::Utf8(_))
+    ));
+
+    struct FailingReader;
+    impl std::io::Read for FailingReader {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::ConnectionReset.into())
+        }
+    }
+
+    let err = parse_prometheus_reader(FailingReader).unwrap_err();
+    assert!(std::error::Error::source(&err).is_some());
+    assert!(matches!(err, ParseError::Io(_)));
+    assert_eq!(
+        err.to_string(),
+        "Failed to read exposition: connection reset"
+    );
+}
+
+#[test]
+fn test_parse_reader() {
+    let test_data = fs::read("./src/prometheus/testdata/node_exporter.txt").unwrap();
+    let expected = parse_prometheus(std::str::from_utf8(&test_data).unwrap()).unwrap();
+    let exposition = parse_prometheus_reader(std::io::Cursor::new(&test_data)).unwrap();
+    assert_eq!(exposition, expected);
+
+    // The size limit applies to the string and reader entry points
+    let options = ParseOptions {
+        max_size: Some(test_data.len()),
+        ..ParseOptions::default()
+    };
+    assert!(
+        parse_prometheus_reader_with_options(std::io::Cursor::new(&test_data), &options).is_ok()
+    );
+
+    let options = ParseOptions {
+        max_size: Some(test_data.len() - 1),
+        ..ParseOptions::default()
+    };
+    let err = parse_prometheus_reader_with_options(std::io::Cursor::new(&test_data), &options)
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "Exposition is larger than the maximum size of {} bytes",
+            test_data.len() - 1
+        )
+    );
+    assert!(
+        parse_prometheus_with_options(std::str::from_utf8(&test_data).unwrap(), &options).is_err()
+    );
+}
+
+#[test]
+fn test_negative_and_fractional_timestamps() {
+    let test_str = "# TYPE mymetric gauge
+mymetric{a=\"1\"} 5 -100.5
+mymetric{a=\"2\"} 5 1520879607789.123
+";
+    assert!(parse_prometheus(test_str).is_err());
+
+    let options = ParseOptions {
+        fractional_timestamps: true,
+        ..ParseOptions::default()
+    };
+    let exposition = parse_prometheus_with_options(test_str, &options).unwrap();
+    let family = &exposition.families["mymetric"];
+    let timestamps: Vec<_> = family.iter_samples().map(|s| s.timestamp).collect();
+    assert_eq!(timestamps, vec![Some(-100.5), Some(1520879607789.123)]);
+
+    assert_eq!(exposition.to_string(), test_str);
+    assert_eq!(
+        parse_prometheus_with_options(&exposition.to_string(), &options).unwrap(),
+        exposition
+    );
+
+    let exposition = parse_prometheus("# TYPE mymetric gauge\nmymetric 5 -100\n").unwrap();
+    let sample = exposition.families["mymetric"]
+        .iter_samples()
+        .next()
+        .unwrap();
+    assert_eq!(sample.timestamp, Some(-100.));
+}
+
+#[test]
+fn test_missing_value() {
+    for test_str in [
+        "foo\n",
+        "foo{a=\"b\"}\n",
+        "foo{a=\"b\"} \n",
+        "# TYPE foo gauge\nfoo 1\nfoo{a=\"b\"}\n",
+        "{\"foo\"}\n",
+    ]
+    .iter()
+    {
+        assert!(parse_prometheus(test_str).is_err(), "{}", test_str);
+        assert!(parse_prometheus_pairs(test_str).is_err(), "{}", test_str);
+    }
+}
+
+#[test]
+fn test_timestamp_units() {
+    // Prometheus timestamps are milliseconds since the epoch
+    let exposition = parse_prometheus("# TYPE foo gauge\nfoo 5 1395066363000\n").unwrap();
+    let sample = exposition.families["foo"].iter_samples().next().unwrap();
+    assert_eq!(sample.timestamp, Some(1395066363000.));
+    assert_eq!(sample.timestamp_as_millis_i64(), Some(1395066363000));
+    assert_eq!(sample.timestamp_as_seconds_f64(), Some(1395066363.));
+
+    assert_eq!(
+        sample.raw_timestamp(),
+        Some(RawTimestamp::Millis(1395066363000))
+    );
+
+    // Timestamps are parsed as integers, so stay exact past 2^53, where floats skip every other integer
+    let exposition = parse_prometheus("# TYPE foo gauge\nfoo 5 9007199254740993\n").unwrap();
+    let sample = exposition.families["foo"].iter_samples().next().unwrap();
+    assert_eq!(sample.timestamp_as_millis_i64(), Some(9007199254740993));
+
+    // Timestamps that aren't whole milliseconds that fit in an i64 are rejected by default
+    for timestamp in ["-100.5", "1e3", "1e400", "99999999999999999999"] {
+        let test_str = format!("# TYPE foo gauge\nfoo 5 {}\n", timestamp);
+        assert!(parse_prometheus(&test_str).is_err(), "{}", timestamp);
+    }
+
+    // If they're allowed, fractional milliseconds aren't truncated, but they still have to be finite
+    let options = ParseOptions {
+        fractional_timestamps: true,
+        ..ParseOptions::default()
+    };
+    assert!(parse_prometheus_with_options("# TYPE foo gauge\nfoo 5 1e400\n", &options).is_err());
+
+    let exposition =
+        parse_prometheus_with_options("# TYPE foo gauge\nfoo 5 -100.5\n", &options).unwrap();
+    let sample = exposition.families["foo"].iter_samples().next().unwrap();
+    assert_eq!(
+        sample.raw_timestamp(),
+        Some(RawTimestamp::FractionalMillis(-100.5))
+    );
+    assert_eq!(sample.timestamp_as_millis_i64(), None);
+    assert_eq!(sample.timestamp_as_seconds_f64(), Some(-0.1005));
+
+    let exposition = parse_prometheus("# TYPE foo gauge\nfoo 5\n").unwrap();
+    let sample = exposition.families["foo"].iter_samples().next().unwrap();
+    assert_eq!(sample.raw_timestamp(), None);
+    assert_eq!(sample.timestamp_as_millis_i64(), None);
+    assert_eq!(sample.timestamp_as_seconds_f64(), None);
+
+    // Changing the timestamp replaces the parsed one
+    let mut sample = sample.clone();
+    sample.timestamp = Some(1000.);
+    assert_eq!(sample.raw_timestamp(), Some(RawTimestamp::Millis(1000)));
+}
+
+#[test]
+fn test_padded_bounds() {
+    let test_str = "# TYPE foo histogram
+foo_bucket{le=\" 0.5\"} 1
+foo_bucket{le=\" +Inf \"} 2
+foo_sum 1.5
+foo_count 2
+# TYPE bar summary
+bar{quantile=\"0.9 \"} 1
+";
+    let exposition = parse_prometheus(test_str).unwrap();
+
+    let histogram = exposition.families["foo"].iter_samples().next().unwrap();
+    match &histogram.value {
+        PrometheusValue::Histogram(h) => {
+            let bounds: Vec<_> = h.buckets.iter().map(|b| b.upper_bound).collect();
+            assert_eq!(bounds, vec![0.5, f64::INFINITY]);
+        }
+        other => panic!("Expected a histogram, got {:?}", other),
+    }
+
+    let summary = exposition.families["bar"].iter_samples().next().unwrap();
+    match &summary.value {
+        PrometheusValue::Summary(s) => assert_eq!(s.quantiles[0].quantile, 0.9),
+        other => panic!("Expected a summary, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_preserve_line_order() {
+    let test_str = "# TYPE request_duration_seconds histogram
+request_duration_seconds_count{path=\"/\"} 3
+request_duration_seconds_sum{path=\"/\"} 1.5
+request_duration_seconds_bucket{le=\"0.5\",path=\"/\"} 1
+request_duration_seconds_bucket{le=\"+Inf\",path=\"/\"} 3
+request_duration_seconds_bucket{le=\"0.5\",path=\"/login\"} 0
+request_duration_seconds_bucket{le=\"+Inf\",path=\"/login\"} 1
+request_duration_seconds_count{path=\"/login\"} 1
+request_duration_seconds_sum{path=\"/login\"} 2
+";
+    let expected = "# TYPE request_duration_seconds histogram
+request_duration_seconds_count{path=\"/\"} 3
+request_duration_seconds_sum{path=\"/\"} 1.5
+request_duration_seconds_bucket{path=\"/\",le=\"0.5\"} 1
+request_duration_seconds_bucket{path=\"/\",le=\"+Inf\"} 3
+request_duration_seconds_bucket{path=\"/login\",le=\"0.5\"} 0
+request_duration_seconds_bucket{path=\"/login\",le=\"+Inf\"} 1
+request_duration_seconds_count{path=\"/login\"} 1
+request_duration_seconds_sum{path=\"/login\"} 2
+";
+
+    let options = ParseOptions {
+        preserve_line_order: true,
+        ..Default::default()
+    };
+    let exposition = parse_prometheus_with_options(test_str, &options).unwrap();
+    assert_eq!(exposition.to_string(), expected);
+
+    // Without the option, lines are grouped by series, and the order doesn't affect equality
+    let grouped = parse_prometheus(test_str).unwrap();
+    assert_ne!(grouped.to_string(), expected);
+    assert_eq!(grouped, exposition);
+
+    // Summaries keep their order too, with quantiles matched by value however they were written
+    let summary = "# TYPE rpc_seconds summary\nrpc_seconds_sum 4\nrpc_seconds{quantile=\"0.50\"} 1\nrpc_seconds_count 2\nrpc_seconds{quantile=\"0.9\"} 3\n";
+    let parsed = parse_prometheus_with_options(summary, &options).unwrap();
+    assert_eq!(
+        parsed.to_string(),
+        "# TYPE rpc_seconds summary\nrpc_seconds_sum 4\nrpc_seconds{quantile=\"0.5\"} 1\nrpc_seconds_count 2\nrpc_seconds{quantile=\"0.9\"} 3\n"
+    );
+
+    // Replacing a sample clears the original order too, even though the family still renders as many lines
+    let mut replaced = exposition.clone();
+    let family = replaced
+        .families
+        .get_mut("request_duration_seconds")
+        .unwrap();
+    let sample = family.iter_samples().next().unwrap().clone();
+    family.upsert_sample(sample).unwrap();
+    assert_eq!(replaced.to_string(), grouped.to_string());
+
+    // Adding a sample means the original order no longer applies, so the family is grouped by series again
+    let mut exposition = exposition;
+    let mut grouped = grouped;
+    for exposition in [&mut exposition, &mut grouped].iter_mut() {
+        let family = exposition
+            .families
+            .get_mut("request_duration_seconds")
+            .unwrap();
+        let value = family.iter_samples().next().unwrap().value.clone();
+        family
+            .add_sample(Sample::new(vec![String::from("/logout")], None, value))
+            .unwrap();
+    }
+    assert_eq!(exposition.to_string(), grouped.to_string());
+}
+
+#[test]
+fn test_dedupe_identical() {
+    let options = ParseOptions {
+        dedupe_identical: true,
+        ..Default::default()
+    };
+
+    // An exact duplicate is ignored with the option, but is still an error without it
+    let duplicated = "# TYPE foo gauge\nfoo{a=\"1\"} 5\nfoo{a=\"2\"} 6\nfoo{a=\"1\"} 5\n";
+    let exposition = parse_prometheus_with_options(duplicated, &options).unwrap();
+    assert_eq!(exposition.families["foo"].iter_samples().count(), 2);
+    assert_eq!(
+        exposition,
+        parse_prometheus("# TYPE foo gauge\nfoo{a=\"1\"} 5\nfoo{a=\"2\"} 6\n").unwrap()
+    );
+    assert!(parse_prometheus(duplicated).is_err());
+
+    // Duplicates with a different value or timestamp conflict, so are still errors
+    for conflicting in [
+        "# TYPE foo gauge\nfoo{a=\"1\"} 5\nfoo{a=\"1\"} 6\n",
+        "# TYPE foo gauge\nfoo{a=\"1\"} 5\nfoo{a=\"1\"} 5 200\n",
+    ]
+    .iter()
+    {
+        assert!(parse_prometheus_with_options(conflicting, &options).is_err());
+    }
+}
+
+#[test]
+fn test_from_str() {
+    let input = "# TYPE foo gauge\nfoo{a=\"1\"} 5\n";
+    let exposition: MetricsExposition<PrometheusType, PrometheusValue> = input.parse().unwrap();
+    assert_eq!(exposition, parse_prometheus(input).unwrap());
+    assert_eq!(MetricsExposition::try_from(input).unwrap(), exposition);
+
+    assert!("foo{a=\"1\"}\n"
+        .parse::<MetricsExposition<PrometheusType, PrometheusValue>>()
+        .is_err());
+}
+
+#[test]
+fn test_missing_mandatory_label() {
+    // Buckets without an `le`, and quantiles without a `quantile`, are errors rather than panics
+    for (input, label) in [
+        (
+            "# TYPE foo histogram\nfoo_bucket{a=\"1\"} 1\nfoo_sum 1\nfoo_count 1\n",
+            "le",
+        ),
+        (
+            "# TYPE foo summary\nfoo{a=\"1\"} 1\nfoo_sum 1\nfoo_count 1\n",
+            "quantile",
+        ),
+        // The `le` label has already been taken off the first line's labels by the time the second is processed
+        (
+            "# TYPE foo histogram\nfoo_bucket{le=\"1\"} 1\nfoo_bucket{a=\"1\"} 1\nfoo_sum 1\nfoo_count 1\n",
+            "le",
+        ),
+    ]
+    .iter()
+    {
+        let err = parse_prometheus(input).unwrap_err().to_string();
+        assert!(
+            err.contains(&format!("Missing mandatory label for metric: {}", label)),
+            "{}",
+            err
+        );
+    }
+}
+
+#[test]
+fn test_multiple_bucket_exemplars() {
+    let test_str = "# TYPE foo histogram
+foo_bucket{le=\"0.5\"} 1 # {trace_id=\"abc\"} 0.25
+foo_bucket{le=\"0.5\"} 1 # {trace_id=\"def\"} 0.3
+foo_bucket{le=\"+Inf\"} 2
+foo_sum 3
+foo_count 2
+";
+    let err = parse_prometheus(test_str).unwrap_err().to_string();
+    assert_eq!(
+        err,
+        "Histogram buckets can't have more than one exemplar (got two on the 0.5 bucket)"
+    );
+}
+
+#[test]
+fn test_syntax_error_position() {
+    let test_str = "# TYPE foo gauge\nfoo 1\nfoo{a=\"1\" 2\n";
+    let err = parse_prometheus(test_str).unwrap_err();
+    assert!(matches!(err, ParseError::Syntax { .. }), "{:?}", err);
+    assert_eq!(err.line_col(), Some((3, 4)));
+    assert!(err.to_string().contains("3:4"), "{}", err);
+
+    // Errors that aren't syntax errors don't have a position
+    let err = parse_prometheus("# TYPE foo gauge\nfoo 1\nfoo 2\n").unwrap_err();
+    assert_eq!(err.line_col(), None);
+}
+
+#[test]
+fn test_max_label_value_len() {
+    let test_str = "# TYPE foo gauge\nfoo{short=\"abc\",long=\"héllo wörld\"} 1\n";
+
+    // By default, long values are rejected
+    let options = ParseOptions {
+        max_label_value_len: Some(5),
+        ..Default::default()
+    };
+    let err = parse_prometheus_with_options(test_str, &options)
+        .unwrap_err()
+        .to_string();
+    assert_eq!(
+        err,
+        "Label value for long is 13 bytes long, which is over the maximum of 5"
+    );
+
+    let options = ParseOptions {
+        max_label_value_len: Some(13),
+        ..Default::default()
+    };
+    assert!(parse_prometheus_with_options(test_str, &options).is_ok());
+
+    // Truncation doesn't split the two byte é, so cuts the value to 1 byte rather than 2
+    let options = ParseOptions {
+        max_label_value_len: Some(2),
+        truncate_label_values: true,
+        ..Default::default()
+    };
+    let exposition = parse_prometheus_with_options(test_str, &options).unwrap();
+    let family = &exposition.families["foo"];
+    assert!(family.contains_labelset(&[("short", "ab"), ("long", "h")]));
+
+    let options = ParseOptions {
+        max_label_value_len: Some(3),
+        truncate_label_values: true,
+        ..Default::default()
+    };
+    let exposition = parse_prometheus_with_options(test_str, &options).unwrap();
+    let family = &exposition.families["foo"];
+    assert!(family.contains_labelset(&[("short", "abc"), ("long", "hé")]));
+
+    // Bucket bounds aren't cut down, which would have made these two buckets the same
+    let test_str = "# TYPE bar histogram
+bar_bucket{le=\"0.00000000000000001234\"} 1
+bar_bucket{le=\"0.00000000000000001235\"} 2
+bar_bucket{le=\"+Inf\"} 3
+bar_sum 1
+bar_count 3
+";
+    for truncate_label_values in [false, true] {
+        let options = ParseOptions {
+            max_label_value_len: Some(5),
+            truncate_label_values,
+            ..Default::default()
+        };
+        let exposition = parse_prometheus_with_options(test_str, &options).unwrap();
+        let sample = exposition.families["bar"].iter_samples().next().unwrap();
+        match &sample.value {
+            PrometheusValue::Histogram(histogram) => {
+                let bounds: Vec<f64> = histogram.buckets.iter().map(|b| b.upper_bound).collect();
+                assert_eq!(bounds, vec![1.234e-17, 1.235e-17, f64::INFINITY]);
+            }
+            other => panic!("Expected a histogram, got {:?}", other),
+        }
+    }
+}
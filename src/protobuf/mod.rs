@@ -0,0 +1,6 @@
+#[cfg(test)]
+mod tests;
+
+mod messages;
+mod parsers;
+pub use parsers::*;
@@ -0,0 +1,286 @@
+use std::{collections::HashMap, convert::TryFrom};
+
+use prost::{bytes::Buf, Message};
+
+use crate::{
+    BucketSpan, CounterValue, Exemplar, HistogramBucket, HistogramValue, MetricFamily,
+    MetricNumber, MetricsExposition, NativeHistogramValue, OpenMetricsType, OpenMetricsValue,
+    ParseError, Quantile, Sample, SummaryValue, Timestamp,
+};
+
+use super::messages;
+
+impl From<prost::DecodeError> for ParseError {
+    fn from(err: prost::DecodeError) -> Self {
+        ParseError::ParseError(err.to_string())
+    }
+}
+
+fn convert_timestamp(timestamp: &messages::Timestamp) -> Timestamp {
+    timestamp.seconds as f64 + f64::from(timestamp.nanos) / 1e9
+}
+
+fn convert_exemplar(exemplar: &messages::Exemplar) -> Exemplar {
+    let labels = exemplar
+        .label
+        .iter()
+        .map(|l| (l.name.clone(), l.value.clone()))
+        .collect();
+
+    Exemplar::new(
+        labels,
+        exemplar.value,
+        exemplar.timestamp.as_ref().map(convert_timestamp),
+    )
+}
+
+fn convert_spans(spans: &[messages::BucketSpan]) -> Vec<BucketSpan> {
+    spans
+        .iter()
+        .map(|s| BucketSpan {
+            offset: s.offset,
+            length: s.length,
+        })
+        .collect()
+}
+
+fn missing_value(family_name: &str) -> ParseError {
+    ParseError::InvalidMetric(format!(
+        "Metric in family {} doesn't have a value of the family's type",
+        family_name
+    ))
+}
+
+/// Classic bucket counts are u64s in the protobuf format, but signed in `MetricNumber`
+fn convert_count(count: u64) -> Result<MetricNumber, ParseError> {
+    i64::try_from(count)
+        .map(MetricNumber::Int)
+        .map_err(|_| ParseError::InvalidMetric(format!("Histogram count {} is too large", count)))
+}
+
+fn float_counts_unsupported() -> ParseError {
+    ParseError::InvalidMetric("Histograms with float counts aren't supported".to_owned())
+}
+
+fn convert_histogram(histogram: &messages::Histogram) -> Result<OpenMetricsValue, ParseError> {
+    let created = histogram.created_timestamp.as_ref().map(convert_timestamp);
+
+    // Histograms can carry both classic buckets and native ones, in which case we keep the classic buckets, as
+    // they're what the text formats would have exposed
+    let is_native = histogram.bucket.is_empty()
+        && (histogram.schema.is_some()
+            || !histogram.positive_span.is_empty()
+            || !histogram.negative_span.is_empty());
+
+    // Our histogram counts are integers, so float histograms are rejected rather than read as empty ones. Unset
+    // fields read as zero in protobuf, so a float count only counts as set if it isn't zero
+    let float_count = histogram.sample_count_float != 0.;
+    if is_native {
+        if float_count
+            || histogram.zero_count_float != 0.
+            || !histogram.positive_count.is_empty()
+            || !histogram.negative_count.is_empty()
+        {
+            return Err(float_counts_unsupported());
+        }
+
+        let native = NativeHistogramValue {
+            schema: histogram.schema.unwrap_or_default(),
+            zero_threshold: histogram.zero_threshold,
+            zero_count: histogram.zero_count,
+            count: histogram.sample_count,
+            sum: MetricNumber::Float(histogram.sample_sum),
+            created,
+            positive_spans: convert_spans(&histogram.positive_span),
+            positive_deltas: histogram.positive_delta.clone(),
+            negative_spans: convert_spans(&histogram.negative_span),
+            negative_deltas: histogram.negative_delta.clone(),
+        };
+        native.validate()?;
+
+        return Ok(OpenMetricsValue::NativeHistogram(native));
+    }
+
+    if float_count
+        || histogram
+            .bucket
+            .iter()
+            .any(|b| b.cumulative_count_float != 0.)
+    {
+        return Err(float_counts_unsupported());
+    }
+
+    let mut buckets = histogram
+        .bucket
+        .iter()
+        .map(|b| {
+            Ok(HistogramBucket {
+                count: convert_count(b.cumulative_count)?,
+                upper_bound: b.upper_bound,
+                exemplar: b.exemplar.as_ref().map(convert_exemplar),
+            })
+        })
+        .collect::<Result<Vec<HistogramBucket>, ParseError>>()?;
+
+    // The +Inf bucket is implicit in the protobuf format
+    if buckets.last().map(|b| b.upper_bound) != Some(f64::INFINITY) {
+        buckets.push(HistogramBucket {
+            count: convert_count(histogram.sample_count)?,
+            upper_bound: f64::INFINITY,
+            exemplar: None,
+        });
+    }
+
+    Ok(OpenMetricsValue::Histogram(HistogramValue {
+        sum: Some(MetricNumber::Float(histogram.sample_sum)),
+        count: Some(histogram.sample_count),
+        created,
+        buckets,
+    }))
+}
+
+fn convert_family(
+    family: messages::MetricFamily,
+) -> Result<MetricFamily<OpenMetricsType, OpenMetricsValue>, ParseError> {
+    let metric_type = messages::MetricType::try_from(family.r#type).map_err(|_| {
+        ParseError::InvalidMetric(format!("Unknown metric type: {}", family.r#type))
+    })?;
+
+    let (family_type, family_name) = match metric_type {
+        // OpenMetrics counter families don't include the _total suffix of their samples
+        messages::MetricType::Counter => (
+            OpenMetricsType::Counter,
+            family
+                .name
+                .strip_suffix("_total")
+                .unwrap_or(&family.name)
+                .to_owned(),
+        ),
+        messages::MetricType::Gauge => (OpenMetricsType::Gauge, family.name.clone()),
+        messages::MetricType::Summary => (OpenMetricsType::Summary, family.name.clone()),
+        messages::MetricType::Untyped => (OpenMetricsType::Unknown, family.name.clone()),
+        messages::MetricType::Histogram => (OpenMetricsType::Histogram, family.name.clone()),
+        messages::MetricType::GaugeHistogram => {
+            (OpenMetricsType::GaugeHistogram, family.name.clone())
+        }
+    };
+
+    let mut label_names: Option<Vec<String>> = None;
+    let mut samples = Vec::with_capacity(family.metric.len());
+    for metric in family.metric.iter() {
+        let mut labels: Vec<(&str, &str)> = metric
+            .label
+            .iter()
+            .map(|l| (l.name.as_str(), l.value.as_str()))
+            .collect();
+        labels.sort_by_key(|l| l.0);
+
+        let names: Vec<String> = labels.iter().map(|(n, _)| (*n).to_owned()).collect();
+        match &label_names {
+            Some(existing) if existing != &names => {
+                return Err(ParseError::InvalidMetric(
+                    "Labels in metrics have different label sets".to_owned(),
+                ));
+            }
+            Some(_) => {}
+            None => label_names = Some(names),
+        }
+
+        let value = match metric_type {
+            messages::MetricType::Counter => {
+                let counter = metric
+                    .counter
+                    .as_ref()
+                    .ok_or_else(|| missing_value(&family.name))?;
+                OpenMetricsValue::Counter(CounterValue {
+                    value: MetricNumber::Float(counter.value),
+                    created: counter.created_timestamp.as_ref().map(convert_timestamp),
+                    exemplar: counter.exemplar.as_ref().map(convert_exemplar),
+                })
+            }
+            messages::MetricType::Gauge => {
+                let gauge = metric
+                    .gauge
+                    .as_ref()
+                    .ok_or_else(|| missing_value(&family.name))?;
+                OpenMetricsValue::Gauge(MetricNumber::Float(gauge.value))
+            }
+            messages::MetricType::Untyped => {
+                let untyped = metric
+                    .untyped
+                    .as_ref()
+                    .ok_or_else(|| missing_value(&family.name))?;
+                OpenMetricsValue::Unknown(MetricNumber::Float(untyped.value))
+            }
+            messages::MetricType::Summary => {
+                let summary = metric
+                    .summary
+                    .as_ref()
+                    .ok_or_else(|| missing_value(&family.name))?;
+                OpenMetricsValue::Summary(SummaryValue {
+                    sum: Some(MetricNumber::Float(summary.sample_sum)),
+                    count: Some(summary.sample_count),
+                    created: summary.created_timestamp.as_ref().map(convert_timestamp),
+                    quantiles: summary
+                        .quantile
+                        .iter()
+                        .map(|q| Quantile {
+                            quantile: q.quantile,
+                            value: MetricNumber::Float(q.value),
+                        })
+                        .collect(),
+                })
+            }
+            messages::MetricType::Histogram | messages::MetricType::GaugeHistogram => {
+                let histogram = metric
+                    .histogram
+                    .as_ref()
+                    .ok_or_else(|| missing_value(&family.name))?;
+                match (metric_type, convert_histogram(histogram)?) {
+                    (messages::MetricType::GaugeHistogram, OpenMetricsValue::Histogram(h)) => {
+                        OpenMetricsValue::GaugeHistogram(h)
+                    }
+                    (_, value) => value,
+                }
+            }
+        };
+
+        let label_values = labels.iter().map(|(_, v)| (*v).to_owned()).collect();
+        // Protobuf timestamps are in milliseconds, but OpenMetrics ones are in seconds
+        let timestamp = metric.timestamp_ms.map(|t| t as f64 / 1000.);
+        samples.push(Sample::new(label_values, timestamp, value));
+    }
+
+    MetricFamily::new(
+        family_name,
+        label_names.unwrap_or_default(),
+        family_type,
+        family.help,
+        family.unit,
+    )
+    .with_samples(samples)
+}
+
+/// Parses an exposition in the Prometheus protobuf format, i.e. a stream of length delimited `MetricFamily`
+/// messages, as served with a content type of
+/// `application/vnd.google.protobuf; proto=io.prometheus.client.MetricFamily; encoding=delimited`.
+/// Unlike the text formats, this can carry native histograms, which are parsed into `OpenMetricsValue::NativeHistogram`s
+pub fn parse_openmetrics_protobuf(
+    mut exposition_bytes: &[u8],
+) -> Result<MetricsExposition<OpenMetricsType, OpenMetricsValue>, ParseError> {
+    let mut families = HashMap::new();
+    while exposition_bytes.has_remaining() {
+        let family = messages::MetricFamily::decode_length_delimited(&mut exposition_bytes)?;
+        let family = convert_family(family)?;
+        if families.contains_key(&family.family_name) {
+            return Err(ParseError::InvalidMetric(format!(
+                "Found two metric families called {}",
+                family.family_name
+            )));
+        }
+
+        families.insert(family.family_name.clone(), family);
+    }
+
+    Ok(MetricsExposition { families })
+}
@@ -0,0 +1,267 @@
+use prost::Message;
+
+use super::messages;
+use crate::protobuf::parse_openmetrics_protobuf;
+use crate::{MetricNumber, NativeHistogramBucket, OpenMetricsType, OpenMetricsValue};
+
+fn encode(families: Vec<messages::MetricFamily>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for family in families {
+        family.encode_length_delimited(&mut buf).unwrap();
+    }
+
+    buf
+}
+
+fn label(name: &str, value: &str) -> messages::LabelPair {
+    messages::LabelPair {
+        name: name.to_owned(),
+        value: value.to_owned(),
+    }
+}
+
+#[test]
+fn test_parse_protobuf() {
+    let bytes = encode(vec![
+        messages::MetricFamily {
+            name: String::from("requests_total"),
+            help: String::from("The number of requests"),
+            r#type: messages::MetricType::Counter as i32,
+            metric: vec![messages::Metric {
+                label: vec![label("path", "/"), label("code", "200")],
+                counter: Some(messages::Counter {
+                    value: 10.,
+                    ..Default::default()
+                }),
+                timestamp_ms: Some(1500),
+                ..Default::default()
+            }],
+            unit: String::new(),
+        },
+        messages::MetricFamily {
+            name: String::from("temperature"),
+            help: String::new(),
+            r#type: messages::MetricType::Gauge as i32,
+            metric: vec![messages::Metric {
+                gauge: Some(messages::Gauge { value: 21.5 }),
+                ..Default::default()
+            }],
+            unit: String::new(),
+        },
+    ]);
+
+    let exposition = parse_openmetrics_protobuf(&bytes).unwrap();
+    assert_eq!(exposition.families.len(), 2);
+
+    let requests = &exposition.families["requests"];
+    assert_eq!(requests.family_type, OpenMetricsType::Counter);
+    assert_eq!(requests.help, "The number of requests");
+    assert_eq!(
+        requests.get_label_names(),
+        &[String::from("code"), String::from("path")]
+    );
+
+    let sample = requests.iter_samples().next().unwrap();
+    assert_eq!(
+        sample.label_values(),
+        &[String::from("200"), String::from("/")]
+    );
+    assert_eq!(sample.timestamp, Some(1.5));
+    match &sample.value {
+        OpenMetricsValue::Counter(c) => assert_eq!(c.value, MetricNumber::Float(10.)),
+        other => panic!("Expected a counter, got {:?}", other),
+    }
+
+    let temperature = &exposition.families["temperature"];
+    assert_eq!(temperature.family_type, OpenMetricsType::Gauge);
+    match &temperature.iter_samples().next().unwrap().value {
+        OpenMetricsValue::Gauge(n) => assert_eq!(*n, MetricNumber::Float(21.5)),
+        other => panic!("Expected a gauge, got {:?}", other),
+    }
+
+    // Garbage and mismatched label sets are rejected
+    assert!(parse_openmetrics_protobuf(&[0x05, 0xff]).is_err());
+    let bytes = encode(vec![messages::MetricFamily {
+        name: String::from("temperature"),
+        r#type: messages::MetricType::Gauge as i32,
+        metric: vec![
+            messages::Metric {
+                label: vec![label("room", "kitchen")],
+                gauge: Some(messages::Gauge { value: 21.5 }),
+                ..Default::default()
+            },
+            messages::Metric {
+                gauge: Some(messages::Gauge { value: 18. }),
+                ..Default::default()
+            },
+        ],
+        ..Default::default()
+    }]);
+    assert!(parse_openmetrics_protobuf(&bytes).is_err());
+}
+
+#[test]
+fn test_native_histogram() {
+    let histogram = |positive_delta: Vec<i64>| messages::MetricFamily {
+        name: String::from("request_duration_seconds"),
+        help: String::new(),
+        r#type: messages::MetricType::Histogram as i32,
+        metric: vec![messages::Metric {
+            histogram: Some(messages::Histogram {
+                sample_count: 8,
+                sample_sum: 3.5,
+                schema: Some(0),
+                zero_threshold: 0.001,
+                zero_count: 2,
+                positive_span: vec![
+                    messages::BucketSpan {
+                        offset: 0,
+                        length: 2,
+                    },
+                    messages::BucketSpan {
+                        offset: 1,
+                        length: 1,
+                    },
+                ],
+                positive_delta,
+                negative_span: vec![messages::BucketSpan {
+                    offset: 0,
+                    length: 1,
+                }],
+                negative_delta: vec![2],
+                ..Default::default()
+            }),
+            ..Default::default()
+        }],
+        unit: String::from("seconds"),
+    };
+
+    let exposition = parse_openmetrics_protobuf(&encode(vec![histogram(vec![1, 1, -1])])).unwrap();
+    let family = &exposition.families["request_duration_seconds"];
+    assert_eq!(family.family_type, OpenMetricsType::Histogram);
+
+    let native = match &family.iter_samples().next().unwrap().value {
+        OpenMetricsValue::NativeHistogram(h) => h.clone(),
+        other => panic!("Expected a native histogram, got {:?}", other),
+    };
+
+    assert_eq!(native.count, 8);
+    assert_eq!(native.sum, MetricNumber::Float(3.5));
+    assert_eq!(
+        native.zero_bucket(),
+        NativeHistogramBucket {
+            lower_bound: -0.001,
+            upper_bound: 0.001,
+            count: 2
+        }
+    );
+    assert_eq!(
+        native.positive_buckets(),
+        vec![
+            NativeHistogramBucket {
+                lower_bound: 0.5,
+                upper_bound: 1.,
+                count: 1
+            },
+            NativeHistogramBucket {
+                lower_bound: 1.,
+                upper_bound: 2.,
+                count: 2
+            },
+            NativeHistogramBucket {
+                lower_bound: 4.,
+                upper_bound: 8.,
+                count: 1
+            },
+        ]
+    );
+    assert_eq!(
+        native.negative_buckets(),
+        vec![NativeHistogramBucket {
+            lower_bound: -1.,
+            upper_bound: -0.5,
+            count: 2
+        }]
+    );
+
+    // The deltas have to match the spans, and can't take a bucket negative
+    assert!(parse_openmetrics_protobuf(&encode(vec![histogram(vec![1, 1])])).is_err());
+    assert!(parse_openmetrics_protobuf(&encode(vec![histogram(vec![1, -2, 1])])).is_err());
+}
+
+#[test]
+fn test_classic_histogram() {
+    let bytes = encode(vec![messages::MetricFamily {
+        name: String::from("request_duration_seconds"),
+        r#type: messages::MetricType::Histogram as i32,
+        metric: vec![messages::Metric {
+            histogram: Some(messages::Histogram {
+                sample_count: 5,
+                sample_sum: 2.,
+                bucket: vec![messages::Bucket {
+                    cumulative_count: 3,
+                    upper_bound: 0.5,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }),
+            ..Default::default()
+        }],
+        ..Default::default()
+    }]);
+
+    let exposition = parse_openmetrics_protobuf(&bytes).unwrap();
+    let family = &exposition.families["request_duration_seconds"];
+    let histogram = match &family.iter_samples().next().unwrap().value {
+        OpenMetricsValue::Histogram(h) => h.clone(),
+        other => panic!("Expected a histogram, got {:?}", other),
+    };
+
+    // The implicit +Inf bucket is filled in from the count
+    assert_eq!(histogram.buckets.len(), 2);
+    assert_eq!(histogram.buckets[1].upper_bound, f64::INFINITY);
+    assert_eq!(histogram.buckets[1].count, MetricNumber::Int(5));
+    assert_eq!(histogram.count, Some(5));
+}
+
+#[test]
+fn test_classic_histogram_counts() {
+    let family = |histogram: messages::Histogram| messages::MetricFamily {
+        name: String::from("request_duration_seconds"),
+        r#type: messages::MetricType::Histogram as i32,
+        metric: vec![messages::Metric {
+            histogram: Some(histogram),
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+
+    // Float histograms would otherwise read as having no observations
+    let float_histogram = messages::Histogram {
+        sample_count_float: 5.,
+        sample_sum: 2.,
+        bucket: vec![messages::Bucket {
+            cumulative_count_float: 3.,
+            upper_bound: 0.5,
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let err = parse_openmetrics_protobuf(&encode(vec![family(float_histogram)])).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Histograms with float counts aren't supported"
+    );
+
+    // Counts that don't fit in an i64 are errors, rather than wrapping around to negative ones
+    let huge_histogram = messages::Histogram {
+        sample_count: u64::MAX,
+        bucket: vec![messages::Bucket {
+            cumulative_count: u64::MAX,
+            upper_bound: 0.5,
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    assert!(parse_openmetrics_protobuf(&encode(vec![family(huge_histogram)])).is_err());
+}
@@ -0,0 +1,338 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::{
+    Exemplar, HistogramBucket, HistogramValue, MetricFamily, MetricNumber, MetricsExposition,
+    ParseError, PrometheusCounterValue, PrometheusType, PrometheusValue, Quantile, Sample,
+    SummaryValue, Timestamp,
+};
+
+/// Builds a HistogramValue from (bound, count) pairs, checking the invariants that the parsers would check
+/// on the rendered histogram: buckets are cumulative, bounds are unique, and there's a +Inf bucket.
+/// Buckets can be added in any order - they're sorted by bound when the histogram is built
+#[derive(Debug, Default, Clone)]
+pub struct HistogramValueBuilder {
+    buckets: Vec<HistogramBucket>,
+    sum: Option<MetricNumber>,
+    count: Option<u64>,
+    created: Option<Timestamp>,
+}
+
+impl HistogramValueBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn bucket<N>(self, upper_bound: f64, count: N) -> Self
+    where
+        N: Into<MetricNumber>,
+    {
+        self.bucket_with_exemplar(upper_bound, count, None)
+    }
+
+    pub fn bucket_with_exemplar<N>(
+        mut self,
+        upper_bound: f64,
+        count: N,
+        exemplar: Option<Exemplar>,
+    ) -> Self
+    where
+        N: Into<MetricNumber>,
+    {
+        self.buckets.push(HistogramBucket {
+            count: count.into(),
+            upper_bound,
+            exemplar,
+        });
+
+        self
+    }
+
+    pub fn sum<N>(mut self, sum: N) -> Self
+    where
+        N: Into<MetricNumber>,
+    {
+        self.sum = Some(sum.into());
+        self
+    }
+
+    /// Sets the count of the histogram. If this isn't set, the count of the +Inf bucket is used
+    pub fn count(mut self, count: u64) -> Self {
+        self.count = Some(count);
+        self
+    }
+
+    pub fn created(mut self, created: Timestamp) -> Self {
+        self.created = Some(created);
+        self
+    }
+
+    pub fn build(mut self) -> Result<HistogramValue, ParseError> {
+        if self.buckets.iter().any(|b| b.upper_bound.is_nan()) {
+            return Err(ParseError::InvalidMetric(
+                "Histogram bounds can't be NaN".to_string(),
+            ));
+        }
+
+        self.buckets
+            .sort_by(|a, b| a.upper_bound.partial_cmp(&b.upper_bound).unwrap());
+
+        for pair in self.buckets.windows(2) {
+            if pair[0].upper_bound == pair[1].upper_bound {
+                return Err(ParseError::InvalidMetric(format!(
+                    "Histogram has duplicate bucket bound: {}",
+                    pair[0].upper_bound
+                )));
+            }
+
+            if pair[1].count.as_f64() < pair[0].count.as_f64() {
+                return Err(ParseError::InvalidMetric(format!(
+                    "Histogram buckets must be cumulative (bucket {} has {}, but bucket {} has {})",
+                    pair[0].upper_bound, pair[0].count, pair[1].upper_bound, pair[1].count
+                )));
+            }
+        }
+
+        let inf_count = match self.buckets.last() {
+            Some(bucket) if bucket.upper_bound == f64::INFINITY => bucket.count,
+            _ => {
+                return Err(ParseError::InvalidMetric(
+                    "Histograms must have a +Inf bucket".to_string(),
+                ))
+            }
+        };
+
+        let count = match (self.count, inf_count.as_u64()) {
+            (Some(count), Some(inf_count)) if count == inf_count => count,
+            (None, Some(inf_count)) => inf_count,
+            _ => {
+                return Err(ParseError::InvalidMetric(format!(
+                    "Histogram +Inf bucket count ({}) doesn't match its count ({:?})",
+                    inf_count, self.count
+                )))
+            }
+        };
+
+        Ok(HistogramValue {
+            sum: self.sum,
+            count: Some(count),
+            created: self.created,
+            buckets: self.buckets,
+        })
+    }
+}
+
+/// Builds a SummaryValue from (quantile, value) pairs, checking that every quantile is between 0 and 1,
+/// and that no quantile appears twice. Quantiles can be added in any order - they're sorted when the summary is built
+#[derive(Debug, Default, Clone)]
+pub struct SummaryValueBuilder {
+    quantiles: Vec<Quantile>,
+    sum: Option<MetricNumber>,
+    count: Option<u64>,
+    created: Option<Timestamp>,
+}
+
+impl SummaryValueBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn quantile<N>(mut self, quantile: f64, value: N) -> Self
+    where
+        N: Into<MetricNumber>,
+    {
+        self.quantiles.push(Quantile {
+            quantile,
+            value: value.into(),
+        });
+
+        self
+    }
+
+    pub fn sum<N>(mut self, sum: N) -> Self
+    where
+        N: Into<MetricNumber>,
+    {
+        self.sum = Some(sum.into());
+        self
+    }
+
+    pub fn count(mut self, count: u64) -> Self {
+        self.count = Some(count);
+        self
+    }
+
+    pub fn created(mut self, created: Timestamp) -> Self {
+        self.created = Some(created);
+        self
+    }
+
+    pub fn build(mut self) -> Result<SummaryValue, ParseError> {
+        for quantile in self.quantiles.iter() {
+            if !(0. ..=1.).contains(&quantile.quantile) {
+                return Err(ParseError::InvalidMetric(format!(
+                    "Summary quantiles must be between 0 and 1 (got: {})",
+                    quantile.quantile
+                )));
+            }
+
+            if quantile.value.as_f64() < 0. {
+                return Err(ParseError::InvalidMetric(format!(
+                    "Summary quantile values can't be negative (got: {})",
+                    quantile.value
+                )));
+            }
+        }
+
+        self.quantiles
+            .sort_by(|a, b| a.quantile.partial_cmp(&b.quantile).unwrap());
+
+        for pair in self.quantiles.windows(2) {
+            if pair[0].quantile == pair[1].quantile {
+                return Err(ParseError::InvalidMetric(format!(
+                    "Summaries can't have duplicate quantiles (got {} twice)",
+                    pair[0].quantile
+                )));
+            }
+        }
+
+        if let Some(sum) = self.sum {
+            if sum.as_f64() < 0. || sum.as_f64().is_nan() {
+                return Err(ParseError::InvalidMetric(format!(
+                    "Summary sums must be non negative (got: {})",
+                    sum
+                )));
+            }
+        }
+
+        Ok(SummaryValue {
+            sum: self.sum,
+            count: self.count,
+            created: self.created,
+            quantiles: self.quantiles,
+        })
+    }
+}
+
+/// A single scalar sample added to an ExpositionBuilder
+#[derive(Debug, Clone)]
+struct ScalarSample {
+    family_type: PrometheusType,
+    labels: BTreeMap<String, String>,
+    value: MetricNumber,
+}
+
+/// Builds an exposition out of individual gauge and counter samples, grouping them into families by name.
+/// A family's label names are all the labels used by any of its samples, with samples that don't have a
+/// label getting an empty value for it (which Prometheus treats the same as the label being missing).
+/// As in the Prometheus text format, counter names have to end in `_total`
+#[derive(Debug, Default, Clone)]
+pub struct ExpositionBuilder {
+    samples: BTreeMap<String, Vec<ScalarSample>>,
+    help: HashMap<String, String>,
+}
+
+impl ExpositionBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn gauge<N>(self, name: &str, labels: &[(&str, &str)], value: N) -> Self
+    where
+        N: Into<MetricNumber>,
+    {
+        self.sample(name, PrometheusType::Gauge, labels, value.into())
+    }
+
+    pub fn counter<N>(self, name: &str, labels: &[(&str, &str)], value: N) -> Self
+    where
+        N: Into<MetricNumber>,
+    {
+        self.sample(name, PrometheusType::Counter, labels, value.into())
+    }
+
+    /// Sets the HELP text of the family with the given name
+    pub fn help(mut self, name: &str, help: &str) -> Self {
+        self.help.insert(name.to_owned(), help.to_owned());
+        self
+    }
+
+    fn sample(
+        mut self,
+        name: &str,
+        family_type: PrometheusType,
+        labels: &[(&str, &str)],
+        value: MetricNumber,
+    ) -> Self {
+        self.samples
+            .entry(name.to_owned())
+            .or_default()
+            .push(ScalarSample {
+                family_type,
+                labels: labels
+                    .iter()
+                    .map(|(n, v)| ((*n).to_owned(), (*v).to_owned()))
+                    .collect(),
+                value,
+            });
+
+        self
+    }
+
+    pub fn build(
+        mut self,
+    ) -> Result<MetricsExposition<PrometheusType, PrometheusValue>, ParseError> {
+        let mut exposition = MetricsExposition::new();
+        for (name, samples) in self.samples {
+            let family_type = samples[0].family_type.clone();
+            if samples.iter().any(|s| s.family_type != family_type) {
+                return Err(ParseError::InvalidMetric(format!(
+                    "Metric {} was added as both a gauge and a counter",
+                    name
+                )));
+            }
+
+            if family_type == PrometheusType::Counter && !name.ends_with("_total") {
+                return Err(ParseError::InvalidMetric(format!(
+                    "Counters should have a _total suffix. Got {}",
+                    name
+                )));
+            }
+
+            let label_names: Vec<String> = samples
+                .iter()
+                .flat_map(|s| s.labels.keys())
+                .cloned()
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .collect();
+
+            let mut family = MetricFamily::new(
+                name.clone(),
+                label_names.clone(),
+                family_type,
+                self.help.remove(&name).unwrap_or_default(),
+                String::new(),
+            );
+
+            for sample in samples {
+                let label_values = label_names
+                    .iter()
+                    .map(|n| sample.labels.get(n).cloned().unwrap_or_default())
+                    .collect();
+                let value = match sample.family_type {
+                    PrometheusType::Counter => PrometheusValue::Counter(PrometheusCounterValue {
+                        value: sample.value,
+                        exemplar: None,
+                    }),
+                    _ => PrometheusValue::Gauge(sample.value),
+                };
+
+                family.add_sample(Sample::new(label_values, None, value))?;
+            }
+
+            exposition.families.insert(name, family);
+        }
+
+        Ok(exposition)
+    }
+}
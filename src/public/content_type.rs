@@ -0,0 +1,40 @@
+use crate::{
+    openmetrics::parse_openmetrics, prometheus::parse_prometheus, MetricsExposition,
+    OpenMetricsType, OpenMetricsValue, ParseError, PrometheusType, PrometheusValue,
+};
+
+/// The content type of the Prometheus text format
+pub const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// The content type of the OpenMetrics text format
+pub const OPENMETRICS_CONTENT_TYPE: &str =
+    "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// An exposition in either of the text formats, as returned by `parse_by_content_type`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Exposition {
+    Prometheus(MetricsExposition<PrometheusType, PrometheusValue>),
+    OpenMetrics(MetricsExposition<OpenMetricsType, OpenMetricsValue>),
+}
+
+/// Parses `body` with the parser for the given `Content-Type` header. Only the media type is looked at, so
+/// parameters like `version` and `charset` are ignored. `application/openmetrics-text` is parsed as OpenMetrics,
+/// and `text/plain` (or an empty content type, as some servers don't send one) as Prometheus.
+/// Any other content type is an error
+pub fn parse_by_content_type(content_type: &str, body: &str) -> Result<Exposition, ParseError> {
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    match media_type.as_str() {
+        "application/openmetrics-text" => parse_openmetrics(body).map(Exposition::OpenMetrics),
+        "text/plain" | "" => parse_prometheus(body).map(Exposition::Prometheus),
+        _ => Err(ParseError::ParseError(format!(
+            "Unsupported content type: {}",
+            content_type
+        ))),
+    }
+}
@@ -0,0 +1,168 @@
+use std::convert::TryFrom;
+
+use crate::{
+    internal::LabelValues, MetricFamily, MetricNumber, MetricsExposition, OpenMetricsType,
+    OpenMetricsValue, ParseError, PrometheusCounterValue, PrometheusType, PrometheusValue, Sample,
+    SharedString, Timestamp,
+};
+
+use super::model::format_float;
+
+type OpenMetricsFamily = MetricFamily<OpenMetricsType, OpenMetricsValue>;
+type PrometheusFamily = MetricFamily<PrometheusType, PrometheusValue>;
+
+/// OpenMetrics timestamps are in seconds, but Prometheus ones are in milliseconds
+fn convert_timestamp(timestamp: Option<Timestamp>) -> Option<Timestamp> {
+    timestamp.map(|t| t * 1000.)
+}
+
+fn gauge_family(
+    family: &OpenMetricsFamily,
+    name: String,
+    label_names: Vec<SharedString>,
+) -> PrometheusFamily {
+    MetricFamily::with_shared_label_names(
+        name,
+        label_names,
+        PrometheusType::Gauge,
+        family.help.clone(),
+        String::new(),
+    )
+}
+
+/// Prometheus has no gaugehistogram type, so each gaugehistogram is split into gauge families for its buckets,
+/// its sum, and its count, named as they are in the OpenMetrics text format
+fn convert_gauge_histogram(
+    family: &OpenMetricsFamily,
+) -> Result<Vec<PrometheusFamily>, ParseError> {
+    let name = &family.family_name;
+    let label_names = family.get_label_names().to_vec();
+    let mut bucket_label_names = label_names.clone();
+    bucket_label_names.push(SharedString::from("le"));
+
+    let mut buckets = gauge_family(family, format!("{}_bucket", name), bucket_label_names);
+    let mut gsum = gauge_family(family, format!("{}_gsum", name), label_names.clone());
+    let mut gcount = gauge_family(family, format!("{}_gcount", name), label_names);
+
+    for sample in family.iter_samples() {
+        let histogram = match &sample.value {
+            OpenMetricsValue::GaugeHistogram(h) => h,
+            _ => unreachable!(),
+        };
+
+        let timestamp = convert_timestamp(sample.timestamp);
+        for bucket in histogram.buckets.iter() {
+            let mut label_values: LabelValues = sample.label_values().iter().cloned().collect();
+            label_values.push(SharedString::from(format_float(bucket.upper_bound)));
+            buckets.add_sample(Sample::with_label_values(
+                label_values,
+                timestamp,
+                PrometheusValue::Gauge(bucket.count),
+            ))?;
+        }
+
+        if let Some(sum) = histogram.sum {
+            gsum.add_sample(Sample::with_label_values(
+                sample.label_values().iter().cloned().collect(),
+                timestamp,
+                PrometheusValue::Gauge(sum),
+            ))?;
+        }
+
+        if let Some(count) = histogram.count {
+            let count = i64::try_from(count)
+                .map(MetricNumber::Int)
+                .unwrap_or(MetricNumber::Float(count as f64));
+            gcount.add_sample(Sample::with_label_values(
+                sample.label_values().iter().cloned().collect(),
+                timestamp,
+                PrometheusValue::Gauge(count),
+            ))?;
+        }
+    }
+
+    Ok(vec![buckets, gsum, gcount]
+        .into_iter()
+        .filter(|family| family.iter_samples().next().is_some())
+        .collect())
+}
+
+fn convert_family(family: &OpenMetricsFamily) -> Result<Vec<PrometheusFamily>, ParseError> {
+    let (name, family_type) = match family.family_type {
+        OpenMetricsType::GaugeHistogram => return convert_gauge_histogram(family),
+        // The samples of counters and infos have suffixes in OpenMetrics, which are part of the family name in Prometheus
+        OpenMetricsType::Counter => (
+            format!("{}_total", family.family_name),
+            PrometheusType::Counter,
+        ),
+        OpenMetricsType::Info => (
+            format!("{}_info", family.family_name),
+            PrometheusType::Gauge,
+        ),
+        OpenMetricsType::StateSet | OpenMetricsType::Gauge => {
+            (family.family_name.clone(), PrometheusType::Gauge)
+        }
+        OpenMetricsType::Histogram => (family.family_name.clone(), PrometheusType::Histogram),
+        OpenMetricsType::Summary => (family.family_name.clone(), PrometheusType::Summary),
+        OpenMetricsType::Unknown => (family.family_name.clone(), PrometheusType::Unknown),
+    };
+
+    let mut converted = MetricFamily::with_shared_label_names(
+        name,
+        family.get_label_names().to_vec(),
+        family_type,
+        family.help.clone(),
+        String::new(),
+    );
+
+    for sample in family.iter_samples() {
+        let value = match &sample.value {
+            OpenMetricsValue::Unknown(n) => PrometheusValue::Unknown(*n),
+            OpenMetricsValue::Gauge(n) | OpenMetricsValue::StateSet(n) => PrometheusValue::Gauge(*n),
+            OpenMetricsValue::Info => PrometheusValue::Gauge(MetricNumber::Int(1)),
+            OpenMetricsValue::Counter(c) => PrometheusValue::Counter(PrometheusCounterValue {
+                value: c.value,
+                exemplar: c.exemplar.clone(),
+            }),
+            OpenMetricsValue::Histogram(h) => PrometheusValue::Histogram(h.clone()),
+            OpenMetricsValue::Summary(s) => PrometheusValue::Summary(s.clone()),
+            OpenMetricsValue::GaugeHistogram(_) => unreachable!(),
+            OpenMetricsValue::NativeHistogram(_) => {
+                return Err(ParseError::InvalidMetric(format!(
+                    "Native histogram {} can't be converted, as the Prometheus text format can't carry them",
+                    family.family_name
+                )))
+            }
+        };
+
+        converted.add_sample(Sample::with_label_values(
+            sample.label_values().iter().cloned().collect(),
+            convert_timestamp(sample.timestamp),
+            value,
+        ))?;
+    }
+
+    Ok(vec![converted])
+}
+
+impl MetricsExposition<OpenMetricsType, OpenMetricsValue> {
+    /// Converts the exposition into the Prometheus data model. This is lossy, as Prometheus has fewer types:
+    ///  - Counter families get their `_total` suffix, and lose their `_created` timestamps
+    ///  - Infos become gauges called `<name>_info`, with a value of 1, and StateSets become gauges
+    ///  - GaugeHistograms are split into `<name>_bucket` (with an `le` label), `<name>_gsum`, and `<name>_gcount`
+    ///    gauge families, losing their bucket exemplars
+    ///  - Units are dropped, as Prometheus has no UNIT line
+    ///
+    /// Timestamps are converted from seconds to milliseconds. Native histograms can't be converted, and return
+    /// an error, as does a converted family's name clashing with another family
+    pub fn to_prometheus(
+        &self,
+    ) -> Result<MetricsExposition<PrometheusType, PrometheusValue>, ParseError> {
+        let mut families = Vec::new();
+        for family in self.families.values() {
+            families.extend(convert_family(family)?);
+        }
+
+        MetricsExposition::try_from_iter(families)
+    }
+}
@@ -0,0 +1,353 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use serde_json::{json, Map, Value};
+
+use crate::{
+    internal::parse_count, HistogramValueBuilder, MetricFamily, MetricNumber, MetricsExposition,
+    ParseError, PrometheusType, PrometheusValue, Sample, SharedString, SummaryValueBuilder,
+    Timestamp,
+};
+
+use super::model::format_float;
+
+/// A single series of the Prometheus data model, after histograms and summaries have been expanded into
+/// their component series
+struct Series<'a> {
+    name: String,
+    extra_label: Option<(&'static str, String)>,
+    labels: Vec<(&'a str, &'a str)>,
+    value: String,
+}
+
+fn expand_value<'a>(
+    name: &str,
+    labels: Vec<(&'a str, &'a str)>,
+    value: &PrometheusValue,
+) -> Vec<Series<'a>> {
+    let series =
+        |suffix: &str, extra_label: Option<(&'static str, String)>, value: String| Series {
+            name: format!("{}{}", name, suffix),
+            extra_label,
+            labels: labels.clone(),
+            value,
+        };
+
+    let number = |n: &MetricNumber| n.to_string();
+    let mut out = Vec::new();
+    match value {
+        PrometheusValue::Unknown(n) | PrometheusValue::Gauge(n) => {
+            out.push(series("", None, number(n)))
+        }
+        PrometheusValue::Counter(c) => out.push(series("", None, number(&c.value))),
+        PrometheusValue::Histogram(h) => {
+            for bucket in h.buckets.iter() {
+                out.push(series(
+                    "_bucket",
+                    Some(("le", format_float(bucket.upper_bound))),
+                    number(&bucket.count),
+                ));
+            }
+
+            if let Some(sum) = h.sum {
+                out.push(series("_sum", None, number(&sum)));
+            }
+
+            if let Some(count) = h.count {
+                out.push(series("_count", None, count.to_string()));
+            }
+
+            if let Some(created) = h.created {
+                out.push(series("_created", None, format_float(created)));
+            }
+        }
+        PrometheusValue::Summary(s) => {
+            for quantile in s.quantiles.iter() {
+                out.push(series(
+                    "",
+                    Some(("quantile", format_float(quantile.quantile))),
+                    number(&quantile.value),
+                ));
+            }
+
+            if let Some(sum) = s.sum {
+                out.push(series("_sum", None, number(&sum)));
+            }
+
+            if let Some(count) = s.count {
+                out.push(series("_count", None, count.to_string()));
+            }
+
+            if let Some(created) = s.created {
+                out.push(series("_created", None, format_float(created)));
+            }
+        }
+    }
+
+    out
+}
+
+/// A single entry in the `result` of a Prometheus HTTP API vector response
+struct QueryResult {
+    name: String,
+    labels: BTreeMap<String, String>,
+    timestamp: Timestamp,
+    value: MetricNumber,
+}
+
+fn malformed(reason: &str) -> ParseError {
+    ParseError::ParseError(format!("Malformed query result: {}", reason))
+}
+
+fn parse_query_result(result: &Value) -> Result<QueryResult, ParseError> {
+    let metric = result
+        .get("metric")
+        .and_then(Value::as_object)
+        .ok_or_else(|| malformed("result without a metric"))?;
+
+    let mut name = None;
+    let mut labels = BTreeMap::new();
+    for (label_name, label_value) in metric {
+        let label_value = label_value
+            .as_str()
+            .ok_or_else(|| malformed("label values must be strings"))?;
+        if label_name == "__name__" {
+            name = Some(label_value.to_owned());
+        } else {
+            labels.insert(label_name.clone(), label_value.to_owned());
+        }
+    }
+
+    let name = name.ok_or_else(|| malformed("result without a __name__"))?;
+    let (timestamp, value) = match result.get("value").and_then(Value::as_array) {
+        Some(pair) if pair.len() == 2 => (pair[0].as_f64(), pair[1].as_str()),
+        _ => return Err(malformed("value must be a [timestamp, value] pair")),
+    };
+
+    Ok(QueryResult {
+        name,
+        labels,
+        timestamp: timestamp.ok_or_else(|| malformed("timestamps must be numbers"))?,
+        value: value
+            .ok_or_else(|| malformed("values must be strings"))?
+            .parse()?,
+    })
+}
+
+/// Works out which family a series belongs to, and its type, from its name and labels
+fn infer_family(
+    result: &QueryResult,
+    histograms: &BTreeSet<String>,
+    summaries: &BTreeSet<String>,
+) -> (String, PrometheusType) {
+    for suffix in ["_bucket", "_sum", "_count"].iter() {
+        if let Some(base) = result.name.strip_suffix(suffix) {
+            if histograms.contains(base) {
+                return (base.to_owned(), PrometheusType::Histogram);
+            }
+
+            if *suffix != "_bucket" && summaries.contains(base) {
+                return (base.to_owned(), PrometheusType::Summary);
+            }
+        }
+    }
+
+    if summaries.contains(&result.name) {
+        return (result.name.clone(), PrometheusType::Summary);
+    }
+
+    (result.name.clone(), PrometheusType::Unknown)
+}
+
+fn build_family(
+    name: String,
+    family_type: PrometheusType,
+    results: Vec<QueryResult>,
+) -> Result<MetricFamily<PrometheusType, PrometheusValue>, ParseError> {
+    let reserved = match family_type {
+        PrometheusType::Histogram => "le",
+        PrometheusType::Summary => "quantile",
+        _ => "",
+    };
+
+    // Series in the same family don't have to have the same labels in the API, so the family gets the union of
+    // them, and a missing label is treated as empty, as Prometheus does
+    let label_names: Vec<String> = results
+        .iter()
+        .flat_map(|r| r.labels.keys())
+        .filter(|n| n.as_str() != reserved)
+        .cloned()
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+
+    let mut series: BTreeMap<Vec<String>, Vec<QueryResult>> = BTreeMap::new();
+    for result in results {
+        let label_values = label_names
+            .iter()
+            .map(|n| result.labels.get(n).cloned().unwrap_or_default())
+            .collect();
+        series.entry(label_values).or_default().push(result);
+    }
+
+    let mut family = MetricFamily::new(
+        name.clone(),
+        label_names,
+        family_type.clone(),
+        String::new(),
+        String::new(),
+    );
+
+    for (label_values, results) in series {
+        let timestamp = Some(results[0].timestamp * 1000.);
+        let value = match family_type {
+            PrometheusType::Histogram => {
+                let mut builder = HistogramValueBuilder::new();
+                for result in results {
+                    let suffix = &result.name[name.len()..];
+                    builder = match (suffix, result.labels.get("le")) {
+                        ("_bucket", Some(le)) => {
+                            builder.bucket(le.parse::<MetricNumber>()?.as_f64(), result.value)
+                        }
+                        ("_sum", _) => builder.sum(result.value),
+                        ("_count", _) => builder.count(parse_count(result.value, "Histogram")?),
+                        _ => return Err(malformed("histogram bucket without an le label")),
+                    };
+                }
+
+                PrometheusValue::Histogram(builder.build()?)
+            }
+            PrometheusType::Summary => {
+                let mut builder = SummaryValueBuilder::new();
+                for result in results {
+                    let suffix = &result.name[name.len()..];
+                    builder = match (suffix, result.labels.get("quantile")) {
+                        ("", Some(quantile)) => builder
+                            .quantile(quantile.parse::<MetricNumber>()?.as_f64(), result.value),
+                        ("_sum", _) => builder.sum(result.value),
+                        ("_count", _) => builder.count(parse_count(result.value, "Summary")?),
+                        _ => return Err(malformed("summary quantile without a quantile label")),
+                    };
+                }
+
+                PrometheusValue::Summary(builder.build()?)
+            }
+            _ => {
+                if results.len() > 1 {
+                    return Err(ParseError::DuplicateMetric);
+                }
+
+                PrometheusValue::Unknown(results[0].value)
+            }
+        };
+
+        family.add_sample(Sample::new(label_values, timestamp, value))?;
+    }
+
+    Ok(family)
+}
+
+impl MetricsExposition<PrometheusType, PrometheusValue> {
+    /// Builds an exposition from the response of an instant query against the Prometheus HTTP API. Series are
+    /// grouped into families by `__name__`, and `_bucket`/`_sum`/`_count` series with `le` labels are grouped into
+    /// histograms, and series with `quantile` labels into summaries. Everything else is Unknown, as the API doesn't
+    /// expose types. Timestamps are converted from seconds to milliseconds
+    pub fn from_query_result_json(json: &str) -> Result<Self, ParseError> {
+        let response: Value =
+            serde_json::from_str(json).map_err(|e| ParseError::ParseError(e.to_string()))?;
+
+        let data = response
+            .get("data")
+            .ok_or_else(|| malformed("response without data"))?;
+        if data.get("resultType").and_then(Value::as_str) != Some("vector") {
+            return Err(malformed("only vector results are supported"));
+        }
+
+        let results = data
+            .get("result")
+            .and_then(Value::as_array)
+            .ok_or_else(|| malformed("data without a result"))?
+            .iter()
+            .map(parse_query_result)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let histograms: BTreeSet<String> = results
+            .iter()
+            .filter(|r| r.labels.contains_key("le"))
+            .filter_map(|r| r.name.strip_suffix("_bucket"))
+            .map(str::to_owned)
+            .collect();
+        let summaries: BTreeSet<String> = results
+            .iter()
+            .filter(|r| r.labels.contains_key("quantile"))
+            .map(|r| r.name.clone())
+            .collect();
+
+        let mut families: HashMap<String, (PrometheusType, Vec<QueryResult>)> = HashMap::new();
+        for result in results {
+            let (name, family_type) = infer_family(&result, &histograms, &summaries);
+            families
+                .entry(name)
+                .or_insert_with(|| (family_type, Vec::new()))
+                .1
+                .push(result);
+        }
+
+        let mut exposition = MetricsExposition::new();
+        for (name, (family_type, results)) in families {
+            let family = build_family(name, family_type, results)?;
+            exposition
+                .families
+                .insert(family.family_name.clone(), family);
+        }
+
+        Ok(exposition)
+    }
+
+    /// Renders the exposition as the response of an instant query against the Prometheus HTTP API,
+    /// i.e. a `vector` result with one entry per series. Histograms and summaries are expanded into their
+    /// component series, with `le` and `quantile` labels. Sample timestamps are converted from milliseconds
+    /// to the seconds that the API uses, and samples without a timestamp use `now` (in seconds)
+    pub fn to_query_result_json(&self, now: Timestamp) -> String {
+        let mut families: Vec<_> = self.families.values().collect();
+        families.sort_by(|a, b| a.family_name.cmp(&b.family_name));
+
+        let mut result = Vec::new();
+        for family in families {
+            let label_names = family.get_label_names();
+            for sample in family.iter_samples() {
+                let labels = label_names
+                    .iter()
+                    .map(SharedString::as_str)
+                    .zip(sample.label_values().iter().map(SharedString::as_str))
+                    .collect();
+                let timestamp = sample.timestamp.map(|t| t / 1000.).unwrap_or(now);
+
+                for series in expand_value(&family.family_name, labels, &sample.value) {
+                    let mut metric = Map::new();
+                    metric.insert(String::from("__name__"), Value::from(series.name));
+                    for (name, value) in series.labels {
+                        metric.insert(name.to_owned(), Value::from(value));
+                    }
+
+                    if let Some((name, value)) = series.extra_label {
+                        metric.insert(name.to_owned(), Value::from(value));
+                    }
+
+                    result.push(json!({
+                        "metric": metric,
+                        "value": [timestamp, series.value],
+                    }));
+                }
+            }
+        }
+
+        json!({
+            "status": "success",
+            "data": {
+                "resultType": "vector",
+                "result": result,
+            },
+        })
+        .to_string()
+    }
+}
@@ -1,6 +1,19 @@
+mod builders;
+mod content_type;
+mod convert;
+#[cfg(feature = "json")]
+mod json;
 mod model;
+mod options;
+mod roundtrip;
+#[cfg(test)]
 mod tests;
 mod types;
+mod visitor;
 
+pub use builders::*;
+pub use content_type::*;
 pub use model::*;
+pub use options::*;
 pub use types::*;
+pub use visitor::*;
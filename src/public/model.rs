@@ -1,15 +1,214 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap, HashSet},
+    convert::TryFrom,
     fmt::{self, Write},
+    iter::FromIterator,
+    str::FromStr,
     sync::Arc,
 };
 
 use auto_ops::impl_op_ex;
 
-use crate::internal::{render_label_values, RenderableMetricValue};
+use crate::internal::{
+    escape_help, exemplars_not_allowed, glob_matches, render_label_values, render_metric_name,
+    render_series, units_not_allowed, LabelInterner, LabelValues, LinePosition, MetricsType,
+    RenderableMetricValue, TransformableMetricValue,
+};
 
+/// A timestamp, in the unit of the exposition it came from. That's milliseconds since the epoch in the Prometheus
+/// format, but seconds in the OpenMetrics one, so `Sample::raw_timestamp` (or `Sample::timestamp_as_millis_i64`
+/// and `Sample::timestamp_as_seconds_f64`) should be used to get one in a known unit
 pub type Timestamp = f64;
 
+/// A sample's timestamp as it was written, along with the unit that it was written in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RawTimestamp {
+    /// Whole milliseconds since the epoch, which is how the Prometheus format writes timestamps
+    Millis(i64),
+
+    /// Milliseconds since the epoch with a fractional part. The Prometheus format doesn't allow these, but some
+    /// exporters write them anyway, so they're parsed if `ParseOptions::fractional_timestamps` is set
+    FractionalMillis(f64),
+
+    /// Seconds since the epoch, which is how the OpenMetrics format writes timestamps
+    Seconds(f64),
+}
+
+impl RawTimestamp {
+    /// Reads a Prometheus timestamp, which is `Millis` if it's a whole number of milliseconds that fits in an
+    /// i64, and `FractionalMillis` otherwise
+    pub fn from_millis(millis: f64) -> RawTimestamp {
+        // i64::MAX rounds up to 2^63 as a float, which is itself out of range
+        if millis.fract() == 0. && millis >= i64::MIN as f64 && millis < i64::MAX as f64 {
+            RawTimestamp::Millis(millis as i64)
+        } else {
+            RawTimestamp::FractionalMillis(millis)
+        }
+    }
+
+    /// The timestamp in the unit it was written in, as stored in `Sample::timestamp`
+    pub fn as_timestamp(&self) -> Timestamp {
+        match self {
+            RawTimestamp::Millis(millis) => *millis as f64,
+            RawTimestamp::FractionalMillis(millis) => *millis,
+            RawTimestamp::Seconds(seconds) => *seconds,
+        }
+    }
+
+    /// The timestamp in whole milliseconds since the epoch. Seconds are rounded to the nearest millisecond, but
+    /// fractional milliseconds return None rather than being silently truncated, as do timestamps that don't fit
+    pub fn as_millis_i64(&self) -> Option<i64> {
+        match self {
+            RawTimestamp::Millis(millis) => Some(*millis),
+            RawTimestamp::FractionalMillis(_) => None,
+            RawTimestamp::Seconds(seconds) => {
+                match RawTimestamp::from_millis((seconds * 1000.).round()) {
+                    RawTimestamp::Millis(millis) => Some(millis),
+                    _ => None,
+                }
+            }
+        }
+    }
+
+    /// The timestamp in seconds since the epoch
+    pub fn as_seconds_f64(&self) -> f64 {
+        match self {
+            RawTimestamp::Millis(millis) => *millis as f64 / 1000.,
+            RawTimestamp::FractionalMillis(millis) => millis / 1000.,
+            RawTimestamp::Seconds(seconds) => *seconds,
+        }
+    }
+}
+
+/// An immutable label name or value. Cloning one is cheap, which lets identical strings across an exposition share
+/// one allocation when `ParseOptions::intern` is set. It derefs to a `str`, and compares equal to `str`s and `String`s
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SharedString(Arc<str>);
+
+impl SharedString {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Whether the two strings share one allocation, i.e. one is an interned copy of the other
+    pub fn ptr_eq(&self, other: &SharedString) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Default for SharedString {
+    fn default() -> Self {
+        SharedString::from("")
+    }
+}
+
+impl std::ops::Deref for SharedString {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for SharedString {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::borrow::Borrow<str> for SharedString {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for SharedString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&*self.0, f)
+    }
+}
+
+impl fmt::Display for SharedString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&*self.0, f)
+    }
+}
+
+impl From<String> for SharedString {
+    fn from(s: String) -> Self {
+        SharedString(Arc::from(s))
+    }
+}
+
+impl From<&String> for SharedString {
+    fn from(s: &String) -> Self {
+        SharedString(Arc::from(s.as_str()))
+    }
+}
+
+impl From<&str> for SharedString {
+    fn from(s: &str) -> Self {
+        SharedString(Arc::from(s))
+    }
+}
+
+impl From<SharedString> for String {
+    fn from(s: SharedString) -> Self {
+        s.0.to_string()
+    }
+}
+
+impl PartialEq<str> for SharedString {
+    fn eq(&self, other: &str) -> bool {
+        &*self.0 == other
+    }
+}
+
+impl PartialEq<&str> for SharedString {
+    fn eq(&self, other: &&str) -> bool {
+        &*self.0 == *other
+    }
+}
+
+impl PartialEq<String> for SharedString {
+    fn eq(&self, other: &String) -> bool {
+        &*self.0 == other.as_str()
+    }
+}
+
+impl PartialEq<SharedString> for str {
+    fn eq(&self, other: &SharedString) -> bool {
+        self == &*other.0
+    }
+}
+
+impl PartialEq<SharedString> for &str {
+    fn eq(&self, other: &SharedString) -> bool {
+        *self == &*other.0
+    }
+}
+
+impl PartialEq<SharedString> for String {
+    fn eq(&self, other: &SharedString) -> bool {
+        self.as_str() == &*other.0
+    }
+}
+
+/// Compares stored label values against ones given as any kind of string, e.g. `&[&str]` or `&[String]`
+fn label_values_eq<S: AsRef<str>>(values: &[SharedString], other: &[S]) -> bool {
+    values.len() == other.len()
+        && values
+            .iter()
+            .zip(other)
+            .all(|(a, b)| a.as_str() == b.as_ref())
+}
+
+/// The base units defined by OpenMetrics, which are used to detect names that already have a unit suffix
+/// https://github.com/OpenObservability/OpenMetrics/blob/main/specification/OpenMetrics.md#units-and-base-units
+const BASE_UNITS: &[&str] = &[
+    "seconds", "celsius", "meters", "bytes", "ratio", "volts", "amperes", "joules", "grams",
+];
+
 /// An OpenMetrics Exemplar (that is also valid in Prometheus)
 /// https://github.com/OpenObservability/OpenMetrics/blob/main/specification/OpenMetrics.md#exemplars
 /// Exemplars are references to data outside of the MetricSet. A common use case are IDs of program traces.
@@ -32,12 +231,24 @@ impl Exemplar {
             timestamp,
         }
     }
+
+    /// The time the exemplar was observed at, if the exposition included one. Parsed timestamps are always finite
+    pub fn timestamp(&self) -> Option<f64> {
+        self.timestamp
+    }
+
+    pub fn has_timestamp(&self) -> bool {
+        self.timestamp.is_some()
+    }
 }
 
 impl fmt::Display for Exemplar {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let names: Vec<&str> = self.labels.keys().map(|s| s.as_str()).collect();
-        let values: Vec<&str> = self.labels.keys().map(|s| s.as_str()).collect();
+        let mut labels: Vec<(&String, &String)> = self.labels.iter().collect();
+        labels.sort();
+
+        let names: Vec<&str> = labels.iter().map(|(name, _)| name.as_str()).collect();
+        let values: Vec<&str> = labels.iter().map(|(_, value)| value.as_str()).collect();
         write!(f, "# {} {}", render_label_values(&names, &values), self.id)?;
         if let Some(timestamp) = self.timestamp {
             write!(f, " {}", format_float(timestamp))?;
@@ -51,14 +262,51 @@ impl fmt::Display for Exemplar {
 /// https://github.com/OpenObservability/OpenMetrics/blob/main/specification/OpenMetrics.md#metricfamily
 /// A MetricFamily MAY have zero or more Metrics. A MetricFamily MUST have a name, HELP, TYPE, and UNIT metadata.
 /// Every Metric within a MetricFamily MUST have a unique LabelSet.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct MetricFamily<TypeSet, ValueType> {
     pub family_name: String,
-    label_names: Arc<Vec<String>>,
+    label_names: Arc<Vec<SharedString>>,
     pub family_type: TypeSet,
     pub help: String,
     pub unit: String,
     metrics: Vec<Sample<ValueType>>,
+
+    /// The order that the family's sample lines were in when it was parsed, as indices into the lines that it
+    /// renders by default. Only set if the family was parsed with `preserve_line_order`, and cleared by anything
+    /// that can change the family's samples, as the order would no longer match them
+    line_order: Option<Vec<usize>>,
+
+    /// How much `apply_counter_sample` has added to each Counter to carry it over resets, keyed by label values.
+    /// The last value pushed for a Counter is its stored value minus this
+    counter_offsets: HashMap<LabelValues, MetricNumber>,
+}
+
+impl<TypeSet, ValueType> MetricFamily<TypeSet, ValueType> {
+    /// Returns whether the family has a sample with exactly the given labels, which can be in any order
+    pub fn contains_labelset(&self, labels: &[(&str, &str)]) -> bool {
+        self.find_sample_by_labels(labels).is_some()
+    }
+
+    fn find_sample_by_labels(&self, labels: &[(&str, &str)]) -> Option<&Sample<ValueType>> {
+        if self.label_names.len() != labels.len() {
+            return None;
+        }
+
+        let label_values = self
+            .label_names
+            .iter()
+            .map(|label_name| {
+                labels
+                    .iter()
+                    .find(|(n, _)| n == label_name)
+                    .map(|(_, value)| *value)
+            })
+            .collect::<Option<Vec<&str>>>()?;
+
+        self.metrics
+            .iter()
+            .find(|sample| sample.label_values.iter().eq(label_values.iter()))
+    }
 }
 
 impl<TypeSet, ValueType> MetricFamily<TypeSet, ValueType>
@@ -72,6 +320,22 @@ where
         family_type: TypeSet,
         help: String,
         unit: String,
+    ) -> Self {
+        Self::with_shared_label_names(
+            family_name,
+            label_names.into_iter().map(SharedString::from).collect(),
+            family_type,
+            help,
+            unit,
+        )
+    }
+
+    pub(crate) fn with_shared_label_names(
+        family_name: String,
+        label_names: Vec<SharedString>,
+        family_type: TypeSet,
+        help: String,
+        unit: String,
     ) -> Self {
         Self {
             family_name,
@@ -80,14 +344,65 @@ where
             help,
             unit,
             metrics: Vec::new(),
+            line_order: None,
+            counter_offsets: HashMap::new(),
         }
     }
 
-    pub fn get_label_names(&self) -> &[String] {
-        return self.label_names.as_ref().as_slice();
+    pub fn get_label_names(&self) -> &[SharedString] {
+        self.label_names.as_ref().as_slice()
     }
 
-    pub fn clone_and_convert_type<T: RenderableMetricValue + Clone>(&self) -> MetricFamily<TypeSet, T> where T: From<ValueType> {
+    /// Returns the name of the family without its unit suffix, e.g. `foo` for `foo_seconds` with a unit of `seconds`.
+    /// If the family doesn't have a unit, or its name doesn't end with it, the full name is returned
+    pub fn base_name(&self) -> &str {
+        if self.unit.is_empty() {
+            return &self.family_name;
+        }
+
+        self.family_name
+            .strip_suffix(self.unit.as_str())
+            .and_then(|name| name.strip_suffix('_'))
+            .filter(|name| !name.is_empty())
+            .unwrap_or(&self.family_name)
+    }
+
+    /// Sets the unit of the family, appending `_<unit>` to the family name if it doesn't already end with it,
+    /// as OpenMetrics requires. Errors if the name already ends with a different unit
+    pub fn with_unit(mut self, unit: &str) -> Result<Self, ParseError> {
+        if unit.is_empty() {
+            return Err(ParseError::InvalidMetric(
+                "Can't set an empty unit on a metric family".to_string(),
+            ));
+        }
+
+        let suffix = format!("_{}", unit);
+        if !self.family_name.ends_with(&suffix) {
+            let conflicting_unit = BASE_UNITS
+                .iter()
+                .copied()
+                .chain(std::iter::once(self.unit.as_str()))
+                .filter(|u| !u.is_empty())
+                .find(|u| self.family_name.ends_with(&format!("_{}", u)));
+
+            if let Some(conflicting_unit) = conflicting_unit {
+                return Err(ParseError::InvalidMetric(format!(
+                    "Can't set the unit of {} to {}, as it already ends with the unit {}",
+                    self.family_name, unit, conflicting_unit
+                )));
+            }
+
+            self.family_name.push_str(&suffix);
+        }
+
+        self.unit = unit.to_owned();
+        Ok(self)
+    }
+
+    pub fn clone_and_convert_type<T>(&self) -> MetricFamily<TypeSet, T>
+    where
+        T: RenderableMetricValue + Clone + From<ValueType>,
+    {
         MetricFamily {
             family_name: self.family_name.clone(),
             label_names: Arc::new((*self.label_names).clone()),
@@ -99,6 +414,8 @@ where
                 .iter()
                 .map(|m| m.clone_with_new_value(m.value.clone().into()))
                 .collect(),
+            line_order: self.line_order.clone(),
+            counter_offsets: self.counter_offsets.clone(),
         }
     }
 
@@ -109,22 +426,22 @@ where
         let mut label_names = self.label_names.as_ref().clone();
         let mut samples = self.metrics.clone();
         for (k, v) in labels {
-            match label_names.binary_search(&k.to_owned() ) {
+            match label_names.binary_search_by(|n| n.as_str().cmp(k)) {
                 Ok(idx) => {
                     for sample in samples.iter_mut() {
-                        sample.label_values[idx] = v.to_owned();
+                        sample.label_values[idx] = SharedString::from(v);
                     }
                 }
                 Err(idx) => {
-                    label_names.insert(idx, k.to_owned());
+                    label_names.insert(idx, SharedString::from(k));
                     for sample in samples.iter_mut() {
-                        sample.label_values.insert(idx,v.to_owned());
+                        sample.label_values.insert(idx, SharedString::from(v));
                     }
                 }
             }
         }
 
-        Self::new(
+        Self::with_shared_label_names(
             self.family_name.clone(),
             label_names,
             self.family_type.clone(),
@@ -140,7 +457,7 @@ where
             Some(idx) => {
                 let mut label_names = self.label_names.as_ref().clone();
                 label_names.remove(idx);
-                let mut base = Self::new(
+                let mut base = Self::with_shared_label_names(
                     self.family_name.clone(),
                     label_names,
                     self.family_type.clone(),
@@ -151,8 +468,11 @@ where
                 for sample in self.metrics.iter() {
                     let mut label_values = sample.label_values.clone();
                     label_values.remove(idx);
-                    let new_sample =
-                        Sample::new(label_values, sample.timestamp, sample.value.clone());
+                    let new_sample = Sample::with_label_values(
+                        label_values,
+                        sample.timestamp,
+                        sample.value.clone(),
+                    );
                     base.add_sample(new_sample)?;
                 }
 
@@ -165,6 +485,14 @@ where
         }
     }
 
+    pub fn num_samples(&self) -> usize {
+        self.metrics.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.metrics.is_empty()
+    }
+
     pub fn into_iter_samples(self) -> impl Iterator<Item = Sample<ValueType>> {
         self.metrics.into_iter()
     }
@@ -174,9 +502,32 @@ where
     }
 
     pub fn iter_samples_mut(&mut self) -> impl Iterator<Item = &mut Sample<ValueType>> {
+        self.line_order = None;
         self.metrics.iter_mut()
     }
 
+    /// Returns a copy of this family, containing only the samples that match the given predicate
+    pub fn filter_samples<F>(&self, predicate: F) -> Self
+    where
+        F: Fn(&Sample<ValueType>) -> bool,
+    {
+        Self {
+            family_name: self.family_name.clone(),
+            label_names: self.label_names.clone(),
+            family_type: self.family_type.clone(),
+            help: self.help.clone(),
+            unit: self.unit.clone(),
+            metrics: self
+                .metrics
+                .iter()
+                .filter(|s| predicate(s))
+                .cloned()
+                .collect(),
+            line_order: None,
+            counter_offsets: self.counter_offsets.clone(),
+        }
+    }
+
     pub fn with_samples<T>(mut self, samples: T) -> Result<Self, ParseError>
     where
         T: IntoIterator<Item = Sample<ValueType>>,
@@ -189,48 +540,51 @@ where
     }
 
     pub fn get_sample_matches(&self, sample: &Sample<ValueType>) -> Option<&Sample<ValueType>> {
-        return self
+        self
             .metrics
             .iter()
-            .find(|&s| s.label_values == sample.label_values);
+            .find(|&s| s.label_values == sample.label_values)
     }
 
     pub fn get_sample_matches_mut(
         &mut self,
         sample: &Sample<ValueType>,
     ) -> Option<&mut Sample<ValueType>> {
-        return self
-            .metrics
+        self.line_order = None;
+        self.metrics
             .iter_mut()
-            .find(|s| s.label_values == sample.label_values);
+            .find(|s| s.label_values == sample.label_values)
     }
 
-    pub fn get_sample_by_label_values(
+    pub fn get_sample_by_label_values<S: AsRef<str>>(
         &self,
-        label_values: &[String],
+        label_values: &[S],
     ) -> Option<&Sample<ValueType>> {
-        return self.metrics.iter().find(|s| s.label_values == label_values);
+        self.metrics
+            .iter()
+            .find(|s| label_values_eq(&s.label_values, label_values))
     }
 
-    pub fn get_sample_by_label_values_mut(
+    pub fn get_sample_by_label_values_mut<S: AsRef<str>>(
         &mut self,
-        label_values: &[String],
+        label_values: &[S],
     ) -> Option<&mut Sample<ValueType>> {
-        return self
-            .metrics
+        self.line_order = None;
+        self.metrics
             .iter_mut()
-            .find(|s| s.label_values == label_values);
+            .find(|s| label_values_eq(&s.label_values, label_values))
     }
 
     pub fn get_sample_by_labelset(&self, labelset: &LabelSet) -> Option<&Sample<ValueType>> {
-        return self.metrics.iter().find(|s| labelset.matches_sample(s));
+        self.metrics.iter().find(|s| labelset.matches_sample(s))
     }
 
     pub fn get_sample_by_labelset_mut(
         &mut self,
         labelset: &LabelSet,
     ) -> Option<&mut Sample<ValueType>> {
-        return self.metrics.iter_mut().find(|s| labelset.matches_sample(s));
+        self.line_order = None;
+        self.metrics.iter_mut().find(|s| labelset.matches_sample(s))
     }
 
     pub fn set_label(&mut self, label_name: &str, label_value: &str) -> Result<(), ParseError> {
@@ -244,17 +598,75 @@ where
             }
         };
 
+        self.line_order = None;
         for metric in self.metrics.iter_mut() {
             if index == metric.label_values.len() {
-                metric.label_values.push(label_value.to_owned());
+                metric.label_values.push(SharedString::from(label_value));
             } else {
-                metric.label_values[index] = label_value.to_owned();
+                metric.label_values[index] = SharedString::from(label_value);
             }
         }
 
         Ok(())
     }
 
+    /// Merges the samples of `other` into this family. Both families must have the same name, type, and label names,
+    /// and none of the samples in `other` can have the same labelset as a sample in this family
+    pub fn merge(&mut self, other: Self) -> Result<(), ParseError>
+    where
+        TypeSet: PartialEq + fmt::Debug,
+    {
+        if self.family_name != other.family_name {
+            return Err(ParseError::InvalidMetric(format!(
+                "Cannot merge a family called {} into a family called {}",
+                other.family_name, self.family_name
+            )));
+        }
+
+        if self.family_type != other.family_type {
+            return Err(ParseError::InvalidMetric(format!(
+                "Cannot merge a {:?} family into a {:?} family ({})",
+                other.family_type, self.family_type, self.family_name
+            )));
+        }
+
+        if self.metrics.is_empty() {
+            self.label_names = other.label_names.clone();
+        } else if !other.metrics.is_empty() && self.label_names != other.label_names {
+            return Err(ParseError::InvalidMetric(format!(
+                "Cannot merge families with different label sets: {:?} {:?}",
+                self.label_names, other.label_names
+            )));
+        }
+
+        if self.help.is_empty() {
+            self.help = other.help;
+        }
+
+        if self.unit.is_empty() {
+            self.unit = other.unit;
+        }
+
+        self.line_order = None;
+        for sample in other.metrics {
+            self.add_sample(sample)?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn intern_labels(&mut self, interner: &mut LabelInterner) {
+        let label_names = interner.intern_label_names(&self.label_names);
+        for sample in self.metrics.iter_mut() {
+            sample.set_label_names(label_names.clone());
+            for value in sample.label_values.iter_mut() {
+                *value = interner.intern(value);
+            }
+        }
+
+        self.label_names = label_names;
+    }
+
     pub fn add_sample(&mut self, mut s: Sample<ValueType>) -> Result<(), ParseError> {
         if s.label_values.len() != self.label_names.len() {
             return Err(ParseError::InvalidMetric(format!(
@@ -273,9 +685,301 @@ where
 
         s.set_label_names(self.label_names.clone());
         self.metrics.push(s);
+        self.line_order = None;
 
         Ok(())
     }
+
+    /// Adds a sample to the family, replacing the sample with the same labelset if there is one, rather than
+    /// erroring like `add_sample`. Returns the replaced sample, if any, so that callers building a snapshot from
+    /// repeated inputs can see what changed
+    pub fn upsert_sample(
+        &mut self,
+        mut s: Sample<ValueType>,
+    ) -> Result<UpsertResult<ValueType>, ParseError> {
+        let idx = match self
+            .metrics
+            .iter()
+            .position(|m| m.label_values == s.label_values)
+        {
+            Some(idx) => idx,
+            None => {
+                self.add_sample(s)?;
+                return Ok(UpsertResult::Inserted);
+            }
+        };
+
+        s.set_label_names(self.label_names.clone());
+        self.line_order = None;
+        Ok(UpsertResult::Replaced(Box::new(std::mem::replace(
+            &mut self.metrics[idx],
+            s,
+        ))))
+    }
+
+    /// Checks that either all the samples in the family have timestamps, or none of them do. The text formats only
+    /// require this of the lines that make up a single sample (e.g. the buckets of a histogram), so parsed families
+    /// can mix them, and `add_sample` doesn't enforce it either. Consumers that need one or the other (e.g. to
+    /// stamp all the samples with the time of the scrape) can use this to reject families that mix them
+    pub fn validate_timestamps(&self) -> Result<(), ParseError> {
+        let timestamped = self
+            .metrics
+            .iter()
+            .filter(|s| s.timestamp.is_some())
+            .count();
+        if timestamped != 0 && timestamped != self.metrics.len() {
+            return Err(ParseError::InvalidMetric(format!(
+                "Missing timestamp in family {} ({} of {} samples had a timestamp)",
+                self.family_name,
+                timestamped,
+                self.metrics.len()
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+impl<TypeSet, ValueType> MetricFamily<TypeSet, ValueType>
+where
+    TypeSet: Clone,
+    ValueType: RenderableMetricValue + TransformableMetricValue + Clone,
+{
+    /// Applies `f` to every numeric value in the family. The components visited are:
+    /// - The value of Gauges, Unknowns, and Counters
+    /// - The sum and bucket counts of Histograms and GaugeHistograms
+    /// - The sum and quantile values of Summaries
+    ///
+    /// Histogram and Summary counts, StateSet values, and timestamps are left untouched
+    pub fn map_values<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&mut MetricNumber),
+    {
+        self.line_order = None;
+        for sample in self.metrics.iter_mut() {
+            sample.value.visit_numbers_mut(&mut f);
+        }
+    }
+
+    /// Sums the values of all the samples in the family, e.g. to get the total number of requests across all
+    /// the labelsets of a Counter. The sum stays an Int as long as all the values are Ints. Returns None if any of
+    /// the samples don't have a single value to sum (i.e. they're Histograms, Summaries, StateSets or Infos)
+    pub fn sum_values(&self) -> Option<MetricNumber> {
+        self.metrics.iter().map(|s| s.value.scalar_value()).sum()
+    }
+
+    /// Aggregates away a label, like `sum without(label)` in PromQL. Samples that have the same labels once
+    /// `label_name` is removed are combined into one sample, whose value is `op` applied to their values, and whose
+    /// timestamp is the latest of theirs. Anything else in the value (e.g. the created timestamp of a Counter) is
+    /// taken from the first of the combined samples. Only Gauges, Counters, and Unknowns can be aggregated
+    pub fn aggregate_without(&self, label_name: &str, op: AggOp) -> Result<Self, ParseError> {
+        let idx = match self.label_names.iter().position(|n| n == label_name) {
+            Some(idx) => idx,
+            None => {
+                return Err(ParseError::InvalidMetric(format!(
+                    "No label `{}` in metric family",
+                    label_name
+                )))
+            }
+        };
+
+        let mut groups: Vec<(LabelValues, Vec<&Sample<ValueType>>)> = Vec::new();
+        let mut group_indices: HashMap<LabelValues, usize> = HashMap::new();
+        for sample in self.metrics.iter() {
+            let mut label_values = sample.label_values.clone();
+            label_values.remove(idx);
+            match group_indices.get(&label_values) {
+                Some(&i) => groups[i].1.push(sample),
+                None => {
+                    group_indices.insert(label_values.clone(), groups.len());
+                    groups.push((label_values, vec![sample]));
+                }
+            }
+        }
+
+        let mut label_names = self.label_names.as_ref().clone();
+        label_names.remove(idx);
+        let mut aggregated = Self::with_shared_label_names(
+            self.family_name.clone(),
+            label_names,
+            self.family_type.clone(),
+            self.help.clone(),
+            self.unit.clone(),
+        );
+
+        for (label_values, samples) in groups {
+            let values = samples
+                .iter()
+                .map(|s| s.value.scalar_value())
+                .collect::<Option<Vec<MetricNumber>>>()
+                .ok_or_else(|| {
+                    ParseError::InvalidMetric(format!(
+                        "Can only aggregate Gauges, Counters, and Unknowns (in family {})",
+                        self.family_name
+                    ))
+                })?;
+
+            let mut value = samples[0].value.clone();
+            *value.scalar_value_mut().unwrap() = op.apply(&values);
+            let timestamp = samples
+                .iter()
+                .filter_map(|s| s.timestamp)
+                .fold(None, |latest: Option<Timestamp>, t| {
+                    Some(latest.map_or(t, |latest| latest.max(t)))
+                });
+
+            aggregated.add_sample(Sample::with_label_values(label_values, timestamp, value))?;
+        }
+
+        Ok(aggregated)
+    }
+
+    /// Applies a pushed Counter sample to the family, e.g. in a gateway that accumulates counters from short lived jobs.
+    /// If the family doesn't have a sample with the same labels, the pushed sample is added as is. Otherwise, the
+    /// pushed value is compared to the last value pushed for those labels, and replaces it unless it's lower. That
+    /// indicates that the counter has reset, which is handled according to the given `CounterResetPolicy`. After a
+    /// `SumWithReset`, the stored value is the total across the resets, and later pushes are added on to that total.
+    /// The stored timestamp is always taken from the pushed sample
+    pub fn apply_counter_sample(
+        &mut self,
+        mut sample: Sample<ValueType>,
+        policy: CounterResetPolicy,
+    ) -> Result<CounterUpdate, ParseError> {
+        let new_value = match sample.value.counter_value_mut() {
+            Some(value) => *value,
+            None => {
+                return Err(ParseError::InvalidMetric(
+                    "Can only apply Counter samples".to_string(),
+                ))
+            }
+        };
+
+        self.line_order = None;
+        let label_values = sample.label_values.clone();
+        let existing = match self
+            .metrics
+            .iter_mut()
+            .find(|s| s.label_values == label_values)
+        {
+            Some(existing) => existing,
+            None => {
+                self.add_sample(sample)?;
+                self.counter_offsets.remove(&label_values);
+                return Ok(CounterUpdate::Added);
+            }
+        };
+
+        let stored_value = match existing.value.counter_value_mut() {
+            Some(value) => value,
+            None => {
+                return Err(ParseError::InvalidMetric(
+                    "Can only apply Counter samples to a Counter".to_string(),
+                ))
+            }
+        };
+
+        let offset = self
+            .counter_offsets
+            .get(&label_values)
+            .copied()
+            .unwrap_or(MetricNumber::Int(0));
+        let last_value = *stored_value - offset;
+
+        let update = if new_value.as_f64() > last_value.as_f64() {
+            CounterUpdate::Increased
+        } else if new_value.as_f64() == last_value.as_f64() {
+            CounterUpdate::Unchanged
+        } else {
+            CounterUpdate::Reset
+        };
+
+        match (update, policy) {
+            (CounterUpdate::Reset, CounterResetPolicy::KeepOld) => {}
+            (CounterUpdate::Reset, CounterResetPolicy::TakeNew) => {
+                self.counter_offsets.remove(&label_values);
+                *stored_value = new_value;
+            }
+            (CounterUpdate::Reset, CounterResetPolicy::SumWithReset) => {
+                let offset = offset + last_value;
+                self.counter_offsets.insert(label_values, offset);
+                *stored_value = offset + new_value;
+            }
+            _ => *stored_value = offset + new_value,
+        }
+
+        existing.timestamp = sample.timestamp;
+        Ok(update)
+    }
+}
+
+/// How `MetricFamily::apply_counter_sample` handles a pushed Counter value that's lower than the stored one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CounterResetPolicy {
+    /// Ignore the pushed value, keeping the stored one
+    KeepOld,
+
+    /// Replace the stored value with the pushed one
+    TakeNew,
+
+    /// Treat the pushed value as counting from zero after the reset, adding it to the stored value
+    /// so that the result stays monotonic
+    SumWithReset,
+}
+
+/// How `MetricFamily::aggregate_without` combines the values of the samples that it merges
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AggOp {
+    Sum,
+    Max,
+    Min,
+    Avg,
+}
+
+impl AggOp {
+    fn apply(&self, values: &[MetricNumber]) -> MetricNumber {
+        let pick = |keep: fn(f64, f64) -> bool| {
+            values
+                .iter()
+                .copied()
+                .reduce(|a, b| if keep(b.as_f64(), a.as_f64()) { b } else { a })
+                .unwrap()
+        };
+
+        match self {
+            AggOp::Sum => MetricNumber::sum(values.iter().copied()),
+            AggOp::Max => pick(|new, current| new > current),
+            AggOp::Min => pick(|new, current| new < current),
+            AggOp::Avg => {
+                MetricNumber::sum(values.iter().copied()) / MetricNumber::Int(values.len() as i64)
+            }
+        }
+    }
+}
+
+/// The result of applying a Counter sample with `MetricFamily::apply_counter_sample`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CounterUpdate {
+    /// There was no sample with the same labels, so the pushed sample was added
+    Added,
+
+    /// The pushed value was higher than the stored one, and replaced it
+    Increased,
+
+    /// The pushed value was the same as the stored one
+    Unchanged,
+
+    /// The pushed value was lower than the stored one, and was handled according to the `CounterResetPolicy`
+    Reset,
+}
+
+/// The result of adding a sample with `MetricFamily::upsert_sample`
+#[derive(Debug, Clone)]
+pub enum UpsertResult<ValueType> {
+    /// There was no sample with the same labels, so the sample was added
+    Inserted,
+
+    /// The sample replaced the one with the same labels, which is returned
+    Replaced(Box<Sample<ValueType>>),
 }
 
 impl<TypeSet, ValueType> fmt::Display for MetricFamily<TypeSet, ValueType>
@@ -285,29 +989,157 @@ where
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         if !self.help.is_empty() {
-            writeln!(f, "# HELP {} {}", self.family_name, self.help)?;
+            writeln!(
+                f,
+                "# HELP {} {}",
+                render_metric_name(&self.family_name),
+                escape_help(&self.help)
+            )?;
         }
 
         if self.family_type != <TypeSet>::default() {
-            writeln!(f, "# TYPE {} {}", self.family_name, self.family_type)?;
+            writeln!(
+                f,
+                "# TYPE {} {}",
+                render_metric_name(&self.family_name),
+                self.family_type
+            )?;
         }
 
         if !self.unit.is_empty() {
-            writeln!(f, "# UNIT {} {}", self.family_name, self.unit)?;
+            writeln!(
+                f,
+                "# UNIT {} {}",
+                render_metric_name(&self.family_name),
+                self.unit
+            )?;
+        }
+
+        match &self.line_order {
+            Some(order) => {
+                let samples = SampleLines(self).to_string();
+                let lines: Vec<&str> = samples.lines().collect();
+                for i in order.iter() {
+                    writeln!(f, "{}", lines[*i])?;
+                }
+
+                Ok(())
+            }
+            None => write!(f, "{}", SampleLines(self)),
+        }
+    }
+}
+
+impl<TypeSet, ValueType> MetricFamily<TypeSet, ValueType>
+where
+    ValueType: RenderableMetricValue,
+{
+    /// Sets the order that the family renders its lines in from where each of the lines that it was parsed from ended
+    /// up. No order is set unless the positions cover every line that the family renders exactly once
+    pub(crate) fn set_line_order(&mut self, positions: &[LinePosition]) {
+        let mut offsets = HashMap::new();
+        let mut num_lines = 0;
+        for sample in self.metrics.iter() {
+            offsets.insert(&sample.label_values, (num_lines, &sample.value));
+            num_lines += sample.value.num_series();
         }
 
-        let label_names: Vec<&str> = self.label_names.iter().map(|s| s.as_str()).collect();
+        let mut seen = HashSet::new();
+        let order: Option<Vec<usize>> = positions
+            .iter()
+            .map(|position| {
+                let (offset, value) = offsets.get(&position.label_values)?;
+                let index = offset + value.line_index(position.suffix, position.bound)?;
+                Some(index).filter(|index| seen.insert(*index))
+            })
+            .collect();
+
+        self.line_order = order.filter(|order| order.len() == num_lines);
+    }
+}
+
+/// Renders the sample lines of a family, in the order that the family stores them
+struct SampleLines<'a, TypeSet, ValueType>(&'a MetricFamily<TypeSet, ValueType>);
+
+impl<TypeSet, ValueType> fmt::Display for SampleLines<'_, TypeSet, ValueType>
+where
+    ValueType: RenderableMetricValue + Clone,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let family = self.0;
+        let label_names: Vec<&str> = family.label_names.iter().map(|s| s.as_str()).collect();
 
-        for metric in self.metrics.iter() {
-            metric.render(f, &self.family_name, &label_names)?;
+        for metric in family.metrics.iter() {
+            metric.render(f, &family.family_name, &label_names)?;
         }
 
         Ok(())
     }
 }
 
+/// Families are equal if they have the same metadata and the same samples, regardless of the order of the
+/// samples or of the label names
+impl<TypeSet, ValueType> PartialEq for MetricFamily<TypeSet, ValueType>
+where
+    TypeSet: PartialEq,
+    ValueType: PartialEq,
+{
+    fn eq(&self, other: &Self) -> bool {
+        if self.family_name != other.family_name
+            || self.family_type != other.family_type
+            || self.help != other.help
+            || self.unit != other.unit
+            || self.metrics.len() != other.metrics.len()
+        {
+            return false;
+        }
+
+        let sorted_labels = |family: &Self, sample: &Sample<ValueType>| {
+            let mut labels: Vec<(SharedString, SharedString)> = family
+                .label_names
+                .iter()
+                .cloned()
+                .zip(sample.label_values.iter().cloned())
+                .collect();
+            labels.sort();
+            labels
+        };
+
+        let mut self_names = self.label_names.as_ref().clone();
+        let mut other_names = other.label_names.as_ref().clone();
+        self_names.sort();
+        other_names.sort();
+        if self_names != other_names {
+            return false;
+        }
+
+        // Label sets are unique within a family, so each sample can only match one sample in the other family
+        let other_samples: HashMap<_, _> = other
+            .metrics
+            .iter()
+            .map(|sample| (sorted_labels(other, sample), sample))
+            .collect();
+
+        self.metrics.iter().all(|sample| {
+            let other_sample = other_samples.get(&sorted_labels(self, sample));
+            other_sample.is_some_and(|other_sample| {
+                sample.timestamp == other_sample.timestamp && sample.value == other_sample.value
+            })
+        })
+    }
+}
+
+/// The metadata of a MetricFamily, as described by its HELP, TYPE, and UNIT lines
+#[derive(Debug, Clone, PartialEq)]
+pub struct FamilyMetadata<TypeSet> {
+    pub name: String,
+    pub family_type: TypeSet,
+    pub help: String,
+    pub unit: String,
+}
+
 /// Exposition is the top level object of the parser. It's a collection of metric families, indexed by name
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct MetricsExposition<TypeSet, ValueType> {
     pub families: HashMap<String, MetricFamily<TypeSet, ValueType>>,
 }
@@ -321,7 +1153,7 @@ where
         for (i, (_, family)) in self.families.iter().enumerate() {
             write!(f, "{}", family)?;
             if i != self.families.len()-1 {
-                write!(f, "\n")?;
+                writeln!(f)?;
             }
         }
 
@@ -335,11 +1167,285 @@ impl<TypeSet, ValueType> Default for MetricsExposition<TypeSet, ValueType> {
     }
 }
 
-impl<TypeSet, ValueType> MetricsExposition<TypeSet, ValueType> {
-    pub fn new() -> MetricsExposition<TypeSet, ValueType> {
-        MetricsExposition {
-            families: HashMap::new(),
+impl<TypeSet, ValueType> MetricsExposition<TypeSet, ValueType> {
+    pub fn new() -> MetricsExposition<TypeSet, ValueType> {
+        MetricsExposition {
+            families: HashMap::new(),
+        }
+    }
+
+    /// Returns every label name used by any family in the exposition. This doesn't include the labels that
+    /// are implied by a family's type, like `le` and `quantile`
+    pub fn all_label_names(&self) -> BTreeSet<String> {
+        self.families
+            .values()
+            .flat_map(|family| family.label_names.iter().map(SharedString::to_string))
+            .collect()
+    }
+
+    /// Consumes the exposition, returning an iterator over the (name, family) pairs that were in it
+    pub fn into_families(self) -> impl Iterator<Item = (String, MetricFamily<TypeSet, ValueType>)> {
+        self.families.into_iter()
+    }
+
+    /// Builds an exposition from the given families, erroring if two of them have the same name
+    pub fn try_from_iter<I>(families: I) -> Result<Self, ParseError>
+    where
+        I: IntoIterator<Item = MetricFamily<TypeSet, ValueType>>,
+    {
+        let mut exposition = MetricsExposition::new();
+        for family in families {
+            if exposition.families.contains_key(&family.family_name) {
+                return Err(ParseError::InvalidMetric(format!(
+                    "Found two metric families called {}",
+                    family.family_name
+                )));
+            }
+
+            exposition
+                .families
+                .insert(family.family_name.clone(), family);
+        }
+
+        Ok(exposition)
+    }
+
+    /// Removes the family with the given name from the exposition, returning it if it existed
+    pub fn remove_family(&mut self, name: &str) -> Option<MetricFamily<TypeSet, ValueType>> {
+        self.families.remove(name)
+    }
+
+    /// Removes every family whose name doesn't match the given predicate
+    pub fn retain_families<F>(&mut self, predicate: F)
+    where
+        F: Fn(&str) -> bool,
+    {
+        self.families.retain(|name, _| predicate(name));
+    }
+
+    /// Returns an iterator over every sample in the exposition, paired with the name of the family it's in
+    pub fn iter_all_samples(&self) -> impl Iterator<Item = (&str, &Sample<ValueType>)> {
+        self.families.values().flat_map(|family| {
+            family
+                .metrics
+                .iter()
+                .map(move |sample| (family.family_name.as_str(), sample))
+        })
+    }
+
+    /// Returns the sample in the family called `name` whose labelset is exactly `labels`, in any order
+    pub fn get_sample(&self, name: &str, labels: &[(&str, &str)]) -> Option<&Sample<ValueType>> {
+        self.families.get(name)?.find_sample_by_labels(labels)
+    }
+
+    /// Returns the families whose names match the given glob, sorted by name. In the glob, `*` matches any
+    /// run of characters and `?` matches a single character, e.g. `http_*` matches every family starting with
+    /// `http_`. Globs are used rather than regexes so that this doesn't need a regex engine
+    pub fn families_matching(&self, pattern: &str) -> Vec<&MetricFamily<TypeSet, ValueType>> {
+        let mut families: Vec<_> = self
+            .families
+            .values()
+            .filter(|family| glob_matches(pattern, &family.family_name))
+            .collect();
+        families.sort_by(|a, b| a.family_name.cmp(&b.family_name));
+        families
+    }
+
+    /// Iterates over the families of the given type, in no particular order
+    pub fn families_of_type(
+        &self,
+        family_type: TypeSet,
+    ) -> impl Iterator<Item = &MetricFamily<TypeSet, ValueType>>
+    where
+        TypeSet: PartialEq,
+    {
+        self.families
+            .values()
+            .filter(move |family| family.family_type == family_type)
+    }
+}
+
+/// Collects families into an exposition, keyed by their names. If two families have the same name, the
+/// last one wins - use `MetricsExposition::try_from_iter` to error instead
+impl<TypeSet, ValueType> FromIterator<MetricFamily<TypeSet, ValueType>>
+    for MetricsExposition<TypeSet, ValueType>
+{
+    fn from_iter<I>(families: I) -> Self
+    where
+        I: IntoIterator<Item = MetricFamily<TypeSet, ValueType>>,
+    {
+        MetricsExposition {
+            families: families
+                .into_iter()
+                .map(|family| (family.family_name.clone(), family))
+                .collect(),
+        }
+    }
+}
+
+impl<TypeSet, ValueType> MetricsExposition<TypeSet, ValueType>
+where
+    TypeSet: Clone,
+    ValueType: RenderableMetricValue + Clone,
+{
+    /// Returns a copy of this exposition, containing only the families that match the given predicate
+    pub fn filter<F>(&self, predicate: F) -> Self
+    where
+        F: Fn(&str, &MetricFamily<TypeSet, ValueType>) -> bool,
+    {
+        MetricsExposition {
+            families: self
+                .families
+                .iter()
+                .filter(|(name, family)| predicate(name, family))
+                .map(|(name, family)| (name.clone(), family.clone()))
+                .collect(),
+        }
+    }
+
+    /// Returns the total number of series in the exposition, expanding histograms and summaries into
+    /// their component series (buckets/quantiles, `_sum`, `_count`, and `_created`)
+    pub fn cardinality(&self) -> usize {
+        self.iter_all_samples()
+            .map(|(_, sample)| sample.value.num_series())
+            .sum()
+    }
+
+    /// Returns the number of series in each family of the exposition, keyed by family name.
+    /// See `cardinality` for how series are counted
+    pub fn per_family_cardinality(&self) -> HashMap<String, usize> {
+        self.families
+            .iter()
+            .map(|(name, family)| {
+                let series = family
+                    .metrics
+                    .iter()
+                    .map(|sample| sample.value.num_series())
+                    .sum();
+                (name.clone(), series)
+            })
+            .collect()
+    }
+}
+
+impl<TypeSet, ValueType> MetricsExposition<TypeSet, ValueType>
+where
+    TypeSet: fmt::Display + Default + PartialEq,
+    ValueType: RenderableMetricValue + Clone,
+{
+    /// Renders each family in name order, so that the output is stable between calls
+    fn render_sorted(&self, separator: &str) -> String {
+        let mut names: Vec<&String> = self.families.keys().collect();
+        names.sort();
+
+        names
+            .into_iter()
+            .map(|name| self.families[name].to_string())
+            .collect::<Vec<String>>()
+            .join(separator)
+    }
+}
+
+impl MetricsExposition<PrometheusType, PrometheusValue> {
+    /// Renders the exposition in the Prometheus text format
+    pub fn to_prometheus_text(&self) -> String {
+        self.render_sorted("\n")
+    }
+}
+
+impl MetricsExposition<OpenMetricsType, OpenMetricsValue> {
+    /// Renders the exposition in the OpenMetrics text format, including the trailing `# EOF`
+    pub fn to_openmetrics_text(&self) -> String {
+        format!("{}# EOF\n", self.render_sorted(""))
+    }
+}
+
+impl<TypeSet, ValueType> MetricsExposition<TypeSet, ValueType>
+where
+    TypeSet: Clone,
+    ValueType: RenderableMetricValue + TransformableMetricValue + Clone,
+{
+    /// Adds `delta` to every timestamp in the exposition - sample timestamps, `created` timestamps, and exemplar timestamps
+    pub fn shift_timestamps(&mut self, delta: Timestamp) {
+        self.visit_timestamps_mut(&mut |timestamp| {
+            if let Some(timestamp) = timestamp {
+                *timestamp += delta;
+            }
+        });
+    }
+
+    /// Removes every timestamp in the exposition, leaving the values intact
+    pub fn clear_timestamps(&mut self) {
+        self.visit_timestamps_mut(&mut |timestamp| *timestamp = None);
+    }
+
+    fn visit_timestamps_mut(&mut self, f: &mut dyn FnMut(&mut Option<Timestamp>)) {
+        for family in self.families.values_mut() {
+            for sample in family.iter_samples_mut() {
+                f(&mut sample.timestamp);
+                sample.value.visit_timestamps_mut(f);
+            }
+        }
+    }
+}
+
+impl<TypeSet, ValueType> MetricsExposition<TypeSet, ValueType>
+where
+    TypeSet: fmt::Display + Default + PartialEq + Clone,
+    ValueType: RenderableMetricValue + TransformableMetricValue + Clone,
+{
+    /// Renders the exposition in a canonical form, for diffing: families are sorted by name, label names
+    /// are sorted, samples are sorted by their label values, and floats with integral values are rendered as
+    /// integers. Expositions that are equal (see `PartialEq`) render identically, which isn't true of `to_string`.
+    /// The output is in the Prometheus text format, but isn't guaranteed to match what was parsed
+    pub fn render_canonical(&self) -> String {
+        let mut canonical = MetricsExposition::new();
+        for (name, family) in self.families.iter() {
+            let mut order: Vec<usize> = (0..family.label_names.len()).collect();
+            order.sort_by_key(|&i| &family.label_names[i]);
+
+            let mut samples: Vec<Sample<ValueType>> = family
+                .metrics
+                .iter()
+                .map(|sample| {
+                    let label_values = order
+                        .iter()
+                        .map(|&i| sample.label_values[i].clone())
+                        .collect();
+                    let mut value = sample.value.clone();
+                    value.visit_numbers_mut(&mut |n| {
+                        if let MetricNumber::Float(f) = *n {
+                            if f.fract() == 0. && f.abs() < i64::MAX as f64 {
+                                *n = MetricNumber::Int(f as i64);
+                            }
+                        }
+                    });
+
+                    Sample::with_label_values(label_values, sample.timestamp, value)
+                })
+                .collect();
+            samples.sort_by(|a, b| a.label_values.cmp(&b.label_values));
+
+            let mut sorted_family = MetricFamily::with_shared_label_names(
+                name.clone(),
+                order
+                    .iter()
+                    .map(|&i| family.label_names[i].clone())
+                    .collect(),
+                family.family_type.clone(),
+                family.help.clone(),
+                family.unit.clone(),
+            );
+            // The samples come from a valid family, so don't need to be checked again by add_sample
+            for mut sample in samples {
+                sample.set_label_names(sorted_family.label_names.clone());
+                sorted_family.metrics.push(sample);
+            }
+
+            canonical.families.insert(name.clone(), sorted_family);
         }
+
+        canonical.render_sorted("\n")
     }
 }
 
@@ -350,7 +1456,29 @@ pub struct CounterValue {
     pub exemplar: Option<Exemplar>,
 }
 
-fn format_float(f: f64) -> String {
+impl TransformableMetricValue for CounterValue {
+    fn visit_timestamps_mut(&mut self, f: &mut dyn FnMut(&mut Option<Timestamp>)) {
+        f(&mut self.created);
+        if let Some(exemplar) = self.exemplar.as_mut() {
+            f(&mut exemplar.timestamp);
+        }
+    }
+
+    fn visit_numbers_mut(&mut self, f: &mut dyn FnMut(&mut MetricNumber)) {
+        f(&mut self.value);
+    }
+
+    fn counter_value_mut(&mut self) -> Option<&mut MetricNumber> {
+        Some(&mut self.value)
+    }
+
+    fn set_exemplar(&mut self, exemplar: Option<Exemplar>) -> Result<(), ParseError> {
+        self.exemplar = exemplar;
+        Ok(())
+    }
+}
+
+pub(crate) fn format_float(f: f64) -> String {
     if f == f64::NEG_INFINITY {
         String::from("-Inf")
     }
@@ -361,10 +1489,25 @@ fn format_float(f: f64) -> String {
         String::from("NaN")
     }
     else {
-        format!("{}", f)
+        format_finite(f)
     }
 }
 
+#[cfg(not(feature = "shortest_floats"))]
+fn format_finite(f: f64) -> String {
+    format!("{}", f)
+}
+
+/// Formats the float with the shortest representation that parses back to the same value. Integral floats
+/// are rendered without a trailing `.0` (e.g. `1` rather than `1.0`), as they are without this feature, but
+/// large and small ones use an exponent (e.g. `1e21` rather than `1000000000000000000000`)
+#[cfg(feature = "shortest_floats")]
+fn format_finite(f: f64) -> String {
+    let mut buffer = ryu::Buffer::new();
+    let formatted = buffer.format_finite(f);
+    formatted.strip_suffix(".0").unwrap_or(formatted).to_owned()
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct HistogramBucket {
     pub count: MetricNumber,
@@ -396,14 +1539,17 @@ impl RenderableMetricValue for HistogramBucket {
 
         write!(
             f,
-            "{}_bucket{} {}",
-            metric_name,
-            render_label_values(&label_names, &label_values),
+            "{} {}",
+            render_series(
+                &format!("{}_bucket", metric_name),
+                &label_names,
+                &label_values
+            ),
             self.count
         )?;
 
         if let Some(ex) = self.exemplar.as_ref() {
-            write!(f, "{}", ex)?;
+            write!(f, " {}", ex)?;
         }
 
         f.write_char('\n')?;
@@ -420,6 +1566,111 @@ pub struct HistogramValue {
     pub buckets: Vec<HistogramBucket>,
 }
 
+impl HistogramValue {
+    /// Builds a histogram from raw observations, with a bucket for each of `bounds` (which don't have to be
+    /// sorted) plus a `+Inf` bucket, if `bounds` doesn't already have one. NaN bounds are ignored. Each bucket
+    /// counts the observations that are less than or equal to its bound, so NaN observations only count
+    /// towards the `+Inf` bucket, as they do in the Prometheus client libraries
+    pub fn from_observations(bounds: &[f64], observations: &[f64]) -> HistogramValue {
+        let mut bounds: Vec<f64> = bounds.iter().copied().filter(|b| !b.is_nan()).collect();
+        bounds.push(f64::INFINITY);
+        bounds.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        bounds.dedup();
+
+        let buckets = bounds
+            .into_iter()
+            .map(|upper_bound| {
+                let count = if upper_bound == f64::INFINITY {
+                    observations.len()
+                } else {
+                    observations.iter().filter(|o| **o <= upper_bound).count()
+                };
+
+                HistogramBucket {
+                    count: MetricNumber::Int(count as i64),
+                    upper_bound,
+                    exemplar: None,
+                }
+            })
+            .collect();
+
+        HistogramValue {
+            sum: Some(MetricNumber::Float(observations.iter().sum())),
+            count: Some(observations.len() as u64),
+            created: None,
+            buckets,
+        }
+    }
+
+    /// The time at which this histogram was created, i.e. the `_created` sample, if it was present
+    pub fn created(&self) -> Option<Timestamp> {
+        self.created
+    }
+
+    pub fn set_created(&mut self, created: Option<Timestamp>) {
+        self.created = created;
+    }
+
+    /// Computes the per-second increase of each bucket between `previous` and this histogram, `seconds` apart,
+    /// as (upper_bound, rate) pairs. Buckets are matched on their upper bound, with buckets missing from
+    /// `previous` counting from 0. If any bucket has decreased then the histogram is assumed to have been reset
+    /// in between, and all of `previous` is treated as 0, as Prometheus' `rate` does
+    pub fn rate_vs(&self, previous: &HistogramValue, seconds: f64) -> Vec<(f64, f64)> {
+        let previous_count = |upper_bound: f64| {
+            previous
+                .buckets
+                .iter()
+                .find(|b| b.upper_bound == upper_bound)
+                .map(|b| b.count.as_f64())
+                .unwrap_or(0.)
+        };
+
+        let reset = self
+            .buckets
+            .iter()
+            .any(|b| b.count.as_f64() < previous_count(b.upper_bound));
+
+        self.buckets
+            .iter()
+            .map(|b| {
+                let start = if reset { 0. } else { previous_count(b.upper_bound) };
+                (b.upper_bound, (b.count.as_f64() - start) / seconds)
+            })
+            .collect()
+    }
+
+    /// Converts the cumulative bucket counts into the number of observations in each bucket alone, as
+    /// (upper_bound, count) pairs in order of their bounds. Each bucket's count is its cumulative count minus
+    /// that of the bucket below it, so the `+Inf` bucket counts the observations above the largest finite bound
+    pub fn absolute_buckets(&self) -> Vec<(f64, f64)> {
+        let mut buckets: Vec<&HistogramBucket> = self.buckets.iter().collect();
+        buckets.sort_by(|a, b| a.upper_bound.partial_cmp(&b.upper_bound).unwrap());
+
+        let mut previous = 0.;
+        buckets
+            .into_iter()
+            .map(|b| {
+                let count = b.count.as_f64();
+                let absolute = count - previous;
+                previous = count;
+                (b.upper_bound, absolute)
+            })
+            .collect()
+    }
+
+    /// The total number of observations, i.e. the `_count` sample, falling back to the count of the `+Inf`
+    /// bucket (which should be the same) if there wasn't one. Returns None if neither is present, or if the
+    /// `+Inf` bucket's count isn't a whole, non-negative number
+    pub fn total_count(&self) -> Option<u64> {
+        self.count.or_else(|| {
+            self.buckets
+                .iter()
+                .find(|b| b.upper_bound == f64::INFINITY)
+                .and_then(|b| b.count.as_u64())
+        })
+    }
+}
+
 impl RenderableMetricValue for HistogramValue {
     fn render(
         &self,
@@ -433,22 +1684,99 @@ impl RenderableMetricValue for HistogramValue {
             bucket.render(f, metric_name, timestamp, label_names, label_values)?;
         }
 
-        let labels = render_label_values(label_names, label_values);
+        let series = |suffix: &str| {
+            render_series(
+                &format!("{}{}", metric_name, suffix),
+                label_names,
+                label_values,
+            )
+        };
 
         if let Some(s) = self.sum {
-            writeln!(f, "{}_sum{} {}", metric_name, labels, s)?;
+            writeln!(f, "{} {}", series("_sum"), s)?;
         }
 
         if let Some(c) = self.count {
-            writeln!(f, "{}_count{} {}", metric_name, labels, c)?;
+            writeln!(f, "{} {}", series("_count"), c)?;
         }
 
         if let Some(c) = self.created {
-            writeln!(f, "{}_created{} {}", metric_name, labels, c)?;
+            writeln!(f, "{} {}", series("_created"), c)?;
         }
 
         Ok(())
     }
+
+    fn num_series(&self) -> usize {
+        self.buckets.len()
+            + self.sum.iter().count()
+            + self.count.iter().count()
+            + self.created.iter().count()
+    }
+
+    fn line_index(&self, suffix: &str, bound: Option<f64>) -> Option<usize> {
+        let bounds: Vec<f64> = self.buckets.iter().map(|b| b.upper_bound).collect();
+        bounded_line_index(
+            &bounds,
+            [
+                self.sum.is_some(),
+                self.count.is_some(),
+                self.created.is_some(),
+            ],
+            suffix,
+            bound,
+        )
+    }
+}
+
+impl TransformableMetricValue for HistogramValue {
+    fn visit_timestamps_mut(&mut self, f: &mut dyn FnMut(&mut Option<Timestamp>)) {
+        f(&mut self.created);
+        for bucket in self.buckets.iter_mut() {
+            if let Some(exemplar) = bucket.exemplar.as_mut() {
+                f(&mut exemplar.timestamp);
+            }
+        }
+    }
+
+    fn visit_numbers_mut(&mut self, f: &mut dyn FnMut(&mut MetricNumber)) {
+        if let Some(sum) = self.sum.as_mut() {
+            f(sum);
+        }
+
+        for bucket in self.buckets.iter_mut() {
+            f(&mut bucket.count);
+        }
+    }
+
+    /// Exemplars are attached to the bucket that their value falls in, replacing any exemplar that bucket had
+    fn set_exemplar(&mut self, exemplar: Option<Exemplar>) -> Result<(), ParseError> {
+        let exemplar = match exemplar {
+            Some(exemplar) => exemplar,
+            None => {
+                for bucket in self.buckets.iter_mut() {
+                    bucket.exemplar = None;
+                }
+
+                return Ok(());
+            }
+        };
+
+        match self
+            .buckets
+            .iter_mut()
+            .find(|b| exemplar.id <= b.upper_bound)
+        {
+            Some(bucket) => {
+                bucket.exemplar = Some(exemplar);
+                Ok(())
+            }
+            None => Err(ParseError::InvalidMetric(format!(
+                "Exemplar value {} isn't in any of the histogram's buckets",
+                format_float(exemplar.id)
+            ))),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -487,9 +1815,8 @@ impl RenderableMetricValue for Quantile {
 
         writeln!(
             f,
-            "{}{} {}",
-            metric_name,
-            render_label_values(&label_names, &label_values),
+            "{} {}",
+            render_series(metric_name, &label_names, &label_values),
             self.value
         )
     }
@@ -503,6 +1830,17 @@ pub struct SummaryValue {
     pub quantiles: Vec<Quantile>,
 }
 
+impl SummaryValue {
+    /// The time at which this summary was created, i.e. the `_created` sample, if it was present
+    pub fn created(&self) -> Option<Timestamp> {
+        self.created
+    }
+
+    pub fn set_created(&mut self, created: Option<Timestamp>) {
+        self.created = created;
+    }
+}
+
 impl RenderableMetricValue for SummaryValue {
     fn render(
         &self,
@@ -516,25 +1854,267 @@ impl RenderableMetricValue for SummaryValue {
             q.render(f, metric_name, timestamp, label_names, label_values)?;
         }
 
-        let labels = render_label_values(label_names, label_values);
+        let series = |suffix: &str| {
+            render_series(
+                &format!("{}{}", metric_name, suffix),
+                label_names,
+                label_values,
+            )
+        };
 
         if let Some(s) = self.sum {
-            writeln!(f, "{}_sum{} {}", metric_name, labels, s)?;
+            writeln!(f, "{} {}", series("_sum"), s)?;
         }
 
         if let Some(s) = self.count {
-            writeln!(f, "{}_count{} {}", metric_name, labels, s)?;
+            writeln!(f, "{} {}", series("_count"), s)?;
         }
 
         if let Some(s) = self.created {
-            writeln!(f, "{}_created{} {}", metric_name, labels, s)?;
+            writeln!(f, "{} {}", series("_created"), s)?;
+        }
+
+        Ok(())
+    }
+
+    fn num_series(&self) -> usize {
+        self.quantiles.len()
+            + self.sum.iter().count()
+            + self.count.iter().count()
+            + self.created.iter().count()
+    }
+
+    fn line_index(&self, suffix: &str, bound: Option<f64>) -> Option<usize> {
+        let bounds: Vec<f64> = self.quantiles.iter().map(|q| q.quantile).collect();
+        bounded_line_index(
+            &bounds,
+            [
+                self.sum.is_some(),
+                self.count.is_some(),
+                self.created.is_some(),
+            ],
+            suffix,
+            bound,
+        )
+    }
+}
+
+/// Where a line is among those rendered by a histogram or summary: one line for each of the `bounds`, followed by the
+/// `_sum`, `_count`, and `_created` lines, for each of those that `present` says the value has
+fn bounded_line_index(
+    bounds: &[f64],
+    present: [bool; 3],
+    suffix: &str,
+    bound: Option<f64>,
+) -> Option<usize> {
+    let [sum, count, created] = present;
+    let after_bounds = |before: &[bool]| bounds.len() + before.iter().filter(|b| **b).count();
+    match (suffix, bound) {
+        ("_sum", None) if sum => Some(after_bounds(&[])),
+        ("_count", None) if count => Some(after_bounds(&[sum])),
+        ("_created", None) if created => Some(after_bounds(&[sum, count])),
+        (_, Some(bound)) => bounds.iter().position(|b| *b == bound),
+        _ => None,
+    }
+}
+
+impl TransformableMetricValue for SummaryValue {
+    fn visit_timestamps_mut(&mut self, f: &mut dyn FnMut(&mut Option<Timestamp>)) {
+        f(&mut self.created);
+    }
+
+    fn visit_numbers_mut(&mut self, f: &mut dyn FnMut(&mut MetricNumber)) {
+        if let Some(sum) = self.sum.as_mut() {
+            f(sum);
+        }
+
+        for quantile in self.quantiles.iter_mut() {
+            f(&mut quantile.value);
+        }
+    }
+}
+
+/// A run of consecutive buckets in a native histogram. `offset` is the index of the first bucket in the span,
+/// relative to the end of the previous span (or to 0 for the first span)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BucketSpan {
+    pub offset: i32,
+    pub length: u32,
+}
+
+/// A single bucket of a native histogram, covering (lower_bound, upper_bound]. Unlike classic histogram
+/// buckets, the counts aren't cumulative
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NativeHistogramBucket {
+    pub lower_bound: f64,
+    pub upper_bound: f64,
+    pub count: u64,
+}
+
+/// A Prometheus native (sparse) histogram. Rather than having explicit bounds, the buckets are exponential, with
+/// bucket `i` covering (base^(i-1), base^i], where base is 2^(2^-schema). Buckets that are in use are described
+/// by spans, with the counts stored as deltas from the previous bucket (in the order the spans list them).
+/// Observations in [-zero_threshold, zero_threshold] are counted in the zero bucket.
+/// Native histograms can only be read from the protobuf format, as the text formats can't carry them
+#[derive(Debug, Clone, PartialEq)]
+pub struct NativeHistogramValue {
+    pub schema: i32,
+    pub zero_threshold: f64,
+    pub zero_count: u64,
+    pub count: u64,
+    pub sum: MetricNumber,
+    pub created: Option<Timestamp>,
+    pub positive_spans: Vec<BucketSpan>,
+    pub positive_deltas: Vec<i64>,
+    pub negative_spans: Vec<BucketSpan>,
+    pub negative_deltas: Vec<i64>,
+}
+
+impl NativeHistogramValue {
+    /// Checks that the spans and deltas describe a valid set of buckets, i.e. that there's a delta for every
+    /// bucket in the spans, and that none of the buckets end up with a negative count
+    pub fn validate(&self) -> Result<(), ParseError> {
+        for (spans, deltas) in [
+            (&self.positive_spans, &self.positive_deltas),
+            (&self.negative_spans, &self.negative_deltas),
+        ]
+        .iter()
+        {
+            let length: u64 = spans.iter().map(|s| u64::from(s.length)).sum();
+            if length != deltas.len() as u64 {
+                return Err(ParseError::InvalidMetric(format!(
+                    "Native histogram spans cover {} buckets, but there are {} deltas",
+                    length,
+                    deltas.len()
+                )));
+            }
+
+            if spans.iter().skip(1).any(|s| s.offset < 0) {
+                return Err(ParseError::InvalidMetric(
+                    "Only the first span of a native histogram can have a negative offset".to_owned(),
+                ));
+            }
+
+            let mut count = 0i64;
+            for delta in deltas.iter() {
+                count += delta;
+                if count < 0 {
+                    return Err(ParseError::InvalidMetric(
+                        "Native histogram buckets can't have negative counts".to_owned(),
+                    ));
+                }
+            }
         }
 
         Ok(())
     }
+
+    /// The bucket that counts observations close enough to zero to not go in any other bucket
+    pub fn zero_bucket(&self) -> NativeHistogramBucket {
+        NativeHistogramBucket {
+            lower_bound: -self.zero_threshold,
+            upper_bound: self.zero_threshold,
+            count: self.zero_count,
+        }
+    }
+
+    /// The buckets that count positive observations, in ascending order. This assumes the histogram is valid
+    /// (see `validate`)
+    pub fn positive_buckets(&self) -> Vec<NativeHistogramBucket> {
+        self.expand_buckets(&self.positive_spans, &self.positive_deltas)
+            .map(|(lower_bound, upper_bound, count)| NativeHistogramBucket {
+                lower_bound,
+                upper_bound,
+                count,
+            })
+            .collect()
+    }
+
+    /// The buckets that count negative observations, in descending order (i.e. the bucket closest to zero
+    /// first). This assumes the histogram is valid (see `validate`)
+    pub fn negative_buckets(&self) -> Vec<NativeHistogramBucket> {
+        self.expand_buckets(&self.negative_spans, &self.negative_deltas)
+            .map(|(lower_bound, upper_bound, count)| NativeHistogramBucket {
+                lower_bound: -upper_bound,
+                upper_bound: -lower_bound,
+                count,
+            })
+            .collect()
+    }
+
+    fn expand_buckets<'a>(
+        &self,
+        spans: &'a [BucketSpan],
+        deltas: &'a [i64],
+    ) -> impl Iterator<Item = (f64, f64, u64)> + 'a {
+        let base = 2f64.powf(2f64.powi(-self.schema));
+        let indexes = spans.iter().scan(0i64, |next, span| {
+            let start = *next + i64::from(span.offset);
+            *next = start + i64::from(span.length);
+            Some(start..*next)
+        });
+
+        indexes
+            .flatten()
+            .zip(deltas.iter().scan(0i64, |count, delta| {
+                *count += delta;
+                Some(*count)
+            }))
+            .map(move |(index, count)| {
+                (
+                    base.powf((index - 1) as f64),
+                    base.powf(index as f64),
+                    count.max(0) as u64,
+                )
+            })
+    }
+}
+
+impl TransformableMetricValue for NativeHistogramValue {
+    fn visit_timestamps_mut(&mut self, f: &mut dyn FnMut(&mut Option<Timestamp>)) {
+        f(&mut self.created);
+    }
+
+    fn visit_numbers_mut(&mut self, f: &mut dyn FnMut(&mut MetricNumber)) {
+        f(&mut self.sum);
+    }
+}
+
+impl RenderableMetricValue for NativeHistogramValue {
+    /// The text formats can't carry the sparse buckets, so native histograms are rendered as a classic histogram
+    /// with only a +Inf bucket, which keeps the count and sum
+    fn render(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        metric_name: &str,
+        timestamp: Option<&Timestamp>,
+        label_names: &[&str],
+        label_values: &[&str],
+    ) -> fmt::Result {
+        HistogramValue {
+            sum: Some(self.sum),
+            count: Some(self.count),
+            created: self.created,
+            buckets: vec![HistogramBucket {
+                count: MetricNumber::Int(self.count as i64),
+                upper_bound: f64::INFINITY,
+                exemplar: None,
+            }],
+        }
+        .render(f, metric_name, timestamp, label_names, label_values)
+    }
+
+    fn num_series(&self) -> usize {
+        3 + self.created.iter().count()
+    }
+
+    /// Native histograms can't be parsed from the text formats, so don't have lines to find
+    fn line_index(&self, _suffix: &str, _bound: Option<f64>) -> Option<usize> {
+        None
+    }
 }
 
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
 pub enum OpenMetricsType {
     /// A Counter that only goes up
     /// Counters measure discrete events. Common examples are the number of HTTP requests received,
@@ -620,10 +2200,83 @@ pub enum OpenMetricsType {
 
     /// Unknown SHOULD NOT be used. Unknown MAY be used when it is impossible to determine the types of individual metrics from 3rd party systems.
     /// A point in a metric with the unknown type MUST have a single value.
+    #[default]
     Unknown,
 }
 
-#[derive(Debug, Clone)]
+impl fmt::Display for OpenMetricsType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let out = match self {
+            OpenMetricsType::Counter => "counter",
+            OpenMetricsType::Gauge => "gauge",
+            OpenMetricsType::Histogram => "histogram",
+            OpenMetricsType::GaugeHistogram => "gaugehistogram",
+            OpenMetricsType::StateSet => "stateset",
+            OpenMetricsType::Summary => "summary",
+            OpenMetricsType::Info => "info",
+            OpenMetricsType::Unknown => "unknown",
+        };
+
+        f.write_str(out)
+    }
+}
+
+impl OpenMetricsType {
+    /// Whether values of this type only go up (until they're reset), i.e. whether it's meaningful to take
+    /// their rate. Counters, Histograms, and Summaries are cumulative, but GaugeHistograms aren't
+    pub fn is_cumulative(&self) -> bool {
+        matches!(
+            self,
+            OpenMetricsType::Counter | OpenMetricsType::Histogram | OpenMetricsType::Summary
+        )
+    }
+}
+
+impl<ValueType> MetricFamily<OpenMetricsType, ValueType> {
+    pub fn is_counter(&self) -> bool {
+        self.family_type == OpenMetricsType::Counter
+    }
+
+    pub fn is_gauge(&self) -> bool {
+        self.family_type == OpenMetricsType::Gauge
+    }
+
+    pub fn is_histogram(&self) -> bool {
+        self.family_type == OpenMetricsType::Histogram
+    }
+
+    pub fn is_gauge_histogram(&self) -> bool {
+        self.family_type == OpenMetricsType::GaugeHistogram
+    }
+
+    pub fn is_state_set(&self) -> bool {
+        self.family_type == OpenMetricsType::StateSet
+    }
+
+    pub fn is_summary(&self) -> bool {
+        self.family_type == OpenMetricsType::Summary
+    }
+
+    pub fn is_info(&self) -> bool {
+        self.family_type == OpenMetricsType::Info
+    }
+
+    pub fn is_unknown(&self) -> bool {
+        self.family_type == OpenMetricsType::Unknown
+    }
+
+    /// Checks the family's descriptors against the rules that the parser enforces on them, i.e. that only
+    /// Counters, Gauges, and Unknowns have a unit. Useful for families that were built programmatically
+    pub fn validate(&self) -> Result<(), ParseError> {
+        if !self.unit.is_empty() && !self.family_type.can_have_units() {
+            return Err(units_not_allowed(&self.family_type));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum OpenMetricsValue {
     Unknown(MetricNumber),
     Gauge(MetricNumber),
@@ -633,6 +2286,33 @@ pub enum OpenMetricsValue {
     GaugeHistogram(HistogramValue),
     Info,
     Summary(SummaryValue),
+    NativeHistogram(NativeHistogramValue),
+}
+
+impl OpenMetricsValue {
+    /// Returns the scalar value of Gauges, Counters, Unknowns, StateSets and Infos (which are always 1),
+    /// or None for Histograms and Summaries which don't have a single value
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            OpenMetricsValue::Unknown(n)
+            | OpenMetricsValue::Gauge(n)
+            | OpenMetricsValue::StateSet(n) => Some(n.as_f64()),
+            OpenMetricsValue::Counter(c) => Some(c.value.as_f64()),
+            OpenMetricsValue::Info => Some(1.),
+            OpenMetricsValue::Histogram(_)
+            | OpenMetricsValue::GaugeHistogram(_)
+            | OpenMetricsValue::Summary(_)
+            | OpenMetricsValue::NativeHistogram(_) => None,
+        }
+    }
+
+    /// Returns the exemplar of a Counter. Histogram exemplars live on the individual buckets
+    pub fn exemplar(&self) -> Option<&Exemplar> {
+        match self {
+            OpenMetricsValue::Counter(c) => c.exemplar.as_ref(),
+            _ => None,
+        }
+    }
 }
 
 impl RenderableMetricValue for OpenMetricsValue {
@@ -651,9 +2331,8 @@ impl RenderableMetricValue for OpenMetricsValue {
             | OpenMetricsValue::StateSet(n) =>{
                 writeln!(
                     f,
-                    "{}{} {}{}",
-                    metric_name,
-                    render_label_values(label_names, label_values),
+                    "{} {}{}",
+                    render_series(metric_name, label_names, label_values),
                     n,
                     timestamp_str
                 )
@@ -661,45 +2340,161 @@ impl RenderableMetricValue for OpenMetricsValue {
             OpenMetricsValue::Counter(c) => {
                 write!(
                     f,
-                    "{}{} {}{}",
-                    metric_name,
-                    render_label_values(label_names, label_values),
+                    "{} {}{}",
+                    render_series(&format!("{}_total", metric_name), label_names, label_values),
                     c.value,
                     timestamp_str
                 )?;
                 if let Some(ex) = c.exemplar.as_ref() {
-                    write!(f, "{}", ex)?;
+                    write!(f, " {}", ex)?;
                 }
 
-                f.write_char('\n')
-            }
+                f.write_char('\n')?;
+
+                if let Some(created) = c.created {
+                    writeln!(
+                        f,
+                        "{} {}{}",
+                        render_series(&format!("{}_created", metric_name), label_names, label_values),
+                        format_float(created),
+                        timestamp_str
+                    )?;
+                }
+
+                Ok(())
+            }
+            OpenMetricsValue::Histogram(h) | OpenMetricsValue::GaugeHistogram(h) => {
+                // TODO: This is actually wrong for GaugeHistograms (they should have _gsum and _gcount), but I'm too lazy to fix this at the moment
+                h.render(f, metric_name, timestamp, label_names, label_values)
+            }
+            OpenMetricsValue::Summary(s) => {
+                s.render(f, metric_name, timestamp, label_names, label_values)
+            }
+            OpenMetricsValue::NativeHistogram(h) => {
+                h.render(f, metric_name, timestamp, label_names, label_values)
+            }
+            OpenMetricsValue::Info => {
+                writeln!(
+                    f,
+                    "{} {}{}",
+                    render_series(&format!("{}_info", metric_name), label_names, label_values),
+                    MetricNumber::Int(1),
+                    timestamp_str
+                )
+            }
+        }
+    }
+
+    fn num_series(&self) -> usize {
+        match self {
+            OpenMetricsValue::Counter(c) => 1 + c.created.iter().count(),
+            OpenMetricsValue::Histogram(h) | OpenMetricsValue::GaugeHistogram(h) => h.num_series(),
+            OpenMetricsValue::Summary(s) => s.num_series(),
+            OpenMetricsValue::NativeHistogram(h) => h.num_series(),
+            OpenMetricsValue::Unknown(_)
+            | OpenMetricsValue::Gauge(_)
+            | OpenMetricsValue::StateSet(_)
+            | OpenMetricsValue::Info => 1,
+        }
+    }
+
+    fn line_index(&self, suffix: &str, bound: Option<f64>) -> Option<usize> {
+        match self {
+            OpenMetricsValue::Counter(c) => match suffix {
+                "_total" => Some(0),
+                "_created" if c.created.is_some() => Some(1),
+                _ => None,
+            },
+            OpenMetricsValue::Histogram(h) => h.line_index(suffix, bound),
+            // Gauge histograms are rendered with `_sum` and `_count` lines, rather than `_gsum` and `_gcount`
+            OpenMetricsValue::GaugeHistogram(h) => {
+                let suffix = match suffix {
+                    "_gsum" => "_sum",
+                    "_gcount" => "_count",
+                    suffix => suffix,
+                };
+                h.line_index(suffix, bound)
+            }
+            OpenMetricsValue::Summary(s) => s.line_index(suffix, bound),
+            OpenMetricsValue::NativeHistogram(h) => h.line_index(suffix, bound),
+            OpenMetricsValue::Unknown(_)
+            | OpenMetricsValue::Gauge(_)
+            | OpenMetricsValue::StateSet(_)
+            | OpenMetricsValue::Info => Some(0),
+        }
+    }
+}
+
+impl TransformableMetricValue for OpenMetricsValue {
+    fn visit_timestamps_mut(&mut self, f: &mut dyn FnMut(&mut Option<Timestamp>)) {
+        match self {
+            OpenMetricsValue::Counter(c) => c.visit_timestamps_mut(f),
+            OpenMetricsValue::Histogram(h) | OpenMetricsValue::GaugeHistogram(h) => {
+                h.visit_timestamps_mut(f)
+            }
+            OpenMetricsValue::Summary(s) => s.visit_timestamps_mut(f),
+            OpenMetricsValue::NativeHistogram(h) => h.visit_timestamps_mut(f),
+            OpenMetricsValue::Unknown(_)
+            | OpenMetricsValue::Gauge(_)
+            | OpenMetricsValue::StateSet(_)
+            | OpenMetricsValue::Info => {}
+        }
+    }
+
+    fn visit_numbers_mut(&mut self, f: &mut dyn FnMut(&mut MetricNumber)) {
+        match self {
+            OpenMetricsValue::Unknown(n) | OpenMetricsValue::Gauge(n) => f(n),
+            OpenMetricsValue::Counter(c) => c.visit_numbers_mut(f),
+            OpenMetricsValue::Histogram(h) | OpenMetricsValue::GaugeHistogram(h) => {
+                h.visit_numbers_mut(f)
+            }
+            OpenMetricsValue::Summary(s) => s.visit_numbers_mut(f),
+            OpenMetricsValue::NativeHistogram(h) => h.visit_numbers_mut(f),
+            OpenMetricsValue::StateSet(_) | OpenMetricsValue::Info => {}
+        }
+    }
+
+    fn counter_value_mut(&mut self) -> Option<&mut MetricNumber> {
+        match self {
+            OpenMetricsValue::Counter(c) => c.counter_value_mut(),
+            _ => None,
+        }
+    }
+
+    fn scalar_value(&self) -> Option<MetricNumber> {
+        match self {
+            OpenMetricsValue::Unknown(n) | OpenMetricsValue::Gauge(n) => Some(*n),
+            OpenMetricsValue::Counter(c) => Some(c.value),
+            _ => None,
+        }
+    }
+
+    fn scalar_value_mut(&mut self) -> Option<&mut MetricNumber> {
+        match self {
+            OpenMetricsValue::Unknown(n) | OpenMetricsValue::Gauge(n) => Some(n),
+            OpenMetricsValue::Counter(c) => Some(&mut c.value),
+            _ => None,
+        }
+    }
+
+    fn set_exemplar(&mut self, exemplar: Option<Exemplar>) -> Result<(), ParseError> {
+        match self {
+            OpenMetricsValue::Counter(c) => c.set_exemplar(exemplar),
             OpenMetricsValue::Histogram(h) | OpenMetricsValue::GaugeHistogram(h) => {
-                // TODO: This is actually wrong for GaugeHistograms (they should have _gsum and _gcount), but I'm too lazy to fix this at the moment
-                h.render(f, metric_name, timestamp, label_names, label_values)
-            }
-            OpenMetricsValue::Summary(s) => {
-                s.render(f, metric_name, timestamp, label_names, label_values)
-            }
-            OpenMetricsValue::Info => {
-                writeln!(
-                    f,
-                    "{}{} {} {}",
-                    metric_name,
-                    render_label_values(label_names, label_values),
-                    MetricNumber::Int(1),
-                    timestamp_str
-                )
+                h.set_exemplar(exemplar)
             }
+            _ => Err(exemplars_not_allowed()),
         }
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Default)]
 pub enum PrometheusType {
     Counter,
     Gauge,
     Histogram,
     Summary,
+    #[default]
     Unknown,
 }
 
@@ -717,12 +2512,66 @@ impl fmt::Display for PrometheusType {
     }
 }
 
+impl PrometheusType {
+    /// Whether values of this type only go up (until they're reset), i.e. whether it's meaningful to take
+    /// their rate. Counters, Histograms, and Summaries are cumulative
+    pub fn is_cumulative(&self) -> bool {
+        matches!(
+            self,
+            PrometheusType::Counter | PrometheusType::Histogram | PrometheusType::Summary
+        )
+    }
+}
+
+impl<ValueType> MetricFamily<PrometheusType, ValueType> {
+    pub fn is_counter(&self) -> bool {
+        self.family_type == PrometheusType::Counter
+    }
+
+    pub fn is_gauge(&self) -> bool {
+        self.family_type == PrometheusType::Gauge
+    }
+
+    pub fn is_histogram(&self) -> bool {
+        self.family_type == PrometheusType::Histogram
+    }
+
+    pub fn is_summary(&self) -> bool {
+        self.family_type == PrometheusType::Summary
+    }
+
+    pub fn is_unknown(&self) -> bool {
+        self.family_type == PrometheusType::Unknown
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct PrometheusCounterValue {
     pub value: MetricNumber,
     pub exemplar: Option<Exemplar>,
 }
 
+impl TransformableMetricValue for PrometheusCounterValue {
+    fn visit_timestamps_mut(&mut self, f: &mut dyn FnMut(&mut Option<Timestamp>)) {
+        if let Some(exemplar) = self.exemplar.as_mut() {
+            f(&mut exemplar.timestamp);
+        }
+    }
+
+    fn visit_numbers_mut(&mut self, f: &mut dyn FnMut(&mut MetricNumber)) {
+        f(&mut self.value);
+    }
+
+    fn counter_value_mut(&mut self) -> Option<&mut MetricNumber> {
+        Some(&mut self.value)
+    }
+
+    fn set_exemplar(&mut self, exemplar: Option<Exemplar>) -> Result<(), ParseError> {
+        self.exemplar = exemplar;
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum PrometheusValue {
     Unknown(MetricNumber),
@@ -732,6 +2581,26 @@ pub enum PrometheusValue {
     Summary(SummaryValue),
 }
 
+impl PrometheusValue {
+    /// Returns the scalar value of Gauges, Counters, and Unknowns, or None for Histograms and Summaries
+    /// which don't have a single value
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            PrometheusValue::Unknown(n) | PrometheusValue::Gauge(n) => Some(n.as_f64()),
+            PrometheusValue::Counter(c) => Some(c.value.as_f64()),
+            PrometheusValue::Histogram(_) | PrometheusValue::Summary(_) => None,
+        }
+    }
+
+    /// Returns the exemplar of a Counter. Histogram exemplars live on the individual buckets
+    pub fn exemplar(&self) -> Option<&Exemplar> {
+        match self {
+            PrometheusValue::Counter(c) => c.exemplar.as_ref(),
+            _ => None,
+        }
+    }
+}
+
 impl RenderableMetricValue for PrometheusValue {
     fn render(
         &self,
@@ -745,23 +2614,21 @@ impl RenderableMetricValue for PrometheusValue {
         match self {
             PrometheusValue::Unknown(n) | PrometheusValue::Gauge(n) => writeln!(
                 f,
-                "{}{} {}{}",
-                metric_name,
-                render_label_values(label_names, label_values),
+                "{} {}{}",
+                render_series(metric_name, label_names, label_values),
                 n,
                 timestamp_str
             ),
             PrometheusValue::Counter(c) => {
                 write!(
                     f,
-                    "{}{} {}{}",
-                    metric_name,
-                    render_label_values(label_names, label_values),
+                    "{} {}{}",
+                    render_series(metric_name, label_names, label_values),
                     c.value,
                     timestamp_str
                 )?;
                 if let Some(ex) = c.exemplar.as_ref() {
-                    write!(f, "{}", ex)?;
+                    write!(f, " {}", ex)?;
                 }
 
                 f.write_char('\n')
@@ -774,14 +2641,88 @@ impl RenderableMetricValue for PrometheusValue {
             }
         }
     }
+
+    fn num_series(&self) -> usize {
+        match self {
+            PrometheusValue::Histogram(h) => h.num_series(),
+            PrometheusValue::Summary(s) => s.num_series(),
+            PrometheusValue::Unknown(_)
+            | PrometheusValue::Gauge(_)
+            | PrometheusValue::Counter(_) => 1,
+        }
+    }
+
+    fn line_index(&self, suffix: &str, bound: Option<f64>) -> Option<usize> {
+        match self {
+            PrometheusValue::Histogram(h) => h.line_index(suffix, bound),
+            PrometheusValue::Summary(s) => s.line_index(suffix, bound),
+            PrometheusValue::Unknown(_)
+            | PrometheusValue::Gauge(_)
+            | PrometheusValue::Counter(_) => Some(0),
+        }
+    }
+}
+
+impl TransformableMetricValue for PrometheusValue {
+    fn visit_timestamps_mut(&mut self, f: &mut dyn FnMut(&mut Option<Timestamp>)) {
+        match self {
+            PrometheusValue::Counter(c) => c.visit_timestamps_mut(f),
+            PrometheusValue::Histogram(h) => h.visit_timestamps_mut(f),
+            PrometheusValue::Summary(s) => s.visit_timestamps_mut(f),
+            PrometheusValue::Unknown(_) | PrometheusValue::Gauge(_) => {}
+        }
+    }
+
+    fn visit_numbers_mut(&mut self, f: &mut dyn FnMut(&mut MetricNumber)) {
+        match self {
+            PrometheusValue::Unknown(n) | PrometheusValue::Gauge(n) => f(n),
+            PrometheusValue::Counter(c) => c.visit_numbers_mut(f),
+            PrometheusValue::Histogram(h) => h.visit_numbers_mut(f),
+            PrometheusValue::Summary(s) => s.visit_numbers_mut(f),
+        }
+    }
+
+    fn counter_value_mut(&mut self) -> Option<&mut MetricNumber> {
+        match self {
+            PrometheusValue::Counter(c) => c.counter_value_mut(),
+            _ => None,
+        }
+    }
+
+    fn scalar_value(&self) -> Option<MetricNumber> {
+        match self {
+            PrometheusValue::Unknown(n) | PrometheusValue::Gauge(n) => Some(*n),
+            PrometheusValue::Counter(c) => Some(c.value),
+            _ => None,
+        }
+    }
+
+    fn scalar_value_mut(&mut self) -> Option<&mut MetricNumber> {
+        match self {
+            PrometheusValue::Unknown(n) | PrometheusValue::Gauge(n) => Some(n),
+            PrometheusValue::Counter(c) => Some(&mut c.value),
+            _ => None,
+        }
+    }
+
+    fn set_exemplar(&mut self, exemplar: Option<Exemplar>) -> Result<(), ParseError> {
+        match self {
+            PrometheusValue::Counter(c) => c.set_exemplar(exemplar),
+            PrometheusValue::Histogram(h) => h.set_exemplar(exemplar),
+            _ => Err(exemplars_not_allowed()),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Sample<ValueType> {
-    label_names: Option<Arc<Vec<String>>>,
-    label_values: Vec<String>,
+    label_names: Option<Arc<Vec<SharedString>>>,
+    label_values: LabelValues,
     pub timestamp: Option<Timestamp>,
     pub value: ValueType,
+
+    /// The timestamp as it was parsed. Only used while it still matches `timestamp`, which can be set directly
+    raw_timestamp: Option<RawTimestamp>,
 }
 
 impl<ValueType> Sample<ValueType>
@@ -789,24 +2730,75 @@ where
     ValueType: RenderableMetricValue + Clone,
 {
     pub fn new(label_values: Vec<String>, timestamp: Option<Timestamp>, value: ValueType) -> Self {
+        Self::with_label_values(
+            label_values.into_iter().map(SharedString::from).collect(),
+            timestamp,
+            value,
+        )
+    }
+
+    pub(crate) fn with_label_values(
+        label_values: LabelValues,
+        timestamp: Option<Timestamp>,
+        value: ValueType,
+    ) -> Self {
         Self {
             label_values,
             timestamp,
             value,
             label_names: None,
+            raw_timestamp: None,
+        }
+    }
+
+    pub(crate) fn with_raw_timestamp(
+        label_values: LabelValues,
+        raw_timestamp: Option<RawTimestamp>,
+        value: ValueType,
+    ) -> Self {
+        Self {
+            raw_timestamp,
+            ..Self::with_label_values(label_values, raw_timestamp.map(|t| t.as_timestamp()), value)
+        }
+    }
+
+    /// The parsed timestamp, if `timestamp` hasn't been changed since
+    fn parsed_timestamp(&self) -> Option<RawTimestamp> {
+        self.raw_timestamp
+            .filter(|raw| Some(raw.as_timestamp()) == self.timestamp)
+    }
+
+    /// The values of this sample's labels, in the same order as the family's label names
+    pub fn label_values(&self) -> &[SharedString] {
+        &self.label_values
+    }
+
+    /// Sets the sample's timestamp, rejecting timestamps that aren't finite, as they can't be rendered in a
+    /// way that parses again
+    pub fn set_timestamp(&mut self, timestamp: Option<Timestamp>) -> Result<(), ParseError> {
+        if let Some(t) = timestamp.filter(|t| !t.is_finite()) {
+            return Err(ParseError::InvalidMetric(format!(
+                "Sample timestamp must be finite (got: {})",
+                t
+            )));
         }
+
+        self.timestamp = timestamp;
+        self.raw_timestamp = None;
+        Ok(())
     }
 
     fn clone_with_new_value<T>(&self, value: T) -> Sample<T> where T: RenderableMetricValue + Clone {
-        return Sample {
+        Sample {
             label_names: self.label_names.clone(),
             label_values: self.label_values.clone(),
-            timestamp: self.timestamp.clone(),
+            timestamp: self.timestamp,
             value,
+            raw_timestamp: self.raw_timestamp,
         }
     }
 
-    fn set_label_names(&mut self, label_names: Arc<Vec<String>>) {
+    fn set_label_names(&mut self, label_names: Arc<Vec<SharedString>>) {
         self.label_names = Some(label_names);
     }
 
@@ -816,16 +2808,20 @@ where
                 let mut label_values = self.label_values.clone();
                 label_values.remove(idx);
 
-                return Ok(Self::new(label_values, self.timestamp.clone(), self.value.clone()));
+                return Ok(Self::with_label_values(
+                    label_values,
+                    self.timestamp,
+                    self.value.clone(),
+                ));
             }
 
             return Err(ParseError::InvalidMetric(format!("Label {} doesn't existin in metric", label_name)));
         }
 
-        return Err(ParseError::InvalidMetric(format!("Metric isn't bound to a family, so doesn't have names")));
+        Err(ParseError::InvalidMetric("Metric isn't bound to a family, so doesn't have names".to_string()))
     }
 
-    pub fn get_labelset(&self) -> Result<LabelSet, ParseError> {
+    pub fn get_labelset(&self) -> Result<LabelSet<'_>, ParseError> {
         if let Some(label_names) = &self.label_names {
             return LabelSet::new(label_names.clone(), self);
         }
@@ -853,12 +2849,106 @@ where
     }
 }
 
+impl<ValueType> Sample<ValueType>
+where
+    ValueType: TransformableMetricValue,
+{
+    /// Attaches an exemplar to the sample, e.g. to link it to a trace. A Counter's exemplar is replaced, and a
+    /// Histogram's exemplar is attached to the bucket that the exemplar's value falls in. Passing None removes all
+    /// of the sample's exemplars. Other types can't have exemplars, and return an error
+    pub fn set_exemplar(&mut self, exemplar: Option<Exemplar>) -> Result<(), ParseError> {
+        self.value.set_exemplar(exemplar)
+    }
+}
+
+impl<ValueType> Sample<ValueType>
+where
+    ValueType: TransformableMetricValue + PartialEq + Clone,
+{
+    /// Returns whether this sample has the same label values and value as `other`, ignoring their timestamps.
+    /// Exemplars are part of the value, so are compared - see `value_eq_ignoring_exemplars` to ignore them too
+    pub fn value_eq(&self, other: &Sample<ValueType>) -> bool {
+        self.label_values == other.label_values && self.value == other.value
+    }
+
+    /// Like `value_eq`, but also ignores the samples' exemplars, e.g. to check whether a scrape actually changed
+    pub fn value_eq_ignoring_exemplars(&self, other: &Sample<ValueType>) -> bool {
+        let without_exemplars = |value: &ValueType| {
+            let mut value = value.clone();
+            // Values that can't have exemplars error here, but then there's nothing to remove anyway
+            let _ = value.set_exemplar(None);
+            value
+        };
+
+        self.label_values == other.label_values
+            && without_exemplars(&self.value) == without_exemplars(&other.value)
+    }
+}
+
+impl Sample<PrometheusValue> {
+    /// The sample's timestamp as it was written, in milliseconds. Whole numbers are parsed as integers, so they're
+    /// exact even past the point where floats can't represent every millisecond
+    pub fn raw_timestamp(&self) -> Option<RawTimestamp> {
+        self.parsed_timestamp()
+            .or_else(|| self.timestamp.map(RawTimestamp::from_millis))
+    }
+
+    /// The sample's timestamp in milliseconds since the epoch. Prometheus timestamps are written in whole
+    /// milliseconds, so this is the timestamp as written. Fractional milliseconds (which Prometheus itself
+    /// doesn't accept) return None, rather than being truncated
+    pub fn timestamp_as_millis_i64(&self) -> Option<i64> {
+        self.raw_timestamp()?.as_millis_i64()
+    }
+
+    /// The sample's timestamp in seconds since the epoch, converted from the milliseconds it's written in
+    pub fn timestamp_as_seconds_f64(&self) -> Option<f64> {
+        self.raw_timestamp().map(|t| t.as_seconds_f64())
+    }
+}
+
+impl Sample<OpenMetricsValue> {
+    /// The sample's timestamp as it was written, in seconds
+    pub fn raw_timestamp(&self) -> Option<RawTimestamp> {
+        self.parsed_timestamp()
+            .or_else(|| self.timestamp.map(RawTimestamp::Seconds))
+    }
+
+    /// The sample's timestamp in milliseconds since the epoch, converted from the seconds that OpenMetrics
+    /// timestamps are written in, and rounded to the nearest millisecond
+    pub fn timestamp_as_millis_i64(&self) -> Option<i64> {
+        self.raw_timestamp()?.as_millis_i64()
+    }
+
+    /// The sample's timestamp in seconds since the epoch. OpenMetrics timestamps are written in seconds, so
+    /// this is the timestamp as written
+    pub fn timestamp_as_seconds_f64(&self) -> Option<f64> {
+        self.raw_timestamp().map(|t| t.as_seconds_f64())
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum MetricNumber {
     Float(f64),
     Int(i64),
 }
 
+/// Parses a sample value. Values that look like integers are parsed as `Int`s, unless they're too big to
+/// fit in an i64, in which case they fall back to `Float`s (losing precision) rather than being rejected
+impl FromStr for MetricNumber {
+    type Err = ParseError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if let Ok(i) = value.parse() {
+            return Ok(MetricNumber::Int(i));
+        }
+
+        // Anything that isn't an i64 - including integers that overflow one - is parsed as a float
+        value.parse().map(MetricNumber::Float).map_err(|_| {
+            ParseError::InvalidMetric(format!("Metric Value must be a number (got: {})", value))
+        })
+    }
+}
+
 impl fmt::Display for MetricNumber {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -883,8 +2973,84 @@ impl MetricNumber {
             _ => None,
         }
     }
+
+    /// Returns the number as a u64, or None if it's negative, has a fractional part, or is too large to fit
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            MetricNumber::Int(i) => u64::try_from(*i).ok(),
+            // u64::MAX rounds up to 2^64 as a float, which is itself out of range
+            MetricNumber::Float(f) if f.fract() == 0. && *f >= 0. && *f < u64::MAX as f64 => {
+                Some(*f as u64)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn abs(&self) -> MetricNumber {
+        match self {
+            MetricNumber::Int(i) => i
+                .checked_abs()
+                .map(MetricNumber::Int)
+                .unwrap_or_else(|| MetricNumber::Float((*i as f64).abs())),
+            MetricNumber::Float(f) => MetricNumber::Float(f.abs()),
+        }
+    }
+
+    /// Sums the given numbers, staying an Int as long as all the numbers are Ints
+    pub fn sum<I>(values: I) -> MetricNumber
+    where
+        I: IntoIterator<Item = MetricNumber>,
+    {
+        values.into_iter().sum()
+    }
+}
+
+impl From<i64> for MetricNumber {
+    fn from(i: i64) -> Self {
+        MetricNumber::Int(i)
+    }
+}
+
+impl From<i32> for MetricNumber {
+    fn from(i: i32) -> Self {
+        MetricNumber::Int(i.into())
+    }
 }
 
+impl From<u32> for MetricNumber {
+    fn from(i: u32) -> Self {
+        MetricNumber::Int(i.into())
+    }
+}
+
+impl From<f64> for MetricNumber {
+    fn from(f: f64) -> Self {
+        MetricNumber::Float(f)
+    }
+}
+
+impl From<f32> for MetricNumber {
+    fn from(f: f32) -> Self {
+        MetricNumber::Float(f.into())
+    }
+}
+
+impl std::iter::Sum for MetricNumber {
+    fn sum<I: Iterator<Item = MetricNumber>>(iter: I) -> Self {
+        iter.fold(MetricNumber::Int(0), |a, b| a + b)
+    }
+}
+
+impl_op_ex!(-|a: &MetricNumber| -> MetricNumber {
+    match a {
+        MetricNumber::Float(f) => MetricNumber::Float(-f),
+        MetricNumber::Int(i) => i
+            .checked_neg()
+            .map(MetricNumber::Int)
+            .unwrap_or_else(|| MetricNumber::Float(-(*i as f64))),
+    }
+});
+
 impl_op_ex!(+ |a: &MetricNumber, b: &MetricNumber| -> MetricNumber {
     match (a, b) {
         (MetricNumber::Float(f), MetricNumber::Float(f2)) => MetricNumber::Float(f + f2),
@@ -939,12 +3105,20 @@ impl_op_ex!(*= |a: &mut MetricNumber, b: &MetricNumber| {
     }
 });
 
+/// Divides two integers. Rather than panicking, division by zero (and the overflowing `i64::MIN / -1`)
+/// falls back to floating point division, so `x / 0` is `+Inf`, `-Inf`, or `NaN` (for `0 / 0`)
+fn int_div(a: i64, b: i64) -> MetricNumber {
+    a.checked_div(b)
+        .map(MetricNumber::Int)
+        .unwrap_or_else(|| MetricNumber::Float(a as f64 / b as f64))
+}
+
 impl_op_ex!(/ |a: &MetricNumber, b: &MetricNumber| -> MetricNumber {
     match (a, b) {
         (MetricNumber::Float(f), MetricNumber::Float(f2)) => MetricNumber::Float(f / f2),
         (MetricNumber::Float(f), MetricNumber::Int(i)) => MetricNumber::Float(f / *i as f64),
         (MetricNumber::Int(i), MetricNumber::Float(f)) => MetricNumber::Float(*i as f64 / f),
-        (MetricNumber::Int(i), MetricNumber::Int(i2)) => MetricNumber::Int(i / i2),
+        (MetricNumber::Int(i), MetricNumber::Int(i2)) => int_div(*i, *i2),
     }
 });
 
@@ -953,15 +3127,34 @@ impl_op_ex!(/= |a: &mut MetricNumber, b: &MetricNumber| {
         (MetricNumber::Float(f), MetricNumber::Float(f2)) => *a = MetricNumber::Float(*f / f2),
         (MetricNumber::Float(f), MetricNumber::Int(i)) => *a = MetricNumber::Float(*f / *i as f64),
         (MetricNumber::Int(i), MetricNumber::Float(f)) => *a = MetricNumber::Float(*i as f64 / f),
-        (MetricNumber::Int(i), MetricNumber::Int(i2)) => *a = MetricNumber::Int(*i / i2),
+        (MetricNumber::Int(i), MetricNumber::Int(i2)) => *a = int_div(*i, *i2),
     }
 });
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum ParseError {
     ParseError(String),
     DuplicateMetric,
     InvalidMetric(String),
+
+    /// An exposition didn't match the grammar of its format. `line` and `col` are where it stopped matching,
+    /// counting from 1, and `message` is pest's rendering of the error, including a snippet of the line
+    Syntax {
+        message: String,
+        line: usize,
+        col: usize,
+    },
+
+    /// An OpenMetrics exposition had text after its `# EOF` line. `offset` is the byte offset
+    /// where that text starts, so the exposition up to it can be reparsed if the trailing text can be ignored
+    TextAfterEOF { offset: usize },
+
+    /// Reading an exposition failed, in one of the reader entry points
+    Io(std::io::Error),
+
+    /// An exposition passed to one of the byte entry points wasn't valid UTF-8
+    Utf8(std::str::Utf8Error),
 }
 
 impl fmt::Display for ParseError {
@@ -970,18 +3163,57 @@ impl fmt::Display for ParseError {
             ParseError::ParseError(e) => e.fmt(f),
             ParseError::DuplicateMetric => f.write_str("Found two metrics with the same labelset"),
             ParseError::InvalidMetric(s) => f.write_str(s),
+            ParseError::Syntax { message, .. } => f.write_str(message),
+            ParseError::TextAfterEOF { offset } => {
+                write!(f, "Found text after the EOF token (at byte {})", offset)
+            }
+            ParseError::Io(e) => write!(f, "Failed to read exposition: {}", e),
+            ParseError::Utf8(e) => write!(f, "Exposition isn't valid UTF-8: {}", e),
+        }
+    }
+}
+
+impl ParseError {
+    /// The (line, column) that a syntax error happened at, counting from 1. Other errors don't have a position
+    pub fn line_col(&self) -> Option<(usize, usize)> {
+        match self {
+            ParseError::Syntax { line, col, .. } => Some((*line, *col)),
+            _ => None,
+        }
+    }
+}
+
+impl std::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ParseError::Io(e) => Some(e),
+            ParseError::Utf8(e) => Some(e),
+            _ => None,
         }
     }
 }
 
+impl From<std::io::Error> for ParseError {
+    fn from(err: std::io::Error) -> Self {
+        ParseError::Io(err)
+    }
+}
+
+impl From<std::str::Utf8Error> for ParseError {
+    fn from(err: std::str::Utf8Error) -> Self {
+        ParseError::Utf8(err)
+    }
+}
+
+#[derive(Debug)]
 pub struct LabelSet<'a> {
-    label_names: Arc<Vec<String>>,
-    label_values: &'a [String],
+    label_names: Arc<Vec<SharedString>>,
+    label_values: &'a [SharedString],
 }
 
 impl<'a> LabelSet<'a> {
     pub fn new<ValueType>(
-        label_names: Arc<Vec<String>>,
+        label_names: Arc<Vec<SharedString>>,
         sample: &'a Sample<ValueType>,
     ) -> Result<Self, ParseError> {
         if label_names.len() != sample.label_values.len() {
@@ -1002,27 +3234,120 @@ impl<'a> LabelSet<'a> {
         self.matches_values(&sample.label_values)
     }
 
-    pub fn matches_values(&self, label_values: &[String]) -> bool {
-        self.label_values == label_values
+    pub fn matches_values<S: AsRef<str>>(&self, label_values: &[S]) -> bool {
+        label_values_eq(self.label_values, label_values)
+    }
+
+    /// Returns whether every label in this set is present, with the same value, in the given sample.
+    /// The sample may have extra labels that aren't in this set
+    pub fn is_subset_of<ValueType>(&self, sample: &Sample<ValueType>) -> bool {
+        let sample_names = match &sample.label_names {
+            Some(names) => names,
+            None => return self.label_names.is_empty(),
+        };
+
+        self.iter().all(|(name, value)| {
+            sample_names
+                .iter()
+                .position(|n| n == name)
+                .map(|i| &sample.label_values[i] == value)
+                .unwrap_or(false)
+        })
+    }
+
+    /// Returns whether every (name, value) pair in the selector is present in this set.
+    /// This set may have extra labels that aren't in the selector
+    pub fn matches_subset(&self, selector: &[(&str, &str)]) -> bool {
+        selector
+            .iter()
+            .all(|(name, value)| self.get_label_value(name) == Some(*value))
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
-        return self.label_names.iter().zip(self.label_values);
+    pub fn iter(&self) -> impl Iterator<Item = (&SharedString, &SharedString)> {
+        self.label_names.iter().zip(self.label_values)
     }
 
-    pub fn iter_names(&self) -> impl Iterator<Item = &String> {
+    pub fn iter_names(&self) -> impl Iterator<Item = &SharedString> {
         self.label_names.iter()
     }
 
-    pub fn iter_values(&self) -> impl Iterator<Item = &String> {
+    pub fn iter_values(&self) -> impl Iterator<Item = &SharedString> {
         self.label_values.iter()
     }
 
     pub fn get_label_value(&self, label_name: &str) -> Option<&str> {
-        return self
+        self
             .label_names
             .iter()
             .position(|s| s == label_name)
-            .map(|i| self.label_values[i].as_str());
+            .map(|i| self.label_values[i].as_str())
+    }
+
+    /// Returns an owned map of label names to label values
+    pub fn to_map(&self) -> HashMap<String, String> {
+        self.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    /// Returns an owned list of (name, value) pairs, sorted by label name
+    pub fn to_sorted_vec(&self) -> Vec<(String, String)> {
+        let mut labels: Vec<(String, String)> = self
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        labels.sort();
+        labels
+    }
+
+    /// Returns a copy of this label set that doesn't borrow from the sample, e.g. to use as a map key
+    pub fn to_owned_set(&self) -> OwnedLabelSet {
+        OwnedLabelSet {
+            labels: self.to_sorted_vec(),
+        }
+    }
+}
+
+/// Renders the label set as it would appear in a sample, e.g. `{a="1",b="2"}`, with the labels sorted by name
+impl fmt::Display for LabelSet<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.to_owned_set().fmt(f)
+    }
+}
+
+/// A label set that owns its names and values. The labels are kept sorted by name, so two sets with the same
+/// labels are equal (and hash the same) regardless of the order of the label names in their families
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct OwnedLabelSet {
+    labels: Vec<(String, String)>,
+}
+
+impl OwnedLabelSet {
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.labels
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub fn get_label_value(&self, label_name: &str) -> Option<&str> {
+        self.labels
+            .iter()
+            .find(|(name, _)| name == label_name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+}
+
+impl fmt::Display for OwnedLabelSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (names, values): (Vec<&str>, Vec<&str>) = self.iter().unzip();
+        f.write_str(&render_label_values(&names, &values))
     }
 }
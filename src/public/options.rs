@@ -0,0 +1,60 @@
+/// Options that control how lenient the parsers are with expositions that don't strictly follow the spec
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+    /// If set, the parsers run extra validation on top of what's required to build the model, rejecting
+    /// expositions that some buggy exporters produce (e.g. a histogram whose +Inf bucket doesn't match its _count).
+    /// This also requires summaries that have one of a _sum or a _count to have both - by default, either can
+    /// appear on its own
+    pub strict: bool,
+
+    /// If set, a metric family that appears again after it has been finalised is merged into the
+    /// earlier family, rather than causing an error. Merged samples must still have unique labelsets
+    pub merge_families: bool,
+
+    /// If set, repeated label names and values share a single copy across the exposition, as do the label name
+    /// lists of families with identical labels. This trades a little parse time for memory on large expositions
+    pub intern: bool,
+
+    /// If set, counters are allowed to have negative or NaN totals. Some exporters misuse counters for
+    /// values that can go down, and this lets those values through as-is rather than rejecting the exposition
+    pub allow_negative_counters: bool,
+
+    /// If set, NaN is rejected as the value of every sample.
+    /// Otherwise, NaN is allowed everywhere except for counts: counter totals (unless `allow_negative_counters` is
+    /// set), histogram bucket counts, and `_count` samples. Gauges, unknowns, summary quantiles, and histogram and
+    /// summary sums can all be NaN, as Prometheus client libraries emit NaN sums when an observation was NaN
+    pub reject_nan: bool,
+
+    /// If set, a HELP or TYPE line that comes after some of its family's samples is applied to that family, rather
+    /// than causing an error. The descriptor still can't contradict an earlier one (e.g. a second TYPE line), and this
+    /// only applies to the Prometheus format. Ignored if `strict` is set
+    pub late_descriptors: bool,
+
+    /// If set, expositions larger than this many bytes are rejected. The reader entry points stop reading once the
+    /// limit is passed, so a misbehaving target can't make them buffer an unbounded amount of data
+    pub max_size: Option<usize>,
+
+    /// If set, families remember the order that their sample lines were in (e.g. a histogram's `_count` before its
+    /// buckets), and render them in that order rather than grouped by series. The order is forgotten if samples are
+    /// added to or removed from the family
+    pub preserve_line_order: bool,
+
+    /// If set, a sample line that's byte-for-byte identical to an earlier one in the same family (i.e. with the same
+    /// labels, value, and timestamp) is ignored, rather than causing a `DuplicateMetric` error. Duplicates that
+    /// conflict with each other are still errors
+    pub dedupe_identical: bool,
+
+    /// If set, label values longer than this many bytes are rejected, or truncated if `truncate_label_values` is
+    /// also set. This doesn't apply to the `le` and `quantile` labels of histograms and summaries, whose values are
+    /// parsed as numbers rather than stored
+    pub max_label_value_len: Option<usize>,
+
+    /// If set, label values longer than `max_label_value_len` are cut down to fit, rather than causing an error.
+    /// Values are only cut between characters, so a truncated value can be a few bytes shorter than the limit
+    pub truncate_label_values: bool,
+
+    /// If set, Prometheus sample timestamps that aren't a whole number of milliseconds (e.g. `1520879607789.123`,
+    /// which some exporters write) are accepted and kept as `RawTimestamp::FractionalMillis`, rather than causing an
+    /// error. Timestamps still have to be finite
+    pub fractional_timestamps: bool,
+}
@@ -0,0 +1,121 @@
+use std::fmt::Debug;
+
+use crate::{
+    openmetrics::parse_openmetrics, prometheus::parse_prometheus, MetricFamily, MetricsExposition,
+    OpenMetricsType, OpenMetricsValue, ParseError, PrometheusType, PrometheusValue,
+    RenderableMetricValue,
+};
+
+fn diverged(family_name: &str, reason: String) -> ParseError {
+    ParseError::InvalidMetric(format!(
+        "Family {} doesn't round trip: {}",
+        family_name, reason
+    ))
+}
+
+/// Compares a family with the result of rendering and reparsing it, describing the first difference
+fn compare_reparsed<TypeSet, ValueType>(
+    original: &MetricFamily<TypeSet, ValueType>,
+    reparsed: Result<MetricsExposition<TypeSet, ValueType>, ParseError>,
+) -> Result<(), ParseError>
+where
+    TypeSet: Debug + PartialEq + Clone,
+    ValueType: Debug + PartialEq + RenderableMetricValue + Clone,
+{
+    let name = &original.family_name;
+    let mut reparsed = reparsed
+        .map_err(|e| diverged(name, format!("the rendered family doesn't parse ({})", e)))?;
+
+    let reparsed = match reparsed.families.remove(name) {
+        Some(family) if reparsed.families.is_empty() => family,
+        _ => {
+            return Err(diverged(
+                name,
+                String::from("the rendered family reparsed into different families"),
+            ))
+        }
+    };
+
+    if original == &reparsed {
+        return Ok(());
+    }
+
+    if original.family_type != reparsed.family_type {
+        return Err(diverged(
+            name,
+            format!(
+                "type {:?} became {:?}",
+                original.family_type, reparsed.family_type
+            ),
+        ));
+    }
+
+    if original.help != reparsed.help || original.unit != reparsed.unit {
+        return Err(diverged(name, String::from("the HELP or UNIT changed")));
+    }
+
+    if original.get_label_names() != reparsed.get_label_names() {
+        return Err(diverged(
+            name,
+            format!(
+                "label names {:?} became {:?}",
+                original.get_label_names(),
+                reparsed.get_label_names()
+            ),
+        ));
+    }
+
+    for sample in original.iter_samples() {
+        let labels = sample.label_values();
+        match reparsed.get_sample_by_label_values(labels) {
+            Some(other) if other.timestamp != sample.timestamp => {
+                return Err(diverged(
+                    name,
+                    format!(
+                        "sample {:?} had timestamp {:?}, but reparsed with {:?}",
+                        labels, sample.timestamp, other.timestamp
+                    ),
+                ))
+            }
+            Some(other) if other.value != sample.value => {
+                return Err(diverged(
+                    name,
+                    format!(
+                        "sample {:?} had value {:?}, but reparsed as {:?}",
+                        labels, sample.value, other.value
+                    ),
+                ))
+            }
+            Some(_) => {}
+            None => {
+                return Err(diverged(
+                    name,
+                    format!("sample {:?} is missing after reparsing", labels),
+                ))
+            }
+        }
+    }
+
+    Err(diverged(
+        name,
+        String::from("the reparsed family has different samples"),
+    ))
+}
+
+impl MetricFamily<PrometheusType, PrometheusValue> {
+    /// Renders the family in the Prometheus text format and parses it again, returning an error that describes
+    /// the first difference if the result isn't the same as this family. Some values don't survive rendering
+    /// (e.g. a Float with an integral value comes back as an Int), so this can be used to check whether a family
+    /// can be safely proxied
+    pub fn assert_roundtrips(&self) -> Result<(), ParseError> {
+        compare_reparsed(self, parse_prometheus(&self.to_string()))
+    }
+}
+
+impl MetricFamily<OpenMetricsType, OpenMetricsValue> {
+    /// Renders the family in the OpenMetrics text format and parses it again, returning an error that describes
+    /// the first difference if the result isn't the same as this family. See the Prometheus version for details
+    pub fn assert_roundtrips(&self) -> Result<(), ParseError> {
+        compare_reparsed(self, parse_openmetrics(&format!("{}# EOF\n", self)))
+    }
+}
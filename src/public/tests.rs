@@ -1,4 +1,4 @@
-use crate::prometheus::parse_prometheus;
+use crate::{openmetrics::parse_openmetrics, prometheus::parse_prometheus};
 
 #[test]
 fn test_label_sets() {
@@ -78,6 +78,215 @@ fn test_render() {
     assert!(parse_prometheus(&exposition_str).is_ok());
 }
 
+#[test]
+fn test_help_escaping() {
+    let test_str = "# HELP escaped_metric A help with a \\\\ backslash\\nand a second line\n# TYPE escaped_metric gauge\nescaped_metric 1\n";
+    let exposition = parse_prometheus(test_str).unwrap();
+    let help = &exposition.families["escaped_metric"].help;
+    assert_eq!(help, "A help with a \\ backslash\nand a second line");
+
+    let exposition_str = exposition.to_string();
+    assert!(exposition_str
+        .contains("# HELP escaped_metric A help with a \\\\ backslash\\nand a second line\n"));
+
+    let exposition = parse_prometheus(&exposition_str).unwrap();
+    assert_eq!(&exposition.families["escaped_metric"].help, help);
+}
+
+#[test]
+fn test_empty_help() {
+    use crate::ExpositionBuilder;
+
+    // An empty HELP, with or without the separating space, is the same as not having one at all
+    for test_str in [
+        "# HELP empty_metric \n# TYPE empty_metric gauge\nempty_metric 1\n",
+        "# HELP empty_metric\n# TYPE empty_metric gauge\nempty_metric 1\n",
+    ]
+    .iter()
+    {
+        let exposition = parse_prometheus(test_str).unwrap();
+        assert_eq!(exposition.families["empty_metric"].help, "");
+
+        let rendered = exposition.to_string();
+        assert!(!rendered.contains("# HELP"));
+        assert_eq!(parse_prometheus(&rendered).unwrap(), exposition);
+    }
+
+    let test_str = "# HELP empty_metric \n# TYPE empty_metric gauge\n# UNIT empty_metric \nempty_metric 1\n# EOF\n";
+    let exposition = parse_openmetrics(test_str).unwrap();
+    let family = &exposition.families["empty_metric"];
+    assert_eq!(family.help, "");
+    assert_eq!(family.unit, "");
+
+    let rendered = exposition.to_string();
+    assert!(!rendered.contains("# HELP"));
+    assert!(!rendered.contains("# UNIT"));
+    assert_eq!(
+        parse_openmetrics(&format!("{}# EOF\n", rendered)).unwrap(),
+        exposition
+    );
+
+    let exposition = ExpositionBuilder::new()
+        .gauge("empty_metric", &[], 1)
+        .help("empty_metric", "")
+        .build()
+        .unwrap();
+    assert!(!exposition.to_string().contains("# HELP"));
+}
+
+#[test]
+fn test_shift_and_clear_timestamps() {
+    use crate::{MetricNumber, OpenMetricsValue};
+
+    let test_str = r#"# TYPE requests counter
+requests_total 5 10
+requests_created 3 10
+# TYPE latency histogram
+latency_bucket{le="+Inf"} 2 10 # {trace_id="def"} 1 5
+latency_count 2 10
+latency_sum 3 10
+latency_created 4 10
+# EOF
+"#;
+
+    let mut exposition = parse_openmetrics(test_str).unwrap();
+    exposition.shift_timestamps(100.);
+
+    let sample = exposition.families["requests"]
+        .iter_samples()
+        .next()
+        .unwrap();
+    assert_eq!(sample.timestamp, Some(110.));
+    match &sample.value {
+        OpenMetricsValue::Counter(c) => {
+            assert_eq!(c.created, Some(103.));
+        }
+        _ => panic!("expected a counter"),
+    }
+
+    let sample = exposition.families["latency"]
+        .iter_samples()
+        .next()
+        .unwrap();
+    assert_eq!(sample.timestamp, Some(110.));
+    match &sample.value {
+        OpenMetricsValue::Histogram(h) => {
+            assert_eq!(h.created, Some(104.));
+            assert_eq!(
+                h.buckets[0].exemplar.as_ref().unwrap().timestamp,
+                Some(105.)
+            );
+        }
+        _ => panic!("expected a histogram"),
+    }
+
+    exposition.clear_timestamps();
+
+    let sample = exposition.families["requests"]
+        .iter_samples()
+        .next()
+        .unwrap();
+    assert_eq!(sample.timestamp, None);
+    match &sample.value {
+        OpenMetricsValue::Counter(c) => {
+            assert_eq!(c.value, MetricNumber::Int(5));
+            assert_eq!(c.created, None);
+        }
+        _ => panic!("expected a counter"),
+    }
+
+    let sample = exposition.families["latency"]
+        .iter_samples()
+        .next()
+        .unwrap();
+    assert_eq!(sample.timestamp, None);
+    match &sample.value {
+        OpenMetricsValue::Histogram(h) => {
+            assert_eq!(h.created, None);
+            assert_eq!(h.count, Some(2));
+            assert_eq!(h.buckets[0].exemplar.as_ref().unwrap().timestamp, None);
+        }
+        _ => panic!("expected a histogram"),
+    }
+}
+
+#[test]
+fn test_filter() {
+    let test_str = include_str!("../prometheus/testdata/upstream_example.txt");
+    let exposition = parse_prometheus(test_str).unwrap();
+
+    let filtered = exposition.filter(|name, _| name.starts_with("http_"));
+    assert_eq!(filtered.families.len(), 2);
+    assert!(filtered.families.contains_key("http_requests_total"));
+    assert!(filtered
+        .families
+        .contains_key("http_request_duration_seconds"));
+    assert_eq!(exposition.families.len(), 6);
+
+    let test_str = r#"# TYPE temperature gauge
+temperature{room="kitchen"} 20 1000
+temperature{room="lounge"} 21 2000
+temperature{room="bedroom"} 19 3000
+"#;
+    let exposition = parse_prometheus(test_str).unwrap();
+    let family = &exposition.families["temperature"];
+    let filtered = family.filter_samples(|s| s.timestamp.unwrap() >= 2000.);
+    assert_eq!(filtered.iter_samples().count(), 2);
+    assert!(filtered
+        .iter_samples()
+        .all(|s| s.get_labelset().unwrap().get_label_value("room") != Some("kitchen")));
+    assert_eq!(family.iter_samples().count(), 3);
+}
+
+#[test]
+fn test_map_values() {
+    use crate::{MetricNumber, PrometheusValue};
+
+    let test_str = r#"# TYPE latency histogram
+latency_bucket{le="1"} 1
+latency_bucket{le="+Inf"} 3
+latency_sum 4.5
+latency_count 3
+# TYPE memory gauge
+memory 1024
+"#;
+    let mut exposition = parse_prometheus(test_str).unwrap();
+    for family in exposition.families.values_mut() {
+        family.map_values(|n| *n *= MetricNumber::Int(2));
+    }
+
+    let sample = exposition.families["latency"]
+        .iter_samples()
+        .next()
+        .unwrap();
+    match &sample.value {
+        PrometheusValue::Histogram(h) => {
+            assert_eq!(h.buckets[0].count, MetricNumber::Int(2));
+            assert_eq!(h.buckets[1].count, MetricNumber::Int(6));
+            assert_eq!(h.sum, Some(MetricNumber::Float(9.)));
+            assert_eq!(h.count, Some(3));
+        }
+        _ => panic!("expected a histogram"),
+    }
+
+    let sample = exposition.families["memory"].iter_samples().next().unwrap();
+    assert!(matches!(
+        sample.value,
+        PrometheusValue::Gauge(MetricNumber::Int(2048))
+    ));
+}
+
+#[test]
+fn test_into_families() {
+    let test_str = include_str!("../prometheus/testdata/upstream_example.txt");
+    let exposition = parse_prometheus(test_str).unwrap();
+    let families: Vec<_> = exposition.into_families().collect();
+    assert_eq!(families.len(), 6);
+    assert!(families
+        .iter()
+        .all(|(name, family)| name == &family.family_name));
+}
+
 #[test]
 fn test_metric_number_operations() {
     use crate::MetricNumber;
@@ -233,3 +442,1812 @@ fn test_metric_number_operations() {
         assert_eq!(a, MetricNumber::Float(1.0 / 3.0));
     }
 }
+
+#[test]
+fn test_metric_number_helpers() {
+    use crate::MetricNumber;
+
+    assert_eq!(MetricNumber::Int(-3).abs(), MetricNumber::Int(3));
+    assert_eq!(MetricNumber::Float(-1.5).abs(), MetricNumber::Float(1.5));
+    assert_eq!(
+        MetricNumber::Int(i64::MIN).abs(),
+        MetricNumber::Float(-(i64::MIN as f64))
+    );
+
+    assert_eq!(-MetricNumber::Int(3), MetricNumber::Int(-3));
+    assert_eq!(-MetricNumber::Float(1.5), MetricNumber::Float(-1.5));
+
+    assert_eq!(
+        MetricNumber::sum(vec![MetricNumber::Int(1), MetricNumber::Int(2)]),
+        MetricNumber::Int(3)
+    );
+    assert_eq!(
+        MetricNumber::sum(vec![MetricNumber::Int(1), MetricNumber::Float(0.5)]),
+        MetricNumber::Float(1.5)
+    );
+    assert_eq!(MetricNumber::sum(Vec::new()), MetricNumber::Int(0));
+
+    assert_eq!(
+        MetricNumber::Int(1) / MetricNumber::Int(0),
+        MetricNumber::Float(f64::INFINITY)
+    );
+    assert_eq!(
+        MetricNumber::Int(-1) / MetricNumber::Int(0),
+        MetricNumber::Float(f64::NEG_INFINITY)
+    );
+    assert!((MetricNumber::Int(0) / MetricNumber::Int(0))
+        .as_f64()
+        .is_nan());
+
+    let mut a = MetricNumber::Int(1);
+    a /= MetricNumber::Int(0);
+    assert_eq!(a, MetricNumber::Float(f64::INFINITY));
+}
+
+#[test]
+fn test_metric_number_conversions() {
+    use crate::MetricNumber;
+
+    assert_eq!(MetricNumber::from(5i64), MetricNumber::Int(5));
+    assert_eq!(MetricNumber::from(-5i32), MetricNumber::Int(-5));
+    assert_eq!(
+        MetricNumber::from(u32::MAX),
+        MetricNumber::Int(u32::MAX as i64)
+    );
+    assert_eq!(MetricNumber::from(1.5f64), MetricNumber::Float(1.5));
+    assert_eq!(MetricNumber::from(1.5f32), MetricNumber::Float(1.5));
+}
+
+#[test]
+fn test_labelset_conversions() {
+    let test_str = r#"# TYPE http_requests_total counter
+http_requests_total{method="post",code="200"} 1027
+"#;
+    let exposition = parse_prometheus(test_str).unwrap();
+    let sample = exposition.families["http_requests_total"]
+        .iter_samples()
+        .next()
+        .unwrap();
+    let labelset = sample.get_labelset().unwrap();
+
+    let map = labelset.to_map();
+    assert_eq!(map.len(), 2);
+    assert_eq!(map["method"], "post");
+    assert_eq!(map["code"], "200");
+
+    assert_eq!(
+        labelset.to_sorted_vec(),
+        vec![
+            (String::from("code"), String::from("200")),
+            (String::from("method"), String::from("post"))
+        ]
+    );
+}
+
+#[test]
+fn test_labelset_subsets() {
+    let test_str = r#"# TYPE http_requests_total counter
+http_requests_total{method="post",code="200"} 1027
+http_requests_total{method="get",code="200"} 12
+# TYPE http_errors_total counter
+http_errors_total{method="post"} 3
+"#;
+    let exposition = parse_prometheus(test_str).unwrap();
+    let requests = &exposition.families["http_requests_total"];
+    let errors = &exposition.families["http_errors_total"];
+    let post_requests = requests.iter_samples().next().unwrap();
+    let get_requests = requests.iter_samples().nth(1).unwrap();
+    let post_errors = errors.iter_samples().next().unwrap();
+
+    let labelset = post_requests.get_labelset().unwrap();
+    assert!(labelset.matches_subset(&[("method", "post")]));
+    assert!(labelset.matches_subset(&[("method", "post"), ("code", "200")]));
+    assert!(labelset.matches_subset(&[]));
+    assert!(!labelset.matches_subset(&[("method", "get")]));
+    assert!(!labelset.matches_subset(&[("path", "/")]));
+
+    let labelset = post_errors.get_labelset().unwrap();
+    assert!(labelset.is_subset_of(post_requests));
+    assert!(!labelset.is_subset_of(get_requests));
+
+    let labelset = post_requests.get_labelset().unwrap();
+    assert!(!labelset.is_subset_of(post_errors));
+}
+
+#[test]
+fn test_num_samples() {
+    let test_str = include_str!("../prometheus/testdata/upstream_example.txt");
+    let exposition = parse_prometheus(test_str).unwrap();
+    let family = &exposition.families["http_requests_total"];
+    assert_eq!(family.num_samples(), 2);
+    assert!(!family.is_empty());
+    assert_eq!(
+        family.iter_samples().next().unwrap().label_values(),
+        &["200", "post"]
+    );
+
+    let family = family.filter_samples(|_| false);
+    assert_eq!(family.num_samples(), 0);
+    assert!(family.is_empty());
+}
+
+#[test]
+fn test_value_accessors() {
+    use crate::{
+        CounterValue, Exemplar, HistogramValue, MetricNumber, OpenMetricsValue,
+        PrometheusCounterValue, PrometheusValue, SummaryValue,
+    };
+    use std::collections::HashMap;
+
+    let exemplar = Exemplar::new(HashMap::new(), 1., None);
+
+    assert_eq!(
+        PrometheusValue::Unknown(MetricNumber::Int(1)).as_f64(),
+        Some(1.)
+    );
+    assert_eq!(
+        PrometheusValue::Gauge(MetricNumber::Float(2.5)).as_f64(),
+        Some(2.5)
+    );
+    let counter = PrometheusValue::Counter(PrometheusCounterValue {
+        value: MetricNumber::Int(3),
+        exemplar: Some(exemplar.clone()),
+    });
+    assert_eq!(counter.as_f64(), Some(3.));
+    assert_eq!(counter.exemplar(), Some(&exemplar));
+    assert_eq!(
+        PrometheusValue::Histogram(HistogramValue::default()).as_f64(),
+        None
+    );
+    assert_eq!(
+        PrometheusValue::Summary(SummaryValue::default()).as_f64(),
+        None
+    );
+    assert_eq!(
+        PrometheusValue::Gauge(MetricNumber::Int(1)).exemplar(),
+        None
+    );
+
+    assert_eq!(
+        OpenMetricsValue::Unknown(MetricNumber::Int(1)).as_f64(),
+        Some(1.)
+    );
+    assert_eq!(
+        OpenMetricsValue::Gauge(MetricNumber::Float(2.5)).as_f64(),
+        Some(2.5)
+    );
+    assert_eq!(
+        OpenMetricsValue::StateSet(MetricNumber::Int(0)).as_f64(),
+        Some(0.)
+    );
+    assert_eq!(OpenMetricsValue::Info.as_f64(), Some(1.));
+    let counter = OpenMetricsValue::Counter(CounterValue {
+        value: MetricNumber::Int(3),
+        created: None,
+        exemplar: Some(exemplar.clone()),
+    });
+    assert_eq!(counter.as_f64(), Some(3.));
+    assert_eq!(counter.exemplar(), Some(&exemplar));
+    assert_eq!(
+        OpenMetricsValue::Histogram(HistogramValue::default()).as_f64(),
+        None
+    );
+    assert_eq!(
+        OpenMetricsValue::GaugeHistogram(HistogramValue::default()).as_f64(),
+        None
+    );
+    assert_eq!(
+        OpenMetricsValue::Summary(SummaryValue::default()).as_f64(),
+        None
+    );
+    assert_eq!(OpenMetricsValue::Info.exemplar(), None);
+}
+
+#[test]
+fn test_exposition_from_families() {
+    use crate::{MetricsExposition, PrometheusMetricFamily, PrometheusType};
+
+    let family = |name: &str, help: &str| {
+        PrometheusMetricFamily::new(
+            name.to_owned(),
+            Vec::new(),
+            PrometheusType::Gauge,
+            help.to_owned(),
+            String::new(),
+        )
+    };
+
+    let exposition: MetricsExposition<_, _> = vec![
+        family("foo", "first"),
+        family("bar", ""),
+        family("foo", "second"),
+    ]
+    .into_iter()
+    .collect();
+    assert_eq!(exposition.families.len(), 2);
+    assert_eq!(exposition.families["foo"].help, "second");
+
+    let exposition =
+        MetricsExposition::try_from_iter(vec![family("foo", ""), family("bar", "")]).unwrap();
+    assert_eq!(exposition.families.len(), 2);
+
+    assert!(MetricsExposition::try_from_iter(vec![family("foo", ""), family("foo", "")]).is_err());
+}
+
+#[test]
+fn test_remove_families() {
+    let test_str = include_str!("../prometheus/testdata/upstream_example.txt");
+    let mut exposition = parse_prometheus(test_str).unwrap();
+
+    let removed = exposition.remove_family("http_requests_total").unwrap();
+    assert_eq!(removed.family_name, "http_requests_total");
+    assert!(!exposition.families.contains_key("http_requests_total"));
+    assert!(exposition.remove_family("http_requests_total").is_none());
+    assert_eq!(exposition.families.len(), 5);
+
+    exposition.retain_families(|name| name.starts_with("http_"));
+    assert_eq!(
+        exposition.families.keys().collect::<Vec<_>>(),
+        vec!["http_request_duration_seconds"]
+    );
+}
+
+#[test]
+fn test_iter_all_samples() {
+    let test_str = include_str!("../prometheus/testdata/upstream_example.txt");
+    let exposition = parse_prometheus(test_str).unwrap();
+
+    let expected: usize = exposition.families.values().map(|f| f.num_samples()).sum();
+    assert_eq!(exposition.iter_all_samples().count(), expected);
+    assert!(exposition
+        .iter_all_samples()
+        .all(|(name, _)| exposition.families.contains_key(name)));
+    assert_eq!(
+        exposition
+            .iter_all_samples()
+            .filter(|(name, _)| *name == "http_requests_total")
+            .count(),
+        exposition.families["http_requests_total"].num_samples()
+    );
+}
+
+#[test]
+fn test_get_sample() {
+    use crate::{MetricNumber, PrometheusValue};
+
+    let test_str = include_str!("../prometheus/testdata/upstream_example.txt");
+    let exposition = parse_prometheus(test_str).unwrap();
+
+    let sample = exposition
+        .get_sample(
+            "http_requests_total",
+            &[("code", "400"), ("method", "post")],
+        )
+        .unwrap();
+    match &sample.value {
+        PrometheusValue::Counter(c) => assert_eq!(c.value, MetricNumber::Int(3)),
+        other => panic!("Expected a counter, got {:?}", other),
+    }
+
+    assert!(exposition
+        .get_sample(
+            "http_requests_total",
+            &[("code", "500"), ("method", "post")]
+        )
+        .is_none());
+    assert!(exposition
+        .get_sample("http_requests_total", &[("code", "400")])
+        .is_none());
+    assert!(exposition
+        .get_sample("http_requests_total", &[("code", "400"), ("path", "post")])
+        .is_none());
+    assert!(exposition
+        .get_sample("metric_without_timestamp_and_labels", &[])
+        .is_some());
+    assert!(exposition.get_sample("not_a_metric", &[]).is_none());
+}
+
+#[test]
+fn test_cardinality() {
+    let test_str = include_str!("../prometheus/testdata/upstream_example.txt");
+    let exposition = parse_prometheus(test_str).unwrap();
+
+    let per_family = exposition.per_family_cardinality();
+    // 6 buckets, plus _sum and _count
+    assert_eq!(per_family["http_request_duration_seconds"], 8);
+    // 5 quantiles, plus _sum and _count
+    assert_eq!(per_family["rpc_duration_seconds"], 7);
+    assert_eq!(per_family["http_requests_total"], 2);
+
+    assert_eq!(exposition.cardinality(), 20);
+    assert_eq!(exposition.cardinality(), per_family.values().sum::<usize>());
+}
+
+#[test]
+fn test_base_name() {
+    use crate::{OpenMetricsMetricFamily, OpenMetricsType};
+
+    let family = |name: &str, unit: &str| {
+        OpenMetricsMetricFamily::new(
+            name.to_owned(),
+            Vec::new(),
+            OpenMetricsType::Gauge,
+            String::new(),
+            unit.to_owned(),
+        )
+    };
+
+    assert_eq!(family("foo_seconds", "seconds").base_name(), "foo");
+    assert_eq!(family("foo_seconds", "").base_name(), "foo_seconds");
+    assert_eq!(family("foo_bytes", "seconds").base_name(), "foo_bytes");
+    assert_eq!(family("fooseconds", "seconds").base_name(), "fooseconds");
+}
+
+#[test]
+fn test_with_unit() {
+    use crate::{OpenMetricsMetricFamily, OpenMetricsType};
+
+    let family = |name: &str| {
+        OpenMetricsMetricFamily::new(
+            name.to_owned(),
+            Vec::new(),
+            OpenMetricsType::Gauge,
+            String::new(),
+            String::new(),
+        )
+    };
+
+    let appended = family("request_duration").with_unit("seconds").unwrap();
+    assert_eq!(appended.family_name, "request_duration_seconds");
+    assert_eq!(appended.unit, "seconds");
+    assert_eq!(appended.base_name(), "request_duration");
+
+    let existing = family("request_duration_seconds")
+        .with_unit("seconds")
+        .unwrap();
+    assert_eq!(existing.family_name, "request_duration_seconds");
+    assert_eq!(existing.unit, "seconds");
+
+    assert!(family("request_size_bytes").with_unit("seconds").is_err());
+    assert!(family("request_duration").with_unit("").is_err());
+
+    // A unit that isn't a base unit is still detected if it's already set on the family
+    let custom = family("queue_length").with_unit("items").unwrap();
+    assert!(custom.with_unit("messages").is_err());
+}
+
+#[test]
+fn test_apply_counter_sample() {
+    use crate::{
+        CounterResetPolicy, CounterUpdate, MetricNumber, PrometheusCounterValue,
+        PrometheusMetricFamily, PrometheusType, PrometheusValue, Sample,
+    };
+
+    let counter = |value: i64| {
+        Sample::new(
+            vec![String::from("a")],
+            None,
+            PrometheusValue::Counter(PrometheusCounterValue {
+                value: MetricNumber::Int(value),
+                exemplar: None,
+            }),
+        )
+    };
+
+    let value = |family: &PrometheusMetricFamily| {
+        family
+            .iter_samples()
+            .next()
+            .unwrap()
+            .value
+            .as_f64()
+            .unwrap()
+    };
+
+    let mut family = PrometheusMetricFamily::new(
+        String::from("requests_total"),
+        vec![String::from("label")],
+        PrometheusType::Counter,
+        String::new(),
+        String::new(),
+    );
+
+    let policy = CounterResetPolicy::KeepOld;
+    assert_eq!(
+        family.apply_counter_sample(counter(5), policy).unwrap(),
+        CounterUpdate::Added
+    );
+    assert_eq!(
+        family.apply_counter_sample(counter(8), policy).unwrap(),
+        CounterUpdate::Increased
+    );
+    assert_eq!(value(&family), 8.);
+    assert_eq!(
+        family.apply_counter_sample(counter(8), policy).unwrap(),
+        CounterUpdate::Unchanged
+    );
+    assert_eq!(value(&family), 8.);
+
+    assert_eq!(
+        family.apply_counter_sample(counter(3), policy).unwrap(),
+        CounterUpdate::Reset
+    );
+    assert_eq!(value(&family), 8.);
+
+    let mut taken = family.clone();
+    assert_eq!(
+        taken
+            .apply_counter_sample(counter(3), CounterResetPolicy::TakeNew)
+            .unwrap(),
+        CounterUpdate::Reset
+    );
+    assert_eq!(value(&taken), 3.);
+
+    assert_eq!(
+        family
+            .apply_counter_sample(counter(3), CounterResetPolicy::SumWithReset)
+            .unwrap(),
+        CounterUpdate::Reset
+    );
+    assert_eq!(value(&family), 11.);
+    assert_eq!(family.num_samples(), 1);
+
+    // Later pushes are compared with the last pushed value rather than the total, and are added on to the total
+    let policy = CounterResetPolicy::SumWithReset;
+    let mut summed = PrometheusMetricFamily::new(
+        String::from("requests_total"),
+        vec![String::from("label")],
+        PrometheusType::Counter,
+        String::new(),
+        String::new(),
+    );
+    for (pushed, update, total) in [
+        (100, CounterUpdate::Added, 100.),
+        (5, CounterUpdate::Reset, 105.),
+        (10, CounterUpdate::Increased, 110.),
+        (10, CounterUpdate::Unchanged, 110.),
+        (2, CounterUpdate::Reset, 112.),
+        (4, CounterUpdate::Increased, 114.),
+    ]
+    .iter()
+    {
+        assert_eq!(
+            summed
+                .apply_counter_sample(counter(*pushed), policy)
+                .unwrap(),
+            *update
+        );
+        assert_eq!(value(&summed), *total);
+    }
+
+    // The timestamp is updated even if the value is kept
+    let mut timestamped = counter(1);
+    timestamped.timestamp = Some(1000.);
+    assert_eq!(
+        summed
+            .apply_counter_sample(timestamped, CounterResetPolicy::KeepOld)
+            .unwrap(),
+        CounterUpdate::Reset
+    );
+    assert_eq!(value(&summed), 114.);
+    assert_eq!(summed.iter_samples().next().unwrap().timestamp, Some(1000.));
+
+    let gauge = Sample::new(
+        vec![String::from("a")],
+        None,
+        PrometheusValue::Gauge(MetricNumber::Int(1)),
+    );
+    assert!(family.apply_counter_sample(gauge, policy).is_err());
+}
+
+#[test]
+fn test_histogram_builder() {
+    use crate::HistogramValueBuilder;
+
+    let histogram = HistogramValueBuilder::new()
+        .bucket(f64::INFINITY, 10)
+        .bucket(0.5, 3)
+        .bucket(1., 7)
+        .sum(12.5)
+        .build()
+        .unwrap();
+    let bounds: Vec<f64> = histogram.buckets.iter().map(|b| b.upper_bound).collect();
+    assert_eq!(bounds, vec![0.5, 1., f64::INFINITY]);
+    assert_eq!(histogram.count, Some(10));
+
+    // Missing +Inf bucket
+    assert!(HistogramValueBuilder::new().bucket(1., 3).build().is_err());
+
+    // Not cumulative
+    assert!(HistogramValueBuilder::new()
+        .bucket(0.5, 5)
+        .bucket(1., 3)
+        .bucket(f64::INFINITY, 10)
+        .build()
+        .is_err());
+
+    // Duplicate bounds
+    assert!(HistogramValueBuilder::new()
+        .bucket(1., 3)
+        .bucket(1., 3)
+        .bucket(f64::INFINITY, 10)
+        .build()
+        .is_err());
+
+    // Count that doesn't match the +Inf bucket
+    assert!(HistogramValueBuilder::new()
+        .bucket(f64::INFINITY, 10)
+        .count(11)
+        .build()
+        .is_err());
+}
+
+#[test]
+fn test_summary_builder() {
+    use crate::SummaryValueBuilder;
+
+    let summary = SummaryValueBuilder::new()
+        .quantile(0.99, 7.)
+        .quantile(0.5, 3.)
+        .sum(100)
+        .count(20)
+        .build()
+        .unwrap();
+    let quantiles: Vec<f64> = summary.quantiles.iter().map(|q| q.quantile).collect();
+    assert_eq!(quantiles, vec![0.5, 0.99]);
+    assert_eq!(summary.count, Some(20));
+
+    assert!(SummaryValueBuilder::new()
+        .quantile(1.5, 3.)
+        .build()
+        .is_err());
+    assert!(SummaryValueBuilder::new()
+        .quantile(0.5, -3.)
+        .build()
+        .is_err());
+    assert!(SummaryValueBuilder::new()
+        .quantile(0.5, 3.)
+        .quantile(0.5, 4.)
+        .build()
+        .is_err());
+    assert!(SummaryValueBuilder::new().sum(-1).build().is_err());
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn test_query_result_json() {
+    let test_str = "# TYPE foo gauge\nfoo{a=\"b\"} 1.5 1000\nfoo{a=\"c\"} 2\n# TYPE bar histogram\nbar_bucket{le=\"1\"} 1\nbar_bucket{le=\"+Inf\"} 3\nbar_sum 4\nbar_count 3\n";
+    let exposition = parse_prometheus(test_str).unwrap();
+    let json: serde_json::Value =
+        serde_json::from_str(&exposition.to_query_result_json(2.5)).unwrap();
+
+    let expected: serde_json::Value = serde_json::from_str(
+        r#"{
+            "status": "success",
+            "data": {
+                "resultType": "vector",
+                "result": [
+                    {"metric": {"__name__": "bar_bucket", "le": "1"}, "value": [2.5, "1"]},
+                    {"metric": {"__name__": "bar_bucket", "le": "+Inf"}, "value": [2.5, "3"]},
+                    {"metric": {"__name__": "bar_sum"}, "value": [2.5, "4"]},
+                    {"metric": {"__name__": "bar_count"}, "value": [2.5, "3"]},
+                    {"metric": {"__name__": "foo", "a": "b"}, "value": [1.0, "1.5"]},
+                    {"metric": {"__name__": "foo", "a": "c"}, "value": [2.5, "2"]}
+                ]
+            }
+        }"#,
+    )
+    .unwrap();
+
+    assert_eq!(json, expected);
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn test_from_query_result_json() {
+    use crate::{MetricNumber, MetricsExposition, PrometheusType, PrometheusValue};
+
+    let test_json = r#"{
+        "status": "success",
+        "data": {
+            "resultType": "vector",
+            "result": [
+                {"metric": {"__name__": "bar_bucket", "le": "+Inf", "job": "api"}, "value": [2.5, "3"]},
+                {"metric": {"__name__": "bar_bucket", "le": "1", "job": "api"}, "value": [2.5, "1"]},
+                {"metric": {"__name__": "bar_sum", "job": "api"}, "value": [2.5, "4.5"]},
+                {"metric": {"__name__": "bar_count", "job": "api"}, "value": [2.5, "3"]},
+                {"metric": {"__name__": "baz", "quantile": "0.5"}, "value": [2.5, "7"]},
+                {"metric": {"__name__": "baz_sum"}, "value": [2.5, "10"]},
+                {"metric": {"__name__": "baz_count"}, "value": [2.5, "2"]},
+                {"metric": {"__name__": "foo", "a": "b"}, "value": [1, "1.5"]},
+                {"metric": {"__name__": "foo"}, "value": [2.5, "NaN"]}
+            ]
+        }
+    }"#;
+
+    let exposition = MetricsExposition::from_query_result_json(test_json).unwrap();
+    assert_eq!(
+        exposition.families["bar"].family_type,
+        PrometheusType::Histogram
+    );
+    assert_eq!(
+        exposition.families["baz"].family_type,
+        PrometheusType::Summary
+    );
+    assert_eq!(
+        exposition.families["foo"].family_type,
+        PrometheusType::Unknown
+    );
+
+    let foo = exposition.get_sample("foo", &[("a", "b")]).unwrap();
+    assert_eq!(foo.timestamp, Some(1000.));
+    assert_eq!(
+        foo.value,
+        PrometheusValue::Unknown(MetricNumber::Float(1.5))
+    );
+    // A label that's missing from a series is treated as empty
+    assert!(exposition.get_sample("foo", &[("a", "")]).is_some());
+
+    let round_tripped: serde_json::Value =
+        serde_json::from_str(&exposition.to_query_result_json(0.)).unwrap();
+    let mut expected: serde_json::Value = serde_json::from_str(test_json).unwrap();
+    // Buckets and samples are sorted, and missing labels become empty ones
+    let results = expected["data"]["result"].as_array_mut().unwrap();
+    results.swap(0, 1);
+    results.swap(7, 8);
+    results[7]["metric"]["a"] = serde_json::Value::from("");
+    results[8]["value"][0] = serde_json::Value::from(1.);
+
+    assert_eq!(round_tripped, expected);
+
+    assert!(MetricsExposition::from_query_result_json("{}").is_err());
+    assert!(MetricsExposition::from_query_result_json(
+        r#"{"status": "success", "data": {"resultType": "matrix", "result": []}}"#
+    )
+    .is_err());
+}
+
+#[test]
+fn test_histogram_rate() {
+    use crate::HistogramValueBuilder;
+
+    let histogram = |counts: [i64; 3]| {
+        HistogramValueBuilder::new()
+            .bucket(0.5, counts[0])
+            .bucket(1., counts[1])
+            .bucket(f64::INFINITY, counts[2])
+            .build()
+            .unwrap()
+    };
+
+    let previous = histogram([10, 20, 30]);
+    let current = histogram([16, 32, 60]);
+    assert_eq!(
+        current.rate_vs(&previous, 60.),
+        vec![(0.5, 0.1), (1., 0.2), (f64::INFINITY, 0.5)]
+    );
+
+    // A bucket going down means the histogram was reset, so everything counts from 0
+    let reset = histogram([3, 6, 12]);
+    assert_eq!(
+        reset.rate_vs(&previous, 3.),
+        vec![(0.5, 1.), (1., 2.), (f64::INFINITY, 4.)]
+    );
+}
+
+#[test]
+fn test_exposition_equality() {
+    let a = parse_prometheus(
+        "# TYPE requests_total counter
+requests_total{path=\"/\",code=\"200\"} 10
+requests_total{path=\"/\",code=\"500\"} 1
+# TYPE temperature gauge
+temperature 21.5
+",
+    )
+    .unwrap();
+
+    // Same samples, with the families, samples and labels in a different order
+    let b = parse_prometheus(
+        "# TYPE temperature gauge
+temperature 21.5
+# TYPE requests_total counter
+requests_total{code=\"500\",path=\"/\"} 1
+requests_total{code=\"200\",path=\"/\"} 10
+",
+    )
+    .unwrap();
+    assert_eq!(a, b);
+
+    let c = parse_prometheus(
+        "# TYPE temperature gauge
+temperature 21.5
+# TYPE requests_total counter
+requests_total{code=\"500\",path=\"/\"} 2
+requests_total{code=\"200\",path=\"/\"} 10
+",
+    )
+    .unwrap();
+    assert_ne!(a, c);
+
+    let a = parse_openmetrics(
+        "# TYPE requests counter
+requests_total{path=\"/\"} 10
+requests_total{path=\"/a\"} 1
+# EOF
+",
+    )
+    .unwrap();
+    let b = parse_openmetrics(
+        "# TYPE requests counter
+requests_total{path=\"/a\"} 1
+requests_total{path=\"/\"} 10
+# EOF
+",
+    )
+    .unwrap();
+    assert_eq!(a, b);
+}
+
+#[test]
+fn test_exposition_builder() {
+    use crate::ExpositionBuilder;
+
+    let exposition = ExpositionBuilder::new()
+        .gauge("temperature_celsius", &[("room", "kitchen")], 21.5)
+        .gauge("temperature_celsius", &[("room", "bedroom")], 18)
+        .gauge(
+            "temperature_celsius",
+            &[("room", "garage"), ("floor", "0")],
+            9.5,
+        )
+        .help("temperature_celsius", "The temperature of each room")
+        .counter("readings_total", &[], 3)
+        .build()
+        .unwrap();
+
+    let family = &exposition.families["temperature_celsius"];
+    assert_eq!(
+        family.get_label_names(),
+        &[String::from("floor"), String::from("room")]
+    );
+    assert_eq!(family.iter_samples().count(), 3);
+
+    // The rendered exposition is valid, and parses back to the same thing
+    let rendered = exposition.to_string();
+    assert!(rendered.contains("# HELP temperature_celsius The temperature of each room\n"));
+    assert!(rendered.contains("temperature_celsius{floor=\"0\",room=\"garage\"} 9.5\n"));
+    assert_eq!(parse_prometheus(&rendered).unwrap(), exposition);
+
+    assert!(ExpositionBuilder::new()
+        .gauge("temperature_celsius", &[], 1)
+        .counter("temperature_celsius", &[], 1)
+        .build()
+        .is_err());
+    assert!(ExpositionBuilder::new()
+        .counter("readings", &[], 1)
+        .build()
+        .is_err());
+    assert!(ExpositionBuilder::new()
+        .gauge("temperature_celsius", &[("room", "kitchen")], 1)
+        .gauge("temperature_celsius", &[("room", "kitchen")], 2)
+        .build()
+        .is_err());
+}
+
+#[test]
+fn test_all_label_names() {
+    let exposition = parse_prometheus(
+        "# TYPE requests_total counter
+requests_total{path=\"/\",code=\"200\"} 10
+# TYPE temperature_celsius gauge
+temperature_celsius{room=\"kitchen\"} 21.5
+# TYPE request_duration_seconds histogram
+request_duration_seconds_bucket{path=\"/\",le=\"+Inf\"} 3
+request_duration_seconds_sum{path=\"/\"} 1.5
+request_duration_seconds_count{path=\"/\"} 3
+# TYPE up gauge
+up 1
+",
+    )
+    .unwrap();
+
+    let names: Vec<String> = exposition.all_label_names().into_iter().collect();
+    assert_eq!(names, vec!["code", "path", "room"]);
+}
+
+#[test]
+fn test_render_canonical() {
+    let a = parse_prometheus(
+        "# TYPE temperature_celsius gauge
+temperature_celsius{room=\"kitchen\",floor=\"0\"} 21.5
+temperature_celsius{room=\"bedroom\",floor=\"1\"} 18
+# TYPE requests_total counter
+requests_total{path=\"/\"} 1e3
+",
+    )
+    .unwrap();
+    let b = parse_prometheus(
+        "# TYPE requests_total counter
+requests_total{path=\"/\"} 1000
+# TYPE temperature_celsius gauge
+temperature_celsius{floor=\"1\",room=\"bedroom\"} 18.0
+temperature_celsius{floor=\"0\",room=\"kitchen\"} 21.5
+",
+    )
+    .unwrap();
+
+    let canonical = a.render_canonical();
+    assert_eq!(canonical, b.render_canonical());
+    assert_eq!(
+        canonical,
+        "# TYPE requests_total counter
+requests_total{path=\"/\"} 1000
+
+# TYPE temperature_celsius gauge
+temperature_celsius{floor=\"0\",room=\"kitchen\"} 21.5
+temperature_celsius{floor=\"1\",room=\"bedroom\"} 18
+"
+    );
+}
+
+#[test]
+fn test_assert_roundtrips() {
+    use crate::{MetricFamily, MetricNumber, PrometheusType, PrometheusValue, Sample};
+
+    let exposition = parse_prometheus(
+        "# HELP temperature_celsius The temperature of each room
+# TYPE temperature_celsius gauge
+temperature_celsius{room=\"kitchen\"} 21.5
+temperature_celsius{room=\"bedroom\"} 18 1000
+",
+    )
+    .unwrap();
+    assert!(exposition.families["temperature_celsius"]
+        .assert_roundtrips()
+        .is_ok());
+
+    let exposition = parse_openmetrics(
+        "# TYPE requests counter
+requests_total{path=\"/\"} 10
+requests_created{path=\"/\"} 1520430000.123
+# EOF
+",
+    )
+    .unwrap();
+    assert!(exposition.families["requests"].assert_roundtrips().is_ok());
+
+    // An integral float renders without a decimal point, so comes back as an int
+    let family = MetricFamily::new(
+        String::from("temperature_celsius"),
+        vec![String::from("room")],
+        PrometheusType::Gauge,
+        String::new(),
+        String::new(),
+    )
+    .with_samples(vec![Sample::new(
+        vec![String::from("kitchen")],
+        None,
+        PrometheusValue::Gauge(MetricNumber::Float(21.)),
+    )])
+    .unwrap();
+
+    let err = family.assert_roundtrips().unwrap_err().to_string();
+    assert!(err.contains("[\"kitchen\"]"), "{}", err);
+    assert!(err.contains("Float(21.0)"), "{}", err);
+    assert!(err.contains("Int(21)"), "{}", err);
+}
+
+#[test]
+fn test_owned_label_sets() {
+    use std::collections::HashMap;
+
+    let a = parse_prometheus(
+        "# TYPE requests_total counter
+requests_total{path=\"/\",code=\"200\"} 10
+requests_total{path=\"/\",code=\"500\"} 1
+",
+    )
+    .unwrap();
+    let b = parse_prometheus(
+        "# TYPE requests_total counter
+requests_total{code=\"200\",path=\"/\"} 15
+",
+    )
+    .unwrap();
+
+    // The label sets outlive the expositions they came from
+    let mut totals: HashMap<_, f64> = HashMap::new();
+    for exposition in [a, b] {
+        for sample in exposition.families["requests_total"].iter_samples() {
+            let labels = sample.get_labelset().unwrap().to_owned_set();
+            let value = match &sample.value {
+                crate::PrometheusValue::Counter(c) => c.value.as_f64(),
+                other => panic!("Expected a counter, got {:?}", other),
+            };
+            *totals.entry(labels).or_default() += value;
+        }
+    }
+
+    assert_eq!(totals.len(), 2);
+    let ok = totals
+        .iter()
+        .find(|(labels, _)| labels.get_label_value("code") == Some("200"))
+        .unwrap();
+    assert_eq!(*ok.1, 25.);
+    assert_eq!(ok.0.to_string(), "{code=\"200\",path=\"/\"}");
+    assert_eq!(ok.0.len(), 2);
+
+    let error = totals
+        .keys()
+        .find(|labels| labels.get_label_value("code") == Some("500"))
+        .unwrap();
+    assert_ne!(ok.0, error);
+}
+
+#[test]
+fn test_label_set_display() {
+    let exposition = parse_prometheus(
+        "# TYPE requests_total counter
+requests_total{b=\"2\",a=\"1\"} 10
+# TYPE up gauge
+up 1
+",
+    )
+    .unwrap();
+
+    let sample = exposition.families["requests_total"]
+        .iter_samples()
+        .next()
+        .unwrap();
+    assert_eq!(
+        sample.get_labelset().unwrap().to_string(),
+        "{a=\"1\",b=\"2\"}"
+    );
+
+    let sample = exposition.families["up"].iter_samples().next().unwrap();
+    assert_eq!(sample.get_labelset().unwrap().to_string(), "");
+}
+
+#[test]
+fn test_escaped_label_values() {
+    let test_str = "# TYPE escaped_metric gauge
+escaped_metric{path=\"C:\\\\dir\",quote=\"say \\\"hi\\\"\\nbye\"} 1
+";
+    let exposition = parse_prometheus(test_str).unwrap();
+    let sample = exposition.families["escaped_metric"]
+        .iter_samples()
+        .next()
+        .unwrap();
+    let labelset = sample.get_labelset().unwrap();
+    assert_eq!(labelset.get_label_value("path"), Some("C:\\dir"));
+    assert_eq!(labelset.get_label_value("quote"), Some("say \"hi\"\nbye"));
+    assert_eq!(
+        labelset.to_string(),
+        "{path=\"C:\\\\dir\",quote=\"say \\\"hi\\\"\\nbye\"}"
+    );
+
+    let rendered = exposition.to_string();
+    assert_eq!(rendered, test_str);
+    assert_eq!(parse_prometheus(&rendered).unwrap(), exposition);
+
+    let test_str = format!("{}# EOF\n", test_str);
+    let exposition = parse_openmetrics(&test_str).unwrap();
+    assert_eq!(
+        parse_openmetrics(&format!("{}# EOF\n", exposition)).unwrap(),
+        exposition
+    );
+}
+
+#[test]
+fn test_metric_number_as_u64() {
+    use crate::MetricNumber;
+
+    assert_eq!(MetricNumber::Int(5).as_u64(), Some(5));
+    assert_eq!(MetricNumber::Float(5.).as_u64(), Some(5));
+    assert_eq!(MetricNumber::Int(i64::MAX).as_u64(), Some(i64::MAX as u64));
+    assert_eq!(
+        MetricNumber::Float(1e19).as_u64(),
+        Some(10_000_000_000_000_000_000)
+    );
+
+    assert_eq!(MetricNumber::Int(-1).as_u64(), None);
+    assert_eq!(MetricNumber::Float(-1.).as_u64(), None);
+    assert_eq!(MetricNumber::Float(1.5).as_u64(), None);
+    assert_eq!(MetricNumber::Float(1e20).as_u64(), None);
+    assert_eq!(MetricNumber::Float(f64::INFINITY).as_u64(), None);
+    assert_eq!(MetricNumber::Float(f64::NAN).as_u64(), None);
+
+    let histogram = |count: &str| {
+        format!(
+            "# TYPE foo histogram
+foo_bucket{{le=\"+Inf\"}} 1
+foo_sum 1
+foo_count {}
+",
+            count
+        )
+    };
+
+    // How the too large count is rendered depends on the float formatting
+    let too_large = format!(
+        "Histogram counts must be less than 2^64 (got: {})",
+        MetricNumber::Float(1e20)
+    );
+    for (count, err) in [
+        ("-1", "Histogram counts must be positive (got: -1)"),
+        ("1.5", "Histogram counts must be integers (got: 1.5)"),
+        ("1e20", too_large.as_str()),
+    ]
+    .iter()
+    {
+        assert_eq!(
+            parse_prometheus(&histogram(count)).unwrap_err().to_string(),
+            *err
+        );
+    }
+}
+
+#[test]
+fn test_validate_timestamps() {
+    use crate::{MetricFamily, MetricNumber, PrometheusType, PrometheusValue, Sample};
+
+    let sample = |instance: &str, timestamp| {
+        Sample::new(
+            vec![String::from(instance)],
+            timestamp,
+            PrometheusValue::Gauge(MetricNumber::Int(1)),
+        )
+    };
+
+    let family = |samples| {
+        MetricFamily::new(
+            String::from("up"),
+            vec![String::from("instance")],
+            PrometheusType::Gauge,
+            String::new(),
+            String::new(),
+        )
+        .with_samples(samples)
+        .unwrap()
+    };
+
+    assert!(
+        family(vec![sample("a", Some(1000.)), sample("b", Some(2000.))])
+            .validate_timestamps()
+            .is_ok()
+    );
+    assert!(family(vec![sample("a", None), sample("b", None)])
+        .validate_timestamps()
+        .is_ok());
+    assert_eq!(
+        family(vec![sample("a", Some(1000.)), sample("b", None)])
+            .validate_timestamps()
+            .unwrap_err()
+            .to_string(),
+        "Missing timestamp in family up (1 of 2 samples had a timestamp)"
+    );
+
+    // Parsed families can mix them too
+    let exposition = parse_prometheus("up{instance=\"a\"} 1 1000\nup{instance=\"b\"} 1\n").unwrap();
+    assert!(exposition.families["up"].validate_timestamps().is_err());
+}
+
+#[test]
+fn test_sum_values() {
+    use crate::MetricNumber;
+
+    let exposition = parse_prometheus(
+        "# TYPE requests_total counter
+requests_total{path=\"/\"} 10
+requests_total{path=\"/login\"} 2
+# TYPE errors_total counter
+errors_total{path=\"/\"} 1
+errors_total{path=\"/login\"} 0.5
+# TYPE request_duration_seconds histogram
+request_duration_seconds_bucket{le=\"+Inf\"} 3
+request_duration_seconds_sum 1.5
+request_duration_seconds_count 3
+",
+    )
+    .unwrap();
+
+    assert_eq!(
+        exposition.families["requests_total"].sum_values(),
+        Some(MetricNumber::Int(12))
+    );
+    assert_eq!(
+        exposition.families["errors_total"].sum_values(),
+        Some(MetricNumber::Float(1.5))
+    );
+    assert_eq!(
+        exposition.families["request_duration_seconds"].sum_values(),
+        None
+    );
+
+    let exposition = parse_openmetrics(
+        "# TYPE requests counter
+requests_total{path=\"/\"} 10
+requests_total{path=\"/login\"} 2.5
+# EOF
+",
+    )
+    .unwrap();
+    assert_eq!(
+        exposition.families["requests"].sum_values(),
+        Some(MetricNumber::Float(12.5))
+    );
+}
+
+#[test]
+fn test_aggregate_without() {
+    use crate::{AggOp, MetricFamily, MetricNumber, PrometheusType, PrometheusValue};
+
+    let exposition = parse_prometheus(
+        "# TYPE memory_bytes gauge
+memory_bytes{namespace=\"default\",pod=\"a\"} 10 1000
+memory_bytes{namespace=\"default\",pod=\"b\"} 20 2000
+memory_bytes{namespace=\"kube-system\",pod=\"c\"} 5 1000
+# TYPE request_duration_seconds histogram
+request_duration_seconds_bucket{pod=\"a\",le=\"+Inf\"} 3
+request_duration_seconds_sum{pod=\"a\"} 1.5
+request_duration_seconds_count{pod=\"a\"} 3
+",
+    )
+    .unwrap();
+    let family = &exposition.families["memory_bytes"];
+
+    let value = |family: &MetricFamily<PrometheusType, PrometheusValue>, namespace: &str| {
+        let sample = family
+            .get_sample_by_label_values(&[String::from(namespace)])
+            .unwrap();
+        (sample.value.as_f64().unwrap(), sample.timestamp)
+    };
+
+    let sums = family.aggregate_without("pod", AggOp::Sum).unwrap();
+    assert_eq!(sums.get_label_names(), &[String::from("namespace")]);
+    assert_eq!(sums.iter_samples().count(), 2);
+    assert_eq!(value(&sums, "default"), (30., Some(2000.)));
+    assert_eq!(value(&sums, "kube-system"), (5., Some(1000.)));
+    assert_eq!(
+        sums.sum_values(),
+        Some(MetricNumber::Int(35)),
+        "sums of Ints should stay Ints"
+    );
+
+    let maxes = family.aggregate_without("pod", AggOp::Max).unwrap();
+    assert_eq!(value(&maxes, "default"), (20., Some(2000.)));
+    let mins = family.aggregate_without("pod", AggOp::Min).unwrap();
+    assert_eq!(value(&mins, "default"), (10., Some(2000.)));
+    let averages = family.aggregate_without("pod", AggOp::Avg).unwrap();
+    assert_eq!(value(&averages, "default"), (15., Some(2000.)));
+
+    assert!(family.aggregate_without("node", AggOp::Sum).is_err());
+    assert!(exposition.families["request_duration_seconds"]
+        .aggregate_without("pod", AggOp::Sum)
+        .is_err());
+}
+
+#[test]
+fn test_set_exemplar() {
+    use crate::{Exemplar, PrometheusValue};
+
+    let mut exposition = parse_prometheus(
+        "# TYPE requests_total counter
+requests_total 10
+# TYPE request_duration_seconds histogram
+request_duration_seconds_bucket{le=\"0.5\"} 1
+request_duration_seconds_bucket{le=\"1\"} 2
+request_duration_seconds_bucket{le=\"+Inf\"} 3
+request_duration_seconds_sum 1.5
+request_duration_seconds_count 3
+# TYPE temperature gauge
+temperature 21
+",
+    )
+    .unwrap();
+
+    let exemplar = |value: f64| {
+        Exemplar::new(
+            vec![(String::from("trace_id"), String::from("abc"))]
+                .into_iter()
+                .collect(),
+            value,
+            Some(1000.),
+        )
+    };
+
+    let family = exposition.families.get_mut("requests_total").unwrap();
+    let sample = family.iter_samples_mut().next().unwrap();
+    sample.set_exemplar(Some(exemplar(1.))).unwrap();
+    assert_eq!(sample.value.exemplar(), Some(&exemplar(1.)));
+    assert!(family
+        .to_string()
+        .contains("requests_total 10 # {trace_id=\"abc\"} 1 1000"));
+
+    let sample = family.iter_samples_mut().next().unwrap();
+    sample.set_exemplar(None).unwrap();
+    assert_eq!(sample.value.exemplar(), None);
+
+    // Histogram exemplars go into the bucket that contains their value
+    let family = exposition
+        .families
+        .get_mut("request_duration_seconds")
+        .unwrap();
+    let sample = family.iter_samples_mut().next().unwrap();
+    sample.set_exemplar(Some(exemplar(0.75))).unwrap();
+    match &sample.value {
+        PrometheusValue::Histogram(h) => {
+            assert_eq!(h.buckets[0].exemplar, None);
+            assert_eq!(h.buckets[1].exemplar, Some(exemplar(0.75)));
+        }
+        other => panic!("Expected a histogram, got {:?}", other),
+    }
+
+    let family = exposition.families.get_mut("temperature").unwrap();
+    let sample = family.iter_samples_mut().next().unwrap();
+    assert_eq!(
+        sample
+            .set_exemplar(Some(exemplar(1.)))
+            .unwrap_err()
+            .to_string(),
+        "Only Counters and Histograms can have exemplars"
+    );
+}
+
+#[test]
+fn test_exemplar_label_order() {
+    use crate::Exemplar;
+
+    let labels: Vec<(String, String)> = ["trace_id", "span_id", "env", "zone", "app"]
+        .iter()
+        .enumerate()
+        .map(|(i, name)| (String::from(*name), i.to_string()))
+        .collect();
+
+    let exemplar = Exemplar::new(labels.iter().cloned().collect(), 0.5, Some(1000.));
+    let expected = "# {app=\"4\",env=\"2\",span_id=\"1\",trace_id=\"0\",zone=\"3\"} 0.5 1000";
+    assert_eq!(exemplar.to_string(), expected);
+
+    // The same labels inserted in a different order render the same way
+    let exemplar = Exemplar::new(labels.into_iter().rev().collect(), 0.5, Some(1000.));
+    assert_eq!(exemplar.to_string(), expected);
+}
+
+#[test]
+fn test_upsert_sample() {
+    use crate::{MetricNumber, PrometheusValue, Sample, UpsertResult};
+
+    let mut exposition = parse_prometheus("# TYPE up gauge\nup{instance=\"a\"} 1\n").unwrap();
+    let family = exposition.families.get_mut("up").unwrap();
+    let sample = |instance: &str, value: i64| {
+        Sample::new(
+            vec![String::from(instance)],
+            None,
+            PrometheusValue::Gauge(MetricNumber::Int(value)),
+        )
+    };
+
+    assert!(matches!(
+        family.upsert_sample(sample("b", 1)).unwrap(),
+        UpsertResult::Inserted
+    ));
+    assert_eq!(family.iter_samples().count(), 2);
+
+    match family.upsert_sample(sample("a", 0)).unwrap() {
+        UpsertResult::Replaced(old) => {
+            assert_eq!(old.value, PrometheusValue::Gauge(MetricNumber::Int(1)))
+        }
+        other => panic!("Expected the sample to be replaced, got {:?}", other),
+    }
+
+    assert_eq!(family.iter_samples().count(), 2);
+    assert_eq!(
+        family.to_string(),
+        "# TYPE up gauge\nup{instance=\"a\"} 0\nup{instance=\"b\"} 1\n"
+    );
+
+    // The labels still have to match the family's
+    let no_labels = Sample::new(vec![], None, PrometheusValue::Gauge(MetricNumber::Int(1)));
+    assert!(family.upsert_sample(no_labels).is_err());
+}
+
+#[test]
+fn test_type_predicates() {
+    use crate::{OpenMetricsType, PrometheusType};
+
+    let exposition = parse_prometheus(
+        "e 1\n# TYPE a_total counter\na_total 1\n# TYPE b gauge\nb 1\n# TYPE c histogram\nc_bucket{le=\"+Inf\"} 1\n# TYPE d summary\nd_count 1\n",
+    )
+    .unwrap();
+    let predicates = |name: &str| {
+        let f = &exposition.families[name];
+        [
+            f.is_counter(),
+            f.is_gauge(),
+            f.is_histogram(),
+            f.is_summary(),
+            f.is_unknown(),
+        ]
+    };
+
+    assert_eq!(predicates("a_total"), [true, false, false, false, false]);
+    assert_eq!(predicates("b"), [false, true, false, false, false]);
+    assert_eq!(predicates("c"), [false, false, true, false, false]);
+    assert_eq!(predicates("d"), [false, false, false, true, false]);
+    assert_eq!(predicates("e"), [false, false, false, false, true]);
+
+    let exposition = parse_openmetrics(
+        "# TYPE a counter\na_total 1\n# TYPE b gauge\nb 1\n# TYPE c histogram\nc_bucket{le=\"+Inf\"} 1\n# TYPE d gaugehistogram\nd_bucket{le=\"+Inf\"} 1\n# TYPE e stateset\ne{e=\"x\"} 1\n# TYPE f summary\nf_count 1\n# TYPE g info\ng_info 1\n# TYPE h unknown\nh 1\n# EOF\n",
+    )
+    .unwrap();
+    let predicates = |name: &str| {
+        let f = &exposition.families[name];
+        [
+            f.is_counter(),
+            f.is_gauge(),
+            f.is_histogram(),
+            f.is_gauge_histogram(),
+            f.is_state_set(),
+            f.is_summary(),
+            f.is_info(),
+            f.is_unknown(),
+        ]
+    };
+
+    for (i, name) in ["a", "b", "c", "d", "e", "f", "g", "h"].iter().enumerate() {
+        let mut expected = [false; 8];
+        expected[i] = true;
+        assert_eq!(predicates(name), expected, "{}", name);
+    }
+
+    assert!(PrometheusType::Counter.is_cumulative());
+    assert!(PrometheusType::Histogram.is_cumulative());
+    assert!(PrometheusType::Summary.is_cumulative());
+    assert!(!PrometheusType::Gauge.is_cumulative());
+    assert!(!PrometheusType::Unknown.is_cumulative());
+
+    assert!(OpenMetricsType::Counter.is_cumulative());
+    assert!(OpenMetricsType::Histogram.is_cumulative());
+    assert!(OpenMetricsType::Summary.is_cumulative());
+    assert!(!OpenMetricsType::GaugeHistogram.is_cumulative());
+    assert!(!OpenMetricsType::Gauge.is_cumulative());
+    assert!(!OpenMetricsType::StateSet.is_cumulative());
+    assert!(!OpenMetricsType::Info.is_cumulative());
+    assert!(!OpenMetricsType::Unknown.is_cumulative());
+}
+
+#[test]
+fn test_float_formatting() {
+    use crate::{MetricNumber, PrometheusValue};
+
+    let tricky = [
+        0.1 + 0.2,
+        1.,
+        -2.5,
+        1e21,
+        1e-7,
+        123456789.125,
+        f64::MAX,
+        f64::MIN_POSITIVE,
+        5e-324,
+    ];
+
+    // Whichever formatting is used, every float parses back to exactly the same value
+    for n in tricky.iter() {
+        let rendered = MetricNumber::Float(*n).to_string();
+        let exposition = parse_prometheus(&format!("# TYPE a gauge\na {}\n", rendered)).unwrap();
+        let value = match &exposition.families["a"]
+            .iter_samples()
+            .next()
+            .unwrap()
+            .value
+        {
+            PrometheusValue::Gauge(value) => value.as_f64(),
+            other => panic!("Expected a gauge, got {:?}", other),
+        };
+
+        assert_eq!(
+            value.to_bits(),
+            n.to_bits(),
+            "{} rendered as {}",
+            n,
+            rendered
+        );
+    }
+
+    let expected: &[&str] = if cfg!(feature = "shortest_floats") {
+        &[
+            "0.30000000000000004",
+            "1",
+            "-2.5",
+            "1e21",
+            "1e-7",
+            "123456789.125",
+        ]
+    } else {
+        &[
+            "0.30000000000000004",
+            "1",
+            "-2.5",
+            "1000000000000000000000",
+            "0.0000001",
+            "123456789.125",
+        ]
+    };
+
+    for (n, expected) in tricky.iter().zip(expected) {
+        assert_eq!(&MetricNumber::Float(*n).to_string(), expected);
+    }
+}
+
+#[test]
+fn test_families_matching() {
+    let exposition = parse_prometheus(
+        "# TYPE http_requests_total counter\nhttp_requests_total 1\n# TYPE http_errors_total counter\nhttp_errors_total 1\n# TYPE https_requests_total counter\nhttps_requests_total 1\n# TYPE process_cpu_seconds_total counter\nprocess_cpu_seconds_total 1\n",
+    )
+    .unwrap();
+
+    let names = |pattern: &str| -> Vec<&str> {
+        exposition
+            .families_matching(pattern)
+            .iter()
+            .map(|f| f.family_name.as_str())
+            .collect()
+    };
+
+    assert_eq!(
+        names("http_*"),
+        ["http_errors_total", "http_requests_total"]
+    );
+    assert_eq!(
+        names("http*_requests_total"),
+        ["http_requests_total", "https_requests_total"]
+    );
+    assert_eq!(names("http?_requests_total"), ["https_requests_total"]);
+    assert_eq!(names("*_seconds_*"), ["process_cpu_seconds_total"]);
+    assert_eq!(names("*").len(), 4);
+    assert_eq!(names("http_requests_total"), ["http_requests_total"]);
+    assert!(names("http_").is_empty());
+    assert!(names("grpc_*").is_empty());
+}
+
+#[test]
+fn test_set_timestamp() {
+    use crate::{MetricNumber, PrometheusValue, Sample};
+
+    let mut sample = Sample::new(vec![], None, PrometheusValue::Gauge(MetricNumber::Int(1)));
+    sample.set_timestamp(Some(1500.)).unwrap();
+    assert_eq!(sample.timestamp, Some(1500.));
+
+    for timestamp in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY].iter() {
+        assert!(sample.set_timestamp(Some(*timestamp)).is_err());
+        assert_eq!(sample.timestamp, Some(1500.));
+    }
+
+    sample.set_timestamp(None).unwrap();
+    assert_eq!(sample.timestamp, None);
+}
+
+#[test]
+fn test_to_prometheus() {
+    let exposition = parse_openmetrics(
+        "# TYPE queue_wait_seconds gaugehistogram
+queue_wait_seconds_bucket{queue=\"a\",le=\"1\"} 2 10
+queue_wait_seconds_bucket{queue=\"a\",le=\"+Inf\"} 3 10
+queue_wait_seconds_gcount{queue=\"a\"} 3 10
+queue_wait_seconds_gsum{queue=\"a\"} 4.5 10
+# TYPE requests counter
+requests_total 7
+# TYPE build info
+build_info{version=\"1.2\"} 1
+# EOF
+",
+    )
+    .unwrap();
+
+    let converted = exposition.to_prometheus().unwrap();
+    let mut names: Vec<_> = converted.families.keys().map(String::as_str).collect();
+    names.sort_unstable();
+    assert_eq!(
+        names,
+        [
+            "build_info",
+            "queue_wait_seconds_bucket",
+            "queue_wait_seconds_gcount",
+            "queue_wait_seconds_gsum",
+            "requests_total",
+        ]
+    );
+
+    // The gaugehistogram is decomposed into gauges, with timestamps converted to milliseconds
+    let buckets = &converted.families["queue_wait_seconds_bucket"];
+    assert!(buckets.is_gauge());
+    assert_eq!(
+        buckets.get_label_names(),
+        &[String::from("queue"), String::from("le")]
+    );
+    let rendered = converted.to_prometheus_text();
+    assert!(rendered.contains("queue_wait_seconds_bucket{queue=\"a\",le=\"1\"} 2 10000\n"));
+    assert!(rendered.contains("queue_wait_seconds_bucket{queue=\"a\",le=\"+Inf\"} 3 10000\n"));
+    assert!(rendered.contains("queue_wait_seconds_gcount{queue=\"a\"} 3 10000\n"));
+    assert!(rendered.contains("queue_wait_seconds_gsum{queue=\"a\"} 4.5 10000\n"));
+
+    assert!(converted.families["requests_total"].is_counter());
+    assert!(rendered.contains("build_info{version=\"1.2\"} 1\n"));
+
+    // The result is valid Prometheus text
+    assert_eq!(parse_prometheus(&rendered).unwrap().families.len(), 5);
+}
+
+#[test]
+fn test_parse_by_content_type() {
+    use crate::{
+        parse_by_content_type, Exposition, OPENMETRICS_CONTENT_TYPE, PROMETHEUS_CONTENT_TYPE,
+    };
+
+    let prometheus = "# TYPE foo gauge\nfoo 1\n";
+    let openmetrics = "# TYPE foo gauge\nfoo 1\n# EOF\n";
+
+    for content_type in [
+        PROMETHEUS_CONTENT_TYPE,
+        "text/plain",
+        "Text/Plain;version=0.0.4",
+        "",
+    ]
+    .iter()
+    {
+        match parse_by_content_type(content_type, prometheus).unwrap() {
+            Exposition::Prometheus(e) => assert_eq!(e, parse_prometheus(prometheus).unwrap()),
+            other => panic!("Expected a Prometheus exposition, got {:?}", other),
+        }
+    }
+
+    for content_type in [
+        OPENMETRICS_CONTENT_TYPE,
+        "application/openmetrics-text",
+        "application/openmetrics-text; version=0.0.1",
+    ]
+    .iter()
+    {
+        match parse_by_content_type(content_type, openmetrics).unwrap() {
+            Exposition::OpenMetrics(e) => assert_eq!(e, parse_openmetrics(openmetrics).unwrap()),
+            other => panic!("Expected an OpenMetrics exposition, got {:?}", other),
+        }
+    }
+
+    // OpenMetrics requires the # EOF, so the wrong parser being picked shows up as an error
+    assert!(parse_by_content_type(OPENMETRICS_CONTENT_TYPE, prometheus).is_err());
+    assert!(parse_by_content_type("application/json", prometheus).is_err());
+}
+
+#[test]
+fn test_histogram_from_observations() {
+    use crate::{HistogramValue, MetricNumber};
+
+    let histogram =
+        HistogramValue::from_observations(&[1., 0.1, 0.5], &[0.05, 0.1, 0.3, 0.7, 2., 5.]);
+
+    let buckets: Vec<_> = histogram
+        .buckets
+        .iter()
+        .map(|b| (b.upper_bound, b.count))
+        .collect();
+    assert_eq!(
+        buckets,
+        vec![
+            (0.1, MetricNumber::Int(2)),
+            (0.5, MetricNumber::Int(3)),
+            (1., MetricNumber::Int(4)),
+            (f64::INFINITY, MetricNumber::Int(6)),
+        ]
+    );
+    assert_eq!(histogram.sum, Some(MetricNumber::Float(8.15)));
+    assert_eq!(histogram.count, Some(6));
+
+    // An explicit +Inf bound isn't duplicated, and an empty histogram still has its +Inf bucket
+    let histogram = HistogramValue::from_observations(&[1., f64::INFINITY], &[]);
+    assert_eq!(histogram.buckets.len(), 2);
+    assert_eq!(histogram.buckets[1].count, MetricNumber::Int(0));
+    assert_eq!(histogram.count, Some(0));
+}
+
+#[test]
+fn test_sample_value_eq() {
+    use crate::{MetricsExposition, PrometheusType, PrometheusValue};
+
+    let first = parse_prometheus(
+        "# TYPE temperature gauge
+temperature{room=\"kitchen\"} 21 1000
+temperature{room=\"garage\"} 9 1000
+# TYPE request_duration_seconds histogram
+request_duration_seconds_bucket{le=\"0.5\"} 1 # {trace_id=\"abc\"} 0.2
+request_duration_seconds_bucket{le=\"+Inf\"} 3
+request_duration_seconds_sum 1.5
+request_duration_seconds_count 3
+",
+    )
+    .unwrap();
+    let second = parse_prometheus(
+        "# TYPE temperature gauge
+temperature{room=\"kitchen\"} 21 2000
+temperature{room=\"garage\"} 10 2000
+# TYPE request_duration_seconds histogram
+request_duration_seconds_bucket{le=\"0.5\"} 1 # {trace_id=\"def\"} 0.3
+request_duration_seconds_bucket{le=\"+Inf\"} 3
+request_duration_seconds_sum 1.5
+request_duration_seconds_count 3
+",
+    )
+    .unwrap();
+
+    let sample =
+        |exposition: &MetricsExposition<PrometheusType, PrometheusValue>,
+         name: &str,
+         labels: &[(&str, &str)]| { exposition.get_sample(name, labels).unwrap().clone() };
+
+    // Equal values with different timestamps are equal
+    let kitchen = sample(&first, "temperature", &[("room", "kitchen")]);
+    assert!(kitchen.value_eq(&sample(&second, "temperature", &[("room", "kitchen")])));
+    assert_ne!(
+        kitchen.timestamp,
+        sample(&second, "temperature", &[("room", "kitchen")]).timestamp
+    );
+
+    // But different values or labels aren't
+    let garage = sample(&first, "temperature", &[("room", "garage")]);
+    assert!(!garage.value_eq(&sample(&second, "temperature", &[("room", "garage")])));
+    assert!(!kitchen.value_eq(&garage));
+
+    // Exemplars are compared, unless they're explicitly ignored
+    let histogram = sample(&first, "request_duration_seconds", &[]);
+    let other = sample(&second, "request_duration_seconds", &[]);
+    assert!(!histogram.value_eq(&other));
+    assert!(histogram.value_eq_ignoring_exemplars(&other));
+    assert!(kitchen.value_eq_ignoring_exemplars(&sample(
+        &second,
+        "temperature",
+        &[("room", "kitchen")]
+    )));
+}
+
+#[test]
+fn test_absolute_buckets() {
+    use crate::PrometheusValue;
+
+    let exposition = parse_prometheus(
+        "# TYPE latency histogram
+latency_bucket{le=\"0.1\"} 2
+latency_bucket{le=\"0.5\"} 5
+latency_bucket{le=\"1\"} 5
+latency_bucket{le=\"+Inf\"} 9
+latency_sum 7.5
+latency_count 9
+",
+    )
+    .unwrap();
+
+    let histogram = match &exposition.families["latency"]
+        .iter_samples()
+        .next()
+        .unwrap()
+        .value
+    {
+        PrometheusValue::Histogram(h) => h.clone(),
+        _ => panic!("Expected a histogram"),
+    };
+
+    assert_eq!(
+        histogram.absolute_buckets(),
+        vec![(0.1, 2.), (0.5, 3.), (1., 0.), (f64::INFINITY, 4.)]
+    );
+
+    // The absolute counts add back up to the total
+    let total: f64 = histogram.absolute_buckets().iter().map(|(_, c)| c).sum();
+    assert_eq!(total, 9.);
+}
+
+#[test]
+fn test_histogram_total_count() {
+    use crate::{HistogramBucket, HistogramValue, MetricNumber};
+
+    let histogram = HistogramValue::from_observations(&[0.5, 1.], &[0.1, 0.7, 3.]);
+    assert_eq!(histogram.total_count(), Some(3));
+
+    // Without a _count, the +Inf bucket is used instead
+    let histogram = HistogramValue {
+        count: None,
+        ..histogram
+    };
+    assert_eq!(histogram.total_count(), Some(3));
+
+    let histogram = HistogramValue {
+        sum: None,
+        count: None,
+        created: None,
+        buckets: vec![HistogramBucket {
+            count: MetricNumber::Int(4),
+            upper_bound: 1.,
+            exemplar: None,
+        }],
+    };
+    assert_eq!(histogram.total_count(), None);
+}
+
+#[test]
+fn test_families_of_type() {
+    use crate::{OpenMetricsType, PrometheusType};
+
+    let exposition = parse_prometheus(
+        "# TYPE requests_total counter
+requests_total 10
+# TYPE latency histogram
+latency_bucket{le=\"+Inf\"} 2
+latency_sum 1
+latency_count 2
+# TYPE size histogram
+size_bucket{le=\"+Inf\"} 1
+size_sum 100
+size_count 1
+# TYPE temperature gauge
+temperature 21.5
+",
+    )
+    .unwrap();
+
+    let mut names: Vec<_> = exposition
+        .families_of_type(PrometheusType::Histogram)
+        .map(|f| f.family_name.as_str())
+        .collect();
+    names.sort_unstable();
+    assert_eq!(names, ["latency", "size"]);
+    assert_eq!(
+        exposition.families_of_type(PrometheusType::Summary).count(),
+        0
+    );
+
+    let exposition = parse_openmetrics(
+        "# TYPE foo histogram
+foo_bucket{le=\"+Inf\"} 2
+foo_sum 1
+foo_count 2
+# TYPE bar gaugehistogram
+bar_bucket{le=\"+Inf\"} 2
+# TYPE baz counter
+baz_total 1
+# EOF
+",
+    )
+    .unwrap();
+
+    let names: Vec<_> = exposition
+        .families_of_type(OpenMetricsType::Histogram)
+        .map(|f| f.family_name.as_str())
+        .collect();
+    assert_eq!(names, ["foo"]);
+}
+
+#[test]
+fn test_contains_labelset() {
+    let exposition = parse_prometheus(
+        "# TYPE requests_total counter
+requests_total{path=\"/\",code=\"200\"} 10
+requests_total{path=\"/\",code=\"500\"} 1
+# TYPE temperature gauge
+temperature 21.5
+",
+    )
+    .unwrap();
+
+    let family = &exposition.families["requests_total"];
+    assert!(family.contains_labelset(&[("path", "/"), ("code", "200")]));
+    // The labels can be in any order
+    assert!(family.contains_labelset(&[("code", "500"), ("path", "/")]));
+
+    assert!(!family.contains_labelset(&[("path", "/"), ("code", "404")]));
+    assert!(!family.contains_labelset(&[("path", "/")]));
+    assert!(!family.contains_labelset(&[("path", "/"), ("code", "200"), ("method", "GET")]));
+    assert!(!family.contains_labelset(&[("path", "/"), ("status", "200")]));
+
+    assert!(exposition.families["temperature"].contains_labelset(&[]));
+}
@@ -0,0 +1,23 @@
+use crate::{Exemplar, MetricNumber, PrometheusType, Timestamp};
+
+/// Callbacks for parsing an exposition without building a `MetricsExposition`, e.g. to stream samples into
+/// a custom representation. The parser calls `on_family_start` once for each family, then `on_sample` for
+/// each of its sample lines (before they're grouped into histograms or summaries), then `on_family_end`
+pub trait MetricVisitor<TypeSet = PrometheusType> {
+    /// Called at the start of each family. Types default to Unknown, and help and unit to empty strings, if the
+    /// family doesn't have descriptors for them
+    fn on_family_start(&mut self, name: &str, family_type: &TypeSet, help: &str, unit: &str);
+
+    /// Called for each sample line in the current family. `name` is the name on the line itself, so it can have a
+    /// suffix (e.g. `_bucket`) that the family name doesn't
+    fn on_sample(
+        &mut self,
+        name: &str,
+        labels: &[(&str, &str)],
+        value: MetricNumber,
+        timestamp: Option<Timestamp>,
+        exemplar: Option<&Exemplar>,
+    );
+
+    fn on_family_end(&mut self) {}
+}